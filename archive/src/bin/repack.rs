@@ -0,0 +1,118 @@
+use clap::Parser;
+use k_archives::write::{builder_for, repack, RepackOp, TargetFormat};
+use k_archives::{mount, ChecksumType};
+use std::path::{Path, PathBuf};
+
+fn parse_put(arg: &str) -> Result<(PathBuf, PathBuf), String> {
+    let (name, source) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=PATH, got {}", arg))?;
+    Ok((name.into(), source.into()))
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Archive to modify, anything unarchive can read
+    input: PathBuf,
+    /// Where to write the result, the format goes by its extension (mar, qar, cab, arc, bar or lst).
+    /// If none, the input is overwritten
+    output: Option<PathBuf>,
+    /// Add a new entry NAME with the contents of the file PATH
+    #[clap(long, value_name = "NAME=PATH", parse(try_from_str = parse_put))]
+    add: Vec<(PathBuf, PathBuf)>,
+    /// Replace the contents of entry NAME with the file PATH
+    #[clap(long, value_name = "NAME=PATH", parse(try_from_str = parse_put))]
+    replace: Vec<(PathBuf, PathBuf)>,
+    /// Leave entry NAME out
+    #[clap(long, value_name = "NAME")]
+    remove: Vec<PathBuf>,
+    /// Size each lst part is kept under, in bytes
+    #[clap(long, default_value_t = 0x4000_0000)]
+    part_size: u64,
+    /// Fix timestamps so repacking the same input twice gives identical output
+    #[clap(long)]
+    reproducible: bool,
+    /// Mount the written archive again and check every file reads back as packed
+    #[clap(long)]
+    verify: bool,
+}
+
+// mar files are encrypted when their name says so, which the parser goes by too
+fn target_for(output: &Path, part_size: u64) -> Option<TargetFormat> {
+    let encrypt = output.to_string_lossy().contains("M32");
+    match output.extension()?.to_str()? {
+        "mar" => Some(TargetFormat::Mar { encrypt }),
+        "cab" => Some(TargetFormat::Cab { compress: true }),
+        "arc" => Some(TargetFormat::Arc { level: 6 }),
+        "bar" => Some(TargetFormat::Bar { short_names: false }),
+        "qar" => Some(TargetFormat::Qar),
+        "lst" => Some(TargetFormat::LstSet {
+            part_size,
+            checksum_type: ChecksumType::Md5,
+        }),
+        _ => None,
+    }
+}
+
+fn main() {
+    let args: Args = Args::parse();
+    let output = args.output.clone().unwrap_or_else(|| args.input.clone());
+    let Some(target) = target_for(&output, args.part_size) else {
+        eprintln!(
+            "can't tell what to write {} as, use a .mar, .qar, .cab, .arc, .bar or .lst extension",
+            output.display()
+        );
+        std::process::exit(1);
+    };
+    // the input is still being read from while the output is written, so overwriting it
+    // goes through a temporary file next to it
+    let in_place = output == args.input;
+    if in_place && matches!(target, TargetFormat::LstSet { .. }) {
+        eprintln!("lst sets can't be repacked in place, give an output path");
+        std::process::exit(1);
+    }
+    let dest = if in_place {
+        let mut name = output.file_name().unwrap_or_default().to_os_string();
+        name.push(".repack");
+        output.with_file_name(name)
+    } else {
+        output.clone()
+    };
+
+    let ops: Vec<RepackOp> = args
+        .add
+        .into_iter()
+        .map(|(name, source)| RepackOp::Add { name, source })
+        .chain(
+            args.replace
+                .into_iter()
+                .map(|(name, source)| RepackOp::Replace { name, source }),
+        )
+        .chain(args.remove.into_iter().map(RepackOp::Remove))
+        .collect();
+    let archive = mount(args.input).expect("Failed to parse konami update archive");
+    let mut builder = builder_for(&target);
+    builder.set_reproducible(args.reproducible);
+    repack(&archive, &ops, builder.as_mut()).expect("Failed to apply changes");
+    builder.finish(&dest).expect("Failed to write the archive");
+    // checked before the input gets replaced, so a bad repack doesn't cost the original
+    if args.verify {
+        let report = builder
+            .verify(&dest)
+            .expect("Failed to mount the written archive");
+        for problem in &report.problems {
+            eprintln!("{}", problem);
+        }
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+        println!("verified {} files", report.verified);
+    }
+    drop(builder);
+    drop(archive);
+    if in_place {
+        std::fs::rename(&dest, &output).expect("Failed to replace the input");
+    }
+    println!("{}", output.display());
+}
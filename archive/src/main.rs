@@ -0,0 +1,174 @@
+use clap::{ArgEnum, Parser};
+use k_archives::write::{builder_for, preset, write_info, TargetFormat, PRESETS};
+use k_archives::ChecksumType;
+use std::path::PathBuf;
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum Format {
+    /// A single MASMAR0 archive
+    Mar,
+    /// A cabinet holding an arcfile and filelist
+    Cab,
+    /// Mar parts split by --part-size, plus a ULST listing them
+    Lst,
+    /// A pop'n music arc, compressed at --level
+    Arc,
+    /// A bar, with 252 byte names if --short-names is given
+    Bar,
+    /// A single QAR archive
+    Qar,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum Encryption {
+    /// The gitadora cipher
+    M32,
+}
+
+fn parse_checksum(name: &str) -> Result<ChecksumType, String> {
+    ChecksumType::from_name(name).ok_or_else(|| format!("unknown checksum type {}", name))
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Folder to pack, entries are named relative to it
+    input: PathBuf,
+    /// Archive to write. For lst, the ULST (parts are written next to it)
+    output: PathBuf,
+    /// Format to pack into
+    #[clap(short, long, arg_enum, required_unless_present = "preset")]
+    format: Option<Format>,
+    /// Pack the way a game expects instead of picking --format and --encrypt (eg. gitadora-m32, popn-m39)
+    #[clap(long, conflicts_with_all = &["format", "encrypt"])]
+    preset: Option<String>,
+    /// Encrypt file contents (mar and lst). Encrypted mar files need "M32" in their name to be read back
+    #[clap(long, arg_enum)]
+    encrypt: Option<Encryption>,
+    /// Store cab members without MSZIP compression
+    #[clap(long)]
+    no_compress: bool,
+    /// Write bar names in 252 bytes instead of 256, like pop'n music bars
+    #[clap(long)]
+    short_names: bool,
+    /// How hard to compress arc entries, from 0 (stored as is) to 9
+    #[clap(long, default_value_t = 6)]
+    level: u8,
+    /// Size each lst part is kept under, in bytes
+    #[clap(long, default_value_t = 0x4000_0000)]
+    part_size: u64,
+    /// Checksum written into the ULST for each part (md5 or crc32), and into the info descriptor
+    #[clap(long, default_value = "md5", parse(try_from_str = parse_checksum))]
+    checksum: ChecksumType,
+    /// Fix timestamps so packing the same folder twice gives identical output
+    #[clap(long)]
+    reproducible: bool,
+    /// Also write a NAME/info descriptor referencing the archive(s), next to the output with an .info extension
+    #[clap(long)]
+    info: bool,
+    /// Mount the written archive again and check every file reads back as packed
+    #[clap(long, conflicts_with = "append")]
+    verify: bool,
+    /// Add the folder's files to the existing output archive (mar or qar) instead of
+    /// overwriting it. What's already in it isn't rewritten
+    #[clap(long)]
+    append: bool,
+}
+
+fn main() {
+    let args: Args = Args::parse();
+    let name = args
+        .output
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (format, encrypt, target) = match (args.format, &args.preset) {
+        (Some(format), _) => {
+            let encrypt = args.encrypt.is_some();
+            let target = match format {
+                Format::Mar => TargetFormat::Mar { encrypt },
+                Format::Cab => TargetFormat::Cab {
+                    compress: !args.no_compress,
+                },
+                Format::Arc => TargetFormat::Arc { level: args.level },
+                Format::Bar => TargetFormat::Bar {
+                    short_names: args.short_names,
+                },
+                Format::Qar => TargetFormat::Qar,
+                Format::Lst => TargetFormat::LstSet {
+                    part_size: args.part_size,
+                    checksum_type: args.checksum,
+                },
+            };
+            (format, encrypt, target)
+        }
+        (None, Some(name)) => {
+            let Some(preset) = preset(name) else {
+                eprintln!("unknown preset {}, the known ones are:", name);
+                for preset in PRESETS {
+                    eprintln!("  {:<14} {}", preset.name, preset.description);
+                }
+                std::process::exit(1);
+            };
+            let (format, encrypt) = match preset.target {
+                TargetFormat::Mar { encrypt } => (Format::Mar, encrypt),
+                TargetFormat::Cab { .. } => (Format::Cab, false),
+                TargetFormat::Arc { .. } => (Format::Arc, false),
+                TargetFormat::Bar { .. } => (Format::Bar, false),
+                TargetFormat::Qar => (Format::Qar, false),
+                TargetFormat::LstSet { .. } | TargetFormat::Directory => (Format::Lst, false),
+            };
+            (format, encrypt, preset.target.clone())
+        }
+        (None, None) => unreachable!("clap requires --format or --preset"),
+    };
+    match format {
+        Format::Mar if encrypt && !name.contains("M32") => {
+            eprintln!(
+                "warning: {} doesn't have M32 in its name, so it won't be decrypted when read back",
+                name
+            )
+        }
+        // lst parts are encrypted based on their name, which comes from the ULST's
+        Format::Lst if encrypt != name.contains("M32") => {
+            eprintln!("lst parts are encrypted exactly when the ULST's name contains M32, rename {} to match", name);
+            std::process::exit(1);
+        }
+        Format::Cab | Format::Arc | Format::Bar | Format::Qar if encrypt => {
+            eprintln!("only mar and lst can be encrypted");
+            std::process::exit(1);
+        }
+        _ => {}
+    }
+    let mut builder = builder_for(&target);
+    builder.set_reproducible(args.reproducible);
+    builder
+        .add_dir(&args.input)
+        .expect("Failed to read the input folder");
+    let archives = if args.append {
+        builder.append(&args.output)
+    } else {
+        builder.finish(&args.output)
+    }
+    .expect("Failed to write the archive");
+    println!("{}", args.output.display());
+    if args.verify {
+        let report = builder
+            .verify(&args.output)
+            .expect("Failed to mount the written archive");
+        for problem in &report.problems {
+            eprintln!("{}", problem);
+        }
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+        println!("verified {} files", report.verified);
+    }
+    if args.info {
+        let info = args.output.with_extension("info");
+        let stem = info.file_stem().unwrap_or_default().to_string_lossy();
+        write_info(&stem, &archives, args.checksum, &info)
+            .expect("Failed to write the info descriptor");
+        println!("{}", info.display());
+    }
+}
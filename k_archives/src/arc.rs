@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::common::*;
+use crate::compressed::Compression;
+
+// the pop'n arc magic, 0x19751120 little endian
+pub(crate) const MAGIC: [u8; 4] = [0x20, 0x11, 0x75, 0x19];
+// magic, version, entry count and compression flag
+const TABLE_START: u64 = 0x10;
+// name offset, data offset, decompressed size, stored size
+const RECORD_SIZE: u64 = 0x10;
+
+// names sit in a string table somewhere after the records, null terminated
+fn read_name(
+    file: &mut InternalFile,
+    offset: u64,
+    encoding: NameEncoding,
+) -> Result<String, KArchiveError> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut name = Vec::new();
+    BufReader::new(file).read_until(0, &mut name)?;
+    if name.pop() != Some(0) {
+        return Err(KArchiveError::ParseError(
+            "name isn't null terminated".into(),
+        ));
+    }
+    encoding.decode(&name)
+}
+
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let buffer = benchmark(&path, options)?;
+    parse_from(path, buffer, options)
+}
+
+/// Parses from `buffer` when there is one, otherwise straight from the file at `path`.
+/// Entries whose stored size differs from their real size are lz77 compressed.
+pub(crate) fn parse_from(
+    path: PathBuf,
+    buffer: Option<Vec<u8>>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    let mut file = match &buffer {
+        Some(buf) => InternalFile::Buffer(Cursor::new(buf)),
+        None => InternalFile::open(&path)?,
+    };
+    let end = archive_len(&buffer, &path)?;
+    file.seek(SeekFrom::Start(4))?;
+    let version = file.read_u32::<LittleEndian>()?;
+    if version != 1 {
+        return Err(KArchiveError::InvalidHeader {
+            format: "arc",
+            reason: format!("unknown version {}", version),
+        });
+    }
+    let count = file.read_u32::<LittleEndian>()?;
+    let _compression = file.read_u32::<LittleEndian>()?;
+    check_entry_count("arc", count as u64, RECORD_SIZE, TABLE_START, end, options)?;
+    let mut records = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut record = [0_u32; 4];
+        file.read_u32_into::<LittleEndian>(&mut record)?;
+        records.push(record);
+    }
+
+    let mut files = HashMap::new();
+    let mut truncated: Option<PathBuf> = None;
+    for (index, [name_offset, offset, size, stored_size]) in records.into_iter().enumerate() {
+        let name = read_name(&mut file, name_offset as u64, options.name_encoding)
+            .map_err(|e| name_error("arc", index, e))?;
+        check_entry_name("arc", index, &name)?;
+        let (offset, size, stored_size) = (offset as u64, size as u64, stored_size as u64);
+        let available =
+            payload_available("arc", index, &name, (offset, stored_size), end, options)?;
+        if available.is_some() {
+            truncated.get_or_insert_with(|| name.clone().into());
+        }
+        files.insert(
+            name.into(),
+            KFileInfo {
+                size,
+                offset,
+                available,
+                compression: (stored_size != size).then_some(Compression::Lz77 {
+                    packed_size: stored_size,
+                }),
+                ..Default::default()
+            },
+        );
+    }
+    let mut archive = KArchive::new(path.clone(), files, buffer);
+    if let Some(entry) = truncated {
+        archive.add_warning(KArchiveWarning::Truncated { path, entry });
+    }
+    Ok(archive)
+}
+
+/// Builds a pop'n music arc. Entries are lz77 compressed unless that doesn't make them
+/// any smaller, in which case they're stored as is.
+pub struct ArcWriter<'a> {
+    files: BTreeMap<PathBuf, EntrySource<'a>>,
+    level: u8,
+}
+
+impl Default for ArcWriter<'_> {
+    fn default() -> Self {
+        Self {
+            files: BTreeMap::new(),
+            level: 6,
+        }
+    }
+}
+
+impl<'a> ArcWriter<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file to the archive from an in memory buffer.
+    pub fn add_file(&mut self, name: impl Into<PathBuf>, data: Vec<u8>) -> &mut Self {
+        self.files.insert(name.into(), EntrySource::Buffer(data));
+        self
+    }
+
+    /// Adds a file that is read from `source` when the archive gets written.
+    pub fn add_path(&mut self, name: impl Into<PathBuf>, source: impl Into<PathBuf>) -> &mut Self {
+        self.files
+            .insert(name.into(), EntrySource::Path(source.into()));
+        self
+    }
+
+    /// Recursively adds every file under `dir`, named relative to `dir`.
+    pub fn add_dir(&mut self, dir: &Path) -> Result<&mut Self, KArchiveError> {
+        for (name, path) in walk_dir(dir)? {
+            self.add_path(name, path);
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn add_source(&mut self, name: PathBuf, source: EntrySource<'a>) -> &mut Self {
+        self.files.insert(name, source);
+        self
+    }
+
+    pub(crate) fn sources(&self) -> &BTreeMap<PathBuf, EntrySource<'a>> {
+        &self.files
+    }
+
+    /// How hard to compress, from 0 (store everything as is) to 9. Defaults to 6.
+    pub fn set_compression_level(&mut self, level: u8) -> &mut Self {
+        self.level = level.min(9);
+        self
+    }
+
+    /// Writes the archive out to `writer`, returning the writer when done.
+    /// Entries being compressed are held in memory one at a time.
+    pub fn write<W: Write + Seek>(&self, mut writer: W) -> Result<W, KArchiveError> {
+        let too_big =
+            |what: &str| KArchiveError::WriteError(format!("{} is too large for an arc", what));
+        let count = u32::try_from(self.files.len()).map_err(|_| too_big("entry count"))?;
+        writer.write_all(&MAGIC)?;
+        for field in [1, count, (self.level > 0) as u32] {
+            writer.write_u32::<LittleEndian>(field)?;
+        }
+        // records get filled in once the payloads have been written and their offsets are known
+        let names_start = TABLE_START + RECORD_SIZE * count as u64;
+        writer.seek(SeekFrom::Start(names_start))?;
+        let mut name_offsets = Vec::with_capacity(self.files.len());
+        let mut pos = names_start;
+        for name in self.files.keys() {
+            let name = archive_name(name);
+            name_offsets.push(pos);
+            writer.write_all(name.as_bytes())?;
+            writer.write_u8(0)?;
+            pos += name.len() as u64 + 1;
+        }
+        let mut records = Vec::with_capacity(self.files.len());
+        for ((name, source), name_offset) in self.files.iter().zip(name_offsets) {
+            let size = source.size()?;
+            // a stored size equal to the real size is what marks an entry as uncompressed,
+            // so compressed data has to come out smaller to be used
+            let stored_size = if self.level > 0 {
+                let mut data = Vec::with_capacity(size as usize);
+                source.reader()?.read_to_end(&mut data)?;
+                let packed = crate::lz77::compress(&data, self.level);
+                let stored = if packed.len() < data.len() {
+                    packed
+                } else {
+                    data
+                };
+                writer.write_all(&stored)?;
+                stored.len() as u64
+            } else {
+                source.copy_to(&mut writer)?
+            };
+            let field = |value: u64| u32::try_from(value).map_err(|_| too_big(&archive_name(name)));
+            records.push([
+                field(name_offset)?,
+                field(pos)?,
+                field(size)?,
+                field(stored_size)?,
+            ]);
+            pos += stored_size;
+        }
+        writer.seek(SeekFrom::Start(TABLE_START))?;
+        for record in records {
+            for field in record {
+                writer.write_u32::<LittleEndian>(field)?;
+            }
+        }
+        writer.seek(SeekFrom::Start(pos))?;
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lz77::tests::pack_literals;
+    use std::fs::File;
+    use std::path::Path;
+
+    #[test]
+    fn arc_mount() {
+        let entries: [(&str, Vec<u8>, bool); 2] = [
+            ("data/chara.ifs", vec![3; 20], true),
+            ("data/raw.bin", b"stored as is".to_vec(), false),
+        ];
+        let mut arc = MAGIC.to_vec();
+        for field in [1, entries.len() as u32, 1] {
+            arc.extend_from_slice(&field.to_le_bytes());
+        }
+        let mut names = Vec::new();
+        let mut payloads = Vec::new();
+        let data_start = TABLE_START as usize + 16 * entries.len();
+        let names_len: usize = entries.iter().map(|(name, ..)| name.len() + 1).sum();
+        for (name, data, compress) in &entries {
+            let stored = if *compress {
+                pack_literals(data)
+            } else {
+                data.clone()
+            };
+            let fields = [
+                (data_start + names.len()) as u32,
+                (data_start + names_len + payloads.len()) as u32,
+                data.len() as u32,
+                stored.len() as u32,
+            ];
+            for field in fields {
+                arc.extend_from_slice(&field.to_le_bytes());
+            }
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+            payloads.extend(stored);
+        }
+        arc.extend(names);
+        arc.extend(payloads);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.arc");
+        std::fs::write(&path, arc).unwrap();
+        let archive = crate::mount(path).unwrap();
+        for (name, data, _) in entries {
+            let name = Path::new(name);
+            assert_eq!(archive.open(name).unwrap().size(), data.len() as u64);
+            assert_eq!(archive.read(name).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn arc_write_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let compressible = b"konami ".repeat(0x100);
+        let noise: Vec<u8> = (0..0x40_u32).map(|i| (i * 97 % 251) as u8).collect();
+        for level in [0, 6] {
+            let path = dir.path().join(format!("level{}.arc", level));
+            let mut writer = ArcWriter::new();
+            writer
+                .add_file("data/text.bin", compressible.clone())
+                .add_file("data/noise.bin", noise.clone())
+                .set_compression_level(level);
+            writer.write(File::create(&path).unwrap()).unwrap();
+            let len = std::fs::metadata(&path).unwrap().len();
+            assert_eq!(len < compressible.len() as u64, level > 0);
+
+            let archive = crate::mount(path).unwrap();
+            assert_eq!(
+                archive.read(Path::new("data/text.bin")).unwrap(),
+                compressible
+            );
+            assert_eq!(archive.read(Path::new("data/noise.bin")).unwrap(), noise);
+        }
+    }
+}
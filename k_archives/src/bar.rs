@@ -1,81 +1,290 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Cursor, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 use crate::common::*;
+use crate::salvage::{carve, plausible_name};
 
-fn read_file_name<T>(rdr: &mut T) -> Result<String, KArchiveError>
-where
-    T: BufRead + Seek,
-{
-    let mut buf = Vec::<u8>::new();
-    let size = rdr.read_until(0, &mut buf)?;
-    rdr.seek(SeekFrom::Current(256 - size as i64))?;
-    Ok(String::from_utf8(
-        buf.strip_suffix(&[0])
-            .ok_or(KArchiveError::Other(
-                "Failed to strip suffix (malformed or incomplete archive)",
-            ))?
-            .to_vec(),
-    )?
-    .trim_start_matches(['.', '\\'])
-    .replace('\\', "/")
-    .to_string())
+// name fields are fixed size and null terminated, with garbage after the terminator
+fn parse_file_name(field: &[u8], encoding: NameEncoding) -> Result<String, KArchiveError> {
+    let len = field
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(KArchiveError::Other(
+            "Failed to strip suffix (malformed or incomplete archive)",
+        ))?;
+    Ok(encoding
+        .decode(&field[..len])?
+        .trim_start_matches(['.', '\\'])
+        .replace('\\', "/"))
 }
 
-pub(crate) fn parse(path: PathBuf) -> Result<KArchive, KArchiveError> {
-    let buffer = benchmark(&path)?;
+// largest record header: 256 byte name, two magic numbers, size and an unknown field
+const HEADER_SIZE: usize = 272;
+
+// the two magic numbers after every record's name
+const RECORD_MAGIC: [u8; 8] = [3, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF];
+
+/// bar has no magic number, but the first record (12 bytes in) has its own after the name.
+pub(crate) fn looks_like_bar(header: &[u8]) -> bool {
+    [252, 256]
+        .iter()
+        .any(|len| header.get(12 + len..12 + len + 8) == Some(&RECORD_MAGIC[..]))
+}
+
+pub(crate) fn is_bar(path: &Path) -> Result<bool, KArchiveError> {
+    let mut header = Vec::new();
+    InternalFile::open(path)?
+        .take(12 + HEADER_SIZE as u64)
+        .read_to_end(&mut header)?;
+    Ok(looks_like_bar(&header))
+}
+
+// recognises an entry record when salvaging, for both the 256 and 252 byte name variants
+fn carve_record(header: &[u8]) -> Option<(String, u64, u64)> {
+    for name_len in [256, 252] {
+        if header[name_len..name_len + 8] != RECORD_MAGIC {
+            continue;
+        }
+        let (name, _) = plausible_name(&header[..name_len])?;
+        let size = u32::from_le_bytes(header[name_len + 8..name_len + 12].try_into().ok()?);
+        let name = name.trim_start_matches(['.', '\\']).replace('\\', "/");
+        return Some((name, name_len as u64 + 16, size as u64));
+    }
+    None
+}
+
+// parses an entry record from its header bytes, returning the name, header length, payload
+// size and the unknown field after the size
+fn parse_record(
+    header: &[u8],
+    index: usize,
+    encoding: NameEncoding,
+) -> Result<(String, u64, u64, u32), KArchiveError> {
+    if header.len() < 252 + 16 {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    let field = |at: usize| i32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+    // bar files are weird. in M39A bars, the filename takes 252 bytes rather than 256
+    // So let's check if this is one of those
+    let name_len = if field(256) == -1 { 252 } else { 256 };
+    if header.len() < name_len + 16 {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    let name =
+        parse_file_name(&header[..name_len], encoding).map_err(|e| name_error("bar", index, e))?;
+    check_entry_name("bar", index, &name)?;
+    let (magic1, magic2) = (field(name_len), field(name_len + 4));
+    if magic1 != 3 || magic2 != -1 {
+        return Err(invalid_entry(
+            "bar",
+            index,
+            &name,
+            format!("magic numbers are {} {}, expected 3 -1", magic1, magic2),
+        ));
+    }
+    let size = field(name_len + 8) as u32 as u64;
+    let unknown = field(name_len + 12) as u32;
+    Ok((name, name_len as u64 + 16, size, unknown))
+}
+
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let buffer = benchmark(&path, options)?;
+    parse_from(path, buffer, options)
+}
+
+/// Parses from `buffer` when there is one, otherwise straight from the file at `path`.
+pub(crate) fn parse_from(
+    path: PathBuf,
+    buffer: Option<Vec<u8>>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    // headers are read whole and payloads skipped, so there's nothing for a BufReader to do
     let mut file = match &buffer {
-        Some(buf) => BufReader::new(InternalFile::Buffer(Cursor::new(buf))),
-        None => BufReader::new(InternalFile::RealFile(File::open(&path)?)),
+        Some(buf) => InternalFile::Buffer(Cursor::new(buf)),
+        None => InternalFile::open(&path)?,
     };
     let mut files: HashMap<PathBuf, KFileInfo> = HashMap::new();
     // Skip the first 10 bytes
-    file.seek_relative(10)?;
+    file.seek(SeekFrom::Start(10))?;
     let file_count = file.read_u16::<LittleEndian>()?;
-    let parse_result = (0..file_count).try_for_each(|_| {
-        let name = read_file_name(&mut file)?;
-        // bar files are weird. in M39A bars, the filename takes 252 bytes rather than 256
-        // So let's check if we just read one of those
-        if file.read_i32::<LittleEndian>()? == -1 {
-            file.seek_relative(-8)?;
-        } else {
-            file.seek_relative(-4)?;
+    let end = archive_len(&buffer, &path)?;
+    // smallest possible record is an empty file with a 252 byte name
+    check_entry_count("bar", file_count as u64, 252 + 16, 12, end, options)?;
+    // entry that got cut off when the archive is truncated. nothing comes after it
+    let mut truncated: Option<PathBuf> = None;
+    // start of the entry being parsed, which is where salvaging starts from if it's bad
+    let mut resume_pos = 12;
+    let mut header = [0_u8; HEADER_SIZE];
+    let parse_result: Result<(), KArchiveError> = (0..file_count as usize).try_for_each(|index| {
+        if truncated.is_some() {
+            return Ok(());
         }
-        let magic1 = file.read_i32::<LittleEndian>()?;
-        let magic2 = file.read_i32::<LittleEndian>()?;
-        if magic1 != 3 || magic2 != -1 {
-            return Err(KArchiveError::ParseError(format!(
-                "magic numbers are wrong: {} {}",
-                magic1, magic2
-            )));
+        // one read per record. the last one can be shorter than the largest header
+        let header_len = usize::min(HEADER_SIZE, (end.saturating_sub(resume_pos)) as usize);
+        file.seek(SeekFrom::Start(resume_pos))?;
+        file.read_exact(&mut header[..header_len])?;
+        let (name, record_len, size, unknown) =
+            parse_record(&header[..header_len], index, options.name_encoding)?;
+        let offset = resume_pos + record_len;
+        let available = payload_available("bar", index, &name, (offset, size), end, options)?;
+        if available.is_some() {
+            truncated = Some(name.clone().into());
         }
-        let size = file.read_u32::<LittleEndian>()? as u64;
-        file.seek_relative(4)?;
-        let offset = file.stream_position()?;
-        file.seek_relative(size as i64)?;
+        resume_pos = offset + size;
 
         files.insert(
             name.into(),
             KFileInfo {
                 size,
                 offset,
-                cipher: None,
+                available,
+                raw_fields: vec![unknown],
+                ..Default::default()
             },
         );
         Ok(())
     });
+    let mut recovered = None;
+    let mut stopped = None;
+    let mut skipped = Vec::new();
     match parse_result {
         Ok(_) => {}
         Err(e) => {
             eprintln!("k_archives: Error in archive parsing: {}", e);
+            if options.salvage {
+                let carved = carve(&mut file, resume_pos, end, HEADER_SIZE, carve_record)?;
+                let mut kept = 0;
+                for (index, entry) in carved.into_iter().enumerate() {
+                    // the same check the entry table gets, so salvaging can't get around it
+                    if let Err(e) = check_entry_name("bar", index, &entry.name) {
+                        skipped.push(e.to_string());
+                        continue;
+                    }
+                    kept += 1;
+                    files.entry(entry.name.into()).or_insert(KFileInfo {
+                        size: entry.size,
+                        offset: entry.offset,
+                        ..Default::default()
+                    });
+                }
+                recovered = Some(kept);
+            } else {
+                // salvaging reports the damage itself
+                stopped = Some((files.len(), e.to_string()));
+            }
             eprintln!("k_archives: Continuing with {} files parsed", files.len());
         }
     }
-    Ok(KArchive::new(path, files, buffer))
+    let mut archive = KArchive::new(path.clone(), files, buffer);
+    for reason in skipped {
+        archive.add_warning(KArchiveWarning::SkippedEntry {
+            path: path.clone(),
+            reason,
+        });
+    }
+    if let Some((parsed, reason)) = stopped {
+        archive.add_warning(KArchiveWarning::ParseStopped {
+            path: path.clone(),
+            parsed,
+            reason,
+        });
+    }
+    if let Some(entry) = truncated {
+        archive.add_warning(KArchiveWarning::Truncated {
+            path: path.clone(),
+            entry,
+        });
+    }
+    if let Some(recovered) = recovered {
+        archive.add_warning(KArchiveWarning::Salvaged { path, recovered });
+    }
+    Ok(archive)
+}
+
+/// Builds a bar archive. Names are written backslash separated with a leading backslash,
+/// like official bars, in 256 byte fields unless `set_short_names` asks for the 252 byte
+/// ones M39A bars use.
+#[derive(Default)]
+pub struct BarWriter<'a> {
+    files: BTreeMap<PathBuf, EntrySource<'a>>,
+    short_names: bool,
+}
+
+impl<'a> BarWriter<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file to the archive from an in memory buffer.
+    pub fn add_file(&mut self, name: impl Into<PathBuf>, data: Vec<u8>) -> &mut Self {
+        self.files.insert(name.into(), EntrySource::Buffer(data));
+        self
+    }
+
+    /// Adds a file that is read from `source` when the archive gets written.
+    pub fn add_path(&mut self, name: impl Into<PathBuf>, source: impl Into<PathBuf>) -> &mut Self {
+        self.files
+            .insert(name.into(), EntrySource::Path(source.into()));
+        self
+    }
+
+    /// Recursively adds every file under `dir`, named relative to `dir`.
+    pub fn add_dir(&mut self, dir: &Path) -> Result<&mut Self, KArchiveError> {
+        for (name, path) in walk_dir(dir)? {
+            self.add_path(name, path);
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn add_source(&mut self, name: PathBuf, source: EntrySource<'a>) -> &mut Self {
+        self.files.insert(name, source);
+        self
+    }
+
+    pub(crate) fn sources(&self) -> &BTreeMap<PathBuf, EntrySource<'a>> {
+        &self.files
+    }
+
+    /// Whether names get 252 byte fields (M39A) instead of 256. Defaults to false.
+    pub fn set_short_names(&mut self, short_names: bool) -> &mut Self {
+        self.short_names = short_names;
+        self
+    }
+
+    /// Writes the archive out to `writer`, returning the writer when done.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<W, KArchiveError> {
+        let name_len = if self.short_names { 252 } else { 256 };
+        let count = u16::try_from(self.files.len())
+            .map_err(|_| KArchiveError::WriteError("Too many files for a bar".to_string()))?;
+        // nobody knows what the first 10 bytes are, they're left zeroed
+        writer.write_all(&[0; 10])?;
+        writer.write_u16::<LittleEndian>(count)?;
+        for (path, source) in &self.files {
+            let name = format!("\\{}", archive_name(path).replace('/', "\\"));
+            if name.len() >= name_len {
+                return Err(KArchiveError::WriteError(format!(
+                    "{} doesn't fit in a {} byte bar name",
+                    name, name_len
+                )));
+            }
+            let size = u32::try_from(source.size()?).map_err(|_| {
+                KArchiveError::WriteError(format!("{} is too large for a bar", name))
+            })?;
+            let mut field = name.into_bytes();
+            field.resize(name_len, 0);
+            writer.write_all(&field)?;
+            writer.write_i32::<LittleEndian>(3)?;
+            writer.write_i32::<LittleEndian>(-1)?;
+            writer.write_u32::<LittleEndian>(size)?;
+            writer.write_u32::<LittleEndian>(0)?;
+            source.copy_to(&mut writer)?;
+        }
+        writer.flush()?;
+        Ok(writer)
+    }
 }
 
 #[cfg(test)]
@@ -101,10 +310,162 @@ mod tests {
             254, 254, 254, 254, 254, 254, 254, 254, 254, 254, 254, 254, 254, 254, 254, 254, 254,
             254, 254, 254, 254, 254, 254,
         ]);
-        let mut filename = BufReader::new(cursor);
         assert_eq!(
-            read_file_name(&mut filename).unwrap(),
+            parse_file_name(cursor.get_ref(), NameEncoding::Utf8).unwrap(),
             "JEA2024041500contents/5/f/8/644f04c9f4012dd725f92143676bacc734246"
         )
     }
+
+    fn bar_record(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut record = name.as_bytes().to_vec();
+        record.resize(256, 0xFE);
+        record[name.len()] = 0;
+        record.extend_from_slice(&3_i32.to_le_bytes());
+        record.extend_from_slice(&(-1_i32).to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&0x0DDBA11_u32.to_le_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    #[test]
+    fn bar_salvage() {
+        let mut bar = vec![0; 10];
+        bar.extend_from_slice(&4_u16.to_le_bytes());
+        bar.extend(bar_record("\\data\\a.bin", &[1; 0x20]));
+        let mut broken = bar_record("\\data\\b.bin", &[2; 0x20]);
+        broken[256] = 0x55; // clobber the magic
+        bar.extend(broken);
+        bar.extend(bar_record("\\data\\c.bin", &[3; 0x20]));
+        bar.extend(bar_record("\\data\\..\\..\\x.bin", &[4; 0x20]));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bar");
+        std::fs::write(&path, bar).unwrap();
+
+        let archive = parse(path.clone(), &MountOptions::default()).unwrap();
+        assert_eq!(archive.list_files().len(), 1);
+        let meta = archive
+            .metadata(std::path::Path::new("data/a.bin"))
+            .unwrap();
+        assert_eq!(meta.raw_fields, vec![0x0DDBA11]);
+
+        let salvage = MountOptions {
+            salvage: true,
+            ..Default::default()
+        };
+        let archive = parse(path, &salvage).unwrap();
+        assert_eq!(archive.list_files().len(), 2);
+        assert_eq!(
+            archive.read(std::path::Path::new("data/c.bin")).unwrap(),
+            vec![3; 0x20]
+        );
+        // carved names get the same check as the entry table's
+        assert!(matches!(
+            archive.warnings(),
+            [
+                KArchiveWarning::SkippedEntry { .. },
+                KArchiveWarning::Salvaged { recovered: 1, .. }
+            ]
+        ));
+    }
+
+    #[test]
+    fn bar_truncated() {
+        let mut bar = vec![0; 10];
+        bar.extend_from_slice(&3_u16.to_le_bytes());
+        bar.extend(bar_record("\\data\\a.bin", &[1; 0x20]));
+        bar.extend(bar_record("\\data\\b.bin", &[2; 0x20]));
+        bar.extend(bar_record("\\data\\c.bin", &[3; 0x20]));
+        // cut the archive off halfway through b.bin
+        bar.truncate(12 + (272 + 0x20) + 272 + 0x10);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bar");
+        std::fs::write(&path, bar).unwrap();
+
+        // without the option the entry count gives the truncation away
+        assert!(matches!(
+            parse(path.clone(), &MountOptions::default()),
+            Err(KArchiveError::InvalidHeader { format: "bar", .. })
+        ));
+
+        let options = MountOptions {
+            allow_truncated: true,
+            ..Default::default()
+        };
+        let archive = parse(path, &options).unwrap();
+        assert_eq!(archive.list_files().len(), 2);
+        let a = std::path::Path::new("data/a.bin");
+        let b = std::path::Path::new("data/b.bin");
+        assert!(archive.is_complete(a));
+        assert!(!archive.is_complete(b));
+        assert_eq!(archive.available_size(b), Some(0x10));
+        assert_eq!(archive.read(a).unwrap(), vec![1; 0x20]);
+        let err = archive.read(b).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(matches!(
+            archive.warnings(),
+            [KArchiveWarning::Truncated { .. }]
+        ));
+    }
+
+    #[test]
+    fn bar_short_name_record() {
+        // M39A style record with a 252 byte name field
+        let mut record = b"\\data\\short.bin\0".to_vec();
+        record.resize(252, 0xFE);
+        record.extend_from_slice(&3_i32.to_le_bytes());
+        record.extend_from_slice(&(-1_i32).to_le_bytes());
+        record.extend_from_slice(&0x20_u32.to_le_bytes());
+        record.extend_from_slice(&[0; 4]);
+        assert_eq!(
+            parse_record(&record, 0, NameEncoding::Utf8).unwrap(),
+            ("data/short.bin".to_string(), 268, 0x20, 0)
+        );
+    }
+
+    #[test]
+    fn pak_dispatch() {
+        let mut bar = vec![0; 10];
+        bar.extend_from_slice(&1_u16.to_le_bytes());
+        bar.extend(bar_record("\\data\\a.bin", &[1; 0x20]));
+        let dir = tempfile::tempdir().unwrap();
+        // some bars are named .pak, those still mount
+        let bar_path = dir.path().join("bar.pak");
+        std::fs::write(&bar_path, bar).unwrap();
+        assert!(crate::mount(bar_path)
+            .unwrap()
+            .exists(std::path::Path::new("data/a.bin")));
+        // anything else isn't parsed as a bar
+        let pak_path = dir.path().join("other.pak");
+        std::fs::write(&pak_path, [0x50; 0x400]).unwrap();
+        assert!(matches!(
+            crate::mount(pak_path),
+            Err(KArchiveError::Other(_))
+        ));
+    }
+
+    #[test]
+    fn bar_write_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        for short_names in [false, true] {
+            let path = dir.path().join(format!("short{}.bar", short_names));
+            let mut writer = BarWriter::new();
+            writer
+                .add_file("data/a.bin", vec![1; 0x20])
+                .add_file("data/sub/b.bin", vec![2; 0x30])
+                .set_short_names(short_names);
+            let bar = writer.write(Vec::new()).unwrap();
+            assert!(looks_like_bar(&bar));
+            std::fs::write(&path, bar).unwrap();
+
+            let archive = crate::mount(path).unwrap();
+            assert_eq!(archive.list_files().len(), 2);
+            assert_eq!(
+                archive
+                    .read(std::path::Path::new("data/sub/b.bin"))
+                    .unwrap(),
+                vec![2; 0x30]
+            );
+        }
+    }
 }
@@ -1,44 +1,67 @@
 use crate::common::*;
-use byteorder::{LittleEndian, ReadBytesExt};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom};
-use std::path::PathBuf;
+use crate::write::REPRODUCIBLE_TIMESTAMP;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-fn read_file_name<T>(rdr: &mut T) -> Result<String, KArchiveError>
+fn read_file_name<T>(rdr: &mut T, encoding: NameEncoding) -> Result<String, KArchiveError>
 where
     T: BufRead + Seek,
 {
     let mut buf = Vec::<u8>::new();
     rdr.read_until(0, &mut buf)?;
-    Ok(String::from_utf8(
-        buf.strip_suffix(&[0])
-            .ok_or(KArchiveError::Other(
-                "Failed to strip suffix (malformed or incomplete archive)",
-            ))?
-            .to_vec(),
-    )?)
+    encoding.decode(buf.strip_suffix(&[0]).ok_or(KArchiveError::Other(
+        "Failed to strip suffix (malformed or incomplete archive)",
+    ))?)
 }
 
 fn read_folder<T>(
     rdr: &mut T,
     mut full_path: PathBuf,
     files: &mut HashMap<PathBuf, KFileInfo>,
+    end: u64,
+    encoding: NameEncoding,
 ) -> Result<(), KArchiveError>
 where
     T: BufRead + Seek,
 {
+    // entries are only counted once they're files, which is close enough to find the bad one
+    let index = files.len();
     let action = rdr.read_u8()?;
-    full_path.push(read_file_name(rdr)?);
+    let name = read_file_name(rdr, encoding).map_err(|e| name_error("cab", index, e))?;
+    check_entry_name("cab", index, &name)?;
+    full_path.push(&name);
     let param = rdr.read_i32::<LittleEndian>()?;
+    if param < 0 {
+        return Err(invalid_entry(
+            "cab",
+            index,
+            &name,
+            format!("negative size or entry count {}", param),
+        ));
+    }
     match action {
         0x00 => {
+            let offset = rdr.stream_position()?;
+            if offset + param as u64 > end {
+                return Err(invalid_entry(
+                    "cab",
+                    index,
+                    &name,
+                    format!(
+                        "{} bytes at {:#x} run past the end of the arcfile ({} bytes)",
+                        param, offset, end
+                    ),
+                ));
+            }
             files.insert(
                 full_path,
                 KFileInfo {
                     size: param as u64,
-                    offset: rdr.stream_position()?,
-                    cipher: None,
+                    offset,
+                    ..Default::default()
                 },
             );
             rdr.seek(SeekFrom::Current(param as i64))?;
@@ -46,36 +69,401 @@ where
         0x01 => {
             let mut entries = param;
             while entries > 0 {
-                read_folder(rdr, full_path.clone(), files)?;
+                read_folder(rdr, full_path.clone(), files, end, encoding)?;
                 entries -= 1;
             }
         }
-        _ => unreachable!("Only two types of entries"),
+        action => {
+            return Err(invalid_entry(
+                "cab",
+                index,
+                &name,
+                format!("unknown entry type {:#x}", action),
+            ))
+        }
     }
     Ok(())
 }
 
-pub(crate) fn parse(path: PathBuf) -> Result<KArchive, KArchiveError> {
-    let cab_file = File::open(&path)?;
+/// Folder the cab's own members (filelist, signatures, scripts...) are listed under when
+/// the actual files come from its arcfile, eg. `__cab__/filelist`.
+pub const CAB_MEMBER_PREFIX: &str = "__cab__";
+
+fn member_names<R: Read + Seek>(cabinet: &cab::Cabinet<R>) -> Vec<String> {
+    cabinet
+        .folder_entries()
+        .flat_map(|folder| folder.file_entries().map(|file| file.name().to_string()))
+        .collect()
+}
+
+// reads the cab members in `names` onto the end of `buffer`, listing them under `prefix`
+fn read_members<R: Read + Seek>(
+    cabinet: &mut cab::Cabinet<R>,
+    names: &[String],
+    prefix: &Path,
+    buffer: &mut Vec<u8>,
+    files: &mut HashMap<PathBuf, KFileInfo>,
+) -> Result<(), KArchiveError> {
+    for (index, name) in names.iter().enumerate() {
+        let entry_name = name.replace('\\', "/");
+        check_entry_name("cab", index, &entry_name)?;
+        // cab times are local to whoever packed it, utc is as good a guess as any
+        let modified = cabinet
+            .get_file_entry(name)
+            .and_then(|entry| entry.datetime())
+            .map(|datetime| SystemTime::from(datetime.assume_utc()));
+        let offset = buffer.len() as u64;
+        cabinet.read_file(name)?.read_to_end(buffer)?;
+        files.insert(
+            prefix.join(entry_name),
+            KFileInfo {
+                size: buffer.len() as u64 - offset,
+                offset,
+                modified,
+                ..Default::default()
+            },
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let cab_file = InternalFile::open(&path)?;
+    parse_from(path, cab_file, options)
+}
+
+/// Parses a cab read from `cab_file`. `path` is only used to name the archive.
+pub(crate) fn parse_from<R: Read + Seek>(
+    path: PathBuf,
+    cab_file: R,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
     let mut cabinet = cab::Cabinet::new(cab_file)?;
+    if cabinet.get_file_entry("arcfile").is_none() {
+        // generic cabs don't have an arcfile, so their own members become the entries
+        let names = member_names(&cabinet);
+        let mut buffer = Vec::new();
+        let mut files = HashMap::new();
+        read_members(&mut cabinet, &names, Path::new(""), &mut buffer, &mut files)?;
+        return Ok(KArchive::new(path, files, Some(buffer)));
+    }
     let arcsize = cabinet
         .get_file_entry("arcfile")
         .ok_or(KArchiveError::Other("Failed to get arcfile from cab"))?
         .uncompressed_size()
         .into();
     // I've never seen a cab file that didn't just have an arcfile and filelist inside so assume the structure will be like that until proven wrong
-    let mut arcfile = BufReader::new(cabinet.read_file("arcfile")?);
     // Due to bugs with the cab crate, i'm storing the arcfile buffer inside the KArchive struct for this specific format.
     // If interfacing with the cab file directly becomes viable, i'll switch away from this method...
     let mut buf = Vec::with_capacity(arcsize as usize);
-    arcfile.read_to_end(&mut buf)?;
+    BufReader::new(cabinet.read_file("arcfile")?).read_to_end(&mut buf)?;
     let mut cursor = Cursor::new(buf);
     let mut files: HashMap<PathBuf, KFileInfo> = HashMap::new();
     while cursor.stream_position()? != arcsize {
-        read_folder(&mut cursor, PathBuf::from(""), &mut files)?;
+        read_folder(
+            &mut cursor,
+            PathBuf::from(""),
+            &mut files,
+            arcsize,
+            options.name_encoding,
+        )?;
     }
     // Leak the buffer to get a static lifetime slice. This is fine because
     // it's guaranteed to live until the program is terminated anyways...
-    let buffer = cursor.into_inner();
+    let mut buffer = cursor.into_inner();
+    // everything besides the arcfile goes on the end of the same buffer
+    let members: Vec<String> = member_names(&cabinet)
+        .into_iter()
+        .filter(|name| name != "arcfile")
+        .collect();
+    read_members(
+        &mut cabinet,
+        &members,
+        Path::new(CAB_MEMBER_PREFIX),
+        &mut buffer,
+        &mut files,
+    )?;
     Ok(KArchive::new(path, files, Some(buffer)))
 }
+
+// the arcfile is a tree, so the flat path list needs to be turned into one before writing
+enum ArcNode<'a> {
+    File(&'a EntrySource<'a>),
+    Folder(BTreeMap<String, ArcNode<'a>>),
+}
+
+fn write_arc_node<W: Write>(wtr: &mut W, name: &str, node: &ArcNode) -> Result<(), KArchiveError> {
+    match node {
+        ArcNode::File(source) => {
+            let size = i32::try_from(source.size()?)
+                .map_err(|_| KArchiveError::Other("File too large to fit in an arcfile"))?;
+            wtr.write_u8(0x00)?;
+            wtr.write_all(name.as_bytes())?;
+            wtr.write_u8(0)?;
+            wtr.write_i32::<LittleEndian>(size)?;
+            source.copy_to(wtr)?;
+        }
+        ArcNode::Folder(children) => {
+            wtr.write_u8(0x01)?;
+            wtr.write_all(name.as_bytes())?;
+            wtr.write_u8(0)?;
+            wtr.write_i32::<LittleEndian>(children.len() as i32)?;
+            for (name, child) in children {
+                write_arc_node(wtr, name, child)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Builds an MSCF cabinet in the layout game updates ship: an `arcfile` holding the
+/// actual file tree plus a `filelist` next to it.
+#[derive(Default)]
+pub struct CabWriter<'a> {
+    files: BTreeMap<PathBuf, EntrySource<'a>>,
+    filelist: Option<Vec<u8>>,
+    compress: bool,
+    reproducible: bool,
+}
+
+impl<'a> CabWriter<'a> {
+    pub fn new() -> Self {
+        Self {
+            compress: true,
+            ..Default::default()
+        }
+    }
+
+    /// Adds a file to the arcfile from an in memory buffer.
+    pub fn add_file(&mut self, name: impl Into<PathBuf>, data: Vec<u8>) -> &mut Self {
+        self.files.insert(name.into(), EntrySource::Buffer(data));
+        self
+    }
+
+    /// Adds a file to the arcfile that is read from `source` when the cab gets written.
+    pub fn add_path(&mut self, name: impl Into<PathBuf>, source: impl Into<PathBuf>) -> &mut Self {
+        self.files
+            .insert(name.into(), EntrySource::Path(source.into()));
+        self
+    }
+
+    /// Recursively adds every file under `dir`, named relative to `dir`.
+    pub fn add_dir(&mut self, dir: &Path) -> Result<&mut Self, KArchiveError> {
+        for (name, path) in walk_dir(dir)? {
+            self.add_path(name, path);
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn add_source(&mut self, name: PathBuf, source: EntrySource<'a>) -> &mut Self {
+        self.files.insert(name, source);
+        self
+    }
+
+    pub(crate) fn sources(&self) -> &BTreeMap<PathBuf, EntrySource<'a>> {
+        &self.files
+    }
+
+    /// Overrides the contents of the `filelist` member. The original format of this file
+    /// hasn't been figured out yet, so when repacking an existing cab it's best to pass the
+    /// original through here. Otherwise a plain newline separated list of paths is written.
+    pub fn set_filelist(&mut self, filelist: Vec<u8>) -> &mut Self {
+        self.filelist = Some(filelist);
+        self
+    }
+
+    /// Whether the cab members should be MSZIP compressed. Defaults to true.
+    pub fn set_compression(&mut self, compress: bool) -> &mut Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Stamps the members with a fixed date instead of the current time, so packing the same
+    /// files twice gives identical cabs. Defaults to false.
+    pub fn set_reproducible(&mut self, reproducible: bool) -> &mut Self {
+        self.reproducible = reproducible;
+        self
+    }
+
+    fn build_tree(&self) -> Result<BTreeMap<String, ArcNode<'_>>, KArchiveError> {
+        let mut root = BTreeMap::new();
+        for (path, source) in &self.files {
+            let components: Vec<String> = path
+                .iter()
+                .map(|c| c.to_string_lossy().into_owned())
+                .collect();
+            let (file_name, dirs) = components
+                .split_last()
+                .ok_or(KArchiveError::Other("Empty file name in cab writer"))?;
+            let mut current = &mut root;
+            for dir in dirs {
+                let node = current
+                    .entry(dir.clone())
+                    .or_insert_with(|| ArcNode::Folder(BTreeMap::new()));
+                current = match node {
+                    ArcNode::Folder(children) => children,
+                    ArcNode::File(_) => {
+                        return Err(KArchiveError::ParseError(format!(
+                            "{} is used as both a file and a folder",
+                            dir
+                        )))
+                    }
+                };
+            }
+            if current
+                .insert(file_name.clone(), ArcNode::File(source))
+                .is_some()
+            {
+                return Err(KArchiveError::ParseError(format!(
+                    "{} is used as both a file and a folder",
+                    path.display()
+                )));
+            }
+        }
+        Ok(root)
+    }
+
+    fn default_filelist(&self) -> Vec<u8> {
+        let mut filelist = String::new();
+        for path in self.files.keys() {
+            filelist.push_str(&archive_name(path));
+            filelist.push('\n');
+        }
+        filelist.into_bytes()
+    }
+
+    /// Writes the cabinet out to `writer`, returning the writer when done.
+    pub fn write<W: Write + Seek>(&self, writer: W) -> Result<W, KArchiveError> {
+        let tree = self.build_tree()?;
+        let ctype = if self.compress {
+            cab::CompressionType::MsZip
+        } else {
+            cab::CompressionType::None
+        };
+        let mut builder = cab::CabinetBuilder::new();
+        let folder = builder.add_folder(ctype);
+        for member in ["arcfile", "filelist"] {
+            let file = folder.add_file(member);
+            if self.reproducible {
+                let fixed =
+                    time::OffsetDateTime::from_unix_timestamp(REPRODUCIBLE_TIMESTAMP as i64)
+                        .expect("fixed timestamp is in range");
+                file.set_datetime(time::PrimitiveDateTime::new(fixed.date(), fixed.time()));
+            }
+        }
+        let mut cab_writer = builder.build(writer)?;
+        while let Some(mut file_writer) = cab_writer.next_file()? {
+            if file_writer.file_name() == "arcfile" {
+                // the arcfile can get big so don't build it in memory first
+                for (name, node) in &tree {
+                    write_arc_node(&mut file_writer, name, node)?;
+                }
+            } else {
+                match &self.filelist {
+                    Some(filelist) => file_writer.write_all(filelist)?,
+                    None => file_writer.write_all(&self.default_filelist())?,
+                }
+            }
+        }
+        Ok(cab_writer.finish()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn cab_round_trip() {
+        let mut writer = CabWriter::new();
+        writer
+            .add_file("data/sound/bgm.bin", vec![1, 2, 3, 4])
+            .add_file("data/sound/se.bin", vec![5; 0x100])
+            .add_file("prop/ea3-config.xml", b"<ea3/>".to_vec());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.cab");
+        writer.write(File::create(&path).unwrap()).unwrap();
+
+        let archive = crate::mount(path).unwrap();
+        // the 3 files plus the filelist
+        assert_eq!(archive.list_files().len(), 4);
+        assert_eq!(
+            archive.read(Path::new("__cab__/filelist")).unwrap(),
+            b"data/sound/bgm.bin\ndata/sound/se.bin\nprop/ea3-config.xml\n"
+        );
+        assert_eq!(
+            archive.read(Path::new("data/sound/bgm.bin")).unwrap(),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(
+            archive.read(Path::new("data/sound/se.bin")).unwrap(),
+            vec![5; 0x100]
+        );
+        assert_eq!(
+            archive.read(Path::new("prop/ea3-config.xml")).unwrap(),
+            b"<ea3/>"
+        );
+    }
+
+    #[test]
+    fn cab_reproducible() {
+        let build = || {
+            let mut writer = CabWriter::new();
+            writer
+                .add_file("data/b.bin", vec![2; 0x40])
+                .add_file("data/a.bin", vec![1; 0x40])
+                .set_reproducible(true);
+            writer.write(Cursor::new(Vec::new())).unwrap().into_inner()
+        };
+        let first = build();
+        assert_eq!(first, build());
+        let cabinet = cab::Cabinet::new(Cursor::new(first)).unwrap();
+        for file in cabinet
+            .folder_entries()
+            .flat_map(|folder| folder.file_entries())
+        {
+            let datetime = file.datetime().unwrap();
+            assert_eq!((datetime.year(), datetime.hour()), (1980, 0));
+        }
+    }
+
+    #[test]
+    fn cab_file_folder_conflict() {
+        let mut writer = CabWriter::new();
+        writer
+            .add_file("data", vec![1])
+            .add_file("data/inner", vec![2]);
+        assert!(writer.write(Cursor::new(Vec::new())).is_err());
+    }
+
+    #[test]
+    fn cab_without_arcfile() {
+        let mut builder = cab::CabinetBuilder::new();
+        let folder = builder.add_folder(cab::CompressionType::MsZip);
+        folder.add_file("readme.txt");
+        folder.add_file("bin\\tool.exe");
+        let mut cab_writer = builder.build(Cursor::new(Vec::new())).unwrap();
+        while let Some(mut file_writer) = cab_writer.next_file().unwrap() {
+            let data = match file_writer.file_name() {
+                "readme.txt" => b"hello".to_vec(),
+                _ => vec![0x4D; 0x80],
+            };
+            file_writer.write_all(&data).unwrap();
+        }
+        let cab = cab_writer.finish().unwrap().into_inner();
+
+        let archive = parse_from(
+            PathBuf::from("generic.cab"),
+            Cursor::new(cab),
+            &MountOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(archive.list_files().len(), 2);
+        assert_eq!(archive.read(Path::new("readme.txt")).unwrap(), b"hello");
+        assert_eq!(
+            archive.read(Path::new("bin/tool.exe")).unwrap(),
+            vec![0x4D; 0x80]
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::common::*;
+use crate::update::UpdateInfo;
+
+/// One update of an `UpdateChain`.
+#[derive(Debug, Clone)]
+pub struct ChainLink {
+    pub path: PathBuf,
+    /// Game code and datecode, from the archive's descriptor or else its file name.
+    pub info: UpdateInfo,
+}
+
+/// A game's updates put in release order and layered into the file set they add up to, where
+/// every path comes from the latest update that has it.
+#[derive(Debug, Clone)]
+pub struct UpdateChain {
+    // oldest first
+    links: Vec<ChainLink>,
+    // every path in the result and the link it comes from
+    files: BTreeMap<PathBuf, usize>,
+    merged: KArchive,
+}
+
+impl UpdateChain {
+    /// Mounts every archive in `paths` and puts them in order by their datecode, whatever
+    /// order they were given in. Archives of different games, or without a datecode to place
+    /// them by, are refused, as is any archive that fails to mount: a missing update would
+    /// change the result.
+    pub fn resolve(paths: &[PathBuf], options: &MountOptions) -> Result<Self, KArchiveError> {
+        let mut updates = Vec::with_capacity(paths.len());
+        for path in paths {
+            // with several archives, the error has to say which one it was
+            let archive = crate::mount_with_options(path.clone(), options).map_err(|e| {
+                KArchiveError::ParseError(format!("failed to mount {}: {}", path.display(), e))
+            })?;
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let info = archive
+                .update_info()
+                .cloned()
+                .unwrap_or_else(|| UpdateInfo::from_name(&name));
+            if info.datecode.is_none() {
+                return Err(KArchiveError::ParseError(format!(
+                    "{} doesn't have a datecode, so where it goes in the chain isn't known",
+                    path.display()
+                )));
+            }
+            updates.push((
+                ChainLink {
+                    path: path.clone(),
+                    info,
+                },
+                archive,
+            ));
+        }
+        let games: Vec<&String> = updates
+            .iter()
+            .filter_map(|(link, _)| link.info.game_code.as_ref())
+            .collect();
+        if let Some(other) = games.iter().find(|game| **game != games[0]) {
+            return Err(KArchiveError::ParseError(format!(
+                "the updates are for more than one game ({} and {})",
+                games[0], other
+            )));
+        }
+        // stable, so updates with the same datecode stay in the order they were given
+        updates.sort_by(|(a, _), (b, _)| a.info.datecode.cmp(&b.info.datecode));
+
+        let mut links = Vec::with_capacity(updates.len());
+        let mut files = BTreeMap::new();
+        let mut merged: Option<KArchive> = None;
+        for (link, archive) in updates {
+            for file in archive.list_files() {
+                files.insert(file, links.len());
+            }
+            match &mut merged {
+                Some(merged) => merged.overlay(archive),
+                None => merged = Some(archive),
+            }
+            links.push(link);
+        }
+        Ok(Self {
+            links,
+            files,
+            merged: merged.unwrap_or_else(KArchive::init_empty),
+        })
+    }
+
+    /// The updates, oldest first.
+    pub fn links(&self) -> &[ChainLink] {
+        &self.links
+    }
+
+    /// Every path in the result and the update it comes from, sorted by path.
+    pub fn files(&self) -> impl Iterator<Item = (&Path, &ChainLink)> {
+        self.files
+            .iter()
+            .map(|(path, &link)| (path.as_path(), &self.links[link]))
+    }
+
+    /// The update `path` comes from, if any has it.
+    pub fn source(&self, path: &Path) -> Option<&ChainLink> {
+        self.files.get(path).map(|&link| &self.links[link])
+    }
+
+    /// The result as one archive, to read or extract the latest version of every path from.
+    pub fn archive(&self) -> &KArchive {
+        &self.merged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarWriter;
+
+    #[test]
+    fn chain_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, files: &[(&str, &[u8])]| {
+            let mut writer = MarWriter::new();
+            for (file, data) in files {
+                writer.add_file(file, data.to_vec());
+            }
+            let path = dir.path().join(name);
+            writer.write(std::fs::File::create(&path).unwrap()).unwrap();
+            path
+        };
+        let base = write(
+            "KFC-2023010100.mar",
+            &[("data/a.bin", b"base a"), ("data/b.bin", b"base b")],
+        );
+        let first = write("KFC-2023020100.mar", &[("data/b.bin", b"first b")]);
+        let second = write(
+            "KFC-2023030100.mar",
+            &[("data/a.bin", b"second a"), ("data/c.bin", b"second c")],
+        );
+
+        // given newest first, applied oldest first
+        let options = MountOptions::default();
+        let chain = UpdateChain::resolve(&[second, base.clone(), first], &options).unwrap();
+        let order: Vec<&str> = chain
+            .links()
+            .iter()
+            .map(|link| link.info.datecode.as_deref().unwrap())
+            .collect();
+        assert_eq!(order, ["2023010100", "2023020100", "2023030100"]);
+        let files: Vec<(String, &str)> = chain
+            .files()
+            .map(|(path, link)| {
+                let datecode = link.info.datecode.as_deref().unwrap();
+                (path.to_string_lossy().replace('\\', "/"), datecode)
+            })
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("data/a.bin".to_string(), "2023030100"),
+                ("data/b.bin".to_string(), "2023020100"),
+                ("data/c.bin".to_string(), "2023030100"),
+            ]
+        );
+        let read = |path: &str| chain.archive().read(Path::new(path)).unwrap();
+        assert_eq!(read("data/a.bin"), b"second a");
+        assert_eq!(read("data/b.bin"), b"first b");
+        assert_eq!(chain.archive().list_files().len(), 3);
+        assert!(chain.source(Path::new("data/nope.bin")).is_none());
+
+        let other = write("M32-2023020100.mar", &[("data/d.bin", b"d")]);
+        assert!(UpdateChain::resolve(&[base.clone(), other], &options).is_err());
+        let undated = write("update.mar", &[("data/d.bin", b"d")]);
+        assert!(UpdateChain::resolve(&[base, undated], &options).is_err());
+    }
+}
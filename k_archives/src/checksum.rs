@@ -0,0 +1,249 @@
+use crate::common::InternalFile;
+use std::io::Read;
+use std::path::Path;
+
+use crc_any::CRCu32;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::common::{KArchive, KArchiveError, KArchiveWarning};
+
+/// Checksum algorithms that show up in update descriptors (ULST and friends).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumType {
+    Md5,
+    Sha1,
+    Sha256,
+    Crc32,
+}
+
+impl ChecksumType {
+    /// Parses the checksum type as it's written in descriptor files. Case insensitive.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "md5" => Some(Self::Md5),
+            "sha1" | "sha-1" => Some(Self::Sha1),
+            "sha256" | "sha-256" => Some(Self::Sha256),
+            "crc32" | "crc" => Some(Self::Crc32),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Crc32 => "crc32",
+        }
+    }
+}
+
+enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    // boxed since it's a lot bigger than the digest states
+    Crc32(Box<CRCu32>),
+}
+
+impl Hasher {
+    fn new(checksum_type: ChecksumType) -> Self {
+        match checksum_type {
+            ChecksumType::Md5 => Self::Md5(Md5::new()),
+            ChecksumType::Sha1 => Self::Sha1(Sha1::new()),
+            ChecksumType::Sha256 => Self::Sha256(Sha256::new()),
+            ChecksumType::Crc32 => Self::Crc32(Box::new(CRCu32::crc32())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Crc32(hasher) => hasher.digest(data),
+        }
+    }
+
+    fn finish(self) -> String {
+        let digest = match self {
+            Self::Md5(hasher) => hasher.finalize().to_vec(),
+            Self::Sha1(hasher) => hasher.finalize().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Crc32(hasher) => hasher.get_crc().to_be_bytes().to_vec(),
+        };
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Hashes data as it streams past, for when it's being read for something else anyway
+/// (eg. extracting). `hash_reader` does the same for a whole reader.
+pub struct Checksummer(Hasher);
+
+impl Checksummer {
+    pub fn new(checksum_type: ChecksumType) -> Self {
+        Checksummer(Hasher::new(checksum_type))
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    /// The digest of everything passed to `update`, as lowercase hex.
+    pub fn finish(self) -> String {
+        self.0.finish()
+    }
+}
+
+/// Hashes everything `rdr` returns, returning the digest as lowercase hex.
+/// Data is streamed through in chunks so this is fine to use on multi gigabyte archives.
+pub fn hash_reader<R: Read>(rdr: &mut R, checksum_type: ChecksumType) -> std::io::Result<String> {
+    let mut hasher = Checksummer::new(checksum_type);
+    let mut buf = vec![0_u8; 0x10_0000];
+    loop {
+        let read = rdr.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher.finish())
+}
+
+pub fn hash_file(path: &Path, checksum_type: ChecksumType) -> std::io::Result<String> {
+    hash_reader(&mut InternalFile::open(path)?, checksum_type)
+}
+
+/// The checksum a file in a contents tree is named after, if it looks like it is. The hex
+/// digits after the last `contents` folder are joined up (`contents/8/c/a/5682f...` is
+/// `8ca5682f...`) and the type goes by how many there are: 32 for md5, 40 for sha1 and
+/// 64 for sha256.
+pub fn content_hash_name(path: &Path) -> Option<(ChecksumType, String)> {
+    let components: Vec<_> = path.iter().map(|c| c.to_string_lossy()).collect();
+    let start = components
+        .iter()
+        .rposition(|c| c.eq_ignore_ascii_case("contents"))?
+        + 1;
+    let name = components[start..].concat();
+    if !name.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let checksum_type = match name.len() {
+        32 => ChecksumType::Md5,
+        40 => ChecksumType::Sha1,
+        64 => ChecksumType::Sha256,
+        _ => return None,
+    };
+    Some((checksum_type, name.to_ascii_lowercase()))
+}
+
+impl KArchive {
+    /// Hashes every complete entry `content_hash_name` recognises, returning how many were
+    /// checked and a checksum mismatch for each one that doesn't match its name. Reads the
+    /// whole contents tree, so this takes about as long as extracting it.
+    pub fn check_content_hashes(&self) -> Result<(usize, Vec<KArchiveWarning>), KArchiveError> {
+        let mut checked = 0;
+        let mut mismatches = Vec::new();
+        for path in self.list_files() {
+            let Some((checksum_type, expected)) = content_hash_name(&path) else {
+                continue;
+            };
+            if !self.is_complete(&path) {
+                continue;
+            }
+            let actual = hash_reader(&mut self.open(&path)?, checksum_type)?;
+            checked += 1;
+            if actual != expected {
+                mismatches.push(KArchiveWarning::ChecksumMismatch {
+                    path,
+                    checksum_type: checksum_type.name().to_string(),
+                    expected,
+                    actual,
+                });
+            }
+        }
+        Ok((checked, mismatches))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Cursor;
+
+    #[test]
+    fn known_digests() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let hash = |ty| hash_reader(&mut Cursor::new(data), ty).unwrap();
+        assert_eq!(hash(ChecksumType::Md5), "9e107d9d372bb6826bd81d3542a419d6");
+        assert_eq!(
+            hash(ChecksumType::Sha1),
+            "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12"
+        );
+        assert_eq!(
+            hash(ChecksumType::Sha256),
+            "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592"
+        );
+        assert_eq!(hash(ChecksumType::Crc32), "414fa339");
+    }
+
+    #[test]
+    fn content_hashes() {
+        assert_eq!(
+            content_hash_name(Path::new(
+                "KFC/contents/8/c/a/5682f39af4538f4ad7806c0c97d5371ab49ab"
+            )),
+            Some((
+                ChecksumType::Sha1,
+                "8ca5682f39af4538f4ad7806c0c97d5371ab49ab".to_string()
+            ))
+        );
+        assert_eq!(
+            content_hash_name(Path::new("data/contents/readme.txt")),
+            None
+        );
+        assert_eq!(
+            content_hash_name(Path::new("9e107d9d372bb6826bd81d3542a419d6")),
+            None
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("contents.mar");
+        let good = hash_reader(&mut Cursor::new(b"good"), ChecksumType::Md5).unwrap();
+        crate::MarWriter::new()
+            .add_file(
+                format!("contents/{}/{}", &good[..2], &good[2..]),
+                b"good".to_vec(),
+            )
+            .add_file("contents/9e107d9d372bb6826bd81d3542a419d6", b"bad".to_vec())
+            .add_file("contents/other.bin", b"unchecked".to_vec())
+            .write(File::create(&path).unwrap())
+            .unwrap();
+        let archive = crate::mount(path).unwrap();
+        let (checked, mismatches) = archive.check_content_hashes().unwrap();
+        assert_eq!(checked, 2);
+        assert!(matches!(
+            mismatches.as_slice(),
+            [KArchiveWarning::ChecksumMismatch { expected, .. }]
+                if expected == "9e107d9d372bb6826bd81d3542a419d6"
+        ));
+    }
+
+    #[test]
+    fn streamed_digest() {
+        let mut checksummer = Checksummer::new(ChecksumType::Md5);
+        checksummer.update(b"The quick brown fox ");
+        checksummer.update(b"jumps over the lazy dog");
+        assert_eq!(checksummer.finish(), "9e107d9d372bb6826bd81d3542a419d6");
+    }
+
+    #[test]
+    fn checksum_names() {
+        assert_eq!(ChecksumType::from_name("SHA1"), Some(ChecksumType::Sha1));
+        assert_eq!(ChecksumType::from_name("md5 "), Some(ChecksumType::Md5));
+        assert_eq!(ChecksumType::from_name("whirlpool"), None);
+    }
+}
@@ -0,0 +1,49 @@
+use std::fmt::Debug;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::mar::MarCipher;
+
+/// A cipher some archives apply to their entries' payloads, for schemes k_archives
+/// doesn't know about itself (eg. the per-title QAR encryption some games use).
+pub trait EntryCipher: Debug + Send + Sync {
+    /// Deciphers `data` in place. `pos` is how far into the entry's payload `data` starts,
+    /// reads can start anywhere since entries are seekable.
+    fn decrypt(&self, pos: u64, data: &mut [u8]);
+}
+
+/// Hands out ciphers for encrypted archives, set with `MountOptions::key_provider`.
+/// Currently asked about every entry of every qar that gets mounted.
+pub trait KeyProvider: Debug + Send + Sync {
+    /// The cipher for `entry` of the archive at `archive`, or None if it isn't encrypted
+    /// (or the key isn't known).
+    fn cipher(&self, archive: &Path, entry: &str) -> Option<Arc<dyn EntryCipher>>;
+}
+
+// what gets applied to an entry's payload as it's read
+#[derive(Debug, Clone)]
+pub(crate) enum Cipher {
+    // gitadora mar files
+    Mar(MarCipher),
+    // from a KeyProvider
+    Custom(Arc<dyn EntryCipher>),
+}
+
+impl Cipher {
+    // `pos` is where `data` starts in the payload
+    pub(crate) fn crypt(&mut self, pos: u64, data: &mut [u8]) {
+        match self {
+            Cipher::Mar(cipher) => cipher.crypt(data),
+            Cipher::Custom(cipher) => cipher.decrypt(pos, data),
+        }
+    }
+
+    pub(crate) fn seek(&mut self, pos: SeekFrom) -> std::io::Result<()> {
+        match self {
+            Cipher::Mar(cipher) => std::io::Seek::seek(cipher, pos).map(|_| ()),
+            // these are handed the position on every read instead
+            Cipher::Custom(_) => Ok(()),
+        }
+    }
+}
@@ -1,12 +1,26 @@
-use crate::mar::MarCipher;
+use crate::checksum::{hash_file, ChecksumType};
+use crate::cipher::{Cipher, KeyProvider};
+use crate::compressed::{Compression, InflateReader};
+use crate::filelist::FileList;
+use crate::info::InfoManifest;
+use crate::lz77::Lz77Reader;
+use crate::update::UpdateInfo;
 use rand::{distributions::Uniform, Rng};
-use std::io::{Cursor, Error, Read, Seek, SeekFrom};
+use std::io::{Cursor, Error, Read, Seek, SeekFrom, Write};
 use std::path::Path;
-use std::time::{Duration, Instant};
-use std::{collections::HashMap, fs::File, path::PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    path::PathBuf,
+};
 use thiserror::Error;
 
 // enum used in both extdrmfs and drmfs as the handle for their file abstractions
+// these live on the stack for the length of a read, boxing the kfile would just add an allocation per open
+#[allow(clippy::large_enum_variant)]
 pub enum CommonFile<'a> {
     File(File),
     KFile(KFile<'a>),
@@ -40,18 +54,72 @@ impl<'a> Seek for CommonFile<'a> {
 }
 
 // Struct containing all the info needed to build a KFile object
-// from a KArchive
-#[derive(Debug, Clone)]
+// from a KArchive. Parsers set what their format has and leave the rest to Default,
+// so anything added here should default to what a format without it means
+#[derive(Debug, Clone, Default)]
 pub(crate) struct KFileInfo {
     pub(crate) size: u64,
     pub(crate) offset: u64,
-    // might want to use an enum or otherwise in the future, but i don't mind hacking this for now
-    pub(crate) cipher: Option<MarCipher>,
+    pub(crate) cipher: Option<Cipher>,
+    // set when the archive is truncated partway through this entry's payload.
+    // holds how many bytes of the payload are actually present
+    pub(crate) available: Option<u64>,
+    // record header fields nobody has figured out the meaning of yet, kept as is
+    pub(crate) raw_fields: Vec<u32>,
+    // how the payload is compressed, if it is. size is always the decompressed size
+    pub(crate) compression: Option<Compression>,
+    // for the formats that keep them
+    pub(crate) modified: Option<SystemTime>,
+    pub(crate) mode: Option<u32>,
 }
 
 pub(crate) enum InternalFile<'a> {
     RealFile(File),
     Buffer(Cursor<&'a [u8]>),
+    // compressed entries, decompressed as they're read out of the raw (but deciphered) entry
+    Lz77(Box<Lz77Reader<KFile<'a>>>),
+    Inflate(Box<InflateReader<KFile<'a>>>),
+    #[cfg(feature = "http")]
+    Remote(crate::remote::RemoteFile),
+}
+
+impl InternalFile<'static> {
+    /// Opens the archive at `path`, which with the http feature can also be an http(s) or s3
+    /// url.
+    pub(crate) fn open(path: &Path) -> std::io::Result<Self> {
+        if let Some(url) = as_url(path) {
+            #[cfg(feature = "http")]
+            return Ok(InternalFile::Remote(crate::remote::RemoteFile::open(url)?));
+            #[cfg(not(feature = "http"))]
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("can't read {}, http support isn't enabled", url),
+            ));
+        }
+        Ok(InternalFile::RealFile(File::open(path)?))
+    }
+}
+
+/// Whether `path` is an http(s) or s3 url rather than a path, see `mount`.
+pub fn is_url(path: &Path) -> bool {
+    as_url(path).is_some()
+}
+
+fn as_url(path: &Path) -> Option<&str> {
+    path.to_str().filter(|path| {
+        ["http://", "https://", "s3://"]
+            .iter()
+            .any(|s| path.starts_with(s))
+    })
+}
+
+// size of the archive at `path`, which can be a url
+pub(crate) fn source_len(path: &Path) -> std::io::Result<u64> {
+    #[cfg(feature = "http")]
+    if let Some(url) = as_url(path) {
+        return Ok(crate::remote::RemoteFile::open(url)?.len());
+    }
+    Ok(std::fs::metadata(path)?.len())
 }
 
 impl<'a> Read for InternalFile<'a> {
@@ -59,6 +127,10 @@ impl<'a> Read for InternalFile<'a> {
         match self {
             InternalFile::RealFile(file) => file.read(buf),
             InternalFile::Buffer(file) => file.read(buf),
+            InternalFile::Lz77(file) => file.read(buf),
+            InternalFile::Inflate(file) => file.read(buf),
+            #[cfg(feature = "http")]
+            InternalFile::Remote(file) => file.read(buf),
         }
     }
 }
@@ -68,6 +140,10 @@ impl<'a> Seek for InternalFile<'a> {
         match self {
             InternalFile::RealFile(file) => file.seek(pos),
             InternalFile::Buffer(file) => file.seek(pos),
+            InternalFile::Lz77(file) => file.seek(pos),
+            InternalFile::Inflate(file) => file.seek(pos),
+            #[cfg(feature = "http")]
+            InternalFile::Remote(file) => file.seek(pos),
         }
     }
 }
@@ -82,10 +158,40 @@ pub struct KFile<'a> {
 impl<'a> KFile<'a> {
     fn open(
         name: PathBuf,
-        file: Option<File>,
+        file: Option<InternalFile<'a>>,
         info: KFileInfo,
         buffer: Option<&'a [u8]>,
     ) -> std::io::Result<Self> {
+        if let Some(compression) = info.compression {
+            // the stored entry is opened as is, then decompressed on top of that.
+            // the cipher (if any) applies to the stored bytes, so it stays with the inner file
+            let stored = KFileInfo {
+                size: compression.packed_size(),
+                compression: None,
+                ..info.clone()
+            };
+            let stored = Self::open(name.clone(), file, stored, buffer)?;
+            let file = match compression {
+                Compression::Lz77 { .. } => {
+                    InternalFile::Lz77(Box::new(Lz77Reader::new(stored, info.size)))
+                }
+                Compression::Deflate { .. } => {
+                    InternalFile::Inflate(Box::new(InflateReader::new(stored, info.size)))
+                }
+            };
+            // offsets are into the decompressed data from here on
+            return Ok(Self {
+                name,
+                file,
+                info: KFileInfo {
+                    offset: 0,
+                    cipher: None,
+                    available: None,
+                    ..info
+                },
+                pos: 0,
+            });
+        }
         if let Some(buffer) = buffer {
             let mut cursor = Cursor::new(buffer);
             cursor.seek(SeekFrom::Start(info.offset))?;
@@ -99,7 +205,7 @@ impl<'a> KFile<'a> {
             file.seek(SeekFrom::Start(info.offset))?;
             Ok(Self {
                 name,
-                file: InternalFile::RealFile(file),
+                file,
                 info,
                 pos: 0,
             })
@@ -121,16 +227,32 @@ impl<'a> Read for KFile<'a> {
         if self.pos >= self.info.size {
             return Ok(0);
         }
+        // reads are only refused once they hit the part that's missing from a truncated archive
+        let mut readable = self.info.size - self.pos;
+        if let Some(available) = self.info.available {
+            if self.pos >= available {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "{} is missing from the truncated archive past byte {}",
+                        self.name.display(),
+                        available
+                    ),
+                ));
+            }
+            readable = available - self.pos;
+        }
         // In both cases we still need to read from the underlying file to the buffer.
-        let bytes_to_read = usize::min(buf.len(), (self.info.size - self.pos) as usize);
+        let bytes_to_read = usize::min(buf.len(), readable as usize);
         let ret_val = self.file.read(&mut buf[..bytes_to_read])?;
+        let start = self.pos;
         self.pos += ret_val as u64;
         if let Some(cipher) = &mut self.info.cipher {
             // gitadora mar files use a non seekable 4 byte block cipher with a 4 byte key and iv.
             // it's entirely self rolled and somewhat annoying to implement in a re-entrant manner.
             // this implementation works though. random access is however not possible currently
             // without a method for seeking the cipher in constant time rather than O(N)
-            cipher.crypt(&mut buf[..ret_val]);
+            cipher.crypt(start, &mut buf[..ret_val]);
         }
         Ok(ret_val)
     }
@@ -175,12 +297,125 @@ impl<'a> Seek for KFile<'a> {
     }
 }
 
+// where the data for an entry being written into an archive comes from
+#[derive(Clone)]
+pub(crate) enum EntrySource<'a> {
+    Buffer(Vec<u8>),
+    Path(PathBuf),
+    // streamed straight out of another mounted archive
+    Archive(&'a KArchive, PathBuf),
+}
+
+impl<'a> EntrySource<'a> {
+    pub(crate) fn size(&self) -> std::io::Result<u64> {
+        match self {
+            EntrySource::Buffer(buf) => Ok(buf.len() as u64),
+            EntrySource::Path(path) => Ok(std::fs::metadata(path)?.len()),
+            EntrySource::Archive(archive, path) => Ok(archive.open(path)?.size()),
+        }
+    }
+
+    pub(crate) fn copy_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<u64> {
+        match self {
+            EntrySource::Buffer(buf) => {
+                wtr.write_all(buf)?;
+                Ok(buf.len() as u64)
+            }
+            EntrySource::Path(path) => std::io::copy(&mut File::open(path)?, wtr),
+            EntrySource::Archive(archive, path) => std::io::copy(&mut archive.open(path)?, wtr),
+        }
+    }
+
+    pub(crate) fn reader(&self) -> std::io::Result<Box<dyn Read + '_>> {
+        match self {
+            EntrySource::Buffer(buf) => Ok(Box::new(Cursor::new(buf))),
+            EntrySource::Path(path) => Ok(Box::new(File::open(path)?)),
+            EntrySource::Archive(archive, path) => Ok(Box::new(archive.open(path)?)),
+        }
+    }
+}
+
+/// Recursively lists every file under `dir`, returning (name relative to `dir`, full path) pairs.
+pub(crate) fn walk_dir(dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>, KArchiveError> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in std::fs::read_dir(&current)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                pending.push(path);
+            } else {
+                let name = path
+                    .strip_prefix(dir)
+                    .map_err(|_| KArchiveError::Other("Failed to make path relative"))?
+                    .to_path_buf();
+                files.push((name, path));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// archive formats all store names with forward slashes, regardless of platform
+pub(crate) fn archive_name(path: &Path) -> String {
+    path.iter()
+        .map(|c| c.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
 #[derive(Debug, Clone)]
 struct KArchiveInner {
     path: PathBuf,
     files: HashMap<PathBuf, KFileInfo>,
     // optional buffer to be used in special circumstances...
     buffer: Option<Vec<u8>>,
+    // (type, value) the ULST/NAME descriptor this archive was mounted through declares for it
+    declared_checksum: Option<(String, String)>,
+    // path is a folder of loose files rather than an archive, and each entry is the file
+    // at path/name
+    directory: bool,
+}
+
+impl KArchiveInner {
+    // the file on disk an entry's data is read from
+    fn source(&self, name: &Path) -> PathBuf {
+        match self.directory {
+            true => self.path.join(name),
+            false => self.path.clone(),
+        }
+    }
+}
+
+/// Metadata about a single entry, see `KArchive::metadata`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KEntryMeta {
+    pub size: u64,
+    /// How many bytes the payload takes up in `archive`. Less than `size` for compressed
+    /// entries (arc, zip), the same otherwise.
+    pub stored_size: u64,
+    /// Where the entry's stored (possibly compressed) payload starts in `archive`. 0 for
+    /// mounted folders.
+    pub offset: u64,
+    /// The archive (or part of a multi part set) the entry is stored in. For mounted
+    /// folders, the entry's own file.
+    pub archive: PathBuf,
+    /// Checksum declared for that archive by the ULST/NAME descriptor it was mounted
+    /// through, as (checksum type as written in the descriptor, checksum).
+    pub declared_checksum: Option<(String, String)>,
+    /// Fields of the entry's record header whose meaning isn't known yet, as stored.
+    /// Empty for formats without any.
+    pub raw_fields: Vec<u32>,
+    /// Whether the payload is stored encrypted (and deciphered as it's read), eg. M32 mar
+    /// entries or qar entries the key provider had a cipher for.
+    pub encrypted: bool,
+    /// When the entry was last modified, for formats that store it (tar, zip, iso, cab
+    /// members and mounted folders).
+    pub modified: Option<SystemTime>,
+    /// Unix permission bits, for formats that store them (tar, zip and mounted folders).
+    pub mode: Option<u32>,
 }
 
 // because of games with multipart updates, we actually need a vector of archive structs.
@@ -188,19 +423,141 @@ struct KArchiveInner {
 #[derive(Debug, Clone)]
 pub struct KArchive {
     archives: Vec<KArchiveInner>,
+    // non fatal problems found while mounting. these used to only be eprintln'd
+    warnings: Vec<KArchiveWarning>,
+    // parsed NAME/info descriptor, if this archive was mounted through one
+    info_manifest: Option<InfoManifest>,
+    // summary of the multi part set this archive was mounted from
+    update_info: Option<UpdateInfo>,
+    // entries that turned out to be archives themselves and got mounted in place
+    nested: Vec<PathBuf>,
+    // the update's FileList.dat, if it has one
+    file_list: Option<FileList>,
 }
 
 impl KArchive {
     pub(crate) fn add_archive(&mut self, arc: &mut Self) {
-        self.archives.append(&mut arc.archives)
+        self.archives.append(&mut arc.archives);
+        self.warnings.append(&mut arc.warnings);
+        if self.info_manifest.is_none() {
+            self.info_manifest = arc.info_manifest.take();
+        }
+        if self.update_info.is_none() {
+            self.update_info = arc.update_info.take();
+        }
+        self.nested.append(&mut arc.nested);
+        if self.file_list.is_none() {
+            self.file_list = arc.file_list.take();
+        }
+    }
+
+    /// Layers `top` over this archive, so they're used as one: entries in `top` shadow the
+    /// ones with the same name in this archive, which stay listed otherwise. Layering several
+    /// updates in release order gives the content they add up to.
+    pub fn overlay(&mut self, mut top: KArchive) {
+        let shadowed: HashSet<PathBuf> = top.list_files().into_iter().collect();
+        for archive in &mut self.archives {
+            archive.files.retain(|name, _| !shadowed.contains(name));
+        }
+        self.nested.retain(|path| !shadowed.contains(path));
+        // entries are looked up in the first archive that has them, so the top goes first
+        std::mem::swap(self, &mut top);
+        self.add_archive(&mut top);
+    }
+
+    // moves every entry under `prefix`, which is how nested archives show up in their parent
+    pub(crate) fn prefix_paths(&mut self, prefix: &Path) {
+        for archive in &mut self.archives {
+            archive.files = std::mem::take(&mut archive.files)
+                .into_iter()
+                .map(|(path, info)| (prefix.join(path), info))
+                .collect();
+        }
+        self.nested = std::mem::take(&mut self.nested)
+            .into_iter()
+            .map(|path| prefix.join(path))
+            .collect();
+        for warning in &mut self.warnings {
+            if let KArchiveWarning::NestedMountFailed { entry, .. } = warning {
+                *entry = prefix.join(&*entry);
+            }
+        }
+    }
+
+    pub(crate) fn set_declared_checksum(&mut self, checksum_type: &str, checksum: &str) {
+        for archive in &mut self.archives {
+            archive.declared_checksum = Some((checksum_type.to_string(), checksum.to_string()));
+        }
+    }
+
+    pub(crate) fn add_nested(&mut self, path: PathBuf) {
+        self.nested.push(path);
+    }
+
+    /// Entries that are archives themselves, with their contents listed under them
+    /// (eg. `inner.bar/data/file.bin`). Only filled in with `MountOptions::mount_nested`.
+    pub fn nested_archives(&self) -> &[PathBuf] {
+        &self.nested
     }
 
     pub(crate) fn init_empty() -> Self {
         Self {
             archives: Vec::new(),
+            warnings: Vec::new(),
+            info_manifest: None,
+            update_info: None,
+            nested: Vec::new(),
+            file_list: None,
         }
     }
 
+    pub(crate) fn set_update_info(&mut self, info: UpdateInfo) {
+        self.update_info = Some(info);
+    }
+
+    /// Game code, datecode and part info for archives mounted through a ULST or NAME descriptor.
+    pub fn update_info(&self) -> Option<&UpdateInfo> {
+        self.update_info.as_ref()
+    }
+
+    pub(crate) fn set_info_manifest(&mut self, manifest: InfoManifest) {
+        self.info_manifest = Some(manifest);
+    }
+
+    /// The parsed NAME/info descriptor when this archive was mounted from one.
+    pub fn info_manifest(&self) -> Option<&InfoManifest> {
+        self.info_manifest.as_ref()
+    }
+
+    pub(crate) fn set_file_list(&mut self, list: FileList) {
+        self.file_list = Some(list);
+    }
+
+    /// The FileList.dat found in the archive, which entries were checked against when mounting.
+    pub fn file_list(&self) -> Option<&FileList> {
+        self.file_list.as_ref()
+    }
+
+    pub(crate) fn add_warning(&mut self, warning: KArchiveWarning) {
+        self.warnings.push(warning);
+    }
+
+    /// Problems that were encountered while mounting but didn't stop the mount from succeeding.
+    pub fn warnings(&self) -> &[KArchiveWarning] {
+        &self.warnings
+    }
+
+    /// Parts of a multi part set (ULST/NAME) that couldn't be mounted and were skipped.
+    pub fn missing_parts(&self) -> Vec<&Path> {
+        self.warnings
+            .iter()
+            .filter_map(|warning| match warning {
+                KArchiveWarning::MissingPart { path, .. } => Some(path.as_path()),
+                _ => None,
+            })
+            .collect()
+    }
+
     pub(crate) fn new(
         path: PathBuf,
         files: HashMap<PathBuf, KFileInfo>,
@@ -211,10 +568,42 @@ impl KArchive {
                 path,
                 files,
                 buffer,
+                declared_checksum: None,
+                directory: false,
             }],
+            warnings: Vec::new(),
+            info_manifest: None,
+            update_info: None,
+            nested: Vec::new(),
+            file_list: None,
         }
     }
 
+    /// Wraps a folder of extracted files, listing each of them under its path relative to `dir`.
+    pub(crate) fn from_directory(dir: PathBuf) -> Result<Self, KArchiveError> {
+        let files = walk_dir(&dir)?
+            .into_iter()
+            .map(|(name, path)| {
+                let meta = std::fs::metadata(path)?;
+                #[cfg(unix)]
+                let mode = Some(std::os::unix::fs::PermissionsExt::mode(&meta.permissions()));
+                #[cfg(not(unix))]
+                let mode = None;
+                let info = KFileInfo {
+                    size: meta.len(),
+                    offset: 0,
+                    modified: meta.modified().ok(),
+                    mode,
+                    ..Default::default()
+                };
+                Ok((name, info))
+            })
+            .collect::<Result<_, KArchiveError>>()?;
+        let mut archive = Self::new(dir, files, None);
+        archive.archives[0].directory = true;
+        Ok(archive)
+    }
+
     pub fn list_files(&self) -> Vec<PathBuf> {
         let mut res = Vec::new();
         self.archives.iter().for_each(|archive| {
@@ -224,7 +613,7 @@ impl KArchive {
         res
     }
 
-    pub fn open(&self, path: &Path) -> std::io::Result<KFile> {
+    pub fn open(&self, path: &Path) -> std::io::Result<KFile<'_>> {
         for archive in &self.archives {
             if let Some(info) = archive.files.get(path) {
                 match &archive.buffer {
@@ -234,7 +623,7 @@ impl KArchive {
                     None => {
                         return KFile::open(
                             path.into(),
-                            Some(File::open(&archive.path)?),
+                            Some(InternalFile::open(&archive.source(path))?),
                             info.clone(),
                             None,
                         );
@@ -255,6 +644,52 @@ impl KArchive {
             .is_some()
     }
 
+    /// Metadata for `path`, or None if it isn't in the archive.
+    pub fn metadata(&self, path: &Path) -> Option<KEntryMeta> {
+        self.archives.iter().find_map(|archive| {
+            let info = archive.files.get(path)?;
+            Some(KEntryMeta {
+                size: info.size,
+                stored_size: info
+                    .compression
+                    .as_ref()
+                    .map_or(info.size, |compression| compression.packed_size()),
+                offset: info.offset,
+                archive: archive.source(path),
+                declared_checksum: archive.declared_checksum.clone(),
+                raw_fields: info.raw_fields.clone(),
+                encrypted: info.cipher.is_some(),
+                modified: info.modified,
+                mode: info.mode,
+            })
+        })
+    }
+
+    // the archive (or part, or mounted folder) `path` was found in
+    pub(crate) fn part_path(&self, path: &Path) -> Option<&Path> {
+        self.archives
+            .iter()
+            .find(|archive| archive.files.contains_key(path))
+            .map(|archive| archive.path.as_path())
+    }
+
+    /// Whether the whole payload of `path` is present. Only false for entries cut off
+    /// by a truncated archive mounted with `MountOptions::allow_truncated`.
+    pub fn is_complete(&self, path: &Path) -> bool {
+        self.archives
+            .iter()
+            .find_map(|archive| archive.files.get(path))
+            .is_some_and(|info| info.available.is_none())
+    }
+
+    /// How many bytes of the payload of `path` are actually present in the archive.
+    pub fn available_size(&self, path: &Path) -> Option<u64> {
+        self.archives
+            .iter()
+            .find_map(|archive| archive.files.get(path))
+            .map(|info| info.available.unwrap_or(info.size))
+    }
+
     pub fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
         let mut file = self.open(path)?;
         let mut buf = Vec::with_capacity(file.info.size as usize);
@@ -262,6 +697,85 @@ impl KArchive {
         Ok(buf)
     }
 
+    /// Writes `path` out to the file `dest`, creating the folders leading up to it.
+    /// `on_chunk` gets every chunk once it's written, so callers can report progress on big
+    /// entries or hash them without reading anything twice. Returns the entry's size.
+    /// The entry's modification time and permissions are applied to `dest` when the format
+    /// stores them. On Windows, `dest` can be longer than 260 characters.
+    pub fn extract_to(
+        &self,
+        path: &Path,
+        dest: &Path,
+        on_chunk: impl FnMut(&[u8]),
+    ) -> std::io::Result<u64> {
+        self.extract_to_with(path, dest, &WriteOptions::default(), on_chunk)
+    }
+
+    /// Like `extract_to`, with control over how `dest` is written.
+    pub fn extract_to_with(
+        &self,
+        path: &Path,
+        dest: &Path,
+        options: &WriteOptions,
+        on_chunk: impl FnMut(&[u8]),
+    ) -> std::io::Result<u64> {
+        let dest = crate::paths::long_path(dest);
+        let dest = dest.as_ref();
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // replaced rather than written through, in case it's a hard link to something else
+        if dest.is_file() {
+            std::fs::remove_file(dest)?;
+        }
+        let file = File::create(dest)?;
+        let meta = self.metadata(path);
+        let size = meta.as_ref().map_or(0, |meta| meta.size);
+        if options.preallocate && size > 0 {
+            preallocate(&file, size);
+        }
+        let mut out = std::io::BufWriter::with_capacity(options.buffer_size.max(1), file);
+        let total = self.extract_into(path, &mut out, on_chunk)?;
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        // a cut off entry comes out shorter than what was reserved
+        if options.preallocate && total != size {
+            file.set_len(total)?;
+        }
+        if let Some(modified) = meta.as_ref().and_then(|meta| meta.modified) {
+            file.set_modified(modified)?;
+        }
+        // kept readable and writable by the owner, so a later run can still update it
+        #[cfg(unix)]
+        if let Some(mode) = meta.and_then(|meta| meta.mode) {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777 | 0o600))?;
+        }
+        Ok(total)
+    }
+
+    /// Like `extract_to`, but streams the entry into `out` (eg. a member of a zip being
+    /// written) instead of a file.
+    pub fn extract_into<W: Write>(
+        &self,
+        path: &Path,
+        out: &mut W,
+        mut on_chunk: impl FnMut(&[u8]),
+    ) -> std::io::Result<u64> {
+        let mut file = self.open(path)?;
+        let mut buf = vec![0_u8; 0x10000];
+        let mut total = 0;
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            out.write_all(&buf[..read])?;
+            total += read as u64;
+            on_chunk(&buf[..read]);
+        }
+        Ok(total)
+    }
+
     pub fn guess_contents_folder(&self) -> Option<PathBuf> {
         Some(
             self.list_files()
@@ -275,6 +789,186 @@ impl KArchive {
     }
 }
 
+/// What to do when a descriptor file (ULST etc.) declares a checksum for an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumPolicy {
+    /// Don't hash anything. Hashing a full update set can take a long time
+    #[default]
+    Ignore,
+    /// Hash and record a warning on mismatch, but still mount the archive
+    Warn,
+    /// Hash and fail the mount on mismatch
+    Error,
+}
+
+/// Whether an archive gets read into memory up front instead of read from disk as entries
+/// are opened. Only the formats read straight from the file (mar, bar, qar, d2, ifs, s3p,
+/// arc and 2dx) are affected, the rest always work one way or the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Buffering {
+    /// When the storage looks slow to seek around in, eg. a network share, as long as the
+    /// archive is no bigger than `MountOptions::max_memory`
+    #[default]
+    Auto,
+    /// Never, for when memory is tight
+    Never,
+    /// Always, for when it's worth it regardless of the storage
+    Always,
+}
+
+/// How entry names are decoded. Older titles store them as Shift-JIS, newer ones as UTF-8,
+/// plain ASCII names come out the same either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameEncoding {
+    /// UTF-8 where the name is valid UTF-8, Shift-JIS otherwise
+    #[default]
+    Auto,
+    Utf8,
+    ShiftJis,
+}
+
+impl NameEncoding {
+    pub fn decode(self, name: &[u8]) -> Result<String, KArchiveError> {
+        let shift_jis = || {
+            encoding_rs::SHIFT_JIS
+                .decode_without_bom_handling_and_without_replacement(name)
+                .map(String::from)
+        };
+        match self {
+            NameEncoding::Utf8 => Ok(String::from_utf8(name.to_vec())?),
+            NameEncoding::ShiftJis => {
+                shift_jis().ok_or_else(|| KArchiveError::ParseError("name isn't Shift-JIS".into()))
+            }
+            NameEncoding::Auto => match std::str::from_utf8(name) {
+                Ok(name) => Ok(name.to_string()),
+                Err(_) => shift_jis().ok_or_else(|| {
+                    KArchiveError::ParseError("name is neither UTF-8 nor Shift-JIS".into())
+                }),
+            },
+        }
+    }
+}
+
+/// How `KArchive::extract_to_with` writes files. `extract_to` uses the defaults.
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    /// How much is gathered up before each write to the file, in bytes. Bigger writes help
+    /// on HDDs and SMR drives
+    pub buffer_size: usize,
+    /// Reserve the entry's whole size before writing it, so big files don't end up
+    /// fragmented. Only a hint, where it's not supported files are written as usual
+    pub preallocate: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            buffer_size: 0x10000,
+            preallocate: false,
+        }
+    }
+}
+
+// fallocate on linux, the end of file moved out (SetEndOfFile) on windows. errors are
+// ignored, the file just gets written without
+fn preallocate(file: &File, size: u64) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        // KEEP_SIZE so the file only grows as it's written, a failed extraction doesn't
+        // leave it padded out with zeroes
+        // SAFETY: the fd belongs to `file`, which outlives the call
+        unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_KEEP_SIZE,
+                0,
+                size.try_into().unwrap_or(libc::off_t::MAX),
+            );
+        }
+    }
+    #[cfg(windows)]
+    let _ = file.set_len(size);
+    #[cfg(not(any(target_os = "linux", windows)))]
+    let _ = (file, size);
+}
+
+/// Options controlling how archives get mounted. `mount` uses the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct MountOptions {
+    pub verify_checksums: ChecksumPolicy,
+    /// Fail the mount if any part of a multi part set can't be mounted,
+    /// rather than skipping it and recording a warning.
+    pub require_all_parts: bool,
+    /// When a bar/qar entry table turns out to be damaged, scan the rest of the file for
+    /// entry records instead of giving up on everything after the bad one.
+    pub salvage: bool,
+    /// Mount archives that were cut off partway (eg. incomplete downloads). Every entry
+    /// that's at least partially present gets listed, and reads only fail once they reach
+    /// the missing part. Use `KArchive::is_complete` to check individual entries.
+    pub allow_truncated: bool,
+    /// Look inside entries for further archives (a bar inside a cab, d2 files inside a qar)
+    /// and list their contents under the entry's path, eg. `inner.bar/data/file.bin`.
+    /// Nested archives are read into memory rather than extracted.
+    pub mount_nested: bool,
+    /// Supplies ciphers for archives whose entries are encrypted with a scheme that
+    /// depends on the title, eg. some qars. Without one they're mounted as stored.
+    pub key_provider: Option<Arc<dyn KeyProvider>>,
+    /// When archives are read into memory, see `Buffering`.
+    pub buffering: Buffering,
+    /// The most `Buffering::Auto` reads into memory for a single archive, in bytes. Bigger
+    /// archives are always read from disk. No limit if None.
+    pub max_memory: Option<u64>,
+    /// How entry names are decoded, see `NameEncoding`.
+    pub name_encoding: NameEncoding,
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum KArchiveWarning {
+    #[error("{checksum_type} checksum mismatch for {}: expected {expected}, got {actual}", path.display())]
+    ChecksumMismatch {
+        path: PathBuf,
+        checksum_type: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("can't verify {}: unsupported checksum type {checksum_type}", path.display())]
+    UnsupportedChecksum {
+        path: PathBuf,
+        checksum_type: String,
+    },
+    #[error("failed to mount part {}: {reason}", path.display())]
+    MissingPart { path: PathBuf, reason: String },
+    #[error("size mismatch for {}: expected {expected}, got {actual}", path.display())]
+    SizeMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("entry table of {} is damaged, recovered {recovered} entries by scanning", path.display())]
+    Salvaged { path: PathBuf, recovered: usize },
+    #[error("{} is truncated, {} is incomplete and anything after it is missing", path.display(), entry.display())]
+    Truncated { path: PathBuf, entry: PathBuf },
+    #[error("{} is listed in {} but isn't in the archive", path.display(), listed_in.display())]
+    MissingEntry { path: PathBuf, listed_in: PathBuf },
+    #[error("stopped reading {} after {parsed} entries: {reason}", path.display())]
+    ParseStopped {
+        path: PathBuf,
+        parsed: usize,
+        reason: String,
+    },
+    #[error("skipped an entry of {}: {reason}", path.display())]
+    SkippedEntry { path: PathBuf, reason: String },
+    #[error("{} looks like an archive but couldn't be mounted: {reason}", entry.display())]
+    NestedMountFailed { entry: PathBuf, reason: String },
+    #[error("ignored line {line} of {}: {content}", path.display())]
+    IgnoredLine {
+        path: PathBuf,
+        line: usize,
+        content: String,
+    },
+}
+
 #[derive(Error, Debug)]
 pub enum KArchiveError {
     #[error("io error encountered: {0}")]
@@ -285,22 +979,315 @@ pub enum KArchiveError {
     BinreadError(#[from] binread::Error),
     #[error("from utf8 error encountered: {0}")]
     FromUTF8Error(#[from] std::string::FromUtf8Error),
+    #[error("{checksum_type} checksum mismatch for {}: expected {expected}, got {actual}", path.display())]
+    ChecksumMismatch {
+        path: PathBuf,
+        checksum_type: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("failed to mount part {}: {source}", path.display())]
+    MissingPart {
+        path: PathBuf,
+        source: Box<KArchiveError>,
+    },
+    #[error("size mismatch for {}: expected {expected}, got {actual}", path.display())]
+    SizeMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("write error encountered: {0}")]
+    WriteError(String),
+    #[error("invalid {format} header: {reason}")]
+    InvalidHeader {
+        format: &'static str,
+        reason: String,
+    },
+    #[error("invalid {format} entry #{index} ({name}): {reason}")]
+    InvalidEntry {
+        format: &'static str,
+        index: usize,
+        name: String,
+        reason: String,
+    },
+    #[error("invalid pattern {pattern}: {reason}")]
+    InvalidPattern { pattern: String, reason: String },
+    #[error("refusing to extract {} under {}: {reason}", path.display(), root.display())]
+    UnsafePath {
+        path: PathBuf,
+        root: PathBuf,
+        reason: &'static str,
+    },
     #[error("error encountered: {0}")]
     Other(&'static str),
 }
 
+/// Runs `f` over every item using a pool of scoped threads, returning the results in
+/// the same order as `items`. Used to mount the parts of multi part sets concurrently
+/// since each part does its own (slow) sequential header scan.
+pub(crate) fn parallel_map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(items.len());
+    if workers <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new((0..items.len()).map(|_| None).collect());
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(idx) else {
+                    break;
+                };
+                let result = f(item);
+                results.lock().unwrap()[idx] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every item gets processed"))
+        .collect()
+}
+
+/// Checks a part of a multi part set against what its descriptor declares.
+/// Mismatches either fail or become warnings on `archive` depending on the options.
+pub(crate) fn verify_part(
+    part_path: &Path,
+    expected_size: Option<u64>,
+    checksum: Option<(&str, &str)>,
+    options: &MountOptions,
+    archive: &mut KArchive,
+) -> Result<(), KArchiveError> {
+    if options.verify_checksums == ChecksumPolicy::Ignore {
+        return Ok(());
+    }
+    let fail = options.verify_checksums == ChecksumPolicy::Error;
+    if let Some(expected) = expected_size {
+        let actual = source_len(part_path)?;
+        if actual != expected {
+            if fail {
+                return Err(KArchiveError::SizeMismatch {
+                    path: part_path.to_path_buf(),
+                    expected,
+                    actual,
+                });
+            }
+            archive.add_warning(KArchiveWarning::SizeMismatch {
+                path: part_path.to_path_buf(),
+                expected,
+                actual,
+            });
+        }
+    }
+    let Some((checksum_type, expected)) = checksum else {
+        return Ok(());
+    };
+    let Some(ty) = ChecksumType::from_name(checksum_type) else {
+        archive.add_warning(KArchiveWarning::UnsupportedChecksum {
+            path: part_path.to_path_buf(),
+            checksum_type: checksum_type.to_string(),
+        });
+        return Ok(());
+    };
+    let expected = expected.trim().to_ascii_lowercase();
+    let actual = hash_file(part_path, ty)?;
+    if actual != expected {
+        if fail {
+            return Err(KArchiveError::ChecksumMismatch {
+                path: part_path.to_path_buf(),
+                checksum_type: checksum_type.to_string(),
+                expected,
+                actual,
+            });
+        }
+        archive.add_warning(KArchiveWarning::ChecksumMismatch {
+            path: part_path.to_path_buf(),
+            checksum_type: checksum_type.to_string(),
+            expected,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+// size of the archive being parsed, whether it got buffered or not
+pub(crate) fn archive_len(buffer: &Option<Vec<u8>>, path: &Path) -> std::io::Result<u64> {
+    match buffer {
+        Some(buf) => Ok(buf.len() as u64),
+        None => source_len(path),
+    }
+}
+
+pub(crate) fn invalid_entry(
+    format: &'static str,
+    index: usize,
+    name: &str,
+    reason: impl Into<String>,
+) -> KArchiveError {
+    KArchiveError::InvalidEntry {
+        format,
+        index,
+        name: name.to_string(),
+        reason: reason.into(),
+    }
+}
+
+// name reading fails on anything from EOF to bad utf8. io errors are passed through as is
+// since they're usually down to the archive being cut off rather than being malformed
+pub(crate) fn name_error(
+    format: &'static str,
+    index: usize,
+    error: KArchiveError,
+) -> KArchiveError {
+    match error {
+        KArchiveError::IoError(_) => error,
+        error => invalid_entry(format, index, "<unreadable name>", error.to_string()),
+    }
+}
+
+/// Rejects names that can't be extracted safely: empty ones, control characters, and
+/// `..` components that would escape the output folder.
+pub(crate) fn check_entry_name(
+    format: &'static str,
+    index: usize,
+    name: &str,
+) -> Result<(), KArchiveError> {
+    let reason = if name.is_empty() {
+        "name is empty"
+    } else if name.chars().any(char::is_control) {
+        "name contains control characters"
+    } else if name.split(['/', '\\']).any(|component| component == "..") {
+        "name escapes the archive root"
+    } else {
+        return Ok(());
+    };
+    Err(invalid_entry(format, index, name, reason))
+}
+
+/// Rejects entry counts that couldn't fit in the archive even if every entry was empty.
+/// Truncated archives are expected to fall short, so the check is skipped for them.
+pub(crate) fn check_entry_count(
+    format: &'static str,
+    count: u64,
+    min_record: u64,
+    start: u64,
+    end: u64,
+    options: &MountOptions,
+) -> Result<(), KArchiveError> {
+    if options.allow_truncated || start + count * min_record <= end {
+        return Ok(());
+    }
+    Err(KArchiveError::InvalidHeader {
+        format,
+        reason: format!("{} entries can't fit in an archive of {} bytes", count, end),
+    })
+}
+
+/// Checks an entry's payload fits in the archive. When it doesn't, the entry is either
+/// an error or (with `allow_truncated`) kept with however many bytes are present.
+pub(crate) fn payload_available(
+    format: &'static str,
+    index: usize,
+    name: &str,
+    (offset, size): (u64, u64),
+    end: u64,
+    options: &MountOptions,
+) -> Result<Option<u64>, KArchiveError> {
+    if offset + size <= end {
+        Ok(None)
+    } else if options.allow_truncated {
+        Ok(Some(end.saturating_sub(offset)))
+    } else {
+        Err(invalid_entry(
+            format,
+            index,
+            name,
+            format!(
+                "{} bytes at {:#x} run past the end of the archive ({} bytes)",
+                size, offset, end
+            ),
+        ))
+    }
+}
+
+// shared by the multi part formats. either bails or records the part as missing depending on the options
+pub(crate) fn handle_missing_part(
+    archive: &mut KArchive,
+    path: PathBuf,
+    error: KArchiveError,
+    options: &MountOptions,
+) -> Result<(), KArchiveError> {
+    if options.require_all_parts {
+        return Err(KArchiveError::MissingPart {
+            path,
+            source: Box::new(error),
+        });
+    }
+    archive.add_warning(KArchiveWarning::MissingPart {
+        path,
+        reason: error.to_string(),
+    });
+    Ok(())
+}
+
+// how many random single byte reads the storage gets benchmarked with
+const BENCH_SEEKS: usize = 10;
+// 4 ms seems like a reasonable target to hit
+const BENCH_TARGET: Duration = Duration::from_millis(4);
+// how much `bench_storage` reads to measure throughput
+const BENCH_SEQUENTIAL: u64 = 0x400_0000;
+
+// whether an archive of `size` bytes gets read into memory, when that doesn't depend on how
+// fast the storage is
+fn buffering_settled(options: &MountOptions, size: u64) -> Option<bool> {
+    match options.buffering {
+        Buffering::Never => Some(false),
+        Buffering::Always => Some(true),
+        // nothing to seek around in
+        Buffering::Auto if size == 0 => Some(false),
+        Buffering::Auto if options.max_memory.is_some_and(|max| size > max) => Some(false),
+        Buffering::Auto => None,
+    }
+}
+
 /// What should this function be called? It benchmarks the underlying fs to
 /// hopefully detect whether we're on a network share or some other high
 /// latency fs. But it returns either a buffer to use or nothing
 /// which has nothing to do with the name...
-pub(crate) fn benchmark(path: &Path) -> Result<Option<Vec<u8>>, Error> {
+pub(crate) fn benchmark(path: &Path, options: &MountOptions) -> Result<Option<Vec<u8>>, Error> {
+    // the block cache takes care of those, reading all of it is what they're meant to avoid
+    if is_url(path) {
+        return Ok(None);
+    }
     let mut bench_file = File::open(path)?;
     let size = bench_file.metadata()?.len();
+    let read_all = |mut file: File| {
+        let mut buf = Vec::with_capacity(size as usize);
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut buf)?;
+        Ok(Some(buf))
+    };
+    match buffering_settled(options, size) {
+        Some(true) => return read_all(bench_file),
+        Some(false) => return Ok(None),
+        None => {}
+    }
     let start = Instant::now();
     let mut rng = rand::thread_rng();
     let range = Uniform::new(0, size);
-    let target_duration = Duration::from_millis(4); // 4 ms seems like a reasonable target to hit
-    for loc in (0..10).map(|_| rng.sample(range)) {
+    for loc in (0..BENCH_SEEKS).map(|_| rng.sample(range)) {
         bench_file.seek(SeekFrom::Start(loc))?;
         // i don't care whether the read actually does anything. only that it happens.
         // i don't want to risk read_exact throwing an irrelevant error
@@ -310,32 +1297,304 @@ pub(crate) fn benchmark(path: &Path) -> Result<Option<Vec<u8>>, Error> {
         // ie. sshfs on not a local network. the benchmark could take easily a few seconds in that situation
         // but we would know that the latency is high after even the first iteration...
         let elapsed = Instant::now().duration_since(start);
-        if elapsed > target_duration {
+        if elapsed > BENCH_TARGET {
             eprintln!("k_archives: High latency storage detected, reading full file into memory to allow faster processing.");
-            let mut buf = Vec::with_capacity(size as usize);
-            bench_file.seek(SeekFrom::Start(0))?;
-            bench_file.read_to_end(&mut buf)?;
-            return Ok(Some(buf));
+            return read_all(bench_file);
         }
     }
     Ok(None)
 }
 
+/// How the storage an archive is on performed, see `bench_storage`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageBench {
+    pub size: u64,
+    /// How long the random single byte reads mounting benchmarks with took on average.
+    /// Zero for empty files
+    pub seek_latency: Duration,
+    /// How long all of them took together, which is what mounting goes by
+    pub seek_total: Duration,
+    /// The latency over which `Buffering::Auto` reads archives into memory
+    pub seek_target: Duration,
+    /// Bytes per second read sequentially from the start of the file
+    pub throughput: f64,
+    /// How many bytes the throughput was measured over, at most 64MiB
+    pub sampled: u64,
+    /// Whether mounting with the same options reads the archive into memory
+    pub buffered: bool,
+}
+
+/// Runs the benchmark mounting uses to decide whether to read an archive into memory
+/// against the file at `path` and reports what it measured, along with how fast the file
+/// reads sequentially. Unlike mounting it always does every read, and the OS caching the
+/// file makes later runs look faster than the first.
+pub fn bench_storage(path: &Path, options: &MountOptions) -> std::io::Result<StorageBench> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut seek_total = Duration::ZERO;
+    if size > 0 {
+        let mut rng = rand::thread_rng();
+        let range = Uniform::new(0, size);
+        for loc in (0..BENCH_SEEKS).map(|_| rng.sample(range)) {
+            let start = Instant::now();
+            file.seek(SeekFrom::Start(loc))?;
+            let _ = file.read(&mut [0])?;
+            seek_total += start.elapsed();
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+    let start = Instant::now();
+    let sampled = std::io::copy(&mut file.take(BENCH_SEQUENTIAL), &mut std::io::sink())?;
+    let elapsed = start.elapsed().as_secs_f64();
+    Ok(StorageBench {
+        size,
+        seek_latency: match size {
+            0 => Duration::ZERO,
+            _ => seek_total / BENCH_SEEKS as u32,
+        },
+        seek_total,
+        seek_target: BENCH_TARGET,
+        throughput: match elapsed > 0.0 {
+            true => sampled as f64 / elapsed,
+            false => 0.0,
+        },
+        sampled,
+        buffered: buffering_settled(options, size).unwrap_or(seek_total > BENCH_TARGET),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn storage_bench() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.bin");
+        std::fs::write(&path, vec![0; 0x1000]).unwrap();
+        let bench = |buffering| {
+            let options = MountOptions {
+                buffering,
+                ..Default::default()
+            };
+            bench_storage(&path, &options).unwrap()
+        };
+        let always = bench(Buffering::Always);
+        assert_eq!(always.size, 0x1000);
+        assert_eq!(always.sampled, 0x1000);
+        assert!(always.buffered);
+        assert!(!bench(Buffering::Never).buffered);
+
+        let empty = dir.path().join("empty.bin");
+        std::fs::write(&empty, []).unwrap();
+        let bench = bench_storage(&empty, &MountOptions::default()).unwrap();
+        assert_eq!(bench.seek_latency, Duration::ZERO);
+        assert!(!bench.buffered);
+    }
+
+    // extraction tools share one mounted archive between threads
+    #[test]
+    fn archive_is_shareable() {
+        fn shareable<T: Send + Sync>() {}
+        shareable::<KArchive>();
+    }
+
+    #[test]
+    fn overlay_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let base_path = dir.path().join("base.mar");
+        let top_path = dir.path().join("top.mar");
+        crate::MarWriter::new()
+            .add_file("data/a.bin", vec![1])
+            .add_file("data/b.bin", vec![2])
+            .write(File::create(&base_path).unwrap())
+            .unwrap();
+        crate::MarWriter::new()
+            .add_file("data/b.bin", vec![3, 3])
+            .add_file("data/c.bin", vec![4])
+            .write(File::create(&top_path).unwrap())
+            .unwrap();
+        let mut archive = crate::mount(base_path.clone()).unwrap();
+        archive.overlay(crate::mount(top_path.clone()).unwrap());
+
+        let mut files = archive.list_files();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("data/a.bin"),
+                PathBuf::from("data/b.bin"),
+                PathBuf::from("data/c.bin")
+            ]
+        );
+        assert_eq!(archive.read(Path::new("data/b.bin")).unwrap(), vec![3, 3]);
+        assert_eq!(
+            archive.metadata(Path::new("data/b.bin")).unwrap().archive,
+            top_path
+        );
+        assert_eq!(
+            archive.metadata(Path::new("data/a.bin")).unwrap().archive,
+            base_path
+        );
+    }
+
+    #[test]
+    fn buffering() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.mar");
+        crate::MarWriter::new()
+            .add_file("data/a.bin", vec![7; 0x100])
+            .write(File::create(&path).unwrap())
+            .unwrap();
+        let mount = |buffering, max_memory| {
+            let options = MountOptions {
+                buffering,
+                max_memory,
+                ..Default::default()
+            };
+            crate::mount_with_options(path.clone(), &options).unwrap()
+        };
+        let buffered = mount(Buffering::Always, None);
+        let unbuffered = mount(Buffering::Never, None);
+        // a local tempdir never looks slow, so the cap is what keeps this one on disk
+        let capped = mount(Buffering::Auto, Some(0x10));
+        // only what was read into memory can still be read once the file is gone
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            buffered.read(Path::new("data/a.bin")).unwrap(),
+            vec![7; 0x100]
+        );
+        assert!(unbuffered.read(Path::new("data/a.bin")).is_err());
+        assert!(capped.read(Path::new("data/a.bin")).is_err());
+    }
+
+    #[test]
+    fn extract_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.mar");
+        crate::MarWriter::new()
+            .add_file("data/big.bin", vec![7; 0x18000])
+            .write(File::create(&path).unwrap())
+            .unwrap();
+        let archive = crate::mount(path).unwrap();
+        let dest = dir.path().join("out/data/big.bin");
+        let mut chunks = Vec::new();
+        let size = archive
+            .extract_to(Path::new("data/big.bin"), &dest, |chunk| {
+                chunks.push(chunk.len() as u64)
+            })
+            .unwrap();
+        assert_eq!(size, 0x18000);
+        assert_eq!(chunks.iter().sum::<u64>(), size);
+        assert!(chunks.len() > 1);
+        assert_eq!(std::fs::read(dest).unwrap(), vec![7; 0x18000]);
+    }
+
+    #[test]
+    fn shift_jis_names() {
+        // 曲名.bin as Shift-JIS, which isn't valid utf8
+        let sjis = b"\x8b\xc8\x96\xbc.bin";
+        assert_eq!(NameEncoding::Auto.decode(b"data.bin").unwrap(), "data.bin");
+        assert_eq!(NameEncoding::Auto.decode(sjis).unwrap(), "曲名.bin");
+        assert_eq!(NameEncoding::ShiftJis.decode(sjis).unwrap(), "曲名.bin");
+        assert!(NameEncoding::Utf8.decode(sjis).is_err());
+        // utf8 names are left alone by auto, while shift-jis reads them as something else
+        assert_eq!(
+            NameEncoding::Auto.decode("曲名".as_bytes()).unwrap(),
+            "曲名"
+        );
+        assert_ne!(
+            NameEncoding::ShiftJis.decode("曲名".as_bytes()).ok(),
+            Some("曲名".to_string())
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("names.mar");
+        let mut mar = Vec::new();
+        crate::MarWriter::new()
+            .add_file("data/XXXX.bin", vec![1; 4])
+            .write(&mut mar)
+            .unwrap();
+        // swapped in afterwards, the writer only takes utf8 names
+        let at = mar.windows(4).position(|window| window == b"XXXX").unwrap();
+        mar[at..at + 4].copy_from_slice(&sjis[..4]);
+        std::fs::write(&path, mar).unwrap();
+        let mount = |name_encoding| {
+            let options = MountOptions {
+                name_encoding,
+                ..Default::default()
+            };
+            crate::mount_with_options(path.clone(), &options)
+        };
+        let archive = mount(NameEncoding::Auto).unwrap();
+        assert_eq!(archive.list_files(), vec![PathBuf::from("data/曲名.bin")]);
+        assert_eq!(
+            archive.read(Path::new("data/曲名.bin")).unwrap(),
+            vec![1; 4]
+        );
+        // mar parsing stops at the bad name, keeping what came before it
+        assert!(mount(NameEncoding::Utf8).unwrap().list_files().is_empty());
+    }
+
+    #[test]
+    fn extract_preallocated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.mar");
+        crate::MarWriter::new()
+            .add_file("data/big.bin", vec![7; 0x18000])
+            .add_file("data/empty.bin", Vec::new())
+            .write(File::create(&path).unwrap())
+            .unwrap();
+        let archive = crate::mount(path).unwrap();
+        let options = WriteOptions {
+            buffer_size: 0x100000,
+            preallocate: true,
+        };
+        for (name, size) in [("data/big.bin", 0x18000), ("data/empty.bin", 0)] {
+            let dest = dir.path().join("out").join(name);
+            let written = archive
+                .extract_to_with(Path::new(name), &dest, &options, |_| {})
+                .unwrap();
+            assert_eq!(written, size);
+            assert_eq!(std::fs::read(dest).unwrap(), vec![7; size as usize]);
+        }
+    }
+
     #[test]
     fn windows_path_join() {
         let mut file_list: HashMap<PathBuf, KFileInfo> = HashMap::new();
         file_list.insert(
             PathBuf::from("reeeeeeeeeeee").join("reeeeeeeeee"),
-            KFileInfo {
-                size: 0,
-                offset: 0,
-                cipher: None,
-            },
+            KFileInfo::default(),
         );
         let archive = KArchive::new("big".into(), file_list, None);
         assert!(archive.exists(&PathBuf::from("reeeeeeeeeeee/reeeeeeeeee")))
     }
+
+    #[test]
+    fn parallel_map_keeps_order() {
+        let items: Vec<u64> = (0..100).collect();
+        let results = parallel_map(&items, |&i| {
+            // make later items finish first to shake out ordering bugs
+            std::thread::sleep(Duration::from_micros(100 - i));
+            i * 2
+        });
+        assert_eq!(results, items.iter().map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn directory_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = dir.path().join("contents");
+        std::fs::create_dir_all(contents.join("0/c")).unwrap();
+        std::fs::write(contents.join("0/c/2cf41d5c"), [5; 12]).unwrap();
+        std::fs::write(contents.join("FileList.dat"), "0/c/2cf41d5c 12\n").unwrap();
+        let archive = crate::mount(contents.clone()).unwrap();
+        assert_eq!(archive.list_files().len(), 2);
+        let name = Path::new("0/c/2cf41d5c");
+        assert_eq!(archive.read(name).unwrap(), vec![5; 12]);
+        assert_eq!(archive.metadata(name).unwrap().archive, contents.join(name));
+        assert_eq!(archive.file_list().unwrap().entries.len(), 1);
+        assert!(archive.warnings().is_empty());
+    }
 }
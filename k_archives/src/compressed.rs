@@ -0,0 +1,206 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use flate2::bufread::DeflateDecoder;
+use flate2::read::MultiGzDecoder;
+
+use crate::common::*;
+
+/// How an entry's payload is compressed in the archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    /// konami's lz77 stream of `packed_size` bytes starting at the entry's offset
+    Lz77 { packed_size: u64 },
+    /// raw deflate stream, as zip members are stored
+    #[cfg_attr(not(feature = "zip"), allow(dead_code))]
+    Deflate { packed_size: u64 },
+}
+
+impl Compression {
+    pub(crate) fn packed_size(&self) -> u64 {
+        match self {
+            Compression::Lz77 { packed_size } | Compression::Deflate { packed_size } => {
+                *packed_size
+            }
+        }
+    }
+}
+
+/// Inflates a raw deflate stream as it's read. Like the lz77 reader, seeking forwards
+/// inflates and throws away everything in between and seeking backwards starts over.
+pub(crate) struct InflateReader<R> {
+    decoder: DeflateDecoder<BufReader<R>>,
+    pos: u64,
+    size: u64,
+}
+
+impl<R: Read + Seek> InflateReader<R> {
+    pub(crate) fn new(inner: R, size: u64) -> Self {
+        Self {
+            decoder: DeflateDecoder::new(BufReader::new(inner)),
+            pos: 0,
+            size,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for InflateReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.decoder.read(buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for InflateReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.size.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        }
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Tried to seek to before the start of the file...",
+            )
+        })?;
+        if target < self.pos {
+            self.decoder.get_mut().seek(SeekFrom::Start(0))?;
+            self.decoder.reset_data();
+            self.pos = 0;
+        }
+        let skip = target - self.pos;
+        self.pos += std::io::copy(&mut (&mut self.decoder).take(skip), &mut std::io::sink())?;
+        Ok(self.pos)
+    }
+}
+
+pub(crate) const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+pub(crate) const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+// name of the archive inside, eg. update.mar for update.mar.gz. used to pick a parser for
+// formats that go by extension
+fn inner_name(path: &Path) -> PathBuf {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz" | "zst" | "zstd") => path.with_extension(""),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Mounts an archive that was gzipped or zstd compressed for distribution. The whole thing
+/// is decompressed into memory, then mounted the same way a nested archive would be.
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let mut magic = [0_u8; 4];
+    InternalFile::open(&path)?.read_exact(&mut magic)?;
+    let data = decompress(&magic, BufReader::new(InternalFile::open(&path)?))?;
+    mount_decompressed(path, data, options)
+}
+
+/// Same as `parse`, for a compressed archive that's already in memory.
+pub(crate) fn parse_from(
+    path: PathBuf,
+    compressed: &[u8],
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    let data = decompress(compressed, compressed)?;
+    mount_decompressed(path, data, options)
+}
+
+// `magic` is at least the first 4 bytes of what `reader` reads
+fn decompress(magic: &[u8], reader: impl BufRead) -> Result<Vec<u8>, KArchiveError> {
+    let mut data = Vec::new();
+    if magic.starts_with(&GZIP_MAGIC) {
+        MultiGzDecoder::new(reader).read_to_end(&mut data)?;
+        return Ok(data);
+    }
+    // zstd is C, which not every target (eg. wasm) can build
+    #[cfg(feature = "zstd")]
+    {
+        zstd::Decoder::with_buffer(reader)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+    #[cfg(not(feature = "zstd"))]
+    Err(KArchiveError::Other(
+        "zstd support isn't enabled, build with the zstd feature",
+    ))
+}
+
+fn mount_decompressed(
+    path: PathBuf,
+    data: Vec<u8>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    let name = inner_name(&path);
+    let Some(parser) = crate::nested::nested_parser(&name, &data, data.len() as u64) else {
+        return Err(KArchiveError::InvalidHeader {
+            format: "compressed",
+            reason: format!(
+                "{} doesn't contain an archive that can be mounted",
+                path.display()
+            ),
+        });
+    };
+    #[cfg(feature = "tar")]
+    let bundle = crate::tarfile::looks_like_tar(&data);
+    // the parsers only use the path to name the archive, and for the mar M32 check
+    #[allow(unused_mut)]
+    let mut archive = parser(path, data, options)?;
+    // a compressed tar is still a bundle, so whatever's in it gets mounted like with a plain one
+    #[cfg(feature = "tar")]
+    if bundle && !options.mount_nested {
+        crate::nested::expand(&mut archive, options, crate::nested::MAX_DEPTH)?;
+    }
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarWriter;
+    use flate2::{write::GzEncoder, Compression};
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn compressed_mount() {
+        let mar = MarWriter::new()
+            .add_file("data/a.bin", vec![4; 0x40])
+            .write(Vec::new())
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        let gz_path = dir.path().join("update.mar.gz");
+        let mut gz = GzEncoder::new(File::create(&gz_path).unwrap(), Compression::fast());
+        gz.write_all(&mar).unwrap();
+        gz.finish().unwrap();
+        #[allow(unused_mut)]
+        let mut paths = vec![gz_path];
+        #[cfg(feature = "zstd")]
+        {
+            let zst_path = dir.path().join("update.mar.zst");
+            std::fs::write(&zst_path, zstd::encode_all(&mar[..], 0).unwrap()).unwrap();
+            paths.push(zst_path);
+        }
+
+        for path in paths {
+            let archive = crate::mount(path).unwrap();
+            assert_eq!(
+                archive.read(Path::new("data/a.bin")).unwrap(),
+                vec![4; 0x40]
+            );
+        }
+
+        let junk_path = dir.path().join("junk.gz");
+        let mut gz = GzEncoder::new(File::create(&junk_path).unwrap(), Compression::fast());
+        gz.write_all(b"not an archive").unwrap();
+        gz.finish().unwrap();
+        assert!(matches!(
+            crate::mount(junk_path),
+            Err(KArchiveError::InvalidHeader {
+                format: "compressed",
+                ..
+            })
+        ));
+    }
+}
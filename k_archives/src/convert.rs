@@ -0,0 +1,119 @@
+use std::path::Path;
+
+use crate::cab::{CabWriter, CAB_MEMBER_PREFIX};
+use crate::checksum::ChecksumType;
+use crate::common::*;
+use crate::write::{builder_for, ArchiveBuilder};
+
+/// Container `convert` rewrites an archive into.
+#[derive(Debug, Clone)]
+pub enum TargetFormat {
+    /// A single MASMAR0 archive, optionally encrypted with the gitadora cipher.
+    Mar { encrypt: bool },
+    /// An MSCF cabinet holding an arcfile and filelist.
+    Cab { compress: bool },
+    /// A QAR archive.
+    Qar,
+    /// A bar archive, with the 252 byte names of M39A bars if `short_names` is set.
+    Bar { short_names: bool },
+    /// A pop'n music arc, lz77 compressed at `level` (0 to 9, 0 stores entries as is).
+    Arc { level: u8 },
+    /// Plain files laid out under a directory.
+    Directory,
+    /// A ULST and the mar parts it lists, each kept under `part_size` bytes.
+    LstSet {
+        part_size: u64,
+        checksum_type: ChecksumType,
+    },
+}
+
+/// Rewrites every entry of a mounted archive into another container. `dest` is the
+/// output file for mar and cab, the output folder for directories, and the path of the
+/// ULST for lst sets (parts are written next to it).
+///
+/// Entries are streamed out of `src` as they're written, so only the cab target (which
+/// compresses in memory) holds more than a chunk at a time.
+///
+/// A source cab's own members (under `CAB_MEMBER_PREFIX`) only make it into directories.
+/// Converting to another cab reuses the source's filelist instead.
+pub fn convert(src: &KArchive, target: &TargetFormat, dest: &Path) -> Result<(), KArchiveError> {
+    let (members, files): (Vec<_>, Vec<_>) = src
+        .list_files()
+        .into_iter()
+        .partition(|name| name.starts_with(CAB_MEMBER_PREFIX));
+    // a cab keeps the source's filelist, and only directories get the source's cab members
+    let mut builder: Box<dyn ArchiveBuilder> = match target {
+        TargetFormat::Cab { compress } => {
+            let mut cab = CabWriter::new();
+            cab.set_compression(*compress);
+            let filelist = Path::new(CAB_MEMBER_PREFIX).join("filelist");
+            if members.contains(&filelist) {
+                cab.set_filelist(src.read(&filelist)?);
+            }
+            Box::new(cab)
+        }
+        target => builder_for(target),
+    };
+    let members = match target {
+        TargetFormat::Directory => members,
+        _ => Vec::new(),
+    };
+    for name in files.into_iter().chain(members) {
+        builder.add_entry(&name, src, &name);
+    }
+    builder.finish(dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mount;
+    use crate::MarWriter;
+    use std::fs::File;
+
+    #[test]
+    fn convert_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let src_path = dir.path().join("src.mar");
+        MarWriter::new()
+            .add_file("data/a.bin", vec![1; 0x30])
+            .add_file("data/sub/b.bin", vec![2; 0x50])
+            .write(File::create(&src_path).unwrap())
+            .unwrap();
+        let src = mount(src_path).unwrap();
+        let mut expected = src.list_files();
+        expected.sort();
+
+        let targets = [
+            (TargetFormat::Cab { compress: true }, "out.cab"),
+            (TargetFormat::Mar { encrypt: true }, "out_M32.mar"),
+            (
+                TargetFormat::LstSet {
+                    part_size: 0x40,
+                    checksum_type: ChecksumType::Md5,
+                },
+                "out.lst",
+            ),
+        ];
+        for (target, name) in targets {
+            let dest = dir.path().join(name);
+            convert(&src, &target, &dest).unwrap();
+            let converted = mount(dest).unwrap();
+            let mut files = converted.list_files();
+            files.retain(|name| !name.starts_with(CAB_MEMBER_PREFIX));
+            files.sort();
+            assert_eq!(files, expected, "{:?}", target);
+            for file in files {
+                assert_eq!(converted.read(&file).unwrap(), src.read(&file).unwrap());
+            }
+        }
+
+        let out_dir = dir.path().join("plain");
+        convert(&src, &TargetFormat::Directory, &out_dir).unwrap();
+        assert_eq!(
+            std::fs::read(out_dir.join("data/sub/b.bin")).unwrap(),
+            vec![2; 0x50]
+        );
+    }
+}
@@ -1,59 +1,134 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Cursor, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 
 use crate::common::*;
 
-fn read_file_header<T>(rdr: &mut T) -> Result<(String, i64), KArchiveError>
+// type byte, name length, size and the 0x10 byte checksum
+const HEADER_SIZE: u64 = 0x19;
+
+fn read_file_header<T>(rdr: &mut T, encoding: NameEncoding) -> Result<(String, i64), KArchiveError>
 where
     T: BufRead + Seek,
 {
     // first byte of file header is always 1
-    assert_eq!(rdr.read_u8()?, 1);
+    let kind = rdr.read_u8()?;
+    if kind != 1 {
+        return Err(KArchiveError::ParseError(format!(
+            "record type is {}, expected 1",
+            kind
+        )));
+    }
     let path_len = rdr.read_u32::<LittleEndian>()?;
     let filesize = rdr.read_u32::<LittleEndian>()?;
     // there's some weird checksum here, no idea how it's calculated...
     rdr.seek(SeekFrom::Current(0x10))?;
+    // names are paths, so anything this long is garbage rather than a real name
+    if path_len > 0x1000 {
+        return Err(KArchiveError::ParseError(format!(
+            "name length {} is implausible",
+            path_len
+        )));
+    }
     let mut buf = vec![0; path_len as usize];
     rdr.read_exact(&mut buf)?;
-    let name = String::from_utf8(buf)?;
+    let name = encoding.decode(&buf)?;
     Ok((name, filesize as i64))
 }
 
-pub(crate) fn parse(path: PathBuf) -> Result<KArchive, KArchiveError> {
-    let buffer = benchmark(&path)?;
+/// d2 has no magic number, so this checks `header` (the start of a file `len` bytes long)
+/// is consistent with one: an entry count that fits, then a first record that starts with
+/// 0x01 and whose name and payload fit.
+pub(crate) fn looks_like_d2(header: &[u8], len: u64) -> bool {
+    let Some(header) = header.get(..8 + HEADER_SIZE as usize) else {
+        return false;
+    };
+    let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap()) as u64;
+    let (count, path_len, size) = (field(0), field(9), field(13));
+    count > 0
+        && 8 + count * HEADER_SIZE <= len
+        && header[8] == 1
+        && (1..=0x1000).contains(&path_len)
+        && 8 + HEADER_SIZE + path_len + size <= len
+}
+
+pub(crate) fn is_d2(path: &Path) -> Result<bool, KArchiveError> {
+    let file = InternalFile::open(path)?;
+    let len = source_len(path)?;
+    let mut header = Vec::new();
+    file.take(8 + HEADER_SIZE).read_to_end(&mut header)?;
+    Ok(looks_like_d2(&header, len))
+}
+
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let buffer = benchmark(&path, options)?;
+    parse_from(path, buffer, options)
+}
+
+/// Parses from `buffer` when there is one, otherwise straight from the file at `path`.
+pub(crate) fn parse_from(
+    path: PathBuf,
+    buffer: Option<Vec<u8>>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
     let mut file = match &buffer {
         Some(buf) => BufReader::new(InternalFile::Buffer(Cursor::new(buf))),
-        None => BufReader::new(InternalFile::RealFile(File::open(&path)?)),
+        None => BufReader::new(InternalFile::open(&path)?),
     };
     let mut files: HashMap<PathBuf, KFileInfo> = HashMap::new();
     let num_files = file.read_u32::<LittleEndian>()?;
     let _archive_size = file.read_u32::<LittleEndian>()?;
-    let parse_result: Result<(), KArchiveError> = (0..num_files).try_for_each(|_| {
-        let (name, size) = read_file_header(&mut file)?;
+    let end = archive_len(&buffer, &path)?;
+    check_entry_count("d2", num_files as u64, HEADER_SIZE, 8, end, options)?;
+    // entry that got cut off when the archive is truncated. nothing comes after it
+    let mut truncated: Option<PathBuf> = None;
+    let parse_result: Result<(), KArchiveError> = (0..num_files as usize).try_for_each(|index| {
+        if truncated.is_some() {
+            return Ok(());
+        }
+        let (name, size) = read_file_header(&mut file, options.name_encoding)
+            .map_err(|e| name_error("d2", index, e))?;
+        check_entry_name("d2", index, &name)?;
         let offset = file.stream_position()?;
+        let available = payload_available("d2", index, &name, (offset, size as u64), end, options)?;
+        if available.is_some() {
+            truncated = Some(name.clone().into());
+        }
         file.seek_relative(size)?;
         files.insert(
             name.into(),
             KFileInfo {
                 size: size as u64,
                 offset,
-                cipher: None,
+                available,
+                ..Default::default()
             },
         );
         Ok(())
     });
+    let mut stopped = None;
     match parse_result {
         Ok(_) => {}
         Err(e) => {
             eprintln!("k_archives: Error in archive parsing: {}", e);
             eprintln!("k_archives: Continuing with {} files parsed", files.len());
+            stopped = Some((files.len(), e.to_string()));
         }
     }
-    Ok(KArchive::new(path, files, buffer))
+    let mut archive = KArchive::new(path.clone(), files, buffer);
+    if let Some((parsed, reason)) = stopped {
+        archive.add_warning(KArchiveWarning::ParseStopped {
+            path: path.clone(),
+            parsed,
+            reason,
+        });
+    }
+    if let Some(entry) = truncated {
+        archive.add_warning(KArchiveWarning::Truncated { path, entry });
+    }
+    Ok(archive)
 }
 
 #[cfg(test)]
@@ -71,11 +146,70 @@ mod tests {
         ]);
         let mut filename = BufReader::new(cursor);
         assert_eq!(
-            read_file_header(&mut filename).unwrap(),
+            read_file_header(&mut filename, NameEncoding::Utf8).unwrap(),
             (
                 "d/LMA/contents/0/0/c/2cf41d5c4279a26cec564899da2299199ca32".into(),
                 47662_i64
             )
         )
     }
+
+    #[test]
+    fn d2_bad_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.d2");
+        // claims far more entries than could fit
+        let mut d2 = 1000_u32.to_le_bytes().to_vec();
+        d2.extend_from_slice(&[0; 4]);
+        std::fs::write(&path, &d2).unwrap();
+        assert!(matches!(
+            parse(path.clone(), &MountOptions::default()),
+            Err(KArchiveError::InvalidHeader { format: "d2", .. })
+        ));
+
+        let mut rdr = BufReader::new(Cursor::new(vec![2; 0x20]));
+        assert!(matches!(
+            read_file_header(&mut rdr, NameEncoding::Utf8),
+            Err(KArchiveError::ParseError(_))
+        ));
+        assert!(matches!(
+            check_entry_name("d2", 0, "../../etc/passwd"),
+            Err(KArchiveError::InvalidEntry { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn dat_dispatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut d2 = 1_u32.to_le_bytes().to_vec();
+        d2.extend_from_slice(&[0; 4]);
+        d2.push(1);
+        d2.extend_from_slice(&5_u32.to_le_bytes());
+        d2.extend_from_slice(&4_u32.to_le_bytes());
+        d2.extend_from_slice(&[0; 0x10]);
+        d2.extend_from_slice(b"a.bin");
+        d2.extend_from_slice(&[9; 4]);
+        assert!(looks_like_d2(&d2, d2.len() as u64));
+        let path = dir.path().join("real.dat");
+        std::fs::write(&path, &d2).unwrap();
+        let archive = crate::mount(path).unwrap();
+        assert_eq!(archive.read(Path::new("a.bin")).unwrap(), vec![9; 4]);
+
+        // a bar that happens to have a .dat extension
+        let mut bar = vec![0; 10];
+        bar.extend_from_slice(&1_u16.to_le_bytes());
+        let mut name = b"b.bin".to_vec();
+        name.resize(256, 0);
+        bar.extend(name);
+        bar.extend_from_slice(&3_i32.to_le_bytes());
+        bar.extend_from_slice(&(-1_i32).to_le_bytes());
+        bar.extend_from_slice(&4_u32.to_le_bytes());
+        bar.extend_from_slice(&[0; 4]);
+        bar.extend_from_slice(&[8; 4]);
+        assert!(!looks_like_d2(&bar, bar.len() as u64));
+        let path = dir.path().join("bar.dat");
+        std::fs::write(&path, &bar).unwrap();
+        let archive = crate::mount(path).unwrap();
+        assert_eq!(archive.read(Path::new("b.bin")).unwrap(), vec![8; 4]);
+    }
 }
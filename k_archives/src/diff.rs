@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::checksum::{hash_reader, ChecksumType};
+use crate::common::*;
+
+/// What changed between two archives, see `diff`. Every list is sorted by path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveDiff {
+    /// Entries only in the new archive
+    pub added: Vec<PathBuf>,
+    /// Entries only in the old archive
+    pub removed: Vec<PathBuf>,
+    /// Entries in both whose size or contents differ
+    pub changed: Vec<PathBuf>,
+    /// How many entries are the same in both
+    pub unchanged: usize,
+}
+
+impl ArchiveDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+// entries that are in both archives are only hashed when their sizes match
+fn same_contents(old: &KArchive, new: &KArchive, path: &Path) -> std::io::Result<bool> {
+    if old.metadata(path).map(|meta| meta.size) != new.metadata(path).map(|meta| meta.size) {
+        return Ok(false);
+    }
+    // what's missing from a cut off entry can't be compared
+    if !old.is_complete(path) || !new.is_complete(path) {
+        return Ok(false);
+    }
+    let old_hash = hash_reader(&mut old.open(path)?, ChecksumType::Md5)?;
+    let new_hash = hash_reader(&mut new.open(path)?, ChecksumType::Md5)?;
+    Ok(old_hash == new_hash)
+}
+
+/// Compares the entries of two archives by path, size and contents, without extracting
+/// either. Entries that are incomplete in either archive count as changed.
+pub fn diff(old: &KArchive, new: &KArchive) -> std::io::Result<ArchiveDiff> {
+    let old_files: HashSet<PathBuf> = old.list_files().into_iter().collect();
+    let new_files: HashSet<PathBuf> = new.list_files().into_iter().collect();
+    let mut diff = ArchiveDiff {
+        added: new_files.difference(&old_files).cloned().collect(),
+        removed: old_files.difference(&new_files).cloned().collect(),
+        ..Default::default()
+    };
+    for path in old_files.intersection(&new_files) {
+        if same_contents(old, new, path)? {
+            diff.unchanged += 1;
+        } else {
+            diff.changed.push(path.clone());
+        }
+    }
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed.sort();
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarWriter;
+    use std::fs::File;
+
+    #[test]
+    fn diff_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let old_path = dir.path().join("old.mar");
+        let new_path = dir.path().join("new.mar");
+        MarWriter::new()
+            .add_file("data/same.bin", vec![1; 0x10])
+            .add_file("data/resized.bin", vec![2; 0x10])
+            .add_file("data/edited.bin", vec![3; 0x10])
+            .add_file("data/gone.bin", vec![4])
+            .write(File::create(&old_path).unwrap())
+            .unwrap();
+        MarWriter::new()
+            .add_file("data/same.bin", vec![1; 0x10])
+            .add_file("data/resized.bin", vec![2; 0x20])
+            .add_file("data/edited.bin", vec![5; 0x10])
+            .add_file("data/new.bin", vec![6])
+            .write(File::create(&new_path).unwrap())
+            .unwrap();
+        let old = crate::mount(old_path).unwrap();
+        let new = crate::mount(new_path).unwrap();
+
+        let changes = diff(&old, &new).unwrap();
+        assert_eq!(changes.added, vec![PathBuf::from("data/new.bin")]);
+        assert_eq!(changes.removed, vec![PathBuf::from("data/gone.bin")]);
+        assert_eq!(
+            changes.changed,
+            vec![
+                PathBuf::from("data/edited.bin"),
+                PathBuf::from("data/resized.bin")
+            ]
+        );
+        assert_eq!(changes.unchanged, 1);
+        assert!(diff(&old, &old).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+
+use crate::common::{CommonFile, KArchive};
+use crate::paths::join_under;
+
+/// A game's file tree as its loader sees it: archive layers over a base folder. Paths are
+/// looked up in the layers first, the last one added winning like an update over the base
+/// game, and fall back to the real file under the base folder when no layer has them.
+pub struct ExtDrmFs {
+    base: PathBuf,
+    // every layer overlaid into one
+    layers: Option<KArchive>,
+}
+
+// the entry name a game path stands for. games ask for "/data/a.bin" as often as "data/a.bin"
+fn entry_name(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
+
+impl ExtDrmFs {
+    /// Files no layer has are read from under `base`.
+    pub fn new(base: impl Into<PathBuf>) -> Self {
+        Self {
+            base: base.into(),
+            layers: None,
+        }
+    }
+
+    /// Adds `archive` over the layers added before it.
+    pub fn add_layer(&mut self, archive: KArchive) -> &mut Self {
+        match &mut self.layers {
+            Some(layers) => layers.overlay(archive),
+            None => self.layers = Some(archive),
+        }
+        self
+    }
+
+    /// Opens `path` out of the top layer that has it, otherwise from the base folder. Paths
+    /// that would lead out of the base folder (with `..` or through a symlink) aren't opened.
+    pub fn open(&self, path: &Path) -> std::io::Result<CommonFile<'_>> {
+        let name = entry_name(path);
+        if let Some(layers) = self.layers.as_ref().filter(|layers| layers.exists(&name)) {
+            return Ok(CommonFile::KFile(layers.open(&name)?));
+        }
+        let real = join_under(&self.base, path)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        if !real.is_file() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "{} isn't in any layer or under {}",
+                    path.display(),
+                    self.base.display()
+                ),
+            ));
+        }
+        Ok(CommonFile::File(File::open(real)?))
+    }
+
+    /// Whether `open` would find `path`.
+    pub fn exists(&self, path: &Path) -> bool {
+        let name = entry_name(path);
+        self.layers
+            .as_ref()
+            .is_some_and(|layers| layers.exists(&name))
+            || join_under(&self.base, path).is_ok_and(|real| real.is_file())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarWriter;
+    use std::io::Read;
+
+    fn layer(files: &[(&str, &[u8])]) -> KArchive {
+        let mut writer = MarWriter::new();
+        for (name, data) in files {
+            writer.add_file(name, data.to_vec());
+        }
+        let mar = writer.write(Vec::new()).unwrap();
+        crate::mount_bytes("layer.mar".into(), mar, &Default::default()).unwrap()
+    }
+
+    fn read(fs: &ExtDrmFs, path: &str) -> (bool, Vec<u8>) {
+        let mut file = fs.open(Path::new(path)).unwrap();
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(file.size(), data.len() as u64);
+        (matches!(file, CommonFile::KFile(_)), data)
+    }
+
+    #[test]
+    fn layers_then_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("data")).unwrap();
+        std::fs::write(dir.path().join("data/a.bin"), b"disk a").unwrap();
+        std::fs::write(dir.path().join("data/loose.bin"), b"loose").unwrap();
+
+        let mut fs = ExtDrmFs::new(dir.path());
+        fs.add_layer(layer(&[
+            ("data/a.bin", b"base a"),
+            ("data/b.bin", b"base b"),
+        ]))
+        .add_layer(layer(&[("data/b.bin", b"update b")]));
+
+        assert_eq!(read(&fs, "/data/a.bin"), (true, b"base a".to_vec()));
+        assert_eq!(read(&fs, "data/b.bin"), (true, b"update b".to_vec()));
+        assert_eq!(read(&fs, "data/loose.bin"), (false, b"loose".to_vec()));
+        assert!(fs.exists(Path::new("data/loose.bin")));
+        assert!(!fs.exists(Path::new("data/nope.bin")));
+        assert!(!fs.exists(Path::new("data")));
+        let error = fs.open(Path::new("data/nope.bin")).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        let error = fs.open(Path::new("../outside.bin")).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+}
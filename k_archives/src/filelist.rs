@@ -0,0 +1,196 @@
+use std::path::PathBuf;
+
+use crate::common::*;
+
+/// One file listed in an update's FileList.dat.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileListEntry {
+    pub path: String,
+    pub size: Option<u64>,
+    pub checksum: Option<String>,
+}
+
+/// A parsed FileList.dat, describing what an update should contain. Each line holds a
+/// path, then optionally a size and a checksum, separated by tabs, commas or spaces.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileList {
+    /// Where the FileList.dat itself sits in the archive.
+    pub source: PathBuf,
+    pub entries: Vec<FileListEntry>,
+    /// Lines that couldn't be made sense of and were skipped, as (line number, line).
+    pub skipped: Vec<(usize, String)>,
+}
+
+pub(crate) const FILE_NAME: &str = "FileList.dat";
+
+impl FileList {
+    pub(crate) fn parse(source: PathBuf, contents: &str) -> Self {
+        let mut list = FileList {
+            source,
+            ..Default::default()
+        };
+        let contents = contents.trim_start_matches('\u{feff}');
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(['#', ';']) || line.starts_with("//") {
+                continue;
+            }
+            let mut fields = line
+                .split(['\t', ',', ' '])
+                .map(str::trim)
+                .filter(|field| !field.is_empty());
+            let path = fields
+                .next()
+                .unwrap_or_default()
+                .replace('\\', "/")
+                .trim_start_matches("./")
+                .trim_start_matches('/')
+                .to_string();
+            let size = fields.next().map(|size| size.parse::<u64>().ok());
+            let checksum = fields
+                .next()
+                .filter(|c| c.chars().all(|c| c.is_ascii_hexdigit()));
+            // the second field has to be a size, otherwise this isn't a line we understand
+            if path.is_empty() || size == Some(None) {
+                list.skipped.push((idx + 1, line.to_string()));
+                continue;
+            }
+            list.entries.push(FileListEntry {
+                path,
+                size: size.flatten(),
+                checksum: checksum.map(str::to_ascii_lowercase),
+            });
+        }
+        list
+    }
+
+    // paths are either relative to the archive root or to the folder the list is in
+    fn resolve(&self, archive: &KArchive, entry: &FileListEntry) -> Option<PathBuf> {
+        let listed = PathBuf::from(&entry.path);
+        let relative = self.source.parent().map(|dir| dir.join(&entry.path));
+        [Some(listed), relative]
+            .into_iter()
+            .flatten()
+            .find(|path| archive.exists(path))
+    }
+}
+
+/// Looks for a FileList.dat in `archive` and, when there is one, keeps it on the archive and
+/// checks the entries it lists exist with the right sizes. Problems are reported as warnings.
+pub(crate) fn attach(archive: &mut KArchive) -> Result<(), KArchiveError> {
+    let Some(source) = archive
+        .list_files()
+        .into_iter()
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.eq_ignore_ascii_case(FILE_NAME))
+        })
+        .min()
+    else {
+        return Ok(());
+    };
+    let contents = archive.read(&source)?;
+    // not every game's FileList.dat is text. anything else is left alone
+    if contents.contains(&0) {
+        return Ok(());
+    }
+    let list = FileList::parse(source.clone(), &String::from_utf8_lossy(&contents));
+    for (line, content) in &list.skipped {
+        archive.add_warning(KArchiveWarning::IgnoredLine {
+            path: source.clone(),
+            line: *line,
+            content: content.clone(),
+        });
+    }
+    for entry in &list.entries {
+        let Some(path) = list.resolve(archive, entry) else {
+            archive.add_warning(KArchiveWarning::MissingEntry {
+                path: PathBuf::from(&entry.path),
+                listed_in: source.clone(),
+            });
+            continue;
+        };
+        let actual = archive.open(&path)?.size();
+        match entry.size {
+            Some(expected) if expected != actual => {
+                archive.add_warning(KArchiveWarning::SizeMismatch {
+                    path,
+                    expected,
+                    actual,
+                });
+            }
+            _ => {}
+        }
+    }
+    archive.set_file_list(list);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarWriter;
+    use std::path::Path;
+
+    #[test]
+    fn file_list_parse() {
+        let list = FileList::parse(
+            PathBuf::from("dev/raw/newdata/FileList.dat"),
+            "# comment\r\n\
+             data\\a.bin\t16\tABCDEF01\r\n\
+             ./data/b.bin, 32\r\n\
+             c.bin\r\n\
+             d.bin not_a_size\r\n",
+        );
+        assert_eq!(
+            list.entries,
+            vec![
+                FileListEntry {
+                    path: "data/a.bin".into(),
+                    size: Some(16),
+                    checksum: Some("abcdef01".into()),
+                },
+                FileListEntry {
+                    path: "data/b.bin".into(),
+                    size: Some(32),
+                    checksum: None,
+                },
+                FileListEntry {
+                    path: "c.bin".into(),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(list.skipped, vec![(5, "d.bin not_a_size".to_string())]);
+    }
+
+    #[test]
+    fn file_list_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.mar");
+        MarWriter::new()
+            .add_file("data/a.bin", vec![1; 16])
+            .add_file("dev/raw/newdata/local.bin", vec![2; 4])
+            .add_file(
+                "dev/raw/newdata/FileList.dat",
+                b"data/a.bin 16\nlocal.bin 5\nmissing.bin 1\n".to_vec(),
+            )
+            .write(std::fs::File::create(&path).unwrap())
+            .unwrap();
+        let archive = crate::mount(path).unwrap();
+        let list = archive.file_list().unwrap();
+        assert_eq!(list.source, Path::new("dev/raw/newdata/FileList.dat"));
+        assert_eq!(list.entries.len(), 3);
+        match archive.warnings() {
+            [KArchiveWarning::SizeMismatch {
+                path,
+                expected: 5,
+                actual: 4,
+            }, KArchiveWarning::MissingEntry { path: missing, .. }] => {
+                assert_eq!(path, Path::new("dev/raw/newdata/local.bin"));
+                assert_eq!(missing, Path::new("missing.bin"));
+            }
+            warnings => panic!("unexpected warnings {:?}", warnings),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use glob::Pattern;
+
+use crate::common::*;
+
+/// Picks entries by glob pattern (`*`, `?` and `[...]`, where `*` also matches across
+/// folders), see `KArchive::list_matching`. An entry matches when it matches any include
+/// pattern (or there aren't any) and no exclude pattern. Entries can also be picked by
+/// their exact names, see `only`.
+#[derive(Debug, Clone, Default)]
+pub struct EntryFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    // exact names from `only`, with forward slashes
+    names: Option<BTreeSet<String>>,
+}
+
+fn compile(pattern: &str) -> Result<Pattern, KArchiveError> {
+    Pattern::new(pattern).map_err(|e| KArchiveError::InvalidPattern {
+        pattern: pattern.to_string(),
+        reason: e.msg.to_string(),
+    })
+}
+
+impl EntryFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep entries matching `pattern` (or one of the other include patterns).
+    pub fn include(&mut self, pattern: &str) -> Result<&mut Self, KArchiveError> {
+        self.include.push(compile(pattern)?);
+        Ok(self)
+    }
+
+    /// Leave out entries matching `pattern`, even if they match an include pattern.
+    pub fn exclude(&mut self, pattern: &str) -> Result<&mut Self, KArchiveError> {
+        self.exclude.push(compile(pattern)?);
+        Ok(self)
+    }
+
+    /// Only keep entries named exactly one of `names` (as listed, eg. `data/sound/a.2dx`).
+    /// Can be given more than once, and the patterns still apply on top.
+    pub fn only<S: AsRef<str>>(&mut self, names: impl IntoIterator<Item = S>) -> &mut Self {
+        let names = names
+            .into_iter()
+            .map(|name| name.as_ref().replace('\\', "/"));
+        self.names.get_or_insert_with(BTreeSet::new).extend(names);
+        self
+    }
+
+    /// The names given to `only` that `archive` doesn't have an entry for, sorted.
+    pub fn missing_from(&self, archive: &KArchive) -> Vec<String> {
+        let Some(names) = &self.names else {
+            return Vec::new();
+        };
+        let present: BTreeSet<String> = archive
+            .list_files()
+            .iter()
+            .map(|path| archive_name(path))
+            .collect();
+        names.difference(&present).cloned().collect()
+    }
+
+    /// Whether the entry named `path` gets through the filter.
+    pub fn matches(&self, path: &Path) -> bool {
+        // entry names always use forward slashes, whatever the platform
+        let name = archive_name(path);
+        self.names
+            .as_ref()
+            .is_none_or(|names| names.contains(&name))
+            && (self.include.is_empty() || self.include.iter().any(|p| p.matches(&name)))
+            && !self.exclude.iter().any(|p| p.matches(&name))
+    }
+}
+
+impl KArchive {
+    /// Like `list_files`, but only the entries `filter` lets through.
+    pub fn list_matching(&self, filter: &EntryFilter) -> Vec<PathBuf> {
+        self.list_files()
+            .into_iter()
+            .filter(|path| filter.matches(path))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarWriter;
+    use std::fs::File;
+
+    #[test]
+    fn filter_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.mar");
+        MarWriter::new()
+            .add_file("data/sound/a.2dx", vec![1])
+            .add_file("data/sound/b.2dx", vec![2])
+            .add_file("data/movie/intro.wmv", vec![3])
+            .add_file("data/info/music.bin", vec![4])
+            .write(File::create(&path).unwrap())
+            .unwrap();
+        let archive = crate::mount(path).unwrap();
+
+        let mut sound = EntryFilter::new();
+        sound.include("*/sound/*").unwrap();
+        let mut found = archive.list_matching(&sound);
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                PathBuf::from("data/sound/a.2dx"),
+                PathBuf::from("data/sound/b.2dx")
+            ]
+        );
+
+        let mut no_movies = EntryFilter::new();
+        no_movies
+            .exclude("*.wmv")
+            .unwrap()
+            .exclude("*/b.2dx")
+            .unwrap();
+        assert_eq!(archive.list_matching(&no_movies).len(), 2);
+        assert!(!no_movies.matches(Path::new("data/movie/intro.wmv")));
+
+        let mut listed = EntryFilter::new();
+        listed
+            .only([
+                "data/sound/a.2dx",
+                "data\\movie\\intro.wmv",
+                "data/gone.bin",
+            ])
+            .exclude("*.wmv")
+            .unwrap();
+        assert_eq!(
+            archive.list_matching(&listed),
+            vec![PathBuf::from("data/sound/a.2dx")]
+        );
+        assert_eq!(listed.missing_from(&archive), vec!["data/gone.bin"]);
+        assert!(sound.missing_from(&archive).is_empty());
+
+        assert!(matches!(
+            EntryFilter::new().include("data/[sound"),
+            Err(KArchiveError::InvalidPattern { .. })
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use crate::common::*;
+use crate::compressed::Compression;
+use crate::kbin::{self, KbinNode};
+
+pub(crate) const SIGNATURE: [u8; 4] = [0x6C, 0xAD, 0x8F, 0x89];
+const AVSLZ_HEADER_SIZE: u64 = 8;
+
+// ifs node names can't contain dots or start with digits, so they get escaped
+fn unescape_name(name: &str) -> String {
+    let name = name.replace("_E", ".").replace("__", "_");
+    match name.strip_prefix('_') {
+        Some(rest) if rest.starts_with(|c: char| c.is_ascii_digit()) => rest.to_string(),
+        _ => name,
+    }
+}
+
+// files are 3s32 nodes holding (offset into the data blob, size, timestamp), folders are plain nodes
+fn walk(node: &KbinNode, dir: &Path, entries: &mut Vec<(PathBuf, Vec<i32>)>) {
+    for child in &node.children {
+        // bookkeeping nodes rather than files
+        if child.name == "_info_" || child.name == "_super_" {
+            continue;
+        }
+        let path = dir.join(unescape_name(&child.name));
+        match child.type_id {
+            kbin::TYPE_NODE => walk(child, &path, entries),
+            kbin::TYPE_3S32 if !child.is_array => entries.push((path, child.s32s())),
+            _ => {}
+        }
+    }
+}
+
+// compressed members (textures, mostly) are wrapped in an avslz header: the decompressed and
+// compressed sizes, big endian, followed by the lz77 stream. there's no flag saying which
+// members are compressed, but the compressed size matching the rest of the entry is telling enough
+fn avslz_header(file: &mut InternalFile, offset: u64, size: u64) -> std::io::Result<Option<u64>> {
+    if size < AVSLZ_HEADER_SIZE {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let unpacked_size = file.read_u32::<BigEndian>()? as u64;
+    let packed_size = file.read_u32::<BigEndian>()? as u64;
+    Ok((packed_size + AVSLZ_HEADER_SIZE == size).then_some(unpacked_size))
+}
+
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let buffer = benchmark(&path, options)?;
+    parse_from(path, buffer, options)
+}
+
+/// Parses from `buffer` when there is one, otherwise straight from the file at `path`.
+pub(crate) fn parse_from(
+    path: PathBuf,
+    buffer: Option<Vec<u8>>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    let mut file = match &buffer {
+        Some(buf) => InternalFile::Buffer(Cursor::new(buf)),
+        None => InternalFile::open(&path)?,
+    };
+    let end = archive_len(&buffer, &path)?;
+    let mut signature = [0_u8; 4];
+    file.read_exact(&mut signature)?;
+    let invalid = |reason: String| KArchiveError::InvalidHeader {
+        format: "ifs",
+        reason,
+    };
+    if signature != SIGNATURE {
+        return Err(invalid(format!("signature is {:02X?}", signature)));
+    }
+    let version = file.read_u16::<BigEndian>()?;
+    let version_check = file.read_u16::<BigEndian>()?;
+    if version ^ version_check != 0xFFFF {
+        return Err(invalid(format!(
+            "version {} doesn't match its complement {}",
+            version, version_check
+        )));
+    }
+    let _time = file.read_u32::<BigEndian>()?;
+    let _tree_size = file.read_u32::<BigEndian>()?;
+    // file data starts straight after the manifest
+    let data_start = file.read_u32::<BigEndian>()? as u64;
+    // newer versions have an md5 of something after the header
+    let manifest_start = if version > 1 { 36 } else { 20 };
+    if data_start < manifest_start || data_start > end {
+        return Err(invalid(format!(
+            "manifest ends at {:#x}, outside of the archive",
+            data_start
+        )));
+    }
+    let mut manifest = vec![0; (data_start - manifest_start) as usize];
+    file.seek(SeekFrom::Start(manifest_start))?;
+    file.read_exact(&mut manifest)?;
+    let root = kbin::parse(&manifest)?;
+
+    let mut entries = Vec::new();
+    walk(&root, Path::new(""), &mut entries);
+    let mut files = HashMap::new();
+    let mut truncated = None;
+    for (index, (path, values)) in entries.into_iter().enumerate() {
+        let name = archive_name(&path);
+        check_entry_name("ifs", index, &name)?;
+        let offset = data_start + values[0] as u32 as u64;
+        let size = values[1] as u32 as u64;
+        let available = payload_available("ifs", index, &name, (offset, size), end, options)?;
+        if available.is_some() {
+            truncated.get_or_insert_with(|| path.clone());
+        }
+        let info = match available {
+            None => avslz_header(&mut file, offset, size)?.map(|unpacked_size| KFileInfo {
+                size: unpacked_size,
+                offset: offset + AVSLZ_HEADER_SIZE,
+                compression: Some(Compression::Lz77 {
+                    packed_size: size - AVSLZ_HEADER_SIZE,
+                }),
+                raw_fields: vec![values[2] as u32],
+                ..Default::default()
+            }),
+            Some(_) => None,
+        };
+        files.insert(
+            path,
+            info.unwrap_or(KFileInfo {
+                size,
+                offset,
+                available,
+                // the timestamp
+                raw_fields: vec![values[2] as u32],
+                ..Default::default()
+            }),
+        );
+    }
+    let mut archive = KArchive::new(path.clone(), files, buffer);
+    if let Some(entry) = truncated {
+        archive.add_warning(KArchiveWarning::Truncated { path, entry });
+    }
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kbin::writer::{write, Value};
+    use crate::lz77::tests::pack_literals;
+    use byteorder::{WriteBytesExt, BE};
+
+    #[test]
+    fn ifs_unescape() {
+        assert_eq!(unescape_name("tex_E1"), "tex.1");
+        assert_eq!(unescape_name("_01_Ebin"), "01.bin");
+        assert_eq!(unescape_name("snake__case"), "snake_case");
+    }
+
+    #[test]
+    fn ifs_mount() {
+        let texture: Vec<u8> = (0..40).collect();
+        let packed = pack_literals(&texture);
+        let mut avslz = (texture.len() as u32).to_be_bytes().to_vec();
+        avslz.extend_from_slice(&(packed.len() as u32).to_be_bytes());
+        avslz.extend(packed);
+        let manifest = write(
+            "imgfs",
+            &Value::Node(vec![
+                ("_info_", Value::Node(vec![])),
+                ("a_Ebin", Value::S32x3([0, 4, 0x5F000000])),
+                (
+                    "tex",
+                    Value::Node(vec![
+                        ("_0_Epng", Value::S32x3([4, 8, 0])),
+                        ("tex_Eraw", Value::S32x3([12, avslz.len() as i32, 0])),
+                    ]),
+                ),
+            ]),
+        );
+        let mut ifs = SIGNATURE.to_vec();
+        ifs.write_u16::<BE>(3).unwrap();
+        ifs.write_u16::<BE>(!3).unwrap();
+        ifs.write_u32::<BE>(0).unwrap();
+        ifs.write_u32::<BE>(0).unwrap();
+        ifs.write_u32::<BE>(36 + manifest.len() as u32).unwrap();
+        ifs.extend_from_slice(&[0; 16]);
+        ifs.extend(manifest);
+        ifs.extend_from_slice(&[1; 4]);
+        ifs.extend_from_slice(&[2; 8]);
+        ifs.extend(avslz);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.ifs");
+        std::fs::write(&path, ifs).unwrap();
+        let archive = crate::mount(path).unwrap();
+        assert_eq!(archive.list_files().len(), 3);
+        assert_eq!(archive.read(Path::new("a.bin")).unwrap(), vec![1; 4]);
+        assert_eq!(archive.read(Path::new("tex/0.png")).unwrap(), vec![2; 8]);
+        let meta = archive.metadata(Path::new("a.bin")).unwrap();
+        assert_eq!(meta.raw_fields, vec![0x5F000000]);
+        let raw = Path::new("tex/tex.raw");
+        assert_eq!(archive.open(raw).unwrap().size(), 40);
+        assert_eq!(archive.read(raw).unwrap(), texture);
+    }
+}
@@ -0,0 +1,295 @@
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use glob::{MatchOptions, Pattern};
+use serde::{Deserialize, Serialize};
+
+use crate::checksum::{hash_reader, ChecksumType};
+use crate::common::*;
+
+/// An entry as the index remembers it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedEntry {
+    /// The entry's path, with `/` between folders.
+    pub path: String,
+    pub size: u64,
+    /// Hash of the entry's contents as lowercase hex, if its archive was indexed with one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// An archive (or multi part set) in the index and the entries it had when it was indexed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedArchive {
+    pub path: PathBuf,
+    /// Size and modification time (unix seconds) of the archive when it was indexed, which is
+    /// how an unchanged archive is told apart. Neither is known for urls.
+    pub len: u64,
+    pub modified: Option<u64>,
+    /// What the entries were hashed with, if they were.
+    pub hash_type: Option<String>,
+    pub entries: Vec<IndexedEntry>,
+}
+
+/// What to look for in an `ArchiveIndex`. Entries have to match everything that's set.
+#[derive(Debug, Clone, Default)]
+pub struct IndexQuery {
+    /// Part of the entry's path, or a glob pattern (`*`, `?`, `[...]`, where `*` also matches
+    /// across folders) if it has any of those. Case insensitive either way.
+    pub name: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// The hash of the entry's contents, found in archives indexed with the same type.
+    pub hash: Option<String>,
+}
+
+/// What's in a collection of archives, kept on disk (as JSON) so which archive has a file
+/// can be answered without mounting any of them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ArchiveIndex {
+    // sorted by path
+    archives: Vec<IndexedArchive>,
+}
+
+// what an archive is indexed under, so it's the same however it was named
+fn key(path: &Path) -> PathBuf {
+    match is_url(path) {
+        true => path.to_path_buf(),
+        false => path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+    }
+}
+
+fn stamp(path: &Path) -> (u64, Option<u64>) {
+    match std::fs::metadata(path) {
+        Ok(meta) => {
+            let modified = meta.modified().ok();
+            let since = modified.and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+            (meta.len(), since.map(|since| since.as_secs()))
+        }
+        Err(_) => (0, None),
+    }
+}
+
+impl ArchiveIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the index saved at `path`, or starts an empty one if there's nothing there yet.
+    pub fn load(path: &Path) -> Result<Self, KArchiveError> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&data).map_err(|e| {
+            KArchiveError::ParseError(format!("{} isn't an index: {}", path.display(), e))
+        })
+    }
+
+    /// Saves the index to `path`. It's written next to it first, so a crash partway through
+    /// doesn't lose the index that was there.
+    pub fn save(&self, path: &Path) -> Result<(), KArchiveError> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let data =
+            serde_json::to_vec(self).map_err(|e| KArchiveError::WriteError(e.to_string()))?;
+        std::fs::write(&temp, data)?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    pub fn archives(&self) -> &[IndexedArchive] {
+        &self.archives
+    }
+
+    /// Indexes the archive at `path`, replacing what the index had for it. With `hash` every
+    /// entry is read and hashed too, which takes as long as extracting it. Archives that are
+    /// the same size and age as when they were last indexed (with the same `hash`) are left
+    /// alone, returns whether it was indexed.
+    pub fn add(
+        &mut self,
+        path: &Path,
+        options: &MountOptions,
+        hash: Option<ChecksumType>,
+    ) -> Result<bool, KArchiveError> {
+        let key = key(path);
+        let (len, modified) = stamp(&key);
+        let hash_type = hash.map(|hash| hash.name().to_string());
+        let at = self
+            .archives
+            .binary_search_by(|archive| archive.path.cmp(&key));
+        if let Ok(at) = at {
+            let old = &self.archives[at];
+            if modified.is_some()
+                && (old.len, old.modified) == (len, modified)
+                && old.hash_type == hash_type
+            {
+                return Ok(false);
+            }
+        }
+        let archive = crate::mount_with_options(key.clone(), options)?;
+        let mut files = archive.list_files();
+        files.sort();
+        let mut entries = Vec::with_capacity(files.len());
+        for file in files {
+            let hash = match hash {
+                Some(hash) => Some(hash_reader(&mut archive.open(&file)?, hash)?),
+                None => None,
+            };
+            entries.push(IndexedEntry {
+                path: file.to_string_lossy().replace('\\', "/"),
+                size: archive.metadata(&file).map_or(0, |meta| meta.size),
+                hash,
+            });
+        }
+        let indexed = IndexedArchive {
+            path: key,
+            len,
+            modified,
+            hash_type,
+            entries,
+        };
+        match at {
+            Ok(at) => self.archives[at] = indexed,
+            Err(at) => self.archives.insert(at, indexed),
+        }
+        Ok(true)
+    }
+
+    /// Takes the archive at `path` out of the index, returns whether it was in it.
+    pub fn remove(&mut self, path: &Path) -> bool {
+        let key = key(path);
+        let before = self.archives.len();
+        self.archives.retain(|archive| archive.path != key);
+        self.archives.len() != before
+    }
+
+    /// Every entry that matches `query` and the archive it's in, by archive then entry path.
+    pub fn search(
+        &self,
+        query: &IndexQuery,
+    ) -> Result<Vec<(&IndexedArchive, &IndexedEntry)>, KArchiveError> {
+        let name = query.name.as_ref().map(|name| name.to_lowercase());
+        let pattern = match &name {
+            Some(name) if name.contains(['*', '?', '[']) => Some(Pattern::new(name).map_err(
+                |e| KArchiveError::InvalidPattern {
+                    pattern: name.clone(),
+                    reason: e.msg.to_string(),
+                },
+            )?),
+            _ => None,
+        };
+        let options = MatchOptions {
+            case_sensitive: false,
+            ..Default::default()
+        };
+        let matches = |entry: &IndexedEntry| {
+            let name_matches = match (&pattern, &name) {
+                (Some(pattern), _) => pattern.matches_with(&entry.path, options),
+                (None, Some(name)) => entry.path.to_lowercase().contains(name.as_str()),
+                (None, None) => true,
+            };
+            name_matches
+                && query.min_size.is_none_or(|min| entry.size >= min)
+                && query.max_size.is_none_or(|max| entry.size <= max)
+                && query.hash.as_ref().is_none_or(|hash| {
+                    entry
+                        .hash
+                        .as_ref()
+                        .is_some_and(|entry| entry.eq_ignore_ascii_case(hash))
+                })
+        };
+        Ok(self
+            .archives
+            .iter()
+            .flat_map(|archive| archive.entries.iter().map(move |entry| (archive, entry)))
+            .filter(|(_, entry)| matches(entry))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarWriter;
+
+    #[test]
+    fn index_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, files: &[(&str, &[u8])]| {
+            let mut writer = MarWriter::new();
+            for (file, data) in files {
+                writer.add_file(file, data.to_vec());
+            }
+            let path = dir.path().join(name);
+            writer.write(std::fs::File::create(&path).unwrap()).unwrap();
+            path
+        };
+        let first = write(
+            "KFC-2023010100.mar",
+            &[
+                ("data/sound/bgm.2dx", &[1; 300]),
+                ("data/info.xml", b"<a/>"),
+            ],
+        );
+        let second = write(
+            "KFC-2023020100.mar",
+            &[("data/graphics/Logo.ifs", &[2; 50])],
+        );
+
+        let mut index = ArchiveIndex::new();
+        let options = MountOptions::default();
+        assert!(index.add(&first, &options, None).unwrap());
+        assert!(index
+            .add(&second, &options, Some(ChecksumType::Md5))
+            .unwrap());
+        // nothing changed, nothing to do
+        assert!(!index.add(&first, &options, None).unwrap());
+
+        let path = dir.path().join("archives.idx");
+        index.save(&path).unwrap();
+        let index = ArchiveIndex::load(&path).unwrap();
+        assert_eq!(index.archives().len(), 2);
+
+        let search = |query: IndexQuery| {
+            let hits = index.search(&query).unwrap();
+            hits.iter()
+                .map(|(archive, entry)| {
+                    let name = archive.path.file_name().unwrap().to_string_lossy();
+                    format!("{}:{}", name, entry.path)
+                })
+                .collect::<Vec<_>>()
+        };
+        let name = |name: &str| IndexQuery {
+            name: Some(name.into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            search(name("BGM")),
+            ["KFC-2023010100.mar:data/sound/bgm.2dx"]
+        );
+        assert_eq!(
+            search(name("*.IFS")),
+            ["KFC-2023020100.mar:data/graphics/Logo.ifs"]
+        );
+        assert_eq!(search(name("data/*")).len(), 3);
+        let sized = IndexQuery {
+            min_size: Some(10),
+            max_size: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(search(sized), ["KFC-2023020100.mar:data/graphics/Logo.ifs"]);
+        let hash = IndexQuery {
+            hash: Some(crate::hash_reader(&mut &[2_u8; 50][..], ChecksumType::Md5).unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(search(hash).len(), 1);
+        assert!(index.search(&name("[")).is_err());
+        assert!(ArchiveIndex::load(&first).is_err());
+        assert!(ArchiveIndex::load(&dir.path().join("new.idx"))
+            .unwrap()
+            .archives()
+            .is_empty());
+    }
+}
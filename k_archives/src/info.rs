@@ -1,23 +1,352 @@
 use std::fs;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
+use crate::checksum::{hash_file, ChecksumType};
 use crate::common::*;
+use crate::update::UpdateInfo;
 
-pub(crate) fn parse(path: PathBuf) -> Result<KArchive, KArchiveError> {
-    let contents = fs::read_to_string(&path)?;
-    let mut archive = KArchive::init_empty();
-    let mut file_names = Vec::new();
-    for line in contents.lines() {
-        if line.starts_with("FILE") {
-            file_names.push(PathBuf::from(line.strip_prefix("FILE : ").unwrap().trim()))
+/// One archive referenced by a NAME/info descriptor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InfoEntry {
+    pub file_name: String,
+    pub size: Option<u64>,
+    /// (checksum type as written in the file, checksum)
+    pub checksum: Option<(String, String)>,
+    /// Any other `KEY : value` lines belonging to this entry
+    pub extra: Vec<(String, String)>,
+}
+
+/// A parsed NAME/info descriptor. Lines before the first `FILE` line go in `header`,
+/// everything after belongs to the file above it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InfoManifest {
+    pub header: Vec<(String, String)>,
+    pub files: Vec<InfoEntry>,
+    /// Lines that couldn't be made sense of and were skipped, as (line number, line).
+    pub skipped: Vec<(usize, String)>,
+}
+
+// descriptors from different games don't quite agree on key names. this maps the
+// variants seen so far (FILENAME, FILE_NAME, FILE01, file...) to the canonical ones
+fn canonical_key(key: &str) -> Option<&'static str> {
+    let key = key
+        .trim_end_matches(|c: char| c.is_ascii_digit())
+        .to_ascii_uppercase()
+        .replace(['_', ' '], "");
+    match key.as_str() {
+        "FILE" | "FILENAME" => Some("FILE"),
+        "SIZE" | "FILESIZE" => Some("SIZE"),
+        _ => None,
+    }
+}
+
+// sizes sometimes come with thousands separators or a unit after them
+fn parse_size(value: &str) -> Option<u64> {
+    let digits: String = value
+        .chars()
+        .filter(|c| *c != ',' && *c != '_')
+        .take_while(char::is_ascii_digit)
+        .collect();
+    digits.parse().ok()
+}
+
+impl InfoManifest {
+    pub(crate) fn parse(contents: &str) -> Self {
+        let mut manifest = InfoManifest::default();
+        let contents = contents.trim_start_matches('\u{feff}');
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(['#', ';']) || line.starts_with("//") {
+                continue;
+            }
+            // some descriptors use = instead of :
+            let Some((key, value)) = line.split_once([':', '=']) else {
+                manifest.skipped.push((idx + 1, line.to_string()));
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if canonical_key(key) == Some("FILE") {
+                manifest.files.push(InfoEntry {
+                    file_name: value.to_string(),
+                    ..Default::default()
+                });
+                continue;
+            }
+            let Some(entry) = manifest.files.last_mut() else {
+                manifest.header.push((key.to_string(), value.to_string()));
+                continue;
+            };
+            match canonical_key(key) {
+                Some("SIZE") => match parse_size(value) {
+                    Some(size) => entry.size = Some(size),
+                    None => manifest.skipped.push((idx + 1, line.to_string())),
+                },
+                _ if ChecksumType::from_name(key).is_some() => {
+                    entry.checksum = Some((key.to_string(), value.to_string()))
+                }
+                _ => entry.extra.push((key.to_string(), value.to_string())),
+            }
+        }
+        manifest
+    }
+
+    /// Writes the descriptor back out in the canonical `KEY : value` layout.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<W, KArchiveError> {
+        for (key, value) in &self.header {
+            writeln!(writer, "{} : {}", key, value)?;
+        }
+        for entry in &self.files {
+            writeln!(writer, "FILE : {}", entry.file_name)?;
+            if let Some(size) = entry.size {
+                writeln!(writer, "SIZE : {}", size)?;
+            }
+            if let Some((checksum_type, checksum)) = &entry.checksum {
+                writeln!(writer, "{} : {}", checksum_type, checksum)?;
+            }
+            for (key, value) in &entry.extra {
+                writeln!(writer, "{} : {}", key, value)?;
+            }
         }
+        writer.flush()?;
+        Ok(writer)
     }
-    for name in file_names {
-        if let Ok(mut arc) = super::mount(path.with_file_name(&name)) {
-            archive.add_archive(&mut arc)
-        } else {
-            eprintln!("INFO: Failed to mount archive: {:?}", name)
+}
+
+/// Builds a NAME/info descriptor referencing a set of already written archives.
+#[derive(Default)]
+pub struct InfoWriter {
+    manifest: InfoManifest,
+}
+
+impl InfoWriter {
+    /// `name` goes in the leading `NAME` line, which is also what `mount` uses to detect the format.
+    pub fn new(name: &str) -> Self {
+        Self {
+            manifest: InfoManifest {
+                header: vec![("NAME".to_string(), name.to_string())],
+                ..Default::default()
+            },
         }
     }
+
+    /// Adds another header line after `NAME`.
+    pub fn add_header(&mut self, key: &str, value: &str) -> &mut Self {
+        self.manifest
+            .header
+            .push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Adds an archive, recording its size and (if given) its checksum.
+    pub fn add_part(
+        &mut self,
+        path: &Path,
+        checksum_type: Option<ChecksumType>,
+    ) -> Result<&mut Self, KArchiveError> {
+        let file_name = path
+            .file_name()
+            .ok_or(KArchiveError::Other("Part path has no file name"))?
+            .to_string_lossy()
+            .into_owned();
+        let checksum = match checksum_type {
+            Some(ty) => Some((ty.name().to_ascii_uppercase(), hash_file(path, ty)?)),
+            None => None,
+        };
+        self.manifest.files.push(InfoEntry {
+            file_name,
+            size: Some(fs::metadata(path)?.len()),
+            checksum,
+            extra: Vec::new(),
+        });
+        Ok(self)
+    }
+
+    pub fn manifest(&self) -> &InfoManifest {
+        &self.manifest
+    }
+
+    pub fn write<W: Write>(&self, writer: W) -> Result<W, KArchiveError> {
+        self.manifest.write(writer)
+    }
+}
+
+/// Reads a NAME/info descriptor without mounting any of the archives it references.
+pub fn parse_manifest(path: &Path) -> Result<InfoManifest, KArchiveError> {
+    let mut contents = String::new();
+    InternalFile::open(path)?.read_to_string(&mut contents)?;
+    Ok(InfoManifest::parse(&contents))
+}
+
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let manifest = parse_manifest(&path)?;
+    let mut archive = KArchive::init_empty();
+    for (line, content) in &manifest.skipped {
+        archive.add_warning(KArchiveWarning::IgnoredLine {
+            path: path.clone(),
+            line: *line,
+            content: content.clone(),
+        });
+    }
+    let results = parallel_map(&manifest.files, |entry| {
+        let part_path = path.with_file_name(&entry.file_name);
+        let result = super::mount_archive(part_path.clone(), options).and_then(|mut arc| {
+            let checksum = entry
+                .checksum
+                .as_ref()
+                .map(|(ty, value)| (ty.as_str(), value.as_str()));
+            verify_part(&part_path, entry.size, checksum, options, &mut arc)?;
+            if let Some((checksum_type, checksum)) = checksum {
+                arc.set_declared_checksum(checksum_type, checksum);
+            }
+            Ok(arc)
+        });
+        (part_path, result)
+    });
+    for (part_path, result) in results {
+        match result {
+            Ok(mut arc) => archive.add_archive(&mut arc),
+            Err(e @ KArchiveError::ChecksumMismatch { .. })
+            | Err(e @ KArchiveError::SizeMismatch { .. }) => return Err(e),
+            Err(e) => handle_missing_part(&mut archive, part_path, e, options)?,
+        }
+    }
+    archive.set_update_info(UpdateInfo::from_parts(manifest.files.iter().map(|entry| {
+        let size = UpdateInfo::part_size(&path.with_file_name(&entry.file_name), entry.size);
+        (entry.file_name.as_str(), size)
+    })));
+    archive.set_info_manifest(manifest);
     Ok(archive)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CabWriter;
+    use std::fs::File;
+
+    #[test]
+    fn info_manifest() {
+        let manifest = InfoManifest::parse(
+            "NAME : L44\nVERSION : 2024041500\nFILE : L44-01.qar\nSIZE : 1234\nMD5 : abcdef\nFILE : L44-02.qar\nSIZE : 99\n",
+        );
+        assert_eq!(
+            manifest.header,
+            vec![
+                ("NAME".to_string(), "L44".to_string()),
+                ("VERSION".to_string(), "2024041500".to_string())
+            ]
+        );
+        assert_eq!(
+            manifest.files,
+            vec![
+                InfoEntry {
+                    file_name: "L44-01.qar".into(),
+                    size: Some(1234),
+                    checksum: Some(("MD5".into(), "abcdef".into())),
+                    extra: vec![],
+                },
+                InfoEntry {
+                    file_name: "L44-02.qar".into(),
+                    size: Some(99),
+                    checksum: None,
+                    extra: vec![],
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn info_manifest_variants() {
+        let manifest = InfoManifest::parse(
+            "\u{feff}NAME : L44\r\n# comment\r\n\r\n  FILE01 :  L44-01.qar  \r\nsize = 1,234 bytes\r\nmd5:abcdef\r\n; another comment\r\nFILE_NAME : L44-02.qar\r\nSIZE : unknown\r\ngarbage line\r\n",
+        );
+        assert_eq!(
+            manifest.header,
+            vec![("NAME".to_string(), "L44".to_string())]
+        );
+        assert_eq!(
+            manifest.files,
+            vec![
+                InfoEntry {
+                    file_name: "L44-01.qar".into(),
+                    size: Some(1234),
+                    checksum: Some(("md5".into(), "abcdef".into())),
+                    extra: vec![],
+                },
+                InfoEntry {
+                    file_name: "L44-02.qar".into(),
+                    ..Default::default()
+                }
+            ]
+        );
+        assert_eq!(
+            manifest.skipped,
+            vec![
+                (9, "SIZE : unknown".to_string()),
+                (10, "garbage line".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn info_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("L44-01.cab");
+        CabWriter::new()
+            .add_file("data/a.bin", vec![1, 2, 3])
+            .write(File::create(&part).unwrap())
+            .unwrap();
+        let size = std::fs::metadata(&part).unwrap().len();
+        let md5 = hash_file(&part, ChecksumType::Md5).unwrap();
+        let info = dir.path().join("info");
+        let strict = MountOptions {
+            verify_checksums: ChecksumPolicy::Error,
+            ..Default::default()
+        };
+
+        std::fs::write(
+            &info,
+            format!("NAME : L44\nFILE : L44-01.cab\nSIZE : {size}\nMD5 : {md5}\n"),
+        )
+        .unwrap();
+        let archive = crate::mount_with_options(info.clone(), &strict).unwrap();
+        assert!(archive.exists(Path::new("data/a.bin")));
+        assert_eq!(archive.info_manifest().unwrap().files[0].size, Some(size));
+
+        std::fs::write(
+            &info,
+            format!("NAME : L44\nFILE : L44-01.cab\nSIZE : {}\n", size + 1),
+        )
+        .unwrap();
+        assert!(matches!(
+            crate::mount_with_options(info, &strict),
+            Err(KArchiveError::SizeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn info_write_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("L44-01.cab");
+        CabWriter::new()
+            .add_file("data/a.bin", vec![1, 2, 3])
+            .write(File::create(&part).unwrap())
+            .unwrap();
+        let mut writer = InfoWriter::new("L44");
+        writer
+            .add_header("VERSION", "2024041500")
+            .add_part(&part, Some(ChecksumType::Md5))
+            .unwrap();
+        let info = dir.path().join("info");
+        writer.write(File::create(&info).unwrap()).unwrap();
+
+        let strict = MountOptions {
+            verify_checksums: ChecksumPolicy::Error,
+            ..Default::default()
+        };
+        let archive = crate::mount_with_options(info, &strict).unwrap();
+        assert!(archive.exists(Path::new("data/a.bin")));
+        assert_eq!(archive.info_manifest(), Some(writer.manifest()));
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::common::*;
+
+pub(crate) const SECTOR_SIZE: u64 = 2048;
+// volume descriptors start after the 16 sector system area
+const DESCRIPTORS_START: u64 = 16;
+const STANDARD_ID: &[u8; 5] = b"CD001";
+const DIRECTORY_FLAG: u8 = 0x02;
+// images this deep are either broken or looping back on themselves
+pub(crate) const MAX_DIR_DEPTH: usize = 64;
+
+/// Whether the file at `path` is an ISO9660 or UDF image. Either starts with its descriptors,
+/// UDF ones with the beginning of its volume recognition sequence.
+pub(crate) fn is_iso(path: &Path) -> Result<bool, KArchiveError> {
+    let mut file = InternalFile::open(path)?;
+    if source_len(path)? < (DESCRIPTORS_START + 1) * SECTOR_SIZE {
+        return Ok(false);
+    }
+    let mut id = [0_u8; 5];
+    file.seek(SeekFrom::Start(DESCRIPTORS_START * SECTOR_SIZE + 1))?;
+    file.read_exact(&mut id)?;
+    Ok(&id == STANDARD_ID || &id == b"BEA01")
+}
+
+// (extent sector, length, flags, name) of a directory record
+fn parse_record(record: &[u8]) -> Option<(u64, u64, u8, &[u8])> {
+    let field = |at: usize| u32::from_le_bytes(record[at..at + 4].try_into().unwrap()) as u64;
+    let name_len = *record.get(32)? as usize;
+    let name = record.get(33..33 + name_len)?;
+    Some((field(2), field(10), record[25], name))
+}
+
+// when a record was written: years since 1900, month, day, hour, minute, second and the
+// offset from gmt in 15 minute steps. all zeroes when unset
+fn record_time(record: &[u8]) -> Option<SystemTime> {
+    let date = record.get(18..25)?;
+    let day = time::Date::from_calendar_date(
+        1900 + date[0] as i32,
+        time::Month::try_from(date[1]).ok()?,
+        date[2],
+    )
+    .ok()?;
+    let time = time::Time::from_hms(date[3], date[4], date[5]).ok()?;
+    let offset = time::UtcOffset::from_whole_seconds(date[6] as i8 as i32 * 15 * 60).ok()?;
+    Some(
+        time::PrimitiveDateTime::new(day, time)
+            .assume_offset(offset)
+            .into(),
+    )
+}
+
+fn decode_name(name: &[u8], joliet: bool) -> String {
+    let name = if joliet {
+        let chars: Vec<u16> = name
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16_lossy(&chars)
+    } else {
+        String::from_utf8_lossy(name).into_owned()
+    };
+    // drop the ";1" version suffix, and the dot plain iso9660 puts on names without an extension
+    let name = name.split(';').next().unwrap_or_default();
+    name.strip_suffix('.').unwrap_or(name).to_string()
+}
+
+struct Walker<'a> {
+    file: InternalFile<'static>,
+    joliet: bool,
+    end: u64,
+    options: &'a MountOptions,
+    files: HashMap<PathBuf, KFileInfo>,
+    // directory extents already walked, so records pointing back up don't loop
+    visited: HashSet<u64>,
+}
+
+impl<'a> Walker<'a> {
+    fn walk(
+        &mut self,
+        sector: u64,
+        len: u64,
+        dir: &Path,
+        depth: usize,
+    ) -> Result<(), KArchiveError> {
+        if depth > MAX_DIR_DEPTH {
+            return Err(KArchiveError::InvalidHeader {
+                format: "iso",
+                reason: format!("directories nest deeper than {} levels", MAX_DIR_DEPTH),
+            });
+        }
+        let in_image = sector
+            .checked_mul(SECTOR_SIZE)
+            .and_then(|start| start.checked_add(len))
+            .is_some_and(|end| end <= self.end);
+        if !in_image {
+            return Err(KArchiveError::InvalidHeader {
+                format: "iso",
+                reason: format!(
+                    "directory {} at sector {} ({} bytes) runs past the end of the image",
+                    dir.display(),
+                    sector,
+                    len
+                ),
+            });
+        }
+        if !self.visited.insert(sector) {
+            return Ok(());
+        }
+        let mut extent = vec![0; len as usize];
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.file.read_exact(&mut extent)?;
+        let mut pos = 0;
+        while pos < extent.len() {
+            let record_len = extent[pos] as usize;
+            // records don't cross sectors, the rest of a sector is zeroed instead
+            if record_len == 0 {
+                pos = (pos / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+                continue;
+            }
+            let record = extent.get(pos..pos + record_len).unwrap_or_default();
+            pos += record_len;
+            let Some((child_sector, child_len, flags, raw_name)) = parse_record(record) else {
+                continue;
+            };
+            // the . and .. entries
+            if raw_name == [0] || raw_name == [1] {
+                continue;
+            }
+            let path = dir.join(decode_name(raw_name, self.joliet));
+            if flags & DIRECTORY_FLAG != 0 {
+                self.walk(child_sector, child_len, &path, depth + 1)?;
+                continue;
+            }
+            let index = self.files.len();
+            let name = archive_name(&path);
+            check_entry_name("iso", index, &name)?;
+            let offset = child_sector * SECTOR_SIZE;
+            let available = payload_available(
+                "iso",
+                index,
+                &name,
+                (offset, child_len),
+                self.end,
+                self.options,
+            )?;
+            self.files.insert(
+                path,
+                KFileInfo {
+                    size: child_len,
+                    offset,
+                    available,
+                    modified: record_time(record),
+                    ..Default::default()
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Lists the files of an ISO9660 or UDF image. UDF is used when the image has it, since it
+/// keeps full names and files over 4GiB, otherwise the Joliet names when there are some.
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let mut file = InternalFile::open(&path)?;
+    let end = source_len(&path)?;
+    let mut root = None;
+    let mut udf = false;
+    let mut descriptor = [0_u8; SECTOR_SIZE as usize];
+    for sector in DESCRIPTORS_START.. {
+        if (sector + 1) * SECTOR_SIZE > end {
+            break;
+        }
+        file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        file.read_exact(&mut descriptor)?;
+        // the udf volume recognition sequence comes after any iso9660 descriptors
+        match &descriptor[1..6] {
+            b"NSR02" | b"NSR03" => {
+                udf = true;
+                continue;
+            }
+            b"BEA01" | b"TEA01" | b"BOOT2" | b"CDW02" => continue,
+            id if id != STANDARD_ID => break,
+            _ => {}
+        }
+        // joliet volumes are supplementary descriptors with a ucs-2 escape sequence
+        let joliet = descriptor[0] == 2
+            && descriptor[88..90] == *b"%/"
+            && matches!(descriptor[90], b'@' | b'C' | b'E');
+        match descriptor[0] {
+            1 if root.is_none() => root = Some((descriptor[156..190].to_vec(), false)),
+            2 if joliet => root = Some((descriptor[156..190].to_vec(), true)),
+            _ => {}
+        }
+    }
+    if udf {
+        match crate::udf::parse(&mut file, end, options) {
+            Ok(listing) => {
+                let mut archive = KArchive::new(path.clone(), listing.files, None);
+                for reason in listing.skipped {
+                    archive.add_warning(KArchiveWarning::SkippedEntry {
+                        path: path.clone(),
+                        reason,
+                    });
+                }
+                return Ok(archive);
+            }
+            // bridge images still have their iso9660 tree to fall back on
+            Err(e) if root.is_none() => return Err(e),
+            Err(_) => {}
+        }
+    }
+    let Some((root, joliet)) = root else {
+        return Err(KArchiveError::InvalidHeader {
+            format: "iso",
+            reason: "no primary volume descriptor or UDF file system".into(),
+        });
+    };
+    let (sector, len, ..) = parse_record(&root).ok_or_else(|| KArchiveError::InvalidHeader {
+        format: "iso",
+        reason: "root directory record is damaged".into(),
+    })?;
+    let mut walker = Walker {
+        file,
+        joliet,
+        end,
+        options,
+        files: HashMap::new(),
+        visited: HashSet::new(),
+    };
+    walker.walk(sector, len, Path::new(""), 0)?;
+    Ok(KArchive::new(path, walker.files, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarWriter;
+
+    fn record(sector: u32, len: u32, flags: u8, name: &[u8]) -> Vec<u8> {
+        let mut record = vec![0; 33];
+        record[2..6].copy_from_slice(&sector.to_le_bytes());
+        record[10..14].copy_from_slice(&len.to_le_bytes());
+        record[25] = flags;
+        record[32] = name.len() as u8;
+        record.extend_from_slice(name);
+        if record.len() % 2 == 1 {
+            record.push(0);
+        }
+        record[0] = record.len() as u8;
+        record
+    }
+
+    fn sector(data: &[u8]) -> Vec<u8> {
+        let mut sector = data.to_vec();
+        sector.resize(SECTOR_SIZE as usize, 0);
+        sector
+    }
+
+    // an image with the root directory at sector 18 and `rest` in the sectors after it
+    fn image(root_dir: &[u8], rest: &[&[u8]]) -> Vec<u8> {
+        let mut pvd = vec![1];
+        pvd.extend_from_slice(STANDARD_ID);
+        pvd.resize(156, 0);
+        pvd.extend(record(18, 2048, DIRECTORY_FLAG, &[0]));
+        let mut terminator = vec![255];
+        terminator.extend_from_slice(STANDARD_ID);
+
+        let mut iso = vec![0; 16 * SECTOR_SIZE as usize];
+        for data in [&pvd[..], &terminator, root_dir].iter().chain(rest) {
+            iso.extend(sector(data));
+        }
+        iso
+    }
+
+    #[test]
+    fn iso_mount() {
+        let mar = MarWriter::new()
+            .add_file("data/a.bin", vec![9; 0x40])
+            .write(Vec::new())
+            .unwrap();
+        // sectors: 16 pvd, 17 terminator, 18 root dir, 19 data dir, 20 readme, 21 mar
+        let root_dir = [
+            record(18, 2048, DIRECTORY_FLAG, &[0]),
+            record(18, 2048, DIRECTORY_FLAG, &[1]),
+            record(20, 6, 0, b"README.TXT;1"),
+            record(19, 2048, DIRECTORY_FLAG, b"DATA"),
+        ]
+        .concat();
+        let data_dir = [
+            record(19, 2048, DIRECTORY_FLAG, &[0]),
+            record(18, 2048, DIRECTORY_FLAG, &[1]),
+            record(21, mar.len() as u32, 0, b"UPDATE.MAR;1"),
+        ]
+        .concat();
+        let iso = image(&root_dir, &[&data_dir, b"hello!", &mar]);
+        let mut dated = record(20, 6, 0, b"README.TXT;1");
+        // 2024-04-15 12:00 at gmt+1
+        dated[18..25].copy_from_slice(&[124, 4, 15, 12, 0, 0, 4]);
+        assert_eq!(
+            record_time(&dated),
+            Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_713_178_800))
+        );
+        assert_eq!(record_time(&root_dir), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disc.iso");
+        std::fs::write(&path, iso).unwrap();
+        let archive = crate::mount(path).unwrap();
+        assert_eq!(archive.read(Path::new("README.TXT")).unwrap(), b"hello!");
+        assert_eq!(
+            archive
+                .read(Path::new("DATA/UPDATE.MAR/data/a.bin"))
+                .unwrap(),
+            vec![9; 0x40]
+        );
+    }
+
+    #[test]
+    fn iso_hostile_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let mount = |iso: Vec<u8>| {
+            let path = dir.path().join("disc.iso");
+            std::fs::write(&path, iso).unwrap();
+            crate::mount(path)
+        };
+        // directories that lead back to the root are only walked once
+        let looping = [
+            record(18, 2048, DIRECTORY_FLAG, b"A"),
+            record(18, 2048, DIRECTORY_FLAG, b"B"),
+            record(19, 2, 0, b"X.BIN;1"),
+        ]
+        .concat();
+        let archive = mount(image(&looping, &[b"xx"])).unwrap();
+        assert_eq!(archive.list_files(), [PathBuf::from("X.BIN")]);
+
+        let huge = record(19, u32::MAX, DIRECTORY_FLAG, b"HUGE");
+        assert!(matches!(
+            mount(image(&huge, &[])),
+            Err(KArchiveError::InvalidHeader { format: "iso", .. })
+        ));
+    }
+}
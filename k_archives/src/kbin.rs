@@ -0,0 +1,372 @@
+// Minimal reader for konami's binary xml (kbinxml). IFS containers store their file tree in it.
+// only what's needed to walk a tree is decoded: node names, attributes, and raw values
+
+use crate::common::KArchiveError;
+
+const SIXBIT_CHARS: &[u8] = b"0123456789:ABCDEFGHIJKLMNOPQRSTUVWXYZ_abcdefghijklmnopqrstuvwxyz";
+const ARRAY_FLAG: u8 = 0x40;
+pub(crate) const TYPE_NODE: u8 = 1;
+pub(crate) const TYPE_BIN: u8 = 10;
+pub(crate) const TYPE_STR: u8 = 11;
+pub(crate) const TYPE_3S32: u8 = 30;
+const TYPE_ATTR: u8 = 46;
+const NODE_END: u8 = 190;
+const DOC_END: u8 = 191;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct KbinNode {
+    pub(crate) name: String,
+    pub(crate) type_id: u8,
+    pub(crate) is_array: bool,
+    // raw big endian bytes of the value, empty for plain nodes
+    pub(crate) value: Vec<u8>,
+    pub(crate) attributes: Vec<(String, String)>,
+    pub(crate) children: Vec<KbinNode>,
+}
+
+impl KbinNode {
+    pub(crate) fn s32s(&self) -> Vec<i32> {
+        self.value
+            .chunks_exact(4)
+            .map(|chunk| i32::from_be_bytes(chunk.try_into().unwrap()))
+            .collect()
+    }
+}
+
+// size of one value of each fixed size type. bin and str are length prefixed instead
+fn type_size(type_id: u8) -> Option<usize> {
+    Some(match type_id {
+        2 | 3 | 52 => 1,
+        4 | 5 | 16 | 17 | 53 => 2,
+        26 | 27 | 54 => 3,
+        6 | 7 | 12 | 13 | 14 | 36 | 37 | 55 => 4,
+        28 | 29 => 6,
+        8 | 9 | 15 | 20 | 21 | 24 | 38 | 39 => 8,
+        30 | 31 | 34 => 12,
+        22 | 23 | 25 | 40 | 41 | 44 | 48..=51 | 56 => 16,
+        32 | 33 | 35 => 24,
+        42 | 43 | 45 => 32,
+        _ => return None,
+    })
+}
+
+fn align4(pos: usize) -> usize {
+    (pos + 3) & !3
+}
+
+fn bad(reason: &str) -> KArchiveError {
+    KArchiveError::InvalidHeader {
+        format: "kbinxml",
+        reason: reason.to_string(),
+    }
+}
+
+struct NodeReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    compressed: bool,
+}
+
+impl<'a> NodeReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], KArchiveError> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.buf.get(self.pos..end))
+            .ok_or_else(|| bad("node buffer ended early"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, KArchiveError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn name(&mut self) -> Result<String, KArchiveError> {
+        if !self.compressed {
+            let len = (self.u8()? & !ARRAY_FLAG) as usize + 1;
+            return Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned());
+        }
+        // names are packed 6 bits per character, most significant bit first
+        let len = self.u8()? as usize;
+        let packed = self.bytes((len * 6).div_ceil(8))?;
+        let mut name = String::with_capacity(len);
+        for idx in 0..len {
+            let bit = idx * 6;
+            let pair =
+                u16::from_be_bytes([packed[bit / 8], *packed.get(bit / 8 + 1).unwrap_or(&0)]);
+            let value = (pair >> (10 - bit % 8)) & 0x3F;
+            name.push(SIXBIT_CHARS[value as usize] as char);
+        }
+        Ok(name)
+    }
+}
+
+// values are packed with 1 and 2 byte values sharing 4 byte slots, so there are separate
+// cursors for those alongside the main one
+struct DataReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    byte_pos: usize,
+    word_pos: usize,
+}
+
+impl<'a> DataReader<'a> {
+    fn slice(&self, start: usize, len: usize) -> Result<&'a [u8], KArchiveError> {
+        start
+            .checked_add(len)
+            .and_then(|end| self.buf.get(start..end))
+            .ok_or_else(|| bad("data buffer ended early"))
+    }
+
+    // length prefixed values: strings, binary blobs and arrays
+    fn auto(&mut self) -> Result<&'a [u8], KArchiveError> {
+        let len = u32::from_be_bytes(self.slice(self.pos, 4)?.try_into().unwrap()) as usize;
+        let data = self.slice(self.pos + 4, len)?;
+        self.pos = align4(self.pos + 4 + len);
+        Ok(data)
+    }
+
+    fn aligned(&mut self, size: usize) -> Result<&'a [u8], KArchiveError> {
+        if self.byte_pos.is_multiple_of(4) {
+            self.byte_pos = self.pos;
+        }
+        if self.word_pos.is_multiple_of(4) {
+            self.word_pos = self.pos;
+        }
+        let data = match size {
+            1 => {
+                let data = self.slice(self.byte_pos, 1)?;
+                self.byte_pos += 1;
+                data
+            }
+            2 => {
+                let data = self.slice(self.word_pos, 2)?;
+                self.word_pos += 2;
+                data
+            }
+            _ => {
+                let data = self.slice(self.pos, size)?;
+                self.pos = align4(self.pos + size);
+                data
+            }
+        };
+        let trailing = usize::max(self.byte_pos, self.word_pos);
+        if self.pos < trailing {
+            self.pos = align4(trailing);
+        }
+        Ok(data)
+    }
+}
+
+/// Decodes a kbinxml document, returning its root node.
+pub(crate) fn parse(data: &[u8]) -> Result<KbinNode, KArchiveError> {
+    if data.len() < 8 || data[0] != 0xA0 {
+        return Err(bad("missing kbinxml signature"));
+    }
+    let compressed = match data[1] {
+        0x42 => true,
+        0x45 => false,
+        _ => return Err(bad("unknown name compression")),
+    };
+    // data[2] is the string encoding, data[3] its complement. names are ascii either way
+    let read_len = |at: usize| -> Result<usize, KArchiveError> {
+        let bytes = at
+            .checked_add(4)
+            .and_then(|end| data.get(at..end))
+            .ok_or_else(|| bad("truncated document"))?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+    };
+    // lengths come straight from the document, so they can add up past usize::MAX where
+    // that's 32 bits (wasm)
+    let node_len = read_len(4)?;
+    let data_start = node_len
+        .checked_add(8)
+        .ok_or_else(|| bad("truncated node buffer"))?;
+    let data_len = read_len(data_start)?;
+    let mut nodes = NodeReader {
+        buf: data
+            .get(8..data_start)
+            .ok_or_else(|| bad("truncated node buffer"))?,
+        pos: 0,
+        compressed,
+    };
+    let mut values = DataReader {
+        buf: data_len
+            .checked_add(data_start + 4)
+            .and_then(|end| data.get(data_start + 4..end))
+            .ok_or_else(|| bad("truncated data buffer"))?,
+        pos: 0,
+        byte_pos: 0,
+        word_pos: 0,
+    };
+
+    // the bottom of the stack collects the root node
+    let mut stack = vec![KbinNode::default()];
+    loop {
+        let raw = nodes.u8()?;
+        let is_array = raw & ARRAY_FLAG != 0;
+        match raw & !ARRAY_FLAG {
+            DOC_END => break,
+            NODE_END => {
+                let node = stack.pop().filter(|_| !stack.is_empty());
+                let node = node.ok_or_else(|| bad("unbalanced node end"))?;
+                stack.last_mut().unwrap().children.push(node);
+            }
+            TYPE_ATTR => {
+                let name = nodes.name()?;
+                let value = values.auto()?;
+                let value = String::from_utf8_lossy(value)
+                    .trim_end_matches('\0')
+                    .to_string();
+                let node = stack.last_mut().unwrap();
+                node.attributes.push((name, value));
+            }
+            type_id => {
+                let name = nodes.name()?;
+                let value = match (type_id, type_size(type_id)) {
+                    (TYPE_NODE, _) => Vec::new(),
+                    (_, Some(size)) if !is_array => values.aligned(size)?.to_vec(),
+                    (TYPE_BIN | TYPE_STR, _) | (_, Some(_)) => values.auto()?.to_vec(),
+                    _ => return Err(bad(&format!("unknown node type {}", type_id))),
+                };
+                stack.push(KbinNode {
+                    name,
+                    type_id,
+                    is_array,
+                    value,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    let root = stack.pop().filter(|_| stack.is_empty());
+    root.and_then(|mut root| root.children.pop())
+        .ok_or_else(|| bad("document has no root node"))
+}
+
+// just enough of a writer to build documents for tests
+#[cfg(test)]
+pub(crate) mod writer {
+    use super::*;
+
+    pub(crate) enum Value {
+        Node(Vec<(&'static str, Value)>),
+        S32x3([i32; 3]),
+        U8(u8),
+        Str(&'static str),
+    }
+
+    fn push_name(nodes: &mut Vec<u8>, name: &str) {
+        nodes.push(name.len() as u8);
+        let mut bits = 0_u32;
+        let mut bit_count = 0;
+        for c in name.bytes() {
+            let value = SIXBIT_CHARS.iter().position(|&b| b == c).unwrap() as u32;
+            bits = (bits << 6) | value;
+            bit_count += 6;
+            while bit_count >= 8 {
+                bit_count -= 8;
+                nodes.push((bits >> bit_count) as u8);
+            }
+        }
+        if bit_count > 0 {
+            nodes.push((bits << (8 - bit_count)) as u8);
+        }
+    }
+
+    fn push_node(nodes: &mut Vec<u8>, data: &mut Vec<u8>, name: &str, value: &Value) {
+        match value {
+            Value::Node(children) => {
+                nodes.push(TYPE_NODE);
+                push_name(nodes, name);
+                for (name, child) in children {
+                    push_node(nodes, data, name, child);
+                }
+            }
+            Value::S32x3(values) => {
+                nodes.push(TYPE_3S32);
+                push_name(nodes, name);
+                for value in values {
+                    data.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            // only valid as the sole small value, since it takes a whole slot
+            Value::U8(value) => {
+                nodes.push(3);
+                push_name(nodes, name);
+                data.extend_from_slice(&[*value, 0, 0, 0]);
+            }
+            Value::Str(value) => {
+                nodes.push(TYPE_STR);
+                push_name(nodes, name);
+                data.extend_from_slice(&(value.len() as u32 + 1).to_be_bytes());
+                data.extend_from_slice(value.as_bytes());
+                data.push(0);
+                data.resize(align4(data.len()), 0);
+            }
+        }
+        nodes.push(NODE_END | ARRAY_FLAG);
+    }
+
+    pub(crate) fn write(name: &str, root: &Value) -> Vec<u8> {
+        let (mut nodes, mut data) = (Vec::new(), Vec::new());
+        push_node(&mut nodes, &mut data, name, root);
+        nodes.push(DOC_END | ARRAY_FLAG);
+        nodes.resize(align4(nodes.len()), 0);
+        let mut out = vec![0xA0, 0x42, 0x80, 0x7F];
+        out.extend_from_slice(&(nodes.len() as u32).to_be_bytes());
+        out.extend(nodes);
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend(data);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::writer::*;
+    use super::*;
+
+    #[test]
+    fn kbin_huge_lengths() {
+        // lengths near u32::MAX, which would overflow adding up on 32 bit targets
+        let mut doc = vec![0xA0, 0x42, 0, 0xFF];
+        doc.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            parse(&doc),
+            Err(KArchiveError::InvalidHeader {
+                format: "kbinxml",
+                ..
+            })
+        ));
+        let mut doc = vec![0xA0, 0x42, 0, 0xFF, 0, 0, 0, 0];
+        doc.extend_from_slice(&(u32::MAX - 2).to_be_bytes());
+        assert!(matches!(
+            parse(&doc),
+            Err(KArchiveError::InvalidHeader {
+                format: "kbinxml",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn kbin_round_trip() {
+        let doc = write(
+            "imgfs",
+            &Value::Node(vec![
+                ("flag", Value::U8(7)),
+                ("label", Value::Str("hello")),
+                ("tex_E1", Value::S32x3([1, -2, 3])),
+            ]),
+        );
+        let root = parse(&doc).unwrap();
+        assert_eq!(root.name, "imgfs");
+        assert_eq!(root.children.len(), 3);
+        assert_eq!(root.children[0].value, vec![7]);
+        assert_eq!(root.children[1].value, b"hello\0");
+        assert_eq!(root.children[2].name, "tex_E1");
+        assert_eq!(root.children[2].s32s(), vec![1, -2, 3]);
+    }
+}
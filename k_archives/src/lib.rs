@@ -1,43 +1,236 @@
+mod arc;
 mod bar;
 mod cab;
+mod chain;
+mod checksum;
+mod cipher;
 mod common;
+mod compressed;
+mod convert;
 mod d2;
-mod info;
-mod lst;
+mod diff;
+mod extdrmfs;
+mod filelist;
+mod filter;
+mod ifs;
+mod index;
+pub mod info;
+mod iso;
+mod kbin;
+pub mod lst;
+mod lz77;
 mod mar;
+mod nested;
+mod paths;
 mod qar;
+#[cfg(feature = "http")]
+mod remote;
+#[cfg(feature = "http")]
+mod s3;
+mod s3p;
+mod salvage;
+mod scan;
+mod stats;
+#[cfg(feature = "tar")]
+mod tarfile;
+mod twodx;
+mod udf;
+mod update;
+pub mod write;
+#[cfg(feature = "zip")]
+mod zipfile;
 use std::{io::Read, path::PathBuf};
 
+pub use crate::arc::ArcWriter;
+pub use crate::bar::BarWriter;
+pub use crate::cab::{CabWriter, CAB_MEMBER_PREFIX};
+pub use crate::chain::{ChainLink, UpdateChain};
+pub use crate::checksum::{content_hash_name, hash_file, hash_reader, ChecksumType, Checksummer};
+pub use crate::cipher::{EntryCipher, KeyProvider};
 pub use crate::common::*;
+pub use crate::convert::{convert, TargetFormat};
+pub use crate::diff::{diff, ArchiveDiff};
+pub use crate::extdrmfs::ExtDrmFs;
+pub use crate::filelist::{FileList, FileListEntry};
+pub use crate::filter::EntryFilter;
+pub use crate::index::{ArchiveIndex, IndexQuery, IndexedArchive, IndexedEntry};
+pub use crate::info::{InfoEntry, InfoManifest, InfoWriter};
+pub use crate::lst::{LstManifestEntry, LstSetBuilder, LstWriter};
+pub use crate::mar::{decrypt_mar_payload, MarWriter};
+pub use crate::paths::{join_under, long_path, windows_safe_path};
+pub use crate::qar::QarWriter;
+pub use crate::scan::{find_archives, is_archive};
+pub use crate::stats::{stats, ArchiveStats, GroupStats};
+pub use crate::update::UpdateInfo;
 
+/// Mounts the archive at `path`. With the http feature `path` can also be an http(s) url or an
+/// `s3://bucket/key` one (see the s3 module for where its endpoint and credentials come from),
+/// whose headers and entries are then downloaded with range requests as they're needed.
 pub fn mount(path: PathBuf) -> Result<KArchive, KArchiveError> {
-    let mut archive = std::fs::File::open(&path)?;
+    mount_with_options(path, &MountOptions::default())
+}
+
+pub fn mount_with_options(
+    path: PathBuf,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    let mut archive = mount_archive(path, options)?;
+    // done once for the whole mount, since a multi part update's list covers every part
+    crate::filelist::attach(&mut archive)?;
+    Ok(archive)
+}
+
+/// Mounts an archive that's already in memory, eg. one dropped on a browser page. Nothing is
+/// read from disk and the storage isn't benchmarked. `name` names the archive, and is what
+/// the formats without a magic number (2dx, d2) and the M32 check for encrypted mars go by.
+/// Multi part sets (ULST, NAME) and disc images need their files on disk, so they can't be
+/// mounted this way.
+pub fn mount_bytes(
+    name: PathBuf,
+    data: Vec<u8>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    let magic: [u8; 4] = data
+        .get(..4)
+        .and_then(|magic| magic.try_into().ok())
+        .unwrap_or_default();
+    let mut archive =
+        match &magic {
+            b"ULST" | b"NAME" => return Err(KArchiveError::Other(
+                "multi part sets need their parts on disk, mount the parts one at a time instead",
+            )),
+            [0x1F, 0x8B, _, _] | &crate::compressed::ZSTD_MAGIC => {
+                let mut archive = crate::compressed::parse_from(name, &data, options)?;
+                if options.mount_nested {
+                    crate::nested::expand(&mut archive, options, crate::nested::MAX_DEPTH)?;
+                }
+                archive
+            }
+            #[cfg(feature = "zip")]
+            b"PK\x03\x04" | b"PK\x05\x06" => {
+                let mut archive = crate::zipfile::parse_from(name, Some(data), options)?;
+                crate::nested::expand(&mut archive, options, crate::nested::MAX_DEPTH)?;
+                archive
+            }
+            _ => {
+                let size = data.len() as u64;
+                let Some(parser) = crate::nested::nested_parser(&name, &data, size) else {
+                    return Err(KArchiveError::InvalidHeader {
+                        format: "unknown",
+                        reason: format!("{} isn't an archive that can be mounted", name.display()),
+                    });
+                };
+                #[cfg(feature = "tar")]
+                let bundle = crate::tarfile::looks_like_tar(&data);
+                #[cfg(not(feature = "tar"))]
+                let bundle = false;
+                let mut archive = parser(name, data, options)?;
+                // same as mounting from disk, bundles always have what's in them mounted
+                if bundle || options.mount_nested {
+                    crate::nested::expand(&mut archive, options, crate::nested::MAX_DEPTH)?;
+                }
+                archive
+            }
+        };
+    crate::filelist::attach(&mut archive)?;
+    Ok(archive)
+}
+
+// everything mount_with_options does short of checking against a FileList.dat.
+// parts of multi part sets are mounted through this
+pub(crate) fn mount_archive(
+    path: PathBuf,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    // already extracted updates (eg. a contents folder) are mounted as is, so they can be
+    // used the same way as the archives they came from
+    if path.is_dir() {
+        let mut archive = KArchive::from_directory(path)?;
+        if options.mount_nested {
+            crate::nested::expand(&mut archive, options, crate::nested::MAX_DEPTH)?;
+        }
+        return Ok(archive);
+    }
+    // tars have their magic number further in. like zips they're bundles of other archives,
+    // so those are always mounted
+    #[cfg(feature = "tar")]
+    if crate::tarfile::is_tar(&path)? {
+        let mut archive = crate::tarfile::parse(path, options)?;
+        crate::nested::expand(&mut archive, options, crate::nested::MAX_DEPTH)?;
+        return Ok(archive);
+    }
+    // same for disc images, whose descriptors start 32KiB in
+    if crate::iso::is_iso(&path)? {
+        let mut archive = crate::iso::parse(path, options)?;
+        crate::nested::expand(&mut archive, options, crate::nested::MAX_DEPTH)?;
+        return Ok(archive);
+    }
+    let mut file = InternalFile::open(&path)?;
     // read the first 4 bytes to see which type it is
     let mut magic = [0_u8; 4];
-    archive.read_exact(&mut magic)?;
-    match &magic {
+    file.read_exact(&mut magic)?;
+    let mut archive = match &magic {
         // QAR\0
-        b"QAR\0" => crate::qar::parse(path),
+        b"QAR\0" => crate::qar::parse(path, options),
         // MASM (full magic is MASMAR0 but this is good enough to know where to go)
-        b"MASM" => crate::mar::parse(path),
+        b"MASM" => crate::mar::parse(path, options),
         // ULST. this is a list file that contains the filenames, sizes, and hashes of a multi file update
         // seems to only be used by gitadora and can be used to mount all of them at once rather than individually
-        b"ULST" => crate::lst::parse(path),
+        // parts are mounted through here, so anything nested in them is already expanded
+        b"ULST" => return crate::lst::parse(path, options),
         // this isn't actually a magic number, this file is just a plain text description with the same info as ULST
-        b"NAME" => crate::info::parse(path),
+        b"NAME" => return crate::info::parse(path, options),
         // Cabinet files are used for some games. They usually contain an arcfile inside as well as a file list
-        b"MSCF" => crate::cab::parse(path),
+        b"MSCF" => crate::cab::parse(path, options),
+        // IFS (imagefs) containers hold game assets, with a kbinxml manifest describing the file tree
+        &crate::ifs::SIGNATURE => crate::ifs::parse(path, options),
+        // S3P (and SSP, the same thing under another name) hold the audio for sdvx and a few others
+        crate::s3p::MAGIC => crate::s3p::parse(path, options),
+        // pop'n music's arc containers, with mostly lz77 compressed entries
+        &crate::arc::MAGIC => crate::arc::parse(path, options),
+        // gzip and zstd wrapped archives, as some get recompressed for distribution
+        [0x1F, 0x8B, _, _] | &crate::compressed::ZSTD_MAGIC => {
+            crate::compressed::parse(path, options)
+        }
+        // zips are just a bundle around the archives that matter, so those are always mounted
+        #[cfg(feature = "zip")]
+        b"PK\x03\x04" | b"PK\x05\x06" => {
+            let mut archive = crate::zipfile::parse(path, options)?;
+            crate::nested::expand(&mut archive, options, crate::nested::MAX_DEPTH)?;
+            return Ok(archive);
+        }
+        #[cfg(not(feature = "zip"))]
+        b"PK\x03\x04" | b"PK\x05\x06" => Err(KArchiveError::Other(
+            "zip support isn't enabled, build with the zip feature",
+        )),
         // neither bar nor d2 have magic numbers, but bar can be weird and have a different extension (car in iidx preload),
-        // so check if extension is d2, otherwise use the bar parser
+        // so check if extension is d2, otherwise use the bar parser.
+        // some games ship bars as .dat too, so the d2 header has to check out as well.
+        // 2dx audio containers don't have one either, but they always have their own extension.
+        // .pak containers aren't understood yet, so rather than a nonsense bar listing they're
+        // refused unless they really are bars
         _ => {
-            if path
+            if path.extension().is_some_and(|ext| ext == "2dx") {
+                crate::twodx::parse(path, options)
+            } else if path.extension().is_some_and(|ext| ext == "pak")
+                && !crate::bar::is_bar(&path)?
+            {
+                Err(KArchiveError::Other(
+                    "pak containers aren't supported yet, their layout isn't known",
+                ))
+            } else if path
                 .extension()
                 .is_some_and(|ext| ext == "d2" || ext == "dat")
+                && crate::d2::is_d2(&path)?
             {
-                crate::d2::parse(path)
+                crate::d2::parse(path, options)
             } else {
-                crate::bar::parse(path)
+                crate::bar::parse(path, options)
             }
         }
+    }?;
+    if options.mount_nested {
+        crate::nested::expand(&mut archive, options, crate::nested::MAX_DEPTH)?;
     }
+    Ok(archive)
 }
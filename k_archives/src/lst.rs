@@ -1,51 +1,453 @@
 use binread::{BinRead, NullString};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
+use crate::checksum::{hash_file, hash_reader, ChecksumType};
 use crate::common::*;
-#[allow(dead_code)]
+use crate::mar::MarWriter;
+use crate::update::UpdateInfo;
+#[allow(dead_code)] // file_count is only needed while parsing
 #[derive(BinRead)]
 #[br(magic = b"ULST")]
-pub struct LstFile {
+struct LstFile {
     #[br(align_after = 0x10)]
-    pub file_count: u16,
+    file_count: u16,
 
     #[br(count = file_count)]
-    pub files: Vec<LstEntry>,
+    files: Vec<LstEntry>,
 }
 
-#[allow(dead_code)]
 #[derive(BinRead)]
-pub struct LstEntry {
+struct LstEntry {
     #[br(pad_size_to = 0x20)]
-    pub name: NullString,
+    name: NullString,
 
     #[br(pad_size_to = 0x40)]
-    pub file_name: NullString,
+    file_name: NullString,
 
     #[br(pad_size_to = 0x8)]
-    pub checksum_type: NullString,
+    checksum_type: NullString,
 
     #[br(pad_size_to = 0x28)]
-    pub checksum: NullString,
+    checksum: NullString,
 
     #[br(pad_after = 0x10)]
+    file_size: u64,
+}
+
+/// One archive referenced by a ULST.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LstManifestEntry {
+    pub name: String,
+    pub file_name: String,
+    pub checksum_type: String,
+    pub checksum: String,
     pub file_size: u64,
 }
 
-pub(crate) fn parse(path: PathBuf) -> Result<KArchive, KArchiveError> {
-    let mut file = File::open(&path)?;
-    let mut archive = KArchive::init_empty();
+impl From<LstEntry> for LstManifestEntry {
+    fn from(entry: LstEntry) -> Self {
+        // NullString's to_string panics on invalid utf8, so go through the lossy conversion instead
+        Self {
+            name: String::from_utf8_lossy(&entry.name).into_owned(),
+            file_name: String::from_utf8_lossy(&entry.file_name).into_owned(),
+            checksum_type: String::from_utf8_lossy(&entry.checksum_type).into_owned(),
+            checksum: String::from_utf8_lossy(&entry.checksum).into_owned(),
+            file_size: entry.file_size,
+        }
+    }
+}
+
+/// Reads a ULST without mounting any of the archives it references.
+pub fn parse_manifest(path: &Path) -> Result<Vec<LstManifestEntry>, KArchiveError> {
+    let mut file = InternalFile::open(path)?;
     let lst_file = LstFile::read(&mut file)?;
-    for entry in lst_file.files {
-        if let Ok(mut arc) = super::mount(path.with_file_name(entry.file_name.to_string())) {
-            archive.add_archive(&mut arc)
-        } else {
-            eprintln!(
-                "LST: Failed to mount archive: {}",
-                entry.file_name.to_string()
-            )
+    Ok(lst_file.files.into_iter().map(Into::into).collect())
+}
+
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let mut archive = KArchive::init_empty();
+    let entries = parse_manifest(&path)?;
+    // parts are mounted (and hashed, if requested) in parallel, but merged in the
+    // order the ULST lists them so lookups still resolve to the earliest part
+    let results = parallel_map(&entries, |entry| {
+        let part_path = path.with_file_name(&entry.file_name);
+        let result = super::mount_archive(part_path.clone(), options).and_then(|mut arc| {
+            verify_part(
+                &part_path,
+                Some(entry.file_size),
+                Some((&entry.checksum_type, &entry.checksum)),
+                options,
+                &mut arc,
+            )?;
+            arc.set_declared_checksum(&entry.checksum_type, &entry.checksum);
+            Ok(arc)
+        });
+        (part_path, result)
+    });
+    for (part_path, result) in results {
+        match result {
+            Ok(mut arc) => archive.add_archive(&mut arc),
+            Err(e @ KArchiveError::ChecksumMismatch { .. })
+            | Err(e @ KArchiveError::SizeMismatch { .. }) => return Err(e),
+            Err(e) => handle_missing_part(&mut archive, part_path, e, options)?,
         }
     }
+    archive.set_update_info(UpdateInfo::from_parts(
+        entries
+            .iter()
+            .map(|entry| (entry.file_name.as_str(), entry.file_size)),
+    ));
     Ok(archive)
 }
+
+// every string in a ULST entry is a null terminated string padded out to a fixed size
+fn write_field<W: Write>(wtr: &mut W, value: &str, size: usize) -> Result<(), KArchiveError> {
+    if value.len() >= size {
+        return Err(KArchiveError::WriteError(format!(
+            "{} doesn't fit in a {} byte ULST field",
+            value, size
+        )));
+    }
+    let mut field = value.as_bytes().to_vec();
+    field.resize(size, 0);
+    wtr.write_all(&field)?;
+    Ok(())
+}
+
+struct LstWriterEntry {
+    name: String,
+    file_name: String,
+    checksum_type: ChecksumType,
+    checksum: String,
+    file_size: u64,
+}
+
+/// Writes ULST files describing the parts of a multi part update.
+#[derive(Default)]
+pub struct LstWriter {
+    entries: Vec<LstWriterEntry>,
+}
+
+impl LstWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an already written part, hashing it with `checksum_type`.
+    /// The ULST only has room for 0x27 characters of checksum, so md5 or crc32 have to be used.
+    pub fn add_part(
+        &mut self,
+        name: &str,
+        path: &Path,
+        checksum_type: ChecksumType,
+    ) -> Result<&mut Self, KArchiveError> {
+        let file_name = path
+            .file_name()
+            .ok_or(KArchiveError::Other("Part path has no file name"))?
+            .to_string_lossy()
+            .into_owned();
+        self.entries.push(LstWriterEntry {
+            name: name.to_string(),
+            file_name,
+            checksum_type,
+            checksum: hash_file(path, checksum_type)?,
+            file_size: std::fs::metadata(path)?.len(),
+        });
+        Ok(self)
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<W, KArchiveError> {
+        let file_count = u16::try_from(self.entries.len())
+            .map_err(|_| KArchiveError::WriteError("Too many parts for a ULST".to_string()))?;
+        writer.write_all(b"ULST")?;
+        writer.write_u16::<LittleEndian>(file_count)?;
+        writer.write_all(&[0; 10])?;
+        for entry in &self.entries {
+            write_field(&mut writer, &entry.name, 0x20)?;
+            write_field(&mut writer, &entry.file_name, 0x40)?;
+            write_field(
+                &mut writer,
+                &entry.checksum_type.name().to_ascii_uppercase(),
+                0x8,
+            )?;
+            write_field(&mut writer, &entry.checksum, 0x28)?;
+            writer.write_u64::<LittleEndian>(entry.file_size)?;
+            writer.write_all(&[0; 0x10])?;
+        }
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+/// Splits a set of files into size capped mar archives and writes a ULST describing them,
+/// which is how gitadora ships its updates.
+pub struct LstSetBuilder<'a> {
+    part_size: u64,
+    checksum_type: ChecksumType,
+    files: BTreeMap<PathBuf, EntrySource<'a>>,
+}
+
+impl<'a> LstSetBuilder<'a> {
+    /// `part_size` is the size each part is kept under. A file bigger than that on its own
+    /// still gets written, just into a part by itself.
+    pub fn new(part_size: u64) -> Self {
+        Self {
+            part_size,
+            checksum_type: ChecksumType::Md5,
+            files: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_file(&mut self, name: impl Into<PathBuf>, data: Vec<u8>) -> &mut Self {
+        self.files.insert(name.into(), EntrySource::Buffer(data));
+        self
+    }
+
+    pub fn add_path(&mut self, name: impl Into<PathBuf>, source: impl Into<PathBuf>) -> &mut Self {
+        self.files
+            .insert(name.into(), EntrySource::Path(source.into()));
+        self
+    }
+
+    /// Recursively adds every file under `dir`, named relative to `dir`.
+    pub fn add_dir(&mut self, dir: &Path) -> Result<&mut Self, KArchiveError> {
+        for (name, path) in walk_dir(dir)? {
+            self.add_path(name, path);
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn add_source(&mut self, name: PathBuf, source: EntrySource<'a>) -> &mut Self {
+        self.files.insert(name, source);
+        self
+    }
+
+    pub(crate) fn sources(&self) -> &BTreeMap<PathBuf, EntrySource<'a>> {
+        &self.files
+    }
+
+    /// Checksum written into the ULST for each part. Defaults to md5.
+    pub fn set_checksum_type(&mut self, checksum_type: ChecksumType) -> &mut Self {
+        self.checksum_type = checksum_type;
+        self
+    }
+
+    // greedily fills parts in name order
+    fn split(&self) -> Result<Vec<Vec<(&PathBuf, &EntrySource<'a>)>>, KArchiveError> {
+        let mut parts = Vec::new();
+        let mut current = Vec::new();
+        let mut current_size = 0;
+        for (name, source) in &self.files {
+            // type byte + name + null terminator + size field + data
+            let entry_size = 6 + archive_name(name).len() as u64 + source.size()?;
+            if !current.is_empty() && current_size + entry_size > self.part_size {
+                parts.push(std::mem::take(&mut current));
+                current_size = 0;
+            }
+            current.push((name, source));
+            current_size += entry_size;
+        }
+        if !current.is_empty() {
+            parts.push(current);
+        }
+        Ok(parts)
+    }
+
+    /// Writes `{stem}-NNN.mar` parts and `{stem}.lst` into `out_dir`, returning the path of the ULST.
+    /// Like the parser, parts are encrypted when `stem` contains "M32".
+    pub fn write(&self, out_dir: &Path, stem: &str) -> Result<PathBuf, KArchiveError> {
+        Ok(self.write_parts(out_dir, stem)?.0)
+    }
+
+    // same as write, also returning the paths of the parts
+    pub(crate) fn write_parts(
+        &self,
+        out_dir: &Path,
+        stem: &str,
+    ) -> Result<(PathBuf, Vec<PathBuf>), KArchiveError> {
+        // find out the checksum won't fit before writing gigabytes of parts, not after
+        let checksum_len = hash_reader(&mut std::io::empty(), self.checksum_type)?.len();
+        if checksum_len >= 0x28 {
+            return Err(KArchiveError::WriteError(format!(
+                "{} checksums don't fit in a ULST, use md5 or crc32",
+                self.checksum_type.name()
+            )));
+        }
+        std::fs::create_dir_all(out_dir)?;
+        let mut lst = LstWriter::new();
+        let mut part_paths = Vec::new();
+        for (idx, part) in self.split()?.into_iter().enumerate() {
+            let part_name = format!("{}-{:03}", stem, idx + 1);
+            let part_path = out_dir.join(format!("{}.mar", part_name));
+            let mut mar = MarWriter::new();
+            mar.set_encryption(stem.contains("M32"));
+            for (name, source) in part {
+                mar.add_source(name.clone(), source.clone());
+            }
+            mar.write(BufWriter::new(File::create(&part_path)?))?;
+            lst.add_part(&part_name, &part_path, self.checksum_type)?;
+            part_paths.push(part_path);
+        }
+        let lst_path = out_dir.join(format!("{}.lst", stem));
+        lst.write(BufWriter::new(File::create(&lst_path)?))?;
+        Ok((lst_path, part_paths))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CabWriter;
+
+    fn write_padded(buf: &mut Vec<u8>, value: &str, size: usize) {
+        write_field(buf, value, size).unwrap();
+    }
+
+    // writes a ULST referencing a single cab part that contains one file
+    fn write_set(dir: &Path, checksum: &str) -> PathBuf {
+        let part = dir.join("KFC-part1.cab");
+        CabWriter::new()
+            .add_file("data/test.bin", vec![0xAB; 0x40])
+            .write(File::create(&part).unwrap())
+            .unwrap();
+        let checksum = match checksum {
+            "correct" => hash_file(&part, ChecksumType::Md5).unwrap(),
+            other => other.to_string(),
+        };
+        let mut lst = b"ULST".to_vec();
+        lst.write_u16::<LittleEndian>(1).unwrap();
+        lst.resize(0x10, 0);
+        write_padded(&mut lst, "part1", 0x20);
+        write_padded(&mut lst, "KFC-part1.cab", 0x40);
+        write_padded(&mut lst, "MD5", 0x8);
+        write_padded(&mut lst, &checksum, 0x28);
+        lst.write_u64::<LittleEndian>(std::fs::metadata(&part).unwrap().len())
+            .unwrap();
+        lst.resize(lst.len() + 0x10, 0);
+        let lst_path = dir.join("KFC.lst");
+        std::fs::write(&lst_path, lst).unwrap();
+        lst_path
+    }
+
+    #[test]
+    fn lst_checksums() {
+        let dir = tempfile::tempdir().unwrap();
+        let warn = MountOptions {
+            verify_checksums: ChecksumPolicy::Warn,
+            ..Default::default()
+        };
+        let error = MountOptions {
+            verify_checksums: ChecksumPolicy::Error,
+            ..Default::default()
+        };
+
+        let good = write_set(dir.path(), "correct");
+        let archive = crate::mount_with_options(good.clone(), &error).unwrap();
+        assert!(archive.exists(Path::new("data/test.bin")));
+        assert!(archive.warnings().is_empty());
+        let meta = archive.metadata(Path::new("data/test.bin")).unwrap();
+        let part = dir.path().join("KFC-part1.cab");
+        assert_eq!(meta.archive, part);
+        assert_eq!(
+            meta.declared_checksum,
+            Some((
+                "MD5".to_string(),
+                hash_file(&part, ChecksumType::Md5).unwrap()
+            ))
+        );
+
+        let bad = write_set(dir.path(), &"0".repeat(32));
+        let archive = crate::mount_with_options(bad.clone(), &warn).unwrap();
+        assert!(archive.exists(Path::new("data/test.bin")));
+        assert!(matches!(
+            archive.warnings(),
+            [KArchiveWarning::ChecksumMismatch { .. }]
+        ));
+        assert!(matches!(
+            crate::mount_with_options(bad, &error),
+            Err(KArchiveError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn lst_missing_part() {
+        let dir = tempfile::tempdir().unwrap();
+        let lst = write_set(dir.path(), "correct");
+        std::fs::remove_file(dir.path().join("KFC-part1.cab")).unwrap();
+
+        let archive = crate::mount(lst.clone()).unwrap();
+        assert!(archive.list_files().is_empty());
+        assert_eq!(
+            archive.missing_parts(),
+            vec![dir.path().join("KFC-part1.cab").as_path()]
+        );
+
+        let strict = MountOptions {
+            require_all_parts: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            crate::mount_with_options(lst, &strict),
+            Err(KArchiveError::MissingPart { .. })
+        ));
+    }
+
+    #[test]
+    fn lst_set_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = LstSetBuilder::new(0x1000);
+        for i in 0..10 {
+            builder.add_file(format!("data/{}.bin", i), vec![i as u8; 0x600]);
+        }
+        let lst = builder.write(dir.path(), "GITADORA_M32").unwrap();
+        assert!(dir.path().join("GITADORA_M32-005.mar").exists());
+        assert!(!dir.path().join("GITADORA_M32-006.mar").exists());
+
+        let strict = MountOptions {
+            verify_checksums: ChecksumPolicy::Error,
+            require_all_parts: true,
+            ..Default::default()
+        };
+        let archive = crate::mount_with_options(lst, &strict).unwrap();
+        assert_eq!(archive.list_files().len(), 10);
+        let update_info = archive.update_info().unwrap();
+        assert_eq!(update_info.part_count, 5);
+        assert!(update_info.total_size > 10 * 0x600);
+        assert_eq!(
+            archive.read(Path::new("data/7.bin")).unwrap(),
+            vec![7; 0x600]
+        );
+
+        let mut builder = LstSetBuilder::new(0x1000);
+        builder
+            .add_file("data/a.bin", vec![1; 0x10])
+            .set_checksum_type(ChecksumType::Sha256);
+        let out = dir.path().join("sha");
+        assert!(builder.write(&out, "GITADORA").is_err());
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn lst_field_too_long() {
+        let mut buf = Vec::new();
+        assert!(write_field(&mut buf, &"a".repeat(0x28), 0x28).is_err());
+        assert!(write_field(&mut buf, &"a".repeat(0x27), 0x28).is_ok());
+    }
+
+    #[test]
+    fn lst_manifest_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let lst = write_set(dir.path(), "correct");
+        std::fs::remove_file(dir.path().join("KFC-part1.cab")).unwrap();
+        let entries = parse_manifest(&lst).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "part1");
+        assert_eq!(entries[0].file_name, "KFC-part1.cab");
+        assert_eq!(entries[0].checksum_type, "MD5");
+        assert_eq!(entries[0].checksum.len(), 32);
+    }
+}
@@ -0,0 +1,310 @@
+// konami's lz77 variant, used by pop'n arc payloads and ifs members among others.
+// a flag byte covers the next 8 items, lsb first: a set bit is a literal byte, a clear bit
+// a big endian u16 holding a 12 bit distance back and a 4 bit length (+3). a zero u16 ends the stream.
+// the window starts out as zeroes, so distances can reach back before the start of the output
+
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+
+const WINDOW_SIZE: usize = 0x1000;
+const MIN_MATCH: usize = 3;
+
+fn corrupt(reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("corrupt lz77 data: {}", reason),
+    )
+}
+
+/// Decompresses a stream as it's read. Seeking forwards decompresses and throws away
+/// everything in between, seeking backwards starts over from the beginning.
+pub(crate) struct Lz77Reader<R> {
+    inner: BufReader<R>,
+    // decompressed size the stream is expected to have
+    size: u64,
+    window: Box<[u8; WINDOW_SIZE]>,
+    // bytes of output produced so far
+    pos: u64,
+    flags: u8,
+    flags_left: u8,
+    // (distance, bytes left) of the backref being copied
+    copy: (usize, usize),
+    done: bool,
+}
+
+impl<R: Read + Seek> Lz77Reader<R> {
+    pub(crate) fn new(inner: R, size: u64) -> Self {
+        Self {
+            inner: BufReader::new(inner),
+            size,
+            window: Box::new([0; WINDOW_SIZE]),
+            pos: 0,
+            flags: 0,
+            flags_left: 0,
+            copy: (0, 0),
+            done: false,
+        }
+    }
+
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.window.fill(0);
+        self.pos = 0;
+        self.flags_left = 0;
+        self.copy = (0, 0);
+        self.done = false;
+        Ok(())
+    }
+
+    fn next_byte(&mut self) -> std::io::Result<u8> {
+        let mut byte = [0];
+        match self.inner.read_exact(&mut byte) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(corrupt("stream ended early")),
+            result => result.map(|_| byte[0]),
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> u8 {
+        self.window[self.pos as usize % WINDOW_SIZE] = byte;
+        self.pos += 1;
+        byte
+    }
+
+    // next byte of output, or None at the end of the stream
+    fn next_output(&mut self) -> std::io::Result<Option<u8>> {
+        loop {
+            if self.done {
+                return Ok(None);
+            }
+            let (distance, left) = self.copy;
+            if left > 0 {
+                self.copy.1 -= 1;
+                let at = (self.pos as usize + WINDOW_SIZE - distance) % WINDOW_SIZE;
+                return Ok(Some(self.push(self.window[at])));
+            }
+            if self.flags_left == 0 {
+                self.flags = self.next_byte()?;
+                self.flags_left = 8;
+            }
+            let literal = self.flags & 1 != 0;
+            self.flags >>= 1;
+            self.flags_left -= 1;
+            if literal {
+                let byte = self.next_byte()?;
+                return Ok(Some(self.push(byte)));
+            }
+            let backref = u16::from_be_bytes([self.next_byte()?, self.next_byte()?]) as usize;
+            if backref == 0 {
+                self.done = true;
+                continue;
+            }
+            self.copy = (backref >> 4, (backref & 0x0F) + MIN_MATCH);
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for Lz77Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.next_output()? {
+                Some(byte) => {
+                    buf[filled] = byte;
+                    filled += 1;
+                }
+                None if self.pos != self.size => {
+                    return Err(corrupt(&format!(
+                        "decompressed to {} bytes, expected {}",
+                        self.pos, self.size
+                    )))
+                }
+                None => break,
+            }
+        }
+        Ok(filled)
+    }
+}
+
+impl<R: Read + Seek> Seek for Lz77Reader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.size.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        }
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Tried to seek to before the start of the file...",
+            )
+        })?;
+        if target < self.pos {
+            self.restart()?;
+        }
+        while self.pos < target {
+            if self.next_output()?.is_none() {
+                break;
+            }
+        }
+        Ok(self.pos)
+    }
+}
+
+const MAX_MATCH: usize = 0x0F + MIN_MATCH;
+const MAX_DISTANCE: usize = WINDOW_SIZE - 1;
+
+// bits of the 3 byte prefix hash used to find match candidates
+const HASH_BITS: usize = 12;
+
+fn hash(data: &[u8]) -> usize {
+    ((data[0] as usize) << 8 ^ (data[1] as usize) << 4 ^ data[2] as usize) & ((1 << HASH_BITS) - 1)
+}
+
+/// Compresses `data` into a stream `Lz77Reader` can read. `level` (0 to 9) is how hard to
+/// look for matches, 0 doesn't look at all and just writes literals.
+pub(crate) fn compress(data: &[u8], level: u8) -> Vec<u8> {
+    // match candidates tried per position
+    let depth = match level.min(9) {
+        0 => 0,
+        level => 1 << (level - 1),
+    };
+    let mut out = Vec::with_capacity(data.len() + data.len() / 8 + 3);
+    // most recent position for each hash, and the position before it with the same hash
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+    let insert = |pos: usize, head: &mut [usize], prev: &mut [usize]| {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(&data[pos..]);
+            prev[pos] = head[h];
+            head[h] = pos;
+        }
+    };
+    // where the current flag byte is, and how many of its bits are used
+    let (mut flag_at, mut flag_bits) = (0, 8);
+    let mut push_flag = |out: &mut Vec<u8>, literal: bool| {
+        if flag_bits == 8 {
+            flag_at = out.len();
+            out.push(0);
+            flag_bits = 0;
+        }
+        out[flag_at] |= (literal as u8) << flag_bits;
+        flag_bits += 1;
+    };
+    let mut pos = 0;
+    while pos < data.len() {
+        let max_len = MAX_MATCH.min(data.len() - pos);
+        let (mut best_len, mut best_distance) = (0, 0);
+        if depth > 0 && max_len >= MIN_MATCH {
+            let mut candidate = head[hash(&data[pos..])];
+            for _ in 0..depth {
+                if candidate == usize::MAX || pos - candidate > MAX_DISTANCE {
+                    break;
+                }
+                let len = (0..max_len)
+                    .take_while(|&i| data[candidate + i] == data[pos + i])
+                    .count();
+                if len > best_len {
+                    (best_len, best_distance) = (len, pos - candidate);
+                    if len == max_len {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+            }
+        }
+        if best_len >= MIN_MATCH {
+            push_flag(&mut out, false);
+            let backref = (best_distance << 4 | (best_len - MIN_MATCH)) as u16;
+            out.extend_from_slice(&backref.to_be_bytes());
+            for at in pos..pos + best_len {
+                insert(at, &mut head, &mut prev);
+            }
+            pos += best_len;
+        } else {
+            push_flag(&mut out, true);
+            out.push(data[pos]);
+            insert(pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+    push_flag(&mut out, false);
+    out.extend_from_slice(&[0, 0]);
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // compresses without any backrefs, which is still a valid stream
+    pub(crate) fn pack_literals(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut chunks = data.chunks(8);
+        loop {
+            match chunks.next() {
+                Some(chunk) if chunk.len() == 8 => {
+                    out.push(0xFF);
+                    out.extend_from_slice(chunk);
+                }
+                chunk => {
+                    let chunk = chunk.unwrap_or_default();
+                    out.push((1 << chunk.len()) - 1);
+                    out.extend_from_slice(chunk);
+                    out.extend_from_slice(&[0, 0]);
+                    return out;
+                }
+            }
+        }
+    }
+
+    fn decompress(data: &[u8], size: u64) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Lz77Reader::new(Cursor::new(data), size).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn lz77_decompress() {
+        // "abc" as literals, then a distance 3 length 6 backref, then the end marker
+        let data = [0b0000_0111, b'a', b'b', b'c', 0x00, 0x33, 0x00, 0x00];
+        assert_eq!(decompress(&data, 9).unwrap(), b"abcabcabc");
+        // reaching back before the output gives zeroes
+        let zeroes = [0b0000_0000, 0x01, 0x00, 0x00, 0x00];
+        assert_eq!(decompress(&zeroes, 3).unwrap(), vec![0; 3]);
+        assert!(decompress(&zeroes, 4).is_err());
+        assert!(decompress(&[0xFF, b'a'], 1).is_err());
+
+        let data: Vec<u8> = (0..100).collect();
+        assert_eq!(decompress(&pack_literals(&data), 100).unwrap(), data);
+    }
+
+    #[test]
+    fn lz77_compress() {
+        let mut data = b"abcabcabcabc konami konami konami".repeat(200);
+        data.extend((0..0x3000_u32).map(|i| (i * 7 % 251) as u8));
+        for level in [0, 1, 6, 9] {
+            let packed = compress(&data, level);
+            assert_eq!(decompress(&packed, data.len() as u64).unwrap(), data);
+            if level > 0 {
+                assert!(packed.len() < data.len() / 2, "level {}", level);
+            }
+        }
+        assert_eq!(decompress(&compress(&[], 6), 0).unwrap(), b"");
+    }
+
+    #[test]
+    fn lz77_seek() {
+        let data = [0b0000_0111, b'a', b'b', b'c', 0x00, 0x33, 0x00, 0x00];
+        let mut rdr = Lz77Reader::new(Cursor::new(data), 9);
+        let mut buf = [0; 2];
+        rdr.seek(SeekFrom::Start(4)).unwrap();
+        rdr.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"bc");
+        rdr.seek(SeekFrom::Current(-6)).unwrap();
+        rdr.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ab");
+        rdr.seek(SeekFrom::End(-1)).unwrap();
+        rdr.read_exact(&mut buf[..1]).unwrap();
+        assert_eq!(buf[0], b'c');
+    }
+}
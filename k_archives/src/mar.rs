@@ -1,11 +1,12 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Cursor, Read, Seek};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crc_any::{CRCu16, CRCu32};
 
+use crate::cipher::Cipher;
 use crate::common::*;
 
 #[derive(Clone, Debug)]
@@ -28,6 +29,7 @@ impl MarCipher {
         }
     }
 
+    #[allow(clippy::manual_is_multiple_of)]
     pub(crate) fn crypt(&mut self, mut data: &mut [u8]) {
         if self.pos == self.size || data.is_empty() {
             return;
@@ -133,6 +135,7 @@ impl MarKeystream {
     /// Returns an iterator that yields a 4-byte array for each block in the keystream.
     /// The iterator will always start at the beginning of the block regardless if
     /// `pos` is in the middle or end of a block.
+    #[allow(clippy::unnecessary_unwrap)]
     pub(crate) fn get_keystream(&mut self, pos: u64) -> MarKeystreamIterator {
         // Blocks in Mar keystreams are 4 bytes long. We need to handle cases where
         // `pos` is in the middle of a block.
@@ -247,15 +250,57 @@ impl MarKeystreamIterator {
     }
 }
 
-fn read_file_name<T>(rdr: &mut T) -> Result<(String, Vec<u8>), KArchiveError>
+// gitadora derives the key and iv for each file from its name as stored in the archive
+fn derive_cipher(real_name: &[u8], size: u64) -> MarCipher {
+    let mut crc32 = CRCu32::crc32();
+    crc32.digest(real_name);
+    let iv = crc32.get_crc();
+    let mut crc_x25 = CRCu16::crc16_x25();
+    crc_x25.digest(real_name);
+    let key = crc_x25.get_crc() as u32 * 3;
+    MarCipher::new(key, iv, size)
+}
+
+/// Deciphers the payload of an M32 mar entry that was recovered without the archive around
+/// it, reading it from `reader` and writing the result to `writer`. `name` is the entry's
+/// path exactly as the archive stored it, since the key comes from it, and `size` is the
+/// payload's size. The cipher works the same both ways, so this also encrypts.
+pub fn decrypt_mar_payload<R: Read, W: Write>(
+    name: &str,
+    size: u64,
+    mut reader: R,
+    mut writer: W,
+) -> std::io::Result<()> {
+    let mut cipher = derive_cipher(name.as_bytes(), size);
+    let mut buf = vec![0_u8; 0x10000];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        cipher.crypt(&mut buf[..read]);
+        writer.write_all(&buf[..read])?;
+    }
+    writer.flush()
+}
+
+fn read_file_name<T>(
+    rdr: &mut T,
+    encoding: NameEncoding,
+) -> Result<(String, Vec<u8>), KArchiveError>
 where
     T: BufRead + Seek,
 {
     let mut buf = Vec::<u8>::new();
     rdr.read_until(0, &mut buf)?;
-    buf.remove(buf.len() - 1);
+    if buf.pop() != Some(0) {
+        return Err(KArchiveError::ParseError(
+            "name isn't null terminated (malformed or incomplete archive)".to_string(),
+        ));
+    }
     Ok((
-        String::from_utf8(buf.clone())?
+        encoding
+            .decode(&buf)?
             .trim_start_matches(['.', '\\', '/'])
             .replace('\\', "/")
             .to_string(),
@@ -263,32 +308,64 @@ where
     ))
 }
 
-pub(crate) fn parse(path: PathBuf) -> Result<KArchive, KArchiveError> {
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let buffer = benchmark(&path, options)?;
+    parse_from(path, buffer, options)
+}
+
+/// Parses from `buffer` when there is one, otherwise straight from the file at `path`.
+pub(crate) fn parse_from(
+    path: PathBuf,
+    buffer: Option<Vec<u8>>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
     // since we are parsing the buffer if it exists, an argument can be made that we should decrypt the contents
     // of the buffer since we would do it in chunks to save memory. is it worth it to actually do so
     // when we mostly aren't going to be seeking anyways?
-    let buffer = benchmark(&path)?;
     let mut file = match &buffer {
         Some(buf) => BufReader::new(InternalFile::Buffer(Cursor::new(buf))),
-        None => BufReader::new(InternalFile::RealFile(File::open(&path)?)),
+        None => BufReader::new(InternalFile::open(&path)?),
     };
     let mut files: HashMap<PathBuf, KFileInfo> = HashMap::new();
     let mut magic = [0_u8; 8];
     file.read_exact(&mut magic)?;
     if &magic != b"MASMAR0\0" {
-        Err(KArchiveError::ParseError(format!(
-            "magic number is wrong: {:?}",
-            magic
-        )))?
+        Err(KArchiveError::InvalidHeader {
+            format: "mar",
+            reason: format!("magic is {:?}, expected MASMAR0", magic),
+        })?
     }
+    let end = archive_len(&buffer, &path)?;
+    // entry that got cut off when the archive is truncated. nothing comes after it
+    let mut truncated: Option<PathBuf> = None;
+    // records seen so far, to point at the bad one in errors
+    let mut index = 0;
+    // why the entries stopped early, if they did
+    let mut stopped = None;
     // Number of files is not known until you read...
     loop {
         let mut parse_result = || -> Result<(), KArchiveError> {
+            index += 1;
+            let index = index - 1;
             match file.read_u8()? {
                 1 => {
-                    let (sanitized_name, real_name) = read_file_name(&mut file)?;
+                    let (sanitized_name, real_name) =
+                        read_file_name(&mut file, options.name_encoding)
+                            .map_err(|e| name_error("mar", index, e))?;
+                    check_entry_name("mar", index, &sanitized_name)?;
                     let size = file.read_u32::<LittleEndian>()? as u64;
                     let offset = file.stream_position()?;
+                    let available = payload_available(
+                        "mar",
+                        index,
+                        &sanitized_name,
+                        (offset, size),
+                        end,
+                        options,
+                    )?;
+                    if available.is_some() {
+                        truncated = Some(sanitized_name.clone().into());
+                    }
                     file.seek_relative(size as i64)?;
                     let crypted = path.file_name().unwrap().to_str().unwrap().contains("M32");
                     if !crypted {
@@ -297,36 +374,41 @@ pub(crate) fn parse(path: PathBuf) -> Result<KArchive, KArchiveError> {
                             KFileInfo {
                                 size,
                                 offset,
-                                cipher: None,
+                                available,
+                                ..Default::default()
                             },
                         );
-                        Ok(())
                     } else {
-                        // derive the key and IV for the cipher here.
-                        let mut crc32 = CRCu32::crc32();
-                        crc32.digest(&real_name);
-                        let iv = crc32.get_crc();
-                        let mut crc_x25 = CRCu16::crc16_x25();
-                        crc_x25.digest(&real_name);
-                        let key = crc_x25.get_crc() as u32 * 3;
                         files.insert(
                             sanitized_name.into(),
                             KFileInfo {
                                 size,
                                 offset,
-                                cipher: Some(MarCipher::new(key, iv, size)),
+                                cipher: Some(Cipher::Mar(derive_cipher(&real_name, size))),
+                                available,
+                                ..Default::default()
                             },
                         );
-                        Ok(())
                     }
+                    if available.is_some() {
+                        // there's nothing left to read after a truncated entry
+                        return Err(KArchiveError::Other("Finished parsing"));
+                    }
+                    Ok(())
                 }
                 2 => {
                     // This is for directories. we read the filename but do nothing with it...
-                    read_file_name(&mut file)?;
+                    read_file_name(&mut file, options.name_encoding)
+                        .map_err(|e| name_error("mar", index, e))?;
                     Ok(())
                 }
                 0xFF => Err(KArchiveError::Other("Finished parsing")),
-                _ => unreachable!("Invalid mar"),
+                kind => Err(invalid_entry(
+                    "mar",
+                    index,
+                    "<unread>",
+                    format!("unknown record type {:#x}", kind),
+                )),
             }
         };
         match parse_result() {
@@ -335,11 +417,214 @@ pub(crate) fn parse(path: PathBuf) -> Result<KArchive, KArchiveError> {
             Err(e) => {
                 eprintln!("k_archives: Error in archive parsing: {}", e);
                 eprintln!("k_archives: Continuing with {} files parsed", files.len());
+                stopped = Some((files.len(), e.to_string()));
                 break;
             }
         }
     }
-    Ok(KArchive::new(path, files, buffer))
+    let mut archive = KArchive::new(path.clone(), files, buffer);
+    if let Some((parsed, reason)) = stopped {
+        archive.add_warning(KArchiveWarning::ParseStopped {
+            path: path.clone(),
+            parsed,
+            reason,
+        });
+    }
+    if let Some(entry) = truncated {
+        archive.add_warning(KArchiveWarning::Truncated { path, entry });
+    }
+    Ok(archive)
+}
+
+/// Builds a MASMAR0 archive. Encryption mirrors what the parser expects from gitadora
+/// updates, so an encrypted archive needs "M32" in its file name to be read back.
+#[derive(Default)]
+pub struct MarWriter<'a> {
+    files: BTreeMap<PathBuf, EntrySource<'a>>,
+    encrypt: bool,
+}
+
+impl<'a> MarWriter<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file to the archive from an in memory buffer.
+    pub fn add_file(&mut self, name: impl Into<PathBuf>, data: Vec<u8>) -> &mut Self {
+        self.files.insert(name.into(), EntrySource::Buffer(data));
+        self
+    }
+
+    /// Adds a file that is read from `source` when the archive gets written.
+    pub fn add_path(&mut self, name: impl Into<PathBuf>, source: impl Into<PathBuf>) -> &mut Self {
+        self.files
+            .insert(name.into(), EntrySource::Path(source.into()));
+        self
+    }
+
+    /// Recursively adds every file under `dir`, named relative to `dir`.
+    pub fn add_dir(&mut self, dir: &Path) -> Result<&mut Self, KArchiveError> {
+        for (name, path) in walk_dir(dir)? {
+            self.add_path(name, path);
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn add_source(&mut self, name: PathBuf, source: EntrySource<'a>) -> &mut Self {
+        self.files.insert(name, source);
+        self
+    }
+
+    pub(crate) fn sources(&self) -> &BTreeMap<PathBuf, EntrySource<'a>> {
+        &self.files
+    }
+
+    /// Whether file contents should be encrypted with the gitadora (M32) cipher. Defaults to false.
+    pub fn set_encryption(&mut self, encrypt: bool) -> &mut Self {
+        self.encrypt = encrypt;
+        self
+    }
+
+    fn write_entry<W: Write>(
+        &self,
+        wtr: &mut W,
+        name: &str,
+        source: &EntrySource,
+    ) -> Result<(), KArchiveError> {
+        let size = source.size()?;
+        let size_u32 = u32::try_from(size).map_err(|_| {
+            KArchiveError::WriteError(format!("{} is too large for a mar archive", name))
+        })?;
+        wtr.write_u8(1)?;
+        wtr.write_all(name.as_bytes())?;
+        wtr.write_u8(0)?;
+        wtr.write_u32::<LittleEndian>(size_u32)?;
+        if self.encrypt {
+            let mut cipher = derive_cipher(name.as_bytes(), size);
+            let mut rdr = source.reader()?;
+            let mut buf = vec![0_u8; 0x10000];
+            loop {
+                let read = rdr.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+                cipher.crypt(&mut buf[..read]);
+                wtr.write_all(&buf[..read])?;
+            }
+        } else {
+            source.copy_to(wtr)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the archive out to `writer`, returning the writer when done.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<W, KArchiveError> {
+        writer.write_all(b"MASMAR0\0")?;
+        self.write_records(&mut writer, BTreeSet::new())?;
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    /// Adds the entries to the existing archive at `path`. They're written over its end
+    /// marker, so none of the payload already in it gets read or rewritten. Names the archive
+    /// already has are refused, since only one of them could ever be read back.
+    pub fn append(&self, path: &Path) -> Result<(), KArchiveError> {
+        let crypted = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .contains("M32");
+        if crypted != self.encrypt {
+            return Err(KArchiveError::WriteError(format!(
+                "{} is {}encrypted going by its name, so the appended entries have to be too",
+                path.display(),
+                if crypted { "" } else { "not " }
+            )));
+        }
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let (end, names, dirs) = scan_records(&mut file)?;
+        if let Some(name) = self.files.keys().find(|name| names.contains(*name)) {
+            return Err(KArchiveError::WriteError(format!(
+                "{} is already in {}",
+                name.display(),
+                path.display()
+            )));
+        }
+        file.seek(SeekFrom::Start(end))?;
+        let mut writer = BufWriter::new(file);
+        self.write_records(&mut writer, dirs)?;
+        let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+        // anything that came after the old end marker isn't part of the archive
+        let len = file.stream_position()?;
+        file.set_len(len)?;
+        Ok(())
+    }
+
+    // writes the entries and the end marker, skipping directory records for `written_dirs`
+    fn write_records<W: Write>(
+        &self,
+        writer: &mut W,
+        mut written_dirs: BTreeSet<PathBuf>,
+    ) -> Result<(), KArchiveError> {
+        for (path, source) in &self.files {
+            // directory records aren't needed by the parser, but official archives have them
+            let mut dir = PathBuf::new();
+            if let Some(parent) = path.parent() {
+                for component in parent.iter() {
+                    dir.push(component);
+                    if written_dirs.insert(dir.clone()) {
+                        writer.write_u8(2)?;
+                        writer.write_all(archive_name(&dir).as_bytes())?;
+                        writer.write_u8(0)?;
+                    }
+                }
+            }
+            self.write_entry(writer, &archive_name(path), source)?;
+        }
+        writer.write_u8(0xFF)?;
+        Ok(())
+    }
+}
+
+// walks the records of an existing archive for appending to it. gives where the end marker
+// is, along with the file and directory names already in it
+fn scan_records(
+    file: &mut File,
+) -> Result<(u64, BTreeSet<PathBuf>, BTreeSet<PathBuf>), KArchiveError> {
+    let mut rdr = BufReader::new(file);
+    let mut magic = [0_u8; 8];
+    rdr.read_exact(&mut magic)?;
+    if &magic != b"MASMAR0\0" {
+        return Err(KArchiveError::InvalidHeader {
+            format: "mar",
+            reason: format!("magic is {:?}, expected MASMAR0", magic),
+        });
+    }
+    let (mut names, mut dirs) = (BTreeSet::new(), BTreeSet::new());
+    loop {
+        let kind = rdr.read_u8().map_err(|_| {
+            KArchiveError::WriteError(
+                "archive has no end marker (incomplete?), refusing to append to it".to_string(),
+            )
+        })?;
+        match kind {
+            1 => {
+                names.insert(read_file_name(&mut rdr, NameEncoding::Utf8)?.0.into());
+                let size = rdr.read_u32::<LittleEndian>()?;
+                rdr.seek_relative(size as i64)?;
+            }
+            2 => {
+                dirs.insert(read_file_name(&mut rdr, NameEncoding::Utf8)?.0.into());
+            }
+            0xFF => return Ok((rdr.stream_position()? - 1, names, dirs)),
+            kind => {
+                return Err(KArchiveError::InvalidHeader {
+                    format: "mar",
+                    reason: format!("unknown record type {:#x}", kind),
+                })
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -357,11 +642,139 @@ mod tests {
         ]);
         let mut filename = BufReader::new(cursor);
         assert_eq!(
-            read_file_name(&mut filename).unwrap().0,
+            read_file_name(&mut filename, NameEncoding::Utf8).unwrap().0,
             "dev/raw/newdata/FileList.dat"
         )
     }
+    fn mar_round_trip(file_name: &str, encrypt: bool) {
+        let data: Vec<u8> = (0..0x2003).map(|i| (i % 251) as u8).collect();
+        let mut writer = MarWriter::new();
+        writer
+            .set_encryption(encrypt)
+            .add_file("dev/raw/newdata/FileList.dat", b"filelist".to_vec())
+            .add_file("dev/raw/data/song.bin", data.clone());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(file_name);
+        writer.write(File::create(&path).unwrap()).unwrap();
+
+        let archive = crate::mount(path).unwrap();
+        assert_eq!(archive.list_files().len(), 2);
+        let meta = archive
+            .metadata(Path::new("dev/raw/data/song.bin"))
+            .unwrap();
+        assert_eq!(meta.encrypted, encrypt);
+        assert_eq!(
+            archive
+                .read(Path::new("dev/raw/newdata/FileList.dat"))
+                .unwrap(),
+            b"filelist"
+        );
+        assert_eq!(
+            archive.read(Path::new("dev/raw/data/song.bin")).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn mar_write_plain() {
+        mar_round_trip("update.mar", false);
+    }
+
+    #[test]
+    fn mar_write_encrypted() {
+        mar_round_trip("update_M32.mar", true);
+    }
+
+    #[test]
+    fn mar_decrypt_loose_payload() {
+        let data: Vec<u8> = (0..0x1003_u32).map(|i| (i * 7) as u8).collect();
+        let mut writer = MarWriter::new();
+        writer
+            .set_encryption(true)
+            .add_file("data/song.bin", data.clone());
+        // without M32 in its name the payload is read back as stored, still encrypted
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.mar");
+        writer.write(File::create(&path).unwrap()).unwrap();
+        let archive = crate::mount(path).unwrap();
+        let payload = archive.read(Path::new("data/song.bin")).unwrap();
+        assert_ne!(payload, data);
+
+        let mut decrypted = Vec::new();
+        decrypt_mar_payload(
+            "data/song.bin",
+            data.len() as u64,
+            &payload[..],
+            &mut decrypted,
+        )
+        .unwrap();
+        assert_eq!(decrypted, data);
+        // the wrong name gives the wrong key
+        let mut wrong = Vec::new();
+        decrypt_mar_payload(
+            "data/other.bin",
+            data.len() as u64,
+            &payload[..],
+            &mut wrong,
+        )
+        .unwrap();
+        assert_ne!(wrong, data);
+    }
+
+    #[test]
+    fn mar_parse_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.mar");
+        let mar = MarWriter::new()
+            .add_file("data/a.bin", vec![1; 0x10])
+            .add_file("data/b.bin", vec![2; 0x10])
+            .write(Vec::new())
+            .unwrap();
+        std::fs::write(&path, &mar[..mar.len() - 8]).unwrap();
+        let archive = crate::mount(path).unwrap();
+        assert_eq!(archive.list_files(), vec![PathBuf::from("data/a.bin")]);
+        assert!(matches!(
+            archive.warnings(),
+            [KArchiveWarning::ParseStopped { parsed: 1, .. }]
+        ));
+    }
+
+    #[test]
+    fn mar_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update_M32.mar");
+        let mut writer = MarWriter::new();
+        writer
+            .set_encryption(true)
+            .add_file("data/a.bin", vec![1; 0x101]);
+        writer.write(File::create(&path).unwrap()).unwrap();
+        let mut more = MarWriter::new();
+        more.set_encryption(true)
+            .add_file("data/b.bin", vec![2; 0x33])
+            .add_file("other/c.bin", vec![3; 7]);
+        more.append(&path).unwrap();
+
+        let archive = crate::mount(path.clone()).unwrap();
+        assert_eq!(archive.list_files().len(), 3);
+        assert_eq!(
+            archive.read(Path::new("data/a.bin")).unwrap(),
+            vec![1; 0x101]
+        );
+        assert_eq!(
+            archive.read(Path::new("data/b.bin")).unwrap(),
+            vec![2; 0x33]
+        );
+        assert_eq!(archive.read(Path::new("other/c.bin")).unwrap(), vec![3; 7]);
+        // appending the same names again would shadow them
+        assert!(matches!(
+            more.append(&path),
+            Err(KArchiveError::WriteError(_))
+        ));
+        assert!(more.set_encryption(false).append(&path).is_err());
+    }
+
     // reference implementation to verify our chunked version against...
+    #[allow(clippy::manual_rotate)] // kept close to the original algorithm on purpose
     fn reference_crypt(key: u32, iv: u32, data: &mut [u8]) {
         let mut idx = 0;
         let mut j = 0;
@@ -461,6 +874,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::manual_rotate)]
     fn test_keystream() {
         let mut rng = rand::thread_rng();
         let key: u32 = rng.gen();
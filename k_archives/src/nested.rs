@@ -0,0 +1,258 @@
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use crate::common::*;
+
+// archives inside archives inside archives... is plenty. this mostly guards against loops
+// of garbage that happen to look like archives
+pub(crate) const MAX_DEPTH: usize = 4;
+
+// enough to see a bar's first record magic, which sits after a 256 byte name
+pub(crate) const PEEK_SIZE: u64 = 12 + 256 + 8;
+
+type NestedParser = fn(PathBuf, Vec<u8>, &MountOptions) -> Result<KArchive, KArchiveError>;
+
+fn parse_qar(
+    path: PathBuf,
+    data: Vec<u8>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    crate::qar::parse_from(path, Some(data), options)
+}
+
+fn parse_mar(
+    path: PathBuf,
+    data: Vec<u8>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    crate::mar::parse_from(path, Some(data), options)
+}
+
+fn parse_cab(
+    path: PathBuf,
+    data: Vec<u8>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    crate::cab::parse_from(path, Cursor::new(data), options)
+}
+
+fn parse_ifs(
+    path: PathBuf,
+    data: Vec<u8>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    crate::ifs::parse_from(path, Some(data), options)
+}
+
+fn parse_arc(
+    path: PathBuf,
+    data: Vec<u8>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    crate::arc::parse_from(path, Some(data), options)
+}
+
+fn parse_s3p(
+    path: PathBuf,
+    data: Vec<u8>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    crate::s3p::parse_from(path, Some(data), options)
+}
+
+#[cfg(feature = "tar")]
+fn parse_tar(
+    path: PathBuf,
+    data: Vec<u8>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    crate::tarfile::parse_from(path, Some(data), options)
+}
+
+fn parse_2dx(
+    path: PathBuf,
+    data: Vec<u8>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    crate::twodx::parse_from(path, Some(data), options)
+}
+
+fn parse_d2(
+    path: PathBuf,
+    data: Vec<u8>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    crate::d2::parse_from(path, Some(data), options)
+}
+
+fn parse_bar(
+    path: PathBuf,
+    data: Vec<u8>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    crate::bar::parse_from(path, Some(data), options)
+}
+
+// picks a parser for an entry from its first few bytes. bar, 2dx and d2 have no magic numbers, so
+// 2dx goes by extension, d2 by extension and its header making sense, and bar by the magic numbers of its first record.
+// multi part descriptors (ULST, NAME) are skipped since their parts live on disk
+pub(crate) fn nested_parser(name: &Path, header: &[u8], size: u64) -> Option<NestedParser> {
+    match header.get(..4)? {
+        b"QAR\0" => Some(parse_qar),
+        b"MASM" => Some(parse_mar),
+        b"MSCF" => Some(parse_cab),
+        magic if magic == crate::ifs::SIGNATURE => Some(parse_ifs),
+        magic if magic == crate::s3p::MAGIC => Some(parse_s3p),
+        magic if magic == crate::arc::MAGIC => Some(parse_arc),
+        #[cfg(feature = "tar")]
+        _ if crate::tarfile::looks_like_tar(header) => Some(parse_tar),
+        _ if name.extension().is_some_and(|ext| ext == "2dx") => Some(parse_2dx),
+        _ if name
+            .extension()
+            .is_some_and(|ext| ext == "d2" || ext == "dat")
+            && crate::d2::looks_like_d2(header, size) =>
+        {
+            Some(parse_d2)
+        }
+        _ if crate::bar::looks_like_bar(header) => Some(parse_bar),
+        _ => None,
+    }
+}
+
+/// Mounts every entry of `archive` that is an archive itself, listing its contents under
+/// the entry's path. Recurses into whatever those contain, up to `depth` levels.
+pub(crate) fn expand(
+    archive: &mut KArchive,
+    options: &MountOptions,
+    depth: usize,
+) -> Result<(), KArchiveError> {
+    if depth == 0 {
+        return Ok(());
+    }
+    for name in archive.list_files() {
+        if !archive.is_complete(&name) {
+            continue;
+        }
+        let mut header = Vec::new();
+        let file = archive.open(&name)?;
+        let size = file.size();
+        file.take(PEEK_SIZE).read_to_end(&mut header)?;
+        let Some(parser) = nested_parser(&name, &header, size) else {
+            continue;
+        };
+        let data = archive.read(&name)?;
+        // the parsers only use the path to name the archive, and for the mar M32 check
+        let mut inner = match parser(name.clone(), data, options) {
+            Ok(inner) => inner,
+            Err(e) => {
+                archive.add_warning(KArchiveWarning::NestedMountFailed {
+                    entry: name,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        expand(&mut inner, options, depth - 1)?;
+        inner.prefix_paths(&name);
+        archive.add_archive(&mut inner);
+        archive.add_nested(name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mount_bytes, mount_with_options, CabWriter, MarWriter};
+
+    fn bar_with(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut bar = vec![0; 10];
+        bar.extend_from_slice(&1_u16.to_le_bytes());
+        let mut record = name.as_bytes().to_vec();
+        record.resize(256, 0);
+        bar.extend(record);
+        bar.extend_from_slice(&3_i32.to_le_bytes());
+        bar.extend_from_slice(&(-1_i32).to_le_bytes());
+        bar.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bar.extend_from_slice(&[0; 4]);
+        bar.extend_from_slice(data);
+        bar
+    }
+
+    #[test]
+    fn nested_mount() {
+        let mut mar = MarWriter::new();
+        mar.add_file("inner.bar", bar_with("\\data\\deep.bin", &[7; 0x10]))
+            .add_file("plain.bin", vec![1; 0x10])
+            .add_file("broken.qar", b"QAR\0\xff\xff\xff\xff".to_vec());
+        let mar = mar.write(Vec::new()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outer.cab");
+        CabWriter::new()
+            .add_file("data/inner.mar", mar)
+            .write(std::fs::File::create(&path).unwrap())
+            .unwrap();
+
+        let plain = mount_with_options(path.clone(), &MountOptions::default()).unwrap();
+        // the mar and the cab's filelist
+        assert_eq!(plain.list_files().len(), 2);
+
+        let options = MountOptions {
+            mount_nested: true,
+            ..Default::default()
+        };
+        let archive = mount_with_options(path, &options).unwrap();
+        let deep = Path::new("data/inner.mar/inner.bar/data/deep.bin");
+        assert_eq!(archive.read(deep).unwrap(), vec![7; 0x10]);
+        assert!(archive.exists(Path::new("data/inner.mar/plain.bin")));
+        // left as it is, with a warning saying why
+        assert!(archive.exists(Path::new("data/inner.mar/broken.qar")));
+        assert!(archive.warnings().iter().any(|warning| matches!(
+            warning,
+            KArchiveWarning::NestedMountFailed { entry, .. }
+                if entry == Path::new("data/inner.mar/broken.qar")
+        )));
+        let mut nested = archive.nested_archives().to_vec();
+        nested.sort();
+        assert_eq!(
+            nested,
+            [
+                PathBuf::from("data/inner.mar"),
+                PathBuf::from("data/inner.mar/inner.bar")
+            ]
+        );
+    }
+
+    #[test]
+    fn bytes_mount() {
+        let mut mar = MarWriter::new();
+        mar.set_encryption(true)
+            .add_file("inner.bar", bar_with("\\data\\deep.bin", &[7; 0x10]))
+            .add_file("plain.bin", vec![1; 0x10]);
+        let mar = mar.write(Vec::new()).unwrap();
+        let options = MountOptions {
+            mount_nested: true,
+            ..Default::default()
+        };
+        // the name is what marks it as encrypted
+        let archive = mount_bytes("update_M32.mar".into(), mar.clone(), &options).unwrap();
+        assert_eq!(archive.read(Path::new("plain.bin")).unwrap(), vec![1; 0x10]);
+        assert_eq!(
+            archive.read(Path::new("inner.bar/data/deep.bin")).unwrap(),
+            vec![7; 0x10]
+        );
+        let archive = mount_bytes("update.mar".into(), mar, &MountOptions::default()).unwrap();
+        assert_ne!(archive.read(Path::new("plain.bin")).unwrap(), vec![1; 0x10]);
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut gz, &bar_with("a.bin", b"abc")).unwrap();
+        let gz = gz.finish().unwrap();
+        let archive = mount_bytes("data.bar.gz".into(), gz, &MountOptions::default()).unwrap();
+        assert_eq!(archive.read(Path::new("a.bin")).unwrap(), b"abc");
+
+        for data in [b"ULST".to_vec(), b"not an archive".to_vec(), Vec::new()] {
+            assert!(mount_bytes("x".into(), data, &MountOptions::default()).is_err());
+        }
+    }
+}
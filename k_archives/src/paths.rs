@@ -0,0 +1,168 @@
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf};
+
+use crate::common::*;
+
+// device names windows won't create files as, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+// paths longer than this need the \\?\ prefix on windows
+#[cfg_attr(not(windows), allow(dead_code))]
+const MAX_PATH: usize = 260;
+
+fn windows_safe_name(name: &str) -> Cow<'_, str> {
+    let invalid = |c: char| c.is_control() || r#"<>:"|?*\"#.contains(c);
+    let mut safe: String = name
+        .chars()
+        .map(|c| if invalid(c) { '_' } else { c })
+        .collect();
+    // explorer can't deal with names ending in either
+    while safe.ends_with(['.', ' ']) {
+        safe.pop();
+    }
+    let stem_len = safe.find('.').unwrap_or(safe.len());
+    if RESERVED_NAMES.contains(&safe[..stem_len].to_ascii_lowercase().as_str()) {
+        safe.insert(stem_len, '_');
+    }
+    if safe.is_empty() {
+        safe.push('_');
+    }
+    match safe == name {
+        true => Cow::Borrowed(name),
+        false => Cow::Owned(safe),
+    }
+}
+
+/// A version of the relative `path` Windows can create: reserved device names (`con`,
+/// `aux`, `com1`, ...) get an underscore after them, characters it doesn't allow
+/// (`<>:"|?*\` and control characters) become underscores and trailing dots and spaces
+/// are dropped. None if it's fine as it is.
+pub fn windows_safe_path(path: &Path) -> Option<PathBuf> {
+    let mut changed = false;
+    let safe = path
+        .iter()
+        .map(|part| {
+            let part = part.to_string_lossy();
+            let safe = windows_safe_name(&part).into_owned();
+            changed |= safe != part;
+            safe
+        })
+        .collect();
+    changed.then_some(safe)
+}
+
+/// Joins the entry name `entry` onto `root`, which is how every output path should be made
+/// when extracting. Absolute names are taken as relative to `root` (like tar does), while
+/// names with `..` in them and ones a symlink already under `root` would lead outside of
+/// it are refused.
+pub fn join_under(root: &Path, entry: &Path) -> Result<PathBuf, KArchiveError> {
+    let refuse = |reason| KArchiveError::UnsafePath {
+        path: entry.to_path_buf(),
+        root: root.to_path_buf(),
+        reason,
+    };
+    let mut joined = root.to_path_buf();
+    for component in entry.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => return Err(refuse("it has .. in it")),
+            // backslashes are separators on windows, where the tree might end up
+            Component::Normal(part) if part.to_string_lossy().split('\\').any(|p| p == "..") => {
+                return Err(refuse("it has .. in it"))
+            }
+            Component::Normal(part) => joined.push(part),
+        }
+    }
+    if joined == root {
+        return Err(refuse("it doesn't name a file"));
+    }
+    // nothing can be in the way if the root doesn't exist yet
+    let Ok(canonical_root) = root.canonicalize() else {
+        return Ok(joined);
+    };
+    let existing = joined
+        .ancestors()
+        .find(|path| path.symlink_metadata().is_ok())
+        .map(Path::canonicalize);
+    match existing {
+        Some(Ok(existing)) if existing.starts_with(&canonical_root) => Ok(joined),
+        _ => Err(refuse("a symlink leads outside of it")),
+    }
+}
+
+/// `path` in a form that can be longer than 260 characters on Windows (absolute, with the
+/// `\\?\` prefix). Anywhere else, or when it's short enough, it's returned as is.
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    #[cfg(windows)]
+    if path.as_os_str().len() >= MAX_PATH && !path.as_os_str().to_string_lossy().starts_with(r"\\")
+    {
+        if let Ok(absolute) = std::path::absolute(path) {
+            let mut long = std::ffi::OsString::from(r"\\?\");
+            long.push(absolute);
+            return Cow::Owned(long.into());
+        }
+    }
+    Cow::Borrowed(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_names() {
+        assert_eq!(windows_safe_path(Path::new("data/sound/a.2dx")), None);
+        assert_eq!(
+            windows_safe_path(Path::new("data/con/aux.txt")),
+            Some(PathBuf::from("data/con_/aux_.txt"))
+        );
+        assert_eq!(
+            windows_safe_path(Path::new("data/what?.bin")),
+            Some(PathBuf::from("data/what_.bin"))
+        );
+        assert_eq!(
+            windows_safe_path(Path::new("trailing. /COM1")),
+            Some(PathBuf::from("trailing/COM1_"))
+        );
+        // only the exact device names are reserved
+        assert_eq!(windows_safe_path(Path::new("console/com10.bin")), None);
+        assert_eq!(long_path(Path::new("short")), Path::new("short"));
+    }
+
+    #[test]
+    fn join_stays_under_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("out");
+        std::fs::create_dir(&root).unwrap();
+        assert_eq!(
+            join_under(&root, Path::new("data/a.bin")).unwrap(),
+            root.join("data/a.bin")
+        );
+        assert_eq!(
+            join_under(&root, Path::new("/etc/passwd")).unwrap(),
+            root.join("etc/passwd")
+        );
+        for name in [
+            "../escape.bin",
+            "data/../../escape.bin",
+            "..\\escape.bin",
+            "/",
+        ] {
+            assert!(
+                matches!(
+                    join_under(&root, Path::new(name)),
+                    Err(KArchiveError::UnsafePath { .. })
+                ),
+                "{}",
+                name
+            );
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path(), root.join("link")).unwrap();
+            assert!(join_under(&root, Path::new("link/escape.bin")).is_err());
+        }
+    }
+}
@@ -1,71 +1,310 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Cursor, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
+use crate::cipher::Cipher;
 use crate::common::*;
+use crate::salvage::{carve, plausible_name};
 
-fn read_file_name<T>(rdr: &mut T) -> Result<String, KArchiveError>
-where
-    T: BufRead + Seek,
-{
-    let mut buf = Vec::<u8>::new();
-    let size = rdr.read_until(0, &mut buf)?;
-    rdr.seek(SeekFrom::Current(132 - size as i64))?;
-    Ok(String::from_utf8(
-        buf.strip_suffix(&[0])
-            .ok_or(KArchiveError::Other(
-                "Failed to strip suffix (malformed or incomplete archive)",
-            ))?
-            .to_vec(),
-    )?
-    .trim_start_matches(['.', '\\'])
-    .replace('\\', "/")
-    .to_string())
+// name fields are fixed size, null terminated and zero padded
+fn parse_file_name(field: &[u8], encoding: NameEncoding) -> Result<String, KArchiveError> {
+    let len = field
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(KArchiveError::Other(
+            "Failed to strip suffix (malformed or incomplete archive)",
+        ))?;
+    Ok(encoding
+        .decode(&field[..len])?
+        .trim_start_matches(['.', '\\'])
+        .replace('\\', "/"))
 }
 
-pub(crate) fn parse(path: PathBuf) -> Result<KArchive, KArchiveError> {
-    let buffer = benchmark(&path)?;
+// 132 byte name, an unknown field, size and another unknown field
+const HEADER_SIZE: usize = 144;
+
+// recognises an entry record when salvaging. there's no magic number in qar records,
+// but name padding is always zeroed which is distinctive enough
+fn carve_record(header: &[u8]) -> Option<(String, u64, u64)> {
+    let (name, padding) = plausible_name(&header[..132])?;
+    if padding.iter().any(|&b| b != 0) {
+        return None;
+    }
+    let size = u32::from_le_bytes(header[136..140].try_into().ok()?);
+    let name = name.trim_start_matches(['.', '\\']).replace('\\', "/");
+    Some((name, HEADER_SIZE as u64, size as u64))
+}
+
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let buffer = benchmark(&path, options)?;
+    parse_from(path, buffer, options)
+}
+
+/// Parses from `buffer` when there is one, otherwise straight from the file at `path`.
+pub(crate) fn parse_from(
+    path: PathBuf,
+    buffer: Option<Vec<u8>>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    // headers are read whole and payloads skipped, so there's nothing for a BufReader to do
     let mut file = match &buffer {
-        Some(buf) => BufReader::new(InternalFile::Buffer(Cursor::new(buf))),
-        None => BufReader::new(InternalFile::RealFile(File::open(&path)?)),
+        Some(buf) => InternalFile::Buffer(Cursor::new(buf)),
+        None => InternalFile::open(&path)?,
     };
     let mut files: HashMap<PathBuf, KFileInfo> = HashMap::new();
     // we already validated the magic so just skip it...
-    file.seek_relative(4)?;
+    file.seek(SeekFrom::Start(4))?;
     let file_count = file.read_u32::<LittleEndian>()?;
-    let parse_result: Result<(), KArchiveError> = (0..file_count).try_for_each(|_| {
-        let name = read_file_name(&mut file)?;
-        file.seek_relative(4)?;
-        let size = file.read_u32::<LittleEndian>()? as u64;
-        file.seek_relative(4)?;
-        let offset = file.stream_position()?;
-        file.seek_relative(size as i64)?;
+    let end = archive_len(&buffer, &path)?;
+    check_entry_count(
+        "qar",
+        file_count as u64,
+        HEADER_SIZE as u64,
+        8,
+        end,
+        options,
+    )?;
+    // entry that got cut off when the archive is truncated. nothing comes after it
+    let mut truncated: Option<PathBuf> = None;
+    // start of the entry being parsed, which is where salvaging starts from if it's bad
+    let mut resume_pos = 8;
+    let mut header = [0_u8; HEADER_SIZE];
+    let parse_result: Result<(), KArchiveError> = (0..file_count as usize).try_for_each(|index| {
+        if truncated.is_some() {
+            return Ok(());
+        }
+        // one read per record, then the fields are picked out of it
+        file.seek(SeekFrom::Start(resume_pos))?;
+        file.read_exact(&mut header)?;
+        let name = parse_file_name(&header[..132], options.name_encoding)
+            .map_err(|e| name_error("qar", index, e))?;
+        check_entry_name("qar", index, &name)?;
+        let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let size = field(136) as u64;
+        // the fields either side of the size. possibly timestamps or flags, nobody knows yet
+        let raw_fields = vec![field(132), field(140)];
+        let offset = resume_pos + HEADER_SIZE as u64;
+        let available = payload_available("qar", index, &name, (offset, size), end, options)?;
+        if available.is_some() {
+            truncated = Some(name.clone().into());
+        }
+        resume_pos = offset + size;
+        // some titles encrypt their qars, which only the key provider can tell
+        let cipher = options
+            .key_provider
+            .as_ref()
+            .and_then(|keys| keys.cipher(&path, &name))
+            .map(Cipher::Custom);
         files.insert(
             name.into(),
             KFileInfo {
                 size,
                 offset,
-                cipher: None,
+                cipher,
+                available,
+                raw_fields,
+                ..Default::default()
             },
         );
         Ok(())
     });
+    let mut recovered = None;
+    let mut stopped = None;
+    let mut skipped = Vec::new();
     match parse_result {
         Ok(_) => {}
         Err(e) => {
             eprintln!("k_archives: Error in archive parsing: {}", e);
+            if options.salvage {
+                let carved = carve(&mut file, resume_pos, end, HEADER_SIZE, carve_record)?;
+                let mut kept = 0;
+                for (index, entry) in carved.into_iter().enumerate() {
+                    // the same check the entry table gets, so salvaging can't get around it
+                    if let Err(e) = check_entry_name("qar", index, &entry.name) {
+                        skipped.push(e.to_string());
+                        continue;
+                    }
+                    kept += 1;
+                    files.entry(entry.name.into()).or_insert(KFileInfo {
+                        size: entry.size,
+                        offset: entry.offset,
+                        ..Default::default()
+                    });
+                }
+                recovered = Some(kept);
+            } else {
+                // salvaging reports the damage itself
+                stopped = Some((files.len(), e.to_string()));
+            }
             eprintln!("k_archives: Continuing with {} files parsed", files.len());
         }
     }
-    Ok(KArchive::new(path, files, buffer))
+    let mut archive = KArchive::new(path.clone(), files, buffer);
+    for reason in skipped {
+        archive.add_warning(KArchiveWarning::SkippedEntry {
+            path: path.clone(),
+            reason,
+        });
+    }
+    if let Some((parsed, reason)) = stopped {
+        archive.add_warning(KArchiveWarning::ParseStopped {
+            path: path.clone(),
+            parsed,
+            reason,
+        });
+    }
+    if let Some(entry) = truncated {
+        archive.add_warning(KArchiveWarning::Truncated {
+            path: path.clone(),
+            entry,
+        });
+    }
+    if let Some(recovered) = recovered {
+        archive.add_warning(KArchiveWarning::Salvaged { path, recovered });
+    }
+    Ok(archive)
+}
+
+/// Builds a QAR archive. Nobody knows what the two fields either side of each entry's
+/// size are, so they're written as zero.
+#[derive(Default)]
+pub struct QarWriter<'a> {
+    files: BTreeMap<PathBuf, EntrySource<'a>>,
+}
+
+impl<'a> QarWriter<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file to the archive from an in memory buffer.
+    pub fn add_file(&mut self, name: impl Into<PathBuf>, data: Vec<u8>) -> &mut Self {
+        self.files.insert(name.into(), EntrySource::Buffer(data));
+        self
+    }
+
+    /// Adds a file that is read from `source` when the archive gets written.
+    pub fn add_path(&mut self, name: impl Into<PathBuf>, source: impl Into<PathBuf>) -> &mut Self {
+        self.files
+            .insert(name.into(), EntrySource::Path(source.into()));
+        self
+    }
+
+    /// Recursively adds every file under `dir`, named relative to `dir`.
+    pub fn add_dir(&mut self, dir: &Path) -> Result<&mut Self, KArchiveError> {
+        for (name, path) in walk_dir(dir)? {
+            self.add_path(name, path);
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn add_source(&mut self, name: PathBuf, source: EntrySource<'a>) -> &mut Self {
+        self.files.insert(name, source);
+        self
+    }
+
+    pub(crate) fn sources(&self) -> &BTreeMap<PathBuf, EntrySource<'a>> {
+        &self.files
+    }
+
+    fn write_entries<W: Write>(&self, writer: &mut W) -> Result<(), KArchiveError> {
+        for (path, source) in &self.files {
+            // official qars name entries like \.\KFC\contents\...
+            let name = format!("\\.\\{}", archive_name(path).replace('/', "\\"));
+            if name.len() >= 132 {
+                return Err(KArchiveError::WriteError(format!(
+                    "{} doesn't fit in a qar name",
+                    name
+                )));
+            }
+            let size = u32::try_from(source.size()?).map_err(|_| {
+                KArchiveError::WriteError(format!("{} is too large for a qar", name))
+            })?;
+            let mut field = name.into_bytes();
+            field.resize(132, 0);
+            writer.write_all(&field)?;
+            writer.write_u32::<LittleEndian>(0)?;
+            writer.write_u32::<LittleEndian>(size)?;
+            writer.write_u32::<LittleEndian>(0)?;
+            source.copy_to(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the archive out to `writer`, returning the writer when done.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<W, KArchiveError> {
+        let count = u32::try_from(self.files.len())
+            .map_err(|_| KArchiveError::WriteError("Too many files for a qar".to_string()))?;
+        writer.write_all(b"QAR\0")?;
+        writer.write_u32::<LittleEndian>(count)?;
+        self.write_entries(&mut writer)?;
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    /// Adds the entries to the end of the existing archive at `path` and bumps its file
+    /// count, without touching the payload already in it. Names the archive already has
+    /// are refused, since only one of them could ever be read back.
+    pub fn append(&self, path: &Path) -> Result<(), KArchiveError> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len();
+        let mut magic = [0_u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != b"QAR\0" {
+            return Err(KArchiveError::InvalidHeader {
+                format: "qar",
+                reason: format!("magic is {:?}, expected QAR", magic),
+            });
+        }
+        let file_count = file.read_u32::<LittleEndian>()?;
+        // skip over the existing entries to find where they end
+        let mut pos = 8;
+        let mut header = [0_u8; HEADER_SIZE];
+        for index in 0..file_count as usize {
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(&mut header)?;
+            // names are only compared with the ones being added, which are utf8
+            let name = parse_file_name(&header[..132], NameEncoding::Utf8)
+                .map_err(|e| name_error("qar", index, e))?;
+            if self.files.contains_key(Path::new(&name)) {
+                return Err(KArchiveError::WriteError(format!(
+                    "{} is already in {}",
+                    name,
+                    path.display()
+                )));
+            }
+            pos += HEADER_SIZE as u64
+                + u32::from_le_bytes(header[136..140].try_into().unwrap()) as u64;
+            if pos > len {
+                return Err(KArchiveError::WriteError(
+                    "archive is incomplete, refusing to append to it".to_string(),
+                ));
+            }
+        }
+        let count = u32::try_from(file_count as usize + self.files.len())
+            .map_err(|_| KArchiveError::WriteError("Too many files for a qar".to_string()))?;
+        file.seek(SeekFrom::Start(pos))?;
+        let mut writer = BufWriter::new(file);
+        self.write_entries(&mut writer)?;
+        let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+        let end = file.stream_position()?;
+        file.set_len(end)?;
+        // the count goes last, so an append that fails part way leaves the archive as it was
+        // (plus some trailing junk the parser won't look at)
+        file.seek(SeekFrom::Start(4))?;
+        file.write_u32::<LittleEndian>(count)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{EntryCipher, KeyProvider};
+    use std::fs::File;
     use std::io::Cursor;
     #[test]
     fn test_filename() {
@@ -77,10 +316,130 @@ mod tests {
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
         ]);
-        let mut filename = BufReader::new(cursor);
         assert_eq!(
-            read_file_name(&mut filename).unwrap(),
+            parse_file_name(cursor.get_ref(), NameEncoding::Utf8).unwrap(),
             "KFC/contents/8/c/a/5682f39af4538f4ad7806c0c97d5371ab49ab"
         )
     }
+
+    fn qar_record(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut record = name.as_bytes().to_vec();
+        record.resize(132, 0);
+        record.extend_from_slice(&0x11_u32.to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&0x22_u32.to_le_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    #[test]
+    fn qar_salvage() {
+        let mut qar = b"QAR\0".to_vec();
+        qar.extend_from_slice(&3_u32.to_le_bytes());
+        qar.extend(qar_record("\\.\\KFC\\a.bin", &[1; 0x20]));
+        // a record with a garbage name followed by a size that runs past the end of the file
+        let mut broken = qar_record("KFC\\b.bin", &[2; 0x20]);
+        broken[0] = 0;
+        broken[136..140].copy_from_slice(&u32::MAX.to_le_bytes());
+        qar.extend(broken);
+        qar.extend(qar_record("\\.\\KFC\\c.bin", &[3; 0x20]));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.qar");
+        std::fs::write(&path, qar).unwrap();
+
+        let salvage = MountOptions {
+            salvage: true,
+            ..Default::default()
+        };
+        let archive = parse(path, &salvage).unwrap();
+        assert_eq!(
+            archive.read(std::path::Path::new("KFC/c.bin")).unwrap(),
+            vec![3; 0x20]
+        );
+        assert!(archive.exists(std::path::Path::new("KFC/a.bin")));
+        let meta = archive.metadata(std::path::Path::new("KFC/a.bin")).unwrap();
+        assert_eq!(meta.raw_fields, vec![0x11, 0x22]);
+        assert_eq!(meta.offset, 8 + HEADER_SIZE as u64);
+    }
+
+    #[test]
+    fn qar_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.qar");
+        let mut writer = QarWriter::new();
+        writer
+            .add_file("KFC/a.bin", vec![1; 0x20])
+            .add_file("KFC/b.bin", vec![2; 0x30]);
+        writer.write(File::create(&path).unwrap()).unwrap();
+        let mut more = QarWriter::new();
+        more.add_file("KFC/c.bin", vec![3; 0x10]);
+        more.append(&path).unwrap();
+
+        let archive = parse(path.clone(), &MountOptions::default()).unwrap();
+        assert_eq!(archive.list_files().len(), 3);
+        assert_eq!(archive.read(Path::new("KFC/a.bin")).unwrap(), vec![1; 0x20]);
+        assert_eq!(archive.read(Path::new("KFC/c.bin")).unwrap(), vec![3; 0x10]);
+        assert!(more.append(&path).is_err());
+    }
+
+    // xors with the low byte of the position and a per-title key byte
+    #[derive(Debug)]
+    struct XorCipher(u8);
+
+    impl EntryCipher for XorCipher {
+        fn decrypt(&self, pos: u64, data: &mut [u8]) {
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte ^= self.0 ^ (pos + i as u64) as u8;
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct TitleKeys;
+
+    impl KeyProvider for TitleKeys {
+        fn cipher(
+            &self,
+            _archive: &std::path::Path,
+            entry: &str,
+        ) -> Option<std::sync::Arc<dyn EntryCipher>> {
+            entry
+                .starts_with("KFC/secret")
+                .then(|| std::sync::Arc::new(XorCipher(0x5A)) as _)
+        }
+    }
+
+    #[test]
+    fn qar_key_provider() {
+        let plain: Vec<u8> = (0..0x40).collect();
+        let mut encrypted = plain.clone();
+        XorCipher(0x5A).decrypt(0, &mut encrypted);
+        let mut qar = b"QAR\0".to_vec();
+        qar.extend_from_slice(&2_u32.to_le_bytes());
+        qar.extend(qar_record("\\.\\KFC\\plain.bin", &plain));
+        qar.extend(qar_record("\\.\\KFC\\secret.bin", &encrypted));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.qar");
+        std::fs::write(&path, qar).unwrap();
+
+        let without_keys = parse(path.clone(), &MountOptions::default()).unwrap();
+        let secret = std::path::Path::new("KFC/secret.bin");
+        assert_eq!(without_keys.read(secret).unwrap(), encrypted);
+
+        let options = MountOptions {
+            key_provider: Some(std::sync::Arc::new(TitleKeys)),
+            ..Default::default()
+        };
+        let archive = parse(path, &options).unwrap();
+        assert_eq!(
+            archive.read(std::path::Path::new("KFC/plain.bin")).unwrap(),
+            plain
+        );
+        assert_eq!(archive.read(secret).unwrap(), plain);
+        let mut file = archive.open(secret).unwrap();
+        let mut buf = [0; 4];
+        file.seek(SeekFrom::Start(0x30)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, plain[0x30..0x34]);
+    }
 }
@@ -0,0 +1,361 @@
+//! Archives read straight off a web server or out of a bucket with range requests, so mounting
+//! only downloads the headers and extracting only downloads the entries that get extracted.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex, OnceLock};
+
+// what gets requested (and cached) at a time. small, since the headers of most formats are
+// spread out between the payloads and scanning them shouldn't drag the payloads along
+const BLOCK_SIZE: u64 = 0x10000;
+// 64MiB worth per url
+const CACHE_BLOCKS: usize = 0x400;
+// reading on from the last request doubles how much the next one asks for, up to 4MiB
+const MAX_READAHEAD: u64 = 0x40;
+
+// where an archive's ranges are asked for
+enum Source {
+    Http,
+    S3(crate::s3::S3Object),
+}
+
+impl Source {
+    fn open(url: &str) -> std::io::Result<Self> {
+        match url.starts_with("s3://") {
+            true => Ok(Source::S3(crate::s3::S3Object::from_url(url)?)),
+            false => Ok(Source::Http),
+        }
+    }
+
+    // asks for bytes `start` to `end` (inclusive), which have to come back as a partial response
+    fn get(
+        &self,
+        agent: &ureq::Agent,
+        url: &str,
+        start: u64,
+        end: u64,
+    ) -> std::io::Result<ureq::Response> {
+        let range = format!("bytes={}-{}", start, end);
+        let request = match self {
+            Source::Http => agent.get(url).set("Range", &range),
+            Source::S3(object) => object.get(agent, &range),
+        };
+        let response = request.call().map_err(|e| http_error(url, e))?;
+        match response.status() {
+            206 => Ok(response),
+            _ => Err(no_ranges(url)),
+        }
+    }
+}
+
+struct Remote {
+    url: String,
+    len: u64,
+    agent: ureq::Agent,
+    source: Source,
+    cache: Mutex<BlockCache>,
+}
+
+#[derive(Default)]
+struct BlockCache {
+    blocks: HashMap<u64, Arc<Vec<u8>>>,
+    // least recently used first
+    order: VecDeque<u64>,
+}
+
+impl BlockCache {
+    fn get(&mut self, index: u64) -> Option<Arc<Vec<u8>>> {
+        let block = self.blocks.get(&index)?.clone();
+        if let Some(at) = self.order.iter().position(|&i| i == index) {
+            self.order.remove(at);
+        }
+        self.order.push_back(index);
+        Some(block)
+    }
+
+    fn insert(&mut self, index: u64, block: Arc<Vec<u8>>) {
+        if self.blocks.insert(index, block).is_none() {
+            self.order.push_back(index);
+        }
+        while self.order.len() > CACHE_BLOCKS {
+            if let Some(evicted) = self.order.pop_front() {
+                self.blocks.remove(&evicted);
+            }
+        }
+    }
+}
+
+fn http_error(url: &str, error: ureq::Error) -> Error {
+    match error {
+        ureq::Error::Status(404, _) => {
+            Error::new(ErrorKind::NotFound, format!("{} not found", url))
+        }
+        // which for a bucket usually means the credentials are missing or wrong
+        ureq::Error::Status(401 | 403, _) => Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} refused access", url),
+        ),
+        e => Error::other(format!("{}: {}", url, e)),
+    }
+}
+
+fn no_ranges(url: &str) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("{} doesn't support range requests", url),
+    )
+}
+
+impl Remote {
+    fn connect(url: &str) -> std::io::Result<Self> {
+        let agent = ureq::AgentBuilder::new().build();
+        let source = Source::open(url)?;
+        // asking for a single byte checks ranges are supported and gives the full size
+        let response = source.get(&agent, url, 0, 0)?;
+        let len = response
+            .header("Content-Range")
+            .and_then(|range| range.rsplit('/').next())
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} didn't say how big it is", url),
+                )
+            })?;
+        Ok(Self {
+            url: url.to_string(),
+            len,
+            agent,
+            source,
+            cache: Mutex::new(BlockCache::default()),
+        })
+    }
+
+    // downloads `count` blocks from `first` on, returning the first
+    fn fetch(&self, first: u64, count: u64) -> std::io::Result<Arc<Vec<u8>>> {
+        let start = first * BLOCK_SIZE;
+        let end = ((first + count) * BLOCK_SIZE).min(self.len);
+        let response = self.source.get(&self.agent, &self.url, start, end - 1)?;
+        let mut data = Vec::with_capacity((end - start) as usize);
+        response
+            .into_reader()
+            .take(end - start)
+            .read_to_end(&mut data)?;
+        if data.len() as u64 != end - start {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("{} stopped sending partway", self.url),
+            ));
+        }
+        let mut cache = self.cache.lock().unwrap();
+        let blocks: Vec<Arc<Vec<u8>>> = data
+            .chunks(BLOCK_SIZE as usize)
+            .map(|block| Arc::new(block.to_vec()))
+            .collect();
+        for (index, block) in (first..).zip(&blocks) {
+            cache.insert(index, block.clone());
+        }
+        Ok(blocks[0].clone())
+    }
+}
+
+/// An archive on a web server or in a bucket, read through a block cache shared with every other reader of
+/// the same url.
+pub(crate) struct RemoteFile {
+    remote: Arc<Remote>,
+    pos: u64,
+    // the block a sequential read would need next, and how many blocks it would fetch
+    next: u64,
+    window: u64,
+}
+
+// connections live as long as the process, so the archive's headers and its entries are
+// only downloaded once however many times it's opened
+fn remotes() -> &'static Mutex<HashMap<String, Arc<Remote>>> {
+    static REMOTES: OnceLock<Mutex<HashMap<String, Arc<Remote>>>> = OnceLock::new();
+    REMOTES.get_or_init(Default::default)
+}
+
+impl RemoteFile {
+    pub(crate) fn open(url: &str) -> std::io::Result<Self> {
+        let existing = remotes().lock().unwrap().get(url).cloned();
+        let remote = match existing {
+            Some(remote) => remote,
+            None => {
+                let remote = Arc::new(Remote::connect(url)?);
+                remotes()
+                    .lock()
+                    .unwrap()
+                    .insert(url.to_string(), remote.clone());
+                remote
+            }
+        };
+        Ok(Self {
+            remote,
+            pos: 0,
+            next: 0,
+            window: 0,
+        })
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.remote.len
+    }
+
+    fn block(&mut self, index: u64) -> std::io::Result<Arc<Vec<u8>>> {
+        let cached = self.remote.cache.lock().unwrap().get(index);
+        let block = match cached {
+            Some(block) => block,
+            None => {
+                self.window = match index == self.next {
+                    true => (self.window * 2).clamp(1, MAX_READAHEAD),
+                    false => 1,
+                };
+                let left = self.remote.len.div_ceil(BLOCK_SIZE) - index;
+                self.remote.fetch(index, self.window.min(left))?
+            }
+        };
+        self.next = index + 1;
+        Ok(block)
+    }
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.remote.len {
+            return Ok(0);
+        }
+        let block = self.block(self.pos / BLOCK_SIZE)?;
+        let start = (self.pos % BLOCK_SIZE) as usize;
+        let read = buf.len().min(block.len() - start);
+        buf[..read].copy_from_slice(&block[start..start + read]);
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for RemoteFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.remote.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        }
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Tried to seek to before the start of the file...",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarWriter;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // serves `data` on a local port to the requests `allow` lets through, answering range
+    // requests only if `ranges` is set. returns the url and a count of the bytes sent so far
+    fn serve(
+        data: Vec<u8>,
+        ranges: bool,
+        allow: fn(&tiny_http::Request) -> bool,
+    ) -> (String, Arc<AtomicU64>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/update.mar", server.server_addr());
+        let sent = Arc::new(AtomicU64::new(0));
+        let counter = sent.clone();
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                if !allow(&request) {
+                    let _ = request.respond(tiny_http::Response::empty(403));
+                    continue;
+                }
+                let range = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Range"))
+                    .and_then(|header| header.value.as_str().strip_prefix("bytes="))
+                    .and_then(|range| range.split_once('-'))
+                    .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)))
+                    .filter(|_| ranges);
+                let (start, end): (usize, usize) = range.unwrap_or((0, data.len() - 1));
+                let body = &data[start..=end.min(data.len() - 1)];
+                counter.fetch_add(body.len() as u64, Ordering::Relaxed);
+                let mut response = tiny_http::Response::from_data(body);
+                if range.is_some() {
+                    let content_range = format!("bytes {}-{}/{}", start, end, data.len());
+                    response = response.with_status_code(206).with_header(
+                        tiny_http::Header::from_bytes("Content-Range", content_range).unwrap(),
+                    );
+                }
+                let _ = request.respond(response);
+            }
+        });
+        (url, sent)
+    }
+
+    #[test]
+    fn remote_mount() {
+        let big: Vec<u8> = (0..0x20_0000_u32).map(|i| (i % 251) as u8).collect();
+        let mar = MarWriter::new()
+            .add_file("big.bin", big.clone())
+            .add_file("small.txt", b"small".to_vec())
+            .write(Vec::new())
+            .unwrap();
+        let (url, sent) = serve(mar.clone(), true, |_| true);
+
+        let archive = crate::mount(url.into()).unwrap();
+        assert_eq!(archive.list_files().len(), 2);
+        assert_eq!(archive.read(Path::new("small.txt")).unwrap(), b"small");
+        // the headers at either end of big.bin, not what's in between
+        assert!(sent.load(Ordering::Relaxed) < 4 * BLOCK_SIZE);
+
+        let mut file = archive.open(Path::new("big.bin")).unwrap();
+        let mut tail = [0; 16];
+        file.seek(SeekFrom::End(-16)).unwrap();
+        file.read_exact(&mut tail).unwrap();
+        assert_eq!(tail, big[big.len() - 16..]);
+        assert_eq!(archive.read(Path::new("big.bin")).unwrap(), big);
+        // read ahead in bigger requests, but only once
+        assert!(sent.load(Ordering::Relaxed) < mar.len() as u64 + 4 * BLOCK_SIZE);
+
+        let (url, _) = serve(mar, false, |_| true);
+        let error = crate::mount(url.into()).unwrap_err();
+        assert!(error.to_string().contains("range requests"), "{}", error);
+    }
+
+    #[test]
+    fn s3_mount() {
+        let mar = MarWriter::new()
+            .add_file("data/a.bin", vec![4; 0x100])
+            .write(Vec::new())
+            .unwrap();
+        // a bucket that only lets signed requests for its own objects through
+        let (url, _) = serve(mar, true, |request| {
+            let signed = request.headers().iter().any(|header| {
+                header.field.equiv("Authorization")
+                    && header.value.as_str().contains("Credential=minio/")
+            });
+            signed && request.url() == "/bucket/update.mar"
+        });
+        // the only test that touches these
+        std::env::set_var("AWS_ENDPOINT_URL_S3", url.trim_end_matches("/update.mar"));
+        std::env::set_var("AWS_ACCESS_KEY_ID", "minio");
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", "minio123");
+
+        let archive = crate::mount("s3://bucket/update.mar".into()).unwrap();
+        assert_eq!(
+            archive.read(Path::new("data/a.bin")).unwrap(),
+            vec![4; 0x100]
+        );
+        let error = crate::mount("s3://bucket/missing.mar".into()).unwrap_err();
+        assert!(error.to_string().contains("refused access"), "{}", error);
+        let error = crate::mount("s3://bucket".into()).unwrap_err();
+        assert!(error.to_string().contains("bucket/key"), "{}", error);
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::common::*;
+
+pub(crate) const MAGIC: &[u8; 4] = b"S3P0";
+// each entry is an S3V0 header in front of a wma stream
+const ENTRY_MAGIC: &[u8; 4] = b"S3V0";
+const ENTRY_HEADER_SIZE: usize = 0x20;
+
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let buffer = benchmark(&path, options)?;
+    parse_from(path, buffer, options)
+}
+
+/// Parses from `buffer` when there is one, otherwise straight from the file at `path`.
+/// SSP files are the same container under a different extension. Entries have no names,
+/// so they're listed by their index in the table (`0.wma`, `1.wma`...).
+pub(crate) fn parse_from(
+    path: PathBuf,
+    buffer: Option<Vec<u8>>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    let mut file = match &buffer {
+        Some(buf) => InternalFile::Buffer(Cursor::new(buf)),
+        None => InternalFile::open(&path)?,
+    };
+    let end = archive_len(&buffer, &path)?;
+    // magic was already checked
+    file.seek(SeekFrom::Start(4))?;
+    let count = file.read_u32::<LittleEndian>()?;
+    // an offset and length per entry, then the entry itself
+    check_entry_count(
+        "s3p",
+        count as u64,
+        8 + ENTRY_HEADER_SIZE as u64,
+        8,
+        end,
+        options,
+    )?;
+    let table = (0..count)
+        .map(|_| {
+            Ok((
+                file.read_u32::<LittleEndian>()? as u64,
+                file.read_u32::<LittleEndian>()? as u64,
+            ))
+        })
+        .collect::<Result<Vec<_>, std::io::Error>>()?;
+
+    let mut files = HashMap::new();
+    let mut truncated: Option<PathBuf> = None;
+    let mut header = [0_u8; ENTRY_HEADER_SIZE];
+    for (index, &(start, length)) in table.iter().enumerate() {
+        let name = format!("{}.wma", index);
+        file.seek(SeekFrom::Start(start))?;
+        if let Err(e) = file.read_exact(&mut header) {
+            if options.allow_truncated {
+                truncated.get_or_insert_with(|| name.into());
+                continue;
+            }
+            return Err(e.into());
+        }
+        if &header[..4] != ENTRY_MAGIC {
+            return Err(invalid_entry(
+                "s3p",
+                index,
+                &name,
+                format!("entry magic is {:02X?}", &header[..4]),
+            ));
+        }
+        let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap()) as u64;
+        let (header_size, size) = (field(4), field(8));
+        if header_size + size > length {
+            return Err(invalid_entry(
+                "s3p",
+                index,
+                &name,
+                format!(
+                    "{} bytes of audio don't fit in a {} byte entry",
+                    size, length
+                ),
+            ));
+        }
+        let offset = start + header_size;
+        let available = payload_available("s3p", index, &name, (offset, size), end, options)?;
+        if available.is_some() {
+            truncated.get_or_insert_with(|| name.clone().into());
+        }
+        files.insert(
+            name.into(),
+            KFileInfo {
+                size,
+                offset,
+                available,
+                // the rest of the S3V0 header, unknown
+                raw_fields: vec![field(12) as u32, field(16) as u32],
+                ..Default::default()
+            },
+        );
+    }
+    let mut archive = KArchive::new(path.clone(), files, buffer);
+    if let Some(entry) = truncated {
+        archive.add_warning(KArchiveWarning::Truncated { path, entry });
+    }
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn s3p_mount() {
+        let tracks: [&[u8]; 2] = [b"first", b"second track"];
+        let mut s3p = MAGIC.to_vec();
+        s3p.extend_from_slice(&(tracks.len() as u32).to_le_bytes());
+        s3p.resize(8 + 8 * tracks.len(), 0);
+        for (idx, track) in tracks.iter().enumerate() {
+            let start = s3p.len() as u32;
+            let length = (ENTRY_HEADER_SIZE + track.len()) as u32;
+            s3p[8 + idx * 8..16 + idx * 8]
+                .copy_from_slice(&[start.to_le_bytes(), length.to_le_bytes()].concat());
+            s3p.extend_from_slice(ENTRY_MAGIC);
+            s3p.extend_from_slice(&(ENTRY_HEADER_SIZE as u32).to_le_bytes());
+            s3p.extend_from_slice(&(track.len() as u32).to_le_bytes());
+            s3p.resize(start as usize + ENTRY_HEADER_SIZE, 0);
+            s3p.extend_from_slice(track);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.s3p");
+        std::fs::write(&path, &s3p).unwrap();
+        let archive = crate::mount(path.clone()).unwrap();
+        assert_eq!(archive.list_files().len(), 2);
+        assert_eq!(archive.read(Path::new("0.wma")).unwrap(), b"first");
+        assert_eq!(archive.read(Path::new("1.wma")).unwrap(), b"second track");
+
+        // cut off partway through the second track
+        s3p.truncate(s3p.len() - 4);
+        std::fs::write(&path, &s3p).unwrap();
+        assert!(matches!(
+            crate::mount(path),
+            Err(KArchiveError::InvalidEntry { index: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,62 @@
+use std::io::{Read, Seek, SeekFrom};
+
+// how much of the file gets scanned per read while carving
+const WINDOW_SIZE: usize = 0x10_0000;
+
+/// A record recovered by `carve`
+pub(crate) struct CarvedEntry {
+    pub(crate) name: String,
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+}
+
+/// Returns the name stored in a fixed size, null terminated name field if it looks like
+/// a real path (printable ascii, not empty), along with the padding that follows it.
+pub(crate) fn plausible_name(field: &[u8]) -> Option<(&str, &[u8])> {
+    let len = field.iter().position(|&b| b == 0)?;
+    if len == 0 || !field[..len].iter().all(|b| (0x20..0x7F).contains(b)) {
+        return None;
+    }
+    let name = std::str::from_utf8(&field[..len]).ok()?;
+    Some((name, &field[len + 1..]))
+}
+
+/// Scans `start..end` of `rdr` byte by byte for entry records, which is used to rebuild
+/// the index of archives whose entry table is damaged. `validate` gets `header_size` bytes
+/// at each candidate position and returns (name, header length, payload size) if they look
+/// like a record. Payloads of recovered records are skipped rather than scanned.
+pub(crate) fn carve<R, F>(
+    rdr: &mut R,
+    start: u64,
+    end: u64,
+    header_size: usize,
+    mut validate: F,
+) -> std::io::Result<Vec<CarvedEntry>>
+where
+    R: Read + Seek,
+    F: FnMut(&[u8]) -> Option<(String, u64, u64)>,
+{
+    let mut entries = Vec::new();
+    let mut window = Vec::new();
+    let mut window_start = 0;
+    let mut pos = start;
+    while pos + header_size as u64 <= end {
+        if pos < window_start || pos + header_size as u64 > window_start + window.len() as u64 {
+            window_start = pos;
+            window.resize(usize::min(WINDOW_SIZE, (end - pos) as usize), 0);
+            rdr.seek(SeekFrom::Start(pos))?;
+            rdr.read_exact(&mut window)?;
+        }
+        let idx = (pos - window_start) as usize;
+        if let Some((name, header_len, size)) = validate(&window[idx..idx + header_size]) {
+            let offset = pos + header_len;
+            if offset + size <= end {
+                entries.push(CarvedEntry { name, offset, size });
+                pos = offset + size;
+                continue;
+            }
+        }
+        pos += 1;
+    }
+    Ok(entries)
+}
@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::common::*;
+
+/// Whether the file at `path` is something `mount` can read, going by its contents rather
+/// than its extension (besides 2dx and d2, which have nothing else to go by).
+pub fn is_archive(path: &Path) -> Result<bool, KArchiveError> {
+    #[cfg(feature = "tar")]
+    if crate::tarfile::is_tar(path)? {
+        return Ok(true);
+    }
+    if crate::iso::is_iso(path)? {
+        return Ok(true);
+    }
+    let mut header = Vec::new();
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    file.take(crate::nested::PEEK_SIZE)
+        .read_to_end(&mut header)?;
+    Ok(match header.get(..4) {
+        Some(b"ULST" | b"NAME") => true,
+        Some([0x1F, 0x8B, _, _]) => true,
+        Some(magic) if magic == crate::compressed::ZSTD_MAGIC => true,
+        #[cfg(feature = "zip")]
+        Some(b"PK\x03\x04" | b"PK\x05\x06") => true,
+        _ => crate::nested::nested_parser(path, &header, size).is_some(),
+    })
+}
+
+// the parts a ULST or NAME descriptor lists, which sit next to it
+fn descriptor_parts(path: &Path) -> Vec<PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let names: Vec<String> = if let Ok(entries) = crate::lst::parse_manifest(path) {
+        entries.into_iter().map(|entry| entry.file_name).collect()
+    } else if let Ok(manifest) = crate::info::parse_manifest(path) {
+        manifest
+            .files
+            .into_iter()
+            .map(|entry| entry.file_name)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    names.into_iter().map(|name| dir.join(name)).collect()
+}
+
+/// Every archive under `dir`, found with `is_archive`. Parts of a multi part set are left
+/// out when the set's ULST/NAME descriptor is there too, since mounting that covers them.
+pub fn find_archives(dir: &Path) -> Result<Vec<PathBuf>, KArchiveError> {
+    let mut archives = Vec::new();
+    for (_, path) in walk_dir(dir)? {
+        if is_archive(&path)? {
+            archives.push(path);
+        }
+    }
+    let mut magic = [0_u8; 4];
+    let parts: Vec<PathBuf> = archives
+        .iter()
+        .filter(|path| {
+            File::open(path)
+                .and_then(|mut file| file.read_exact(&mut magic))
+                .is_ok()
+                && (&magic == b"ULST" || &magic == b"NAME")
+        })
+        .flat_map(|path| descriptor_parts(path))
+        .collect();
+    archives.retain(|path| !parts.contains(path));
+    Ok(archives)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LstSetBuilder, MarWriter};
+
+    #[test]
+    fn scan_for_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        // no extension to go by
+        MarWriter::new()
+            .add_file("data/a.bin", vec![1; 0x10])
+            .write(File::create(dir.path().join("a/b/update_file")).unwrap())
+            .unwrap();
+        std::fs::write(dir.path().join("a/readme.txt"), "not an archive").unwrap();
+        let lst = LstSetBuilder::new(0x40)
+            .add_file("data/x.bin", vec![2; 0x30])
+            .add_file("data/y.bin", vec![3; 0x30])
+            .write(dir.path(), "set")
+            .unwrap();
+
+        let found = find_archives(dir.path()).unwrap();
+        assert_eq!(found, vec![dir.path().join("a/b/update_file"), lst]);
+        assert!(!is_archive(&dir.path().join("a/readme.txt")).unwrap());
+    }
+}
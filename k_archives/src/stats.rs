@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::common::*;
+
+/// Entry counts and sizes for one group of entries in `ArchiveStats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GroupStats {
+    pub name: String,
+    pub entries: usize,
+    /// Total size once extracted
+    pub size: u64,
+    /// Total size as stored, less than `size` when entries are compressed
+    pub stored_size: u64,
+}
+
+impl GroupStats {
+    fn add(&mut self, meta: &KEntryMeta) {
+        self.entries += 1;
+        self.size += meta.size;
+        self.stored_size += meta.stored_size;
+    }
+}
+
+/// What an archive is made of, see `stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveStats {
+    /// Every entry, named after the archive
+    pub total: GroupStats,
+    /// The biggest entries as (path, size), biggest first
+    pub largest: Vec<(PathBuf, u64)>,
+    /// By lowercased extension, "" for entries without one
+    pub by_extension: Vec<GroupStats>,
+    /// By the first folder in the entry's path, "" for entries at the top
+    pub by_folder: Vec<GroupStats>,
+    /// By the archive each entry is stored in. Multi part sets have one per part, single
+    /// archives just the one
+    pub by_part: Vec<GroupStats>,
+}
+
+fn grouped(groups: BTreeMap<String, GroupStats>) -> Vec<GroupStats> {
+    groups
+        .into_iter()
+        .map(|(name, group)| GroupStats { name, ..group })
+        .collect()
+}
+
+/// Counts and sizes of what's in `archive`, overall and broken down by extension, top
+/// level folder and part, along with its `largest` biggest entries. Nothing is read, so
+/// it's quick even for big archives. Groups are sorted by name.
+pub fn stats(archive: &KArchive, name: &str, largest: usize) -> ArchiveStats {
+    let mut stats = ArchiveStats {
+        total: GroupStats {
+            name: name.to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut by_extension = BTreeMap::<String, GroupStats>::new();
+    let mut by_folder = BTreeMap::<String, GroupStats>::new();
+    let mut by_part = BTreeMap::<String, GroupStats>::new();
+    for path in archive.list_files() {
+        let Some(meta) = archive.metadata(&path) else {
+            continue;
+        };
+        stats.total.add(&meta);
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        by_extension.entry(extension).or_default().add(&meta);
+        let folder = match path.parent().is_some_and(|parent| parent != Path::new("")) {
+            true => path
+                .iter()
+                .next()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into(),
+            false => String::new(),
+        };
+        by_folder.entry(folder).or_default().add(&meta);
+        let part = archive
+            .part_path(&path)
+            .map(|part| part.display().to_string())
+            .unwrap_or_default();
+        by_part.entry(part).or_default().add(&meta);
+        stats.largest.push((path, meta.size));
+    }
+    // ties go by path so the list is the same every time
+    stats
+        .largest
+        .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    stats.largest.truncate(largest);
+    stats.by_extension = grouped(by_extension);
+    stats.by_folder = grouped(by_folder);
+    stats.by_part = grouped(by_part);
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ArcWriter;
+    use std::fs::File;
+
+    #[test]
+    fn archive_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.arc");
+        ArcWriter::new()
+            .add_file("data/sound/a.2DX", vec![0; 0x1000])
+            .add_file("data/sound/b.2dx", vec![0; 0x100])
+            .add_file("data/movie.wmv", vec![1; 0x10])
+            .add_file("readme", vec![2; 4])
+            .write(File::create(&path).unwrap())
+            .unwrap();
+        let archive = crate::mount(path.clone()).unwrap();
+
+        let stats = stats(&archive, "test.arc", 2);
+        assert_eq!(stats.total.name, "test.arc");
+        assert_eq!(stats.total.entries, 4);
+        assert_eq!(stats.total.size, 0x1000 + 0x100 + 0x10 + 4);
+        // the runs of zeroes compress
+        assert!(stats.total.stored_size < stats.total.size);
+        assert_eq!(
+            stats.largest,
+            vec![
+                (PathBuf::from("data/sound/a.2DX"), 0x1000),
+                (PathBuf::from("data/sound/b.2dx"), 0x100)
+            ]
+        );
+        let summary = |groups: &[GroupStats]| -> Vec<(String, usize)> {
+            groups
+                .iter()
+                .map(|group| (group.name.clone(), group.entries))
+                .collect()
+        };
+        assert_eq!(
+            summary(&stats.by_extension),
+            vec![("".into(), 1), ("2dx".into(), 2), ("wmv".into(), 1)]
+        );
+        assert_eq!(
+            summary(&stats.by_folder),
+            vec![("".into(), 1), ("data".into(), 3)]
+        );
+        assert_eq!(
+            summary(&stats.by_part),
+            vec![(path.display().to_string(), 4)]
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::common::*;
+
+// ustar (posix) and gnu tars both have "ustar" at the start of the magic field
+const MAGIC_OFFSET: usize = 257;
+
+/// Whether `header`, the start of a file, is a tar header.
+pub(crate) fn looks_like_tar(header: &[u8]) -> bool {
+    header.get(MAGIC_OFFSET..MAGIC_OFFSET + 5) == Some(b"ustar")
+}
+
+pub(crate) fn is_tar(path: &Path) -> Result<bool, KArchiveError> {
+    let mut header = Vec::new();
+    InternalFile::open(path)?
+        .take(MAGIC_OFFSET as u64 + 5)
+        .read_to_end(&mut header)?;
+    Ok(looks_like_tar(&header))
+}
+
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    parse_from(path, None, options)
+}
+
+/// Parses from `buffer` when there is one, otherwise straight from the file at `path`.
+/// Only regular files are listed, links and folders are skipped.
+pub(crate) fn parse_from(
+    path: PathBuf,
+    buffer: Option<Vec<u8>>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    let file = match &buffer {
+        Some(buf) => InternalFile::Buffer(Cursor::new(buf)),
+        None => InternalFile::open(&path)?,
+    };
+    let end = archive_len(&buffer, &path)?;
+    let mut tar = tar::Archive::new(file);
+    let mut files = HashMap::new();
+    let mut truncated: Option<PathBuf> = None;
+    for (index, entry) in tar.entries_with_seek()?.enumerate() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // a cut off tar just ends early
+            Err(_) if truncated.is_some() => break,
+            Err(e) => return Err(e.into()),
+        };
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = archive_name(&entry.path()?);
+        check_entry_name("tar", index, &name)?;
+        let (offset, size) = (entry.raw_file_position(), entry.size());
+        let available = payload_available("tar", index, &name, (offset, size), end, options)?;
+        if available.is_some() {
+            truncated.get_or_insert_with(|| name.clone().into());
+        }
+        files.insert(
+            name.into(),
+            KFileInfo {
+                size,
+                offset,
+                available,
+                // an unset mtime is 0, which is never what was meant
+                modified: entry
+                    .header()
+                    .mtime()
+                    .ok()
+                    .filter(|&mtime| mtime != 0)
+                    .map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime)),
+                mode: entry.header().mode().ok(),
+                ..Default::default()
+            },
+        );
+    }
+    let mut archive = KArchive::new(path.clone(), files, buffer);
+    if let Some(entry) = truncated {
+        archive.add_warning(KArchiveWarning::Truncated { path, entry });
+    }
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarWriter;
+    use flate2::{write::GzEncoder, Compression};
+    use std::fs::File;
+
+    fn append(tar: &mut tar::Builder<impl std::io::Write>, name: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o640);
+        header.set_mtime(1_700_000_000);
+        header.set_cksum();
+        tar.append_data(&mut header, name, data).unwrap();
+    }
+
+    #[test]
+    fn tar_mount() {
+        let mar = MarWriter::new()
+            .add_file("data/a.bin", vec![8; 0x40])
+            .write(Vec::new())
+            .unwrap();
+        let mut tar = tar::Builder::new(Vec::new());
+        append(&mut tar, "notes.txt", b"preserved");
+        append(&mut tar, "set/update.mar", &mar);
+        let tar = tar.into_inner().unwrap();
+        assert!(looks_like_tar(&tar));
+
+        let dir = tempfile::tempdir().unwrap();
+        let tar_path = dir.path().join("set.tar");
+        std::fs::write(&tar_path, &tar).unwrap();
+        let tgz_path = dir.path().join("set.tar.gz");
+        let mut gz = GzEncoder::new(File::create(&tgz_path).unwrap(), Compression::fast());
+        std::io::Write::write_all(&mut gz, &tar).unwrap();
+        gz.finish().unwrap();
+
+        for path in [tar_path, tgz_path] {
+            let archive = crate::mount(path).unwrap();
+            assert_eq!(archive.read(Path::new("notes.txt")).unwrap(), b"preserved");
+            let meta = archive.metadata(Path::new("notes.txt")).unwrap();
+            let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+            assert_eq!(meta.modified, Some(modified));
+            assert_eq!(meta.mode, Some(0o640));
+            let dest = dir.path().join("out/notes.txt");
+            archive
+                .extract_to(Path::new("notes.txt"), &dest, |_| {})
+                .unwrap();
+            assert_eq!(
+                std::fs::metadata(&dest).unwrap().modified().unwrap(),
+                modified
+            );
+            // archives in a bundle are mounted without having to ask
+            assert_eq!(
+                archive
+                    .read(Path::new("set/update.mar/data/a.bin"))
+                    .unwrap(),
+                vec![8; 0x40]
+            );
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::common::*;
+
+// 16 byte name, header size, entry count and 48 unknown bytes, then the entry offsets
+const TABLE_START: u64 = 0x48;
+// every entry starts with one of these before its wav data
+const ENTRY_MAGIC: &[u8; 4] = b"2DX9";
+const ENTRY_HEADER_SIZE: usize = 0x18;
+
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    let buffer = benchmark(&path, options)?;
+    parse_from(path, buffer, options)
+}
+
+/// Parses from `buffer` when there is one, otherwise straight from the file at `path`.
+/// Entries have no names, so they're listed by their index in the table (`0.wav`, `1.wav`...).
+pub(crate) fn parse_from(
+    path: PathBuf,
+    buffer: Option<Vec<u8>>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    let mut file = match &buffer {
+        Some(buf) => InternalFile::Buffer(Cursor::new(buf)),
+        None => InternalFile::open(&path)?,
+    };
+    let end = archive_len(&buffer, &path)?;
+    // the name at the start is just the name of the file without the extension, nothing to check there
+    file.seek(SeekFrom::Start(0x10))?;
+    let _header_size = file.read_u32::<LittleEndian>()?;
+    let count = file.read_u32::<LittleEndian>()?;
+    check_entry_count(
+        "2dx",
+        count as u64,
+        4 + ENTRY_HEADER_SIZE as u64,
+        TABLE_START,
+        end,
+        options,
+    )?;
+    file.seek(SeekFrom::Start(TABLE_START))?;
+    let offsets = (0..count)
+        .map(|_| file.read_u32::<LittleEndian>())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut files = HashMap::new();
+    let mut truncated: Option<PathBuf> = None;
+    let mut header = [0_u8; ENTRY_HEADER_SIZE];
+    for (index, &start) in offsets.iter().enumerate() {
+        let name = format!("{}.wav", index);
+        file.seek(SeekFrom::Start(start as u64))?;
+        if let Err(e) = file.read_exact(&mut header) {
+            if options.allow_truncated {
+                truncated.get_or_insert_with(|| name.into());
+                continue;
+            }
+            return Err(e.into());
+        }
+        if &header[..4] != ENTRY_MAGIC {
+            return Err(invalid_entry(
+                "2dx",
+                index,
+                &name,
+                format!("entry magic is {:02X?}", &header[..4]),
+            ));
+        }
+        let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let half = |at: usize| u16::from_le_bytes(header[at..at + 2].try_into().unwrap()) as u32;
+        let offset = start as u64 + field(4) as u64;
+        let size = field(8) as u64;
+        let available = payload_available("2dx", index, &name, (offset, size), end, options)?;
+        if available.is_some() {
+            truncated.get_or_insert_with(|| name.clone().into());
+        }
+        files.insert(
+            name.into(),
+            KFileInfo {
+                size,
+                offset,
+                available,
+                // track id, attenuation and loop point
+                raw_fields: vec![half(14), half(18), field(20)],
+                ..Default::default()
+            },
+        );
+    }
+    let mut archive = KArchive::new(path.clone(), files, buffer);
+    if let Some(entry) = truncated {
+        archive.add_warning(KArchiveWarning::Truncated { path, entry });
+    }
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn build_2dx(tracks: &[&[u8]]) -> Vec<u8> {
+        let mut out = b"test".to_vec();
+        out.resize(0x10, 0);
+        let table_end = TABLE_START as usize + 4 * tracks.len();
+        out.extend_from_slice(&(table_end as u32).to_le_bytes());
+        out.extend_from_slice(&(tracks.len() as u32).to_le_bytes());
+        out.resize(table_end, 0);
+        for (idx, track) in tracks.iter().enumerate() {
+            let start = out.len() as u32;
+            out[0x48 + idx * 4..0x4C + idx * 4].copy_from_slice(&start.to_le_bytes());
+            out.extend_from_slice(ENTRY_MAGIC);
+            out.extend_from_slice(&(ENTRY_HEADER_SIZE as u32).to_le_bytes());
+            out.extend_from_slice(&(track.len() as u32).to_le_bytes());
+            out.extend_from_slice(&[0x31, 0x32, 0xFF, 0xFF, 0x40, 0, 0, 0]);
+            out.extend_from_slice(&0_u32.to_le_bytes());
+            out.extend_from_slice(track);
+        }
+        out
+    }
+
+    #[test]
+    fn twodx_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.2dx");
+        std::fs::write(&path, build_2dx(&[b"RIFFfirst", b"RIFFsecond"])).unwrap();
+        let archive = crate::mount(path.clone()).unwrap();
+        assert_eq!(archive.list_files().len(), 2);
+        assert_eq!(archive.read(Path::new("1.wav")).unwrap(), b"RIFFsecond");
+        let meta = archive.metadata(Path::new("0.wav")).unwrap();
+        assert_eq!(meta.raw_fields, vec![0xFFFF, 0, 0]);
+
+        let mut bad = build_2dx(&[b"RIFF"]);
+        bad[0x4C] = b'X';
+        std::fs::write(&path, bad).unwrap();
+        assert!(matches!(
+            crate::mount(path),
+            Err(KArchiveError::InvalidEntry { format: "2dx", .. })
+        ));
+    }
+}
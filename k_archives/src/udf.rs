@@ -0,0 +1,832 @@
+//! UDF (ECMA-167, as narrowed down by the OSTA UDF spec), which DVD and Blu-ray images use
+//! either on their own or next to an ISO9660 tree. Only what listing and reading files takes
+//! is read: the anchor, the partition and logical volume descriptors, the file set, and the
+//! file entries and identifiers of the directory tree.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::common::*;
+use crate::iso::{MAX_DIR_DEPTH, SECTOR_SIZE};
+
+// where the anchor is on every disc. it's repeated in the last sector too
+const ANCHOR_SECTOR: u64 = 256;
+
+// descriptor tag identifiers
+const TAG_ANCHOR: u16 = 2;
+const TAG_PARTITION: u16 = 5;
+const TAG_LOGICAL_VOLUME: u16 = 6;
+const TAG_TERMINATOR: u16 = 8;
+const TAG_FILE_SET: u16 = 256;
+const TAG_FILE_IDENTIFIER: u16 = 257;
+const TAG_FILE_ENTRY: u16 = 261;
+const TAG_EXTENDED_FILE_ENTRY: u16 = 266;
+
+const FILE_TYPE_DIRECTORY: u8 = 4;
+const FILE_TYPE_FILE: u8 = 5;
+const DELETED: u8 = 0x04;
+const PARENT: u8 = 0x08;
+// the fixed part of a file identifier, before the implementation use and the name
+const FILE_IDENTIFIER_SIZE: usize = 38;
+// the partition map udf 2.50 and later keep file entries and directories in
+const METADATA_PARTITION: &[u8] = b"*UDF Metadata Partition";
+
+fn invalid(reason: impl Into<String>) -> KArchiveError {
+    KArchiveError::InvalidHeader {
+        format: "udf",
+        reason: reason.into(),
+    }
+}
+
+// the identifier of the descriptor tag `block` starts with, if its checksum adds up
+fn tag_id(block: &[u8]) -> Option<u16> {
+    let tag = block.get(..16)?;
+    let sum = tag
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 4)
+        .fold(0_u8, |sum, (_, &b)| sum.wrapping_add(b));
+    (sum == tag[4]).then(|| LittleEndian::read_u16(tag))
+}
+
+// a timestamp: type and time zone (minutes from utc in the low 12 bits, -2047 when unset),
+// year, month, day, hour, minute, second, then centi, hundreds of micro and microseconds
+fn timestamp(stamp: &[u8]) -> Option<SystemTime> {
+    let zone = ((LittleEndian::read_u16(stamp) << 4) as i16 >> 4) as i32;
+    let zone = if zone == -2047 { 0 } else { zone };
+    let day = time::Date::from_calendar_date(
+        LittleEndian::read_i16(&stamp[2..]) as i32,
+        time::Month::try_from(stamp[4]).ok()?,
+        stamp[5],
+    )
+    .ok()?;
+    let micros = stamp[9] as u32 * 10_000 + stamp[10] as u32 * 100 + stamp[11] as u32;
+    let time = time::Time::from_hms_micro(stamp[6], stamp[7], stamp[8], micros).ok()?;
+    let offset = time::UtcOffset::from_whole_seconds(zone * 60).ok()?;
+    Some(
+        time::PrimitiveDateTime::new(day, time)
+            .assume_offset(offset)
+            .into(),
+    )
+}
+
+// names start with how they're stored: a byte per character or big endian utf-16
+fn decode_name(name: &[u8]) -> Option<String> {
+    match name.split_first()? {
+        (8 | 254, rest) => Some(rest.iter().map(|&b| b as char).collect()),
+        (16 | 255, rest) => {
+            let chars: Vec<u16> = rest
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            Some(String::from_utf16_lossy(&chars))
+        }
+        _ => None,
+    }
+}
+
+enum Partition {
+    // a partition descriptor's area, as (offset, length) in the image
+    Physical(u64, u64),
+    // the metadata file's blocks, as the (offset, length) extents it's stored in
+    Metadata(Vec<(u64, u64)>),
+}
+
+impl Partition {
+    fn offset(&self, block: u32) -> Option<u64> {
+        let pos = block as u64 * SECTOR_SIZE;
+        match self {
+            Partition::Physical(start, len) => (pos < *len).then_some(start + pos),
+            Partition::Metadata(extents) => {
+                let mut pos = pos;
+                for &(offset, len) in extents {
+                    if pos < len {
+                        return Some(offset + pos);
+                    }
+                    pos -= len;
+                }
+                None
+            }
+        }
+    }
+}
+
+enum Extent {
+    Recorded {
+        partition: u16,
+        block: u32,
+        len: u64,
+    },
+    // allocated or not, but never written, so it reads as zeroes
+    Zeroes(u64),
+    // stored in the file entry itself, at an offset in the image
+    Embedded {
+        offset: u64,
+        len: u64,
+    },
+}
+
+// what a file entry (or an extended one) says about a file or folder
+struct Entry {
+    // where the entry is in the image, which tells folders apart
+    at: u64,
+    file_type: u8,
+    size: u64,
+    modified: Option<SystemTime>,
+    extents: Vec<Extent>,
+}
+
+struct Udf<'a> {
+    file: &'a mut InternalFile<'static>,
+    end: u64,
+    options: &'a MountOptions,
+    partitions: Vec<Partition>,
+    files: HashMap<PathBuf, KFileInfo>,
+    // files that couldn't be listed, and why
+    skipped: Vec<String>,
+    // folders already walked, so identifiers pointing back up don't loop
+    visited: HashSet<u64>,
+}
+
+impl<'a> Udf<'a> {
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, KArchiveError> {
+        if offset.checked_add(len).is_none_or(|end| end > self.end) {
+            return Err(invalid(format!(
+                "{} bytes at {:#x} run past the end of the image",
+                len, offset
+            )));
+        }
+        let mut data = vec![0; len as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn block_offset(&self, partition: u16, block: u32) -> Result<u64, KArchiveError> {
+        self.partitions
+            .get(partition as usize)
+            .and_then(|p| p.offset(block))
+            .ok_or_else(|| {
+                invalid(format!(
+                    "block {} of partition {} isn't in it",
+                    block, partition
+                ))
+            })
+    }
+
+    fn extent(&self, partition: u16, len: u32, block: u32) -> Result<Extent, KArchiveError> {
+        let size = (len & 0x3FFF_FFFF) as u64;
+        match len >> 30 {
+            0 => Ok(Extent::Recorded {
+                partition,
+                block,
+                len: size,
+            }),
+            1 | 2 => Ok(Extent::Zeroes(size)),
+            _ => Err(invalid(
+                "allocation descriptors continued in another extent aren't supported",
+            )),
+        }
+    }
+
+    // the file entry in `block` of `partition`, whose short allocation descriptors are
+    // in that same partition
+    fn entry(&mut self, partition: u16, block: u32) -> Result<Entry, KArchiveError> {
+        let at = self.block_offset(partition, block)?;
+        let data = self.read_at(at, SECTOR_SIZE)?;
+        // where the modification time and the extended attribute length are
+        let (modified_at, attributes_at) = match tag_id(&data) {
+            Some(TAG_FILE_ENTRY) => (84, 168),
+            Some(TAG_EXTENDED_FILE_ENTRY) => (92, 208),
+            _ => {
+                return Err(invalid(format!(
+                    "no file entry in block {} of partition {}",
+                    block, partition
+                )))
+            }
+        };
+        let attributes_len = LittleEndian::read_u32(&data[attributes_at..]) as usize;
+        let descriptors_len = LittleEndian::read_u32(&data[attributes_at + 4..]) as usize;
+        let start = (attributes_at + 8).saturating_add(attributes_len);
+        let descriptors = start
+            .checked_add(descriptors_len)
+            .and_then(|end| data.get(start..end))
+            .ok_or_else(|| invalid(format!("file entry at {:#x} overflows its block", at)))?;
+        // lists end early at a descriptor with no length
+        let listed = |size| {
+            descriptors
+                .chunks_exact(size)
+                .take_while(|ad| LittleEndian::read_u32(ad) & 0x3FFF_FFFF != 0)
+        };
+        let extents = match LittleEndian::read_u16(&data[34..]) & 7 {
+            0 => listed(8)
+                .map(|ad| {
+                    self.extent(
+                        partition,
+                        LittleEndian::read_u32(ad),
+                        LittleEndian::read_u32(&ad[4..]),
+                    )
+                })
+                .collect::<Result<_, _>>()?,
+            1 => listed(16)
+                .map(|ad| {
+                    let partition = LittleEndian::read_u16(&ad[8..]);
+                    self.extent(
+                        partition,
+                        LittleEndian::read_u32(ad),
+                        LittleEndian::read_u32(&ad[4..]),
+                    )
+                })
+                .collect::<Result<_, _>>()?,
+            3 => vec![Extent::Embedded {
+                offset: at + start as u64,
+                len: descriptors_len as u64,
+            }],
+            _ => return Err(invalid("extended allocation descriptors aren't supported")),
+        };
+        Ok(Entry {
+            at,
+            file_type: data[27],
+            size: LittleEndian::read_u64(&data[56..]),
+            modified: timestamp(&data[modified_at..]),
+            extents,
+        })
+    }
+
+    // the contents of a folder (or the metadata file), which don't have to be in one piece
+    fn read_data(&mut self, entry: &Entry) -> Result<Vec<u8>, KArchiveError> {
+        let mut data = Vec::new();
+        for extent in &entry.extents {
+            let wanted = entry.size - data.len() as u64;
+            match *extent {
+                _ if wanted == 0 => break,
+                Extent::Recorded {
+                    partition,
+                    block,
+                    len,
+                } => {
+                    let len = len.min(wanted);
+                    for i in 0..len.div_ceil(SECTOR_SIZE) {
+                        let block = block
+                            .checked_add(i as u32)
+                            .ok_or_else(|| invalid("extent runs past the last block"))?;
+                        let offset = self.block_offset(partition, block)?;
+                        let take = (len - i * SECTOR_SIZE).min(SECTOR_SIZE);
+                        data.extend(self.read_at(offset, take)?);
+                    }
+                }
+                Extent::Zeroes(len) => data.resize(data.len() + len.min(wanted) as usize, 0),
+                Extent::Embedded { offset, len } => {
+                    data.extend(self.read_at(offset, len.min(wanted))?)
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    // where a file's data starts, when it's all in one piece
+    fn contiguous(&self, entry: &Entry) -> Result<Option<u64>, KArchiveError> {
+        let (mut start, mut next, mut covered) = (None, 0, 0);
+        for extent in &entry.extents {
+            if covered >= entry.size {
+                break;
+            }
+            let (offset, len) = match *extent {
+                Extent::Recorded {
+                    partition,
+                    block,
+                    len,
+                } => (self.block_offset(partition, block)?, len),
+                Extent::Embedded { offset, len } => (offset, len),
+                Extent::Zeroes(_) => return Ok(None),
+            };
+            if start.is_some() && offset != next {
+                return Ok(None);
+            }
+            start.get_or_insert(offset);
+            next = offset + len;
+            covered += len;
+        }
+        match entry.size {
+            0 => Ok(Some(start.unwrap_or_default())),
+            size => Ok(start.filter(|_| covered >= size)),
+        }
+    }
+
+    fn add_file(&mut self, path: PathBuf, entry: Entry) -> Result<(), KArchiveError> {
+        let index = self.files.len();
+        let name = archive_name(&path);
+        check_entry_name("udf", index, &name)?;
+        let Some(offset) = self.contiguous(&entry)? else {
+            self.skipped.push(format!(
+                "{} isn't stored in one piece, which isn't supported",
+                name
+            ));
+            return Ok(());
+        };
+        let available = payload_available(
+            "udf",
+            index,
+            &name,
+            (offset, entry.size),
+            self.end,
+            self.options,
+        )?;
+        self.files.insert(
+            path,
+            KFileInfo {
+                size: entry.size,
+                offset,
+                available,
+                modified: entry.modified,
+                ..Default::default()
+            },
+        );
+        Ok(())
+    }
+
+    fn walk(&mut self, entry: Entry, dir: &Path, depth: usize) -> Result<(), KArchiveError> {
+        if depth > MAX_DIR_DEPTH {
+            return Err(invalid(format!(
+                "directories nest deeper than {} levels",
+                MAX_DIR_DEPTH
+            )));
+        }
+        if entry.size > self.end {
+            return Err(invalid(format!(
+                "directory {} is bigger than the image",
+                dir.display()
+            )));
+        }
+        if !self.visited.insert(entry.at) {
+            return Ok(());
+        }
+        let data = self.read_data(&entry)?;
+        let mut pos = 0;
+        while pos + FILE_IDENTIFIER_SIZE <= data.len() {
+            let identifier = &data[pos..];
+            if tag_id(identifier) != Some(TAG_FILE_IDENTIFIER) {
+                return Err(invalid(format!(
+                    "damaged file identifier in directory {}",
+                    dir.display()
+                )));
+            }
+            let characteristics = identifier[18];
+            let name_len = identifier[19] as usize;
+            let block = LittleEndian::read_u32(&identifier[24..]);
+            let partition = LittleEndian::read_u16(&identifier[28..]);
+            let name_start =
+                FILE_IDENTIFIER_SIZE + LittleEndian::read_u16(&identifier[36..]) as usize;
+            let raw_name = identifier
+                .get(name_start..name_start + name_len)
+                .ok_or_else(|| {
+                    invalid(format!("file identifier in {} is cut off", dir.display()))
+                })?;
+            // padded to a multiple of 4
+            pos += (name_start + name_len + 3) & !3;
+            if characteristics & (DELETED | PARENT) != 0 {
+                continue;
+            }
+            let name = decode_name(raw_name).unwrap_or_default();
+            let path = dir.join(&name);
+            if name.is_empty() || name.contains('/') {
+                return Err(invalid_entry(
+                    "udf",
+                    self.files.len(),
+                    &archive_name(&path),
+                    "name isn't a single path component",
+                ));
+            }
+            let child = self.entry(partition, block)?;
+            match child.file_type {
+                FILE_TYPE_DIRECTORY => self.walk(child, &path, depth + 1)?,
+                FILE_TYPE_FILE => self.add_file(path, child)?,
+                // symlinks, devices and the like
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What's in an image's UDF file system.
+pub(crate) struct Listing {
+    pub(crate) files: HashMap<PathBuf, KFileInfo>,
+    /// Files that were left out, and why. Only ones stored in a single piece can be read.
+    pub(crate) skipped: Vec<String>,
+}
+
+/// Lists the files of the UDF file system in `file`, which is `end` bytes long. Partitions
+/// are read through type 1 and metadata partition maps, sparable and virtual ones (from
+/// rewritable and multisession discs) aren't supported.
+pub(crate) fn parse(
+    file: &mut InternalFile<'static>,
+    end: u64,
+    options: &MountOptions,
+) -> Result<Listing, KArchiveError> {
+    let mut udf = Udf {
+        file,
+        end,
+        options,
+        partitions: Vec::new(),
+        files: HashMap::new(),
+        skipped: Vec::new(),
+        visited: HashSet::new(),
+    };
+    let mut anchor = None;
+    for sector in [ANCHOR_SECTOR, (end / SECTOR_SIZE).saturating_sub(1)] {
+        if let Ok(data) = udf.read_at(sector * SECTOR_SIZE, SECTOR_SIZE) {
+            if tag_id(&data) == Some(TAG_ANCHOR) {
+                anchor = Some(data);
+                break;
+            }
+        }
+    }
+    let anchor = anchor.ok_or_else(|| invalid("no anchor volume descriptor"))?;
+
+    // the main volume descriptor sequence, for the partitions (by number) and the volume
+    let sequence_len = LittleEndian::read_u32(&anchor[16..]) as u64;
+    let sequence_start = LittleEndian::read_u32(&anchor[20..]) as u64;
+    let mut descriptors = HashMap::new();
+    let mut volume = None;
+    for sector in sequence_start..sequence_start + sequence_len / SECTOR_SIZE {
+        let data = udf.read_at(sector * SECTOR_SIZE, SECTOR_SIZE)?;
+        match tag_id(&data) {
+            Some(TAG_PARTITION) => {
+                let start = LittleEndian::read_u32(&data[188..]) as u64 * SECTOR_SIZE;
+                let len = LittleEndian::read_u32(&data[192..]) as u64 * SECTOR_SIZE;
+                descriptors.insert(LittleEndian::read_u16(&data[22..]), (start, len));
+            }
+            Some(TAG_LOGICAL_VOLUME) => {
+                volume.get_or_insert(data);
+            }
+            Some(TAG_TERMINATOR) => break,
+            _ => {}
+        }
+    }
+    let volume = volume.ok_or_else(|| invalid("no logical volume descriptor"))?;
+    let block_size = LittleEndian::read_u32(&volume[212..]);
+    if block_size as u64 != SECTOR_SIZE {
+        return Err(invalid(format!(
+            "{} byte blocks aren't supported, only {}",
+            block_size, SECTOR_SIZE
+        )));
+    }
+
+    // partition maps say which partition each reference is. metadata ones are filled in
+    // once the physical partitions they're in can be read
+    let maps_len = LittleEndian::read_u32(&volume[264..]) as usize;
+    let maps = volume
+        .get(440..440 + maps_len)
+        .ok_or_else(|| invalid("partition maps don't fit in the logical volume descriptor"))?;
+    let physical = |number: u16| {
+        descriptors
+            .get(&number)
+            .map(|&(start, len)| Partition::Physical(start, len))
+            .ok_or_else(|| invalid(format!("partition {} isn't described", number)))
+    };
+    let mut numbers = Vec::new();
+    let mut metadata = Vec::new();
+    let mut pos = 0;
+    for _ in 0..LittleEndian::read_u32(&volume[268..]) {
+        let map = maps
+            .get(pos..)
+            .filter(|map| map.len() >= 2 && map[1] >= 6 && map.len() >= map[1] as usize)
+            .ok_or_else(|| invalid("partition map is cut off"))?;
+        let number = match map[0] {
+            1 => {
+                let number = LittleEndian::read_u16(&map[4..]);
+                udf.partitions.push(physical(number)?);
+                number
+            }
+            2 if map.len() >= 44 && map[5..].starts_with(METADATA_PARTITION) => {
+                let number = LittleEndian::read_u16(&map[38..]);
+                metadata.push((
+                    udf.partitions.len(),
+                    number,
+                    LittleEndian::read_u32(&map[40..]),
+                ));
+                udf.partitions.push(Partition::Metadata(Vec::new()));
+                number
+            }
+            _ => return Err(invalid(
+                "only plain and metadata partitions are supported, not sparable or virtual ones",
+            )),
+        };
+        numbers.push(number);
+        pos += map[1] as usize;
+    }
+    for (reference, number, block) in metadata {
+        let physical = numbers
+            .iter()
+            .zip(&udf.partitions)
+            .position(|(&n, p)| n == number && matches!(p, Partition::Physical(..)))
+            .ok_or_else(|| invalid(format!("metadata partition {} has no physical one", number)))?;
+        let file = udf.entry(physical as u16, block)?;
+        let mut extents = Vec::new();
+        for extent in &file.extents {
+            let Extent::Recorded {
+                partition,
+                block,
+                len,
+            } = *extent
+            else {
+                return Err(invalid("the metadata file has to be recorded"));
+            };
+            extents.push((udf.block_offset(partition, block)?, len));
+        }
+        udf.partitions[reference] = Partition::Metadata(extents);
+    }
+
+    let file_set = udf.block_offset(
+        LittleEndian::read_u16(&volume[256..]),
+        LittleEndian::read_u32(&volume[252..]),
+    )?;
+    let file_set = udf.read_at(file_set, SECTOR_SIZE)?;
+    if tag_id(&file_set) != Some(TAG_FILE_SET) {
+        return Err(invalid("no file set descriptor"));
+    }
+    let root = udf.entry(
+        LittleEndian::read_u16(&file_set[408..]),
+        LittleEndian::read_u32(&file_set[404..]),
+    )?;
+    if root.file_type != FILE_TYPE_DIRECTORY {
+        return Err(invalid("the root isn't a directory"));
+    }
+    udf.walk(root, Path::new(""), 0)?;
+    Ok(Listing {
+        files: udf.files,
+        skipped: udf.skipped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarWriter;
+
+    // fills in the tag of descriptor `data`, with its checksum
+    fn tag(id: u16, mut data: Vec<u8>) -> Vec<u8> {
+        data.resize(data.len().max(16), 0);
+        data[..2].copy_from_slice(&id.to_le_bytes());
+        data[2] = 2;
+        data[4] = 0;
+        data[4] = data[..16].iter().fold(0_u8, |sum, &b| sum.wrapping_add(b));
+        data
+    }
+
+    fn put(data: &mut Vec<u8>, at: usize, bytes: &[u8]) {
+        if data.len() < at + bytes.len() {
+            data.resize(at + bytes.len(), 0);
+        }
+        data[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn short_ad(len: u32, block: u32) -> Vec<u8> {
+        [len.to_le_bytes(), block.to_le_bytes()].concat()
+    }
+
+    fn long_ad(len: u32, block: u32, partition: u16) -> Vec<u8> {
+        let mut ad = short_ad(len, block);
+        put(&mut ad, 8, &partition.to_le_bytes());
+        put(&mut ad, 15, &[0]);
+        ad
+    }
+
+    // a file entry (extended with `extended`) with descriptors `ads` of type `ad_type`
+    fn entry(file_type: u8, size: u64, ad_type: u16, ads: &[u8], extended: bool) -> Vec<u8> {
+        let (id, modified_at, attributes_at) = match extended {
+            true => (TAG_EXTENDED_FILE_ENTRY, 92, 208),
+            false => (TAG_FILE_ENTRY, 84, 168),
+        };
+        let mut data = Vec::new();
+        put(&mut data, 27, &[file_type]);
+        put(&mut data, 34, &ad_type.to_le_bytes());
+        put(&mut data, 56, &size.to_le_bytes());
+        // 2024-04-15 12:00 at utc+1
+        let zone = (1_u16 << 12) | 60;
+        let mut stamp = zone.to_le_bytes().to_vec();
+        stamp.extend_from_slice(&2024_i16.to_le_bytes());
+        stamp.extend_from_slice(&[4, 15, 12, 0, 0, 0, 0, 0]);
+        put(&mut data, modified_at, &stamp);
+        put(
+            &mut data,
+            attributes_at + 4,
+            &(ads.len() as u32).to_le_bytes(),
+        );
+        put(&mut data, attributes_at + 8, ads);
+        tag(id, data)
+    }
+
+    fn identifier(name: &str, characteristics: u8, block: u32, partition: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        put(&mut data, 18, &[characteristics]);
+        put(
+            &mut data,
+            20,
+            &long_ad(SECTOR_SIZE as u32, block, partition),
+        );
+        put(&mut data, 37, &[0]);
+        let name: Vec<u8> = match name {
+            "" => Vec::new(),
+            name if name.is_ascii() => [&[8], name.as_bytes()].concat(),
+            name => std::iter::once(16)
+                .chain(name.encode_utf16().flat_map(u16::to_be_bytes))
+                .collect(),
+        };
+        data[19] = name.len() as u8;
+        data.extend(name);
+        data.resize((data.len() + 3) & !3, 0);
+        tag(TAG_FILE_IDENTIFIER, data)
+    }
+
+    const PARTITION_START: usize = 262;
+    // where the metadata partition's blocks are in the physical one, when there is one
+    const METADATA_START: u32 = 30;
+
+    // a udf only image holding `mar`. with `metadata` the file entries and folders are in
+    // a metadata partition (reference 1) instead of straight in the physical one
+    fn disc(mar: &[u8], metadata: bool) -> Vec<u8> {
+        let mut image = vec![0; (PARTITION_START + 64) * SECTOR_SIZE as usize];
+        let mut sector = |n: usize, data: &[u8]| put(&mut image, n * SECTOR_SIZE as usize, data);
+        for (n, id) in [(16, b"BEA01"), (17, b"NSR02"), (18, b"TEA01")] {
+            sector(n, &[&[0][..], id, &[1]].concat());
+        }
+        let mut anchor = Vec::new();
+        put(&mut anchor, 16, &(4 * SECTOR_SIZE as u32).to_le_bytes());
+        put(&mut anchor, 20, &257_u32.to_le_bytes());
+        sector(256, &tag(TAG_ANCHOR, anchor));
+        let mut partition = Vec::new();
+        put(&mut partition, 188, &(PARTITION_START as u32).to_le_bytes());
+        put(&mut partition, 192, &64_u32.to_le_bytes());
+        sector(257, &tag(TAG_PARTITION, partition));
+
+        // folders and file entries are in `icb`, payloads are in the physical partition
+        let (icb, base) = match metadata {
+            true => (1, METADATA_START),
+            false => (0, 0),
+        };
+        let mut volume = Vec::new();
+        put(&mut volume, 212, &(SECTOR_SIZE as u32).to_le_bytes());
+        put(&mut volume, 248, &long_ad(SECTOR_SIZE as u32, 0, icb));
+        let mut maps = vec![1, 6, 1, 0, 0, 0];
+        if metadata {
+            let mut map = vec![2, 64];
+            put(&mut map, 5, METADATA_PARTITION);
+            put(&mut map, 40, &20_u32.to_le_bytes());
+            put(&mut map, 63, &[0]);
+            maps.extend(map);
+            // the metadata file, in the physical partition
+            let file = entry(
+                250,
+                16 * SECTOR_SIZE,
+                0,
+                &short_ad(16 * SECTOR_SIZE as u32, base),
+                false,
+            );
+            sector(PARTITION_START + 20, &file);
+        }
+        put(&mut volume, 264, &(maps.len() as u32).to_le_bytes());
+        put(&mut volume, 268, &(1 + metadata as u32).to_le_bytes());
+        put(&mut volume, 440, &maps);
+        sector(258, &tag(TAG_LOGICAL_VOLUME, volume));
+        sector(259, &tag(TAG_TERMINATOR, Vec::new()));
+
+        // blocks 0-9 are in `icb`, 10 on are payloads
+        let mut block = |n: u32, data: &[u8]| {
+            let n = if n < 10 { base + n } else { n };
+            put(
+                &mut image,
+                (PARTITION_START + n as usize) * SECTOR_SIZE as usize,
+                data,
+            )
+        };
+        let mut file_set = Vec::new();
+        put(&mut file_set, 400, &long_ad(SECTOR_SIZE as u32, 1, icb));
+        block(0, &tag(TAG_FILE_SET, file_set));
+        let root = [
+            identifier("", PARENT, 1, icb),
+            identifier("readme.txt", 0, 3, icb),
+            identifier("DATA", 0x02, 4, icb),
+            identifier("split.bin", 0, 7, icb),
+            identifier("deleted.bin", DELETED, 3, icb),
+            identifier("日本語.txt", 0, 8, icb),
+        ]
+        .concat();
+        block(
+            1,
+            &entry(
+                FILE_TYPE_DIRECTORY,
+                root.len() as u64,
+                0,
+                &short_ad(root.len() as u32, 2),
+                false,
+            ),
+        );
+        block(2, &root);
+        block(3, &entry(FILE_TYPE_FILE, 6, 3, b"hello!", false));
+        let data_dir = [
+            identifier("", PARENT, 1, icb),
+            identifier("update.mar", 0, 6, icb),
+            // back up to the root
+            identifier("loop", 0x02, 1, icb),
+        ]
+        .concat();
+        block(
+            4,
+            &entry(
+                FILE_TYPE_DIRECTORY,
+                data_dir.len() as u64,
+                0,
+                &short_ad(data_dir.len() as u32, 5),
+                false,
+            ),
+        );
+        block(5, &data_dir);
+        block(
+            6,
+            &entry(
+                FILE_TYPE_FILE,
+                mar.len() as u64,
+                1,
+                &long_ad(mar.len() as u32, 10, 0),
+                false,
+            ),
+        );
+        block(10, mar);
+        // two pieces that aren't next to each other
+        let split = [long_ad(SECTOR_SIZE as u32, 12, 0), long_ad(10, 14, 0)].concat();
+        block(
+            7,
+            &entry(FILE_TYPE_FILE, SECTOR_SIZE + 10, 1, &split, false),
+        );
+        block(
+            8,
+            &entry(FILE_TYPE_FILE, 7, 1, &long_ad(7, 9 + 10, 0), true),
+        );
+        block(19, b"unicode");
+        image
+    }
+
+    #[test]
+    fn udf_mount() {
+        let mar = MarWriter::new()
+            .add_file("data/a.bin", vec![9; 0x40])
+            .write(Vec::new())
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        for metadata in [false, true] {
+            let path = dir.path().join("disc.iso");
+            std::fs::write(&path, disc(&mar, metadata)).unwrap();
+            let archive = crate::mount(path).unwrap();
+            assert_eq!(archive.read(Path::new("readme.txt")).unwrap(), b"hello!");
+            assert_eq!(archive.read(Path::new("日本語.txt")).unwrap(), b"unicode");
+            assert_eq!(
+                archive
+                    .read(Path::new("DATA/update.mar/data/a.bin"))
+                    .unwrap(),
+                vec![9; 0x40]
+            );
+            assert_eq!(
+                archive.metadata(Path::new("readme.txt")).unwrap().modified,
+                Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_713_178_800))
+            );
+            assert!(!archive.exists(Path::new("deleted.bin")));
+            assert!(!archive.exists(Path::new("DATA/loop/readme.txt")));
+            // files in pieces are left out, with a warning
+            assert!(!archive.exists(Path::new("split.bin")));
+            assert!(matches!(
+                archive.warnings(),
+                [KArchiveWarning::SkippedEntry { reason, .. }] if reason.contains("split.bin")
+            ));
+        }
+    }
+
+    #[test]
+    fn udf_rejects() {
+        let dir = tempfile::tempdir().unwrap();
+        let mount = |image: Vec<u8>| {
+            let path = dir.path().join("disc.iso");
+            std::fs::write(&path, image).unwrap();
+            crate::mount(path)
+        };
+        let mut no_anchor = disc(b"", false);
+        no_anchor[256 * SECTOR_SIZE as usize] ^= 0xFF;
+        assert!(matches!(
+            mount(no_anchor),
+            Err(KArchiveError::InvalidHeader { format: "udf", .. })
+        ));
+        // the root folder claiming to be bigger than the whole image
+        let mut huge = disc(b"", false);
+        let root = (PARTITION_START + 1) * SECTOR_SIZE as usize;
+        huge[root + 56..root + 64].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            mount(huge),
+            Err(KArchiveError::InvalidHeader { format: "udf", .. })
+        ));
+    }
+}
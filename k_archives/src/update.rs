@@ -0,0 +1,107 @@
+use std::path::Path;
+
+use crate::common::source_len;
+
+/// Summary of a multi part update set (ULST or NAME mounts), pieced together from the
+/// names of the archives it references. Konami names these like `KFC-2024041500-01.mar`,
+/// so the game code and datecode are pulled from there when present.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpdateInfo {
+    /// Three character model code (KFC, M32, L44, ...)
+    pub game_code: Option<String>,
+    /// Ten digit datecode (YYYYMMDDRR)
+    pub datecode: Option<String>,
+    pub part_count: usize,
+    /// Combined size of all the parts in bytes
+    pub total_size: u64,
+}
+
+fn game_code(name: &str) -> Option<String> {
+    let code = name.get(..3)?;
+    let valid = code
+        .chars()
+        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        && code.chars().next()?.is_ascii_uppercase();
+    valid.then(|| code.to_string())
+}
+
+fn datecode(name: &str) -> Option<String> {
+    // first run of exactly 10 digits
+    let bytes = name.as_bytes();
+    let mut start = 0;
+    while start < bytes.len() {
+        if !bytes[start].is_ascii_digit() {
+            start += 1;
+            continue;
+        }
+        let end = start
+            + bytes[start..]
+                .iter()
+                .take_while(|b| b.is_ascii_digit())
+                .count();
+        if end - start == 10 {
+            return Some(name[start..end].to_string());
+        }
+        start = end;
+    }
+    None
+}
+
+impl UpdateInfo {
+    /// Game code and datecode from a single archive's file name, for archives that weren't
+    /// mounted through a descriptor. The part count and size are left at zero.
+    pub fn from_name(name: &str) -> Self {
+        UpdateInfo {
+            game_code: game_code(name),
+            datecode: datecode(name),
+            ..Default::default()
+        }
+    }
+
+    /// Builds the summary from (part file name, part size) pairs.
+    pub(crate) fn from_parts<'a>(parts: impl IntoIterator<Item = (&'a str, u64)>) -> Self {
+        let mut info = UpdateInfo::default();
+        for (name, size) in parts {
+            info.part_count += 1;
+            info.total_size += size;
+            if info.game_code.is_none() {
+                info.game_code = game_code(name);
+            }
+            if info.datecode.is_none() {
+                info.datecode = datecode(name);
+            }
+        }
+        info
+    }
+
+    // the info descriptor doesn't always list sizes, so fall back to what's on disk
+    pub(crate) fn part_size(path: &Path, declared: Option<u64>) -> u64 {
+        declared.or_else(|| source_len(path).ok()).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_info_from_names() {
+        let info = UpdateInfo::from_parts([
+            ("M32-2024041500-01.mar", 100),
+            ("M32-2024041500-02.mar", 50),
+        ]);
+        assert_eq!(info.game_code.as_deref(), Some("M32"));
+        assert_eq!(info.datecode.as_deref(), Some("2024041500"));
+        assert_eq!(info.part_count, 2);
+        assert_eq!(info.total_size, 150);
+
+        let info = UpdateInfo::from_parts([("update_12345.qar", 1)]);
+        assert_eq!(info.game_code, None);
+        assert_eq!(info.datecode, None);
+
+        let info = UpdateInfo::from_name("KFC-2023120500-full.zip");
+        assert_eq!(info.game_code.as_deref(), Some("KFC"));
+        assert_eq!(info.datecode.as_deref(), Some("2023120500"));
+        assert_eq!(info.part_count, 0);
+    }
+}
@@ -0,0 +1,681 @@
+//! Packing archives. Every writer implements `ArchiveBuilder`, so code that just needs
+//! "whatever format this game expects" can pick one with `builder_for` and not care which.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+pub use crate::arc::ArcWriter;
+pub use crate::bar::BarWriter;
+pub use crate::cab::CabWriter;
+use crate::common::*;
+pub use crate::convert::TargetFormat;
+use crate::info::InfoWriter;
+pub use crate::lst::LstSetBuilder;
+pub use crate::mar::MarWriter;
+pub use crate::qar::QarWriter;
+use crate::{hash_reader, ChecksumType};
+use thiserror::Error;
+
+// what reproducible packing stamps files with, in seconds since the unix epoch.
+// 1980-01-01, the earliest date cabs (and dos timestamps in general) can hold
+pub(crate) const REPRODUCIBLE_TIMESTAMP: u64 = 315_532_800;
+
+/// The part all archive writers have in common. Entries are only read once `finish` is
+/// called, so adding paths and mounted entries is cheap.
+pub trait ArchiveBuilder<'a> {
+    /// Adds a file from an in memory buffer.
+    fn add_file(&mut self, name: &Path, data: Vec<u8>);
+    /// Adds a file that is read from `source` when the archive gets written.
+    fn add_path(&mut self, name: &Path, source: &Path);
+    /// Adds `entry` of a mounted archive, streamed out of it when the archive gets written.
+    fn add_entry(&mut self, name: &Path, archive: &'a KArchive, entry: &Path);
+    /// Recursively adds every file under `dir`, named relative to `dir`.
+    fn add_dir(&mut self, dir: &Path) -> Result<(), KArchiveError> {
+        for (name, path) in walk_dir(dir)? {
+            self.add_path(&name, &path);
+        }
+        Ok(())
+    }
+    /// Makes the output byte for byte the same every time the same files are packed: entries
+    /// go in sorted by name (which every writer already does), padding is zeroed (likewise)
+    /// and timestamps are fixed. Formats without timestamps have nothing to change.
+    fn set_reproducible(&mut self, _reproducible: bool) {}
+    /// Writes everything out to `dest`. That's the archive itself for single file formats,
+    /// the output folder for directories and the ULST for lst sets (parts go next to it).
+    /// Returns the archives that were written, which for lst sets are the parts and for
+    /// directories is nothing.
+    fn finish(&self, dest: &Path) -> Result<Vec<PathBuf>, KArchiveError>;
+    /// Like `finish`, but adds the entries to the existing archive at `dest` without
+    /// rewriting what's already in it. Only mar and qar can be appended to.
+    fn append(&self, dest: &Path) -> Result<Vec<PathBuf>, KArchiveError> {
+        Err(KArchiveError::WriteError(format!(
+            "{} can't be appended to, only mar and qar can",
+            dest.display()
+        )))
+    }
+    /// Mounts what `finish` wrote to `dest` and checks every entry reads back with the
+    /// same size and contents as what was packed.
+    fn verify(&self, dest: &Path) -> Result<VerifyReport, KArchiveError>;
+}
+
+/// Something `ArchiveBuilder::verify` found wrong with a written archive.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum VerifyProblem {
+    #[error("{} is missing from the written archive", .0.display())]
+    Missing(PathBuf),
+    #[error("{} is in the written archive but wasn't packed", .0.display())]
+    Unexpected(PathBuf),
+    #[error("size mismatch for {}: packed {expected}, read back {actual}", path.display())]
+    SizeMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("{} reads back with different contents", .0.display())]
+    ContentMismatch(PathBuf),
+}
+
+/// What `ArchiveBuilder::verify` found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Entries that read back exactly as packed.
+    pub verified: usize,
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// compares what got packed against what mounting the output gives back, by size then md5
+fn verify_written(
+    sources: &BTreeMap<PathBuf, EntrySource>,
+    dest: &Path,
+) -> Result<VerifyReport, KArchiveError> {
+    let written = crate::mount(dest.to_path_buf())?;
+    let mut report = VerifyReport::default();
+    for name in written.list_files() {
+        // the cab writer adds its filelist on top of what was packed
+        if !sources.contains_key(&name) && !name.starts_with(crate::cab::CAB_MEMBER_PREFIX) {
+            report.problems.push(VerifyProblem::Unexpected(name));
+        }
+    }
+    for (name, source) in sources {
+        if !written.exists(name) {
+            report.problems.push(VerifyProblem::Missing(name.clone()));
+            continue;
+        }
+        let mut file = written.open(name)?;
+        let (expected, actual) = (source.size()?, file.size());
+        if expected != actual {
+            report.problems.push(VerifyProblem::SizeMismatch {
+                path: name.clone(),
+                expected,
+                actual,
+            });
+        } else if hash_reader(&mut source.reader()?, ChecksumType::Md5)?
+            != hash_reader(&mut file, ChecksumType::Md5)?
+        {
+            report
+                .problems
+                .push(VerifyProblem::ContentMismatch(name.clone()));
+        } else {
+            report.verified += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Packing settings matching what a game's updater expects, so nobody has to know which
+/// format and quirks that is.
+#[derive(Debug, Clone)]
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub target: TargetFormat,
+}
+
+/// Every preset `preset` knows about.
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "gitadora-m32",
+        description:
+            "M32 encrypted mar, as gitadora updates ship. The file name has to contain M32",
+        target: TargetFormat::Mar { encrypt: true },
+    },
+    Preset {
+        name: "gitadora",
+        description: "plain mar",
+        target: TargetFormat::Mar { encrypt: false },
+    },
+    Preset {
+        name: "iidx",
+        description: "bar with 256 byte names",
+        target: TargetFormat::Bar { short_names: false },
+    },
+    Preset {
+        name: "popn-m39",
+        description: "bar with the 252 byte names pop'n music (M39A) bars have",
+        target: TargetFormat::Bar { short_names: true },
+    },
+    Preset {
+        name: "popn-arc",
+        description: "lz77 compressed pop'n music arc",
+        target: TargetFormat::Arc { level: 6 },
+    },
+];
+
+/// Looks up a preset by name, case insensitive.
+pub fn preset(name: &str) -> Option<&'static Preset> {
+    PRESETS
+        .iter()
+        .find(|preset| preset.name.eq_ignore_ascii_case(name))
+}
+
+/// The writer for `target`, set up with the target's options.
+pub fn builder_for<'a>(target: &TargetFormat) -> Box<dyn ArchiveBuilder<'a> + 'a> {
+    match target {
+        TargetFormat::Mar { encrypt } => {
+            let mut mar = MarWriter::new();
+            mar.set_encryption(*encrypt);
+            Box::new(mar)
+        }
+        TargetFormat::Cab { compress } => {
+            let mut cab = CabWriter::new();
+            cab.set_compression(*compress);
+            Box::new(cab)
+        }
+        TargetFormat::Qar => Box::new(QarWriter::new()),
+        TargetFormat::Bar { short_names } => {
+            let mut bar = BarWriter::new();
+            bar.set_short_names(*short_names);
+            Box::new(bar)
+        }
+        TargetFormat::Arc { level } => {
+            let mut arc = ArcWriter::new();
+            arc.set_compression_level(*level);
+            Box::new(arc)
+        }
+        TargetFormat::Directory => Box::new(DirectoryWriter::new()),
+        TargetFormat::LstSet {
+            part_size,
+            checksum_type,
+        } => {
+            let mut set = LstSetBuilder::new(*part_size);
+            set.set_checksum_type(*checksum_type);
+            Box::new(set)
+        }
+    }
+}
+
+/// Writes a NAME/info descriptor at `dest` referencing `archives`, with their sizes and
+/// `checksum_type` checksums, like the ones some updates ship next to their archives.
+/// `name` goes in the `NAME` line. The archives have to be in the same folder as `dest`
+/// for the descriptor to mount.
+pub fn write_info(
+    name: &str,
+    archives: &[PathBuf],
+    checksum_type: ChecksumType,
+    dest: &Path,
+) -> Result<(), KArchiveError> {
+    let mut info = InfoWriter::new(name);
+    for archive in archives {
+        info.add_part(archive, Some(checksum_type))?;
+    }
+    info.write(BufWriter::new(File::create(dest)?))?;
+    Ok(())
+}
+
+/// A change `repack` makes to an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepackOp {
+    /// Adds a new entry from a file on disk. Fails if the entry already exists.
+    Add { name: PathBuf, source: PathBuf },
+    /// Swaps the contents of an existing entry for a file on disk.
+    Replace { name: PathBuf, source: PathBuf },
+    /// Leaves an existing entry out.
+    Remove(PathBuf),
+}
+
+/// Fills `builder` with the entries of `src` after applying `ops`, ready to `finish`.
+/// Unchanged entries are streamed straight out of `src` when the archive gets written,
+/// so nothing is read into memory here. A source cab's own members are left out, like
+/// with `convert`.
+pub fn repack<'a>(
+    src: &'a KArchive,
+    ops: &[RepackOp],
+    builder: &mut dyn ArchiveBuilder<'a>,
+) -> Result<(), KArchiveError> {
+    let mut sources: BTreeMap<PathBuf, Option<PathBuf>> = src
+        .list_files()
+        .into_iter()
+        .filter(|name| !name.starts_with(crate::cab::CAB_MEMBER_PREFIX))
+        .map(|name| (name, None))
+        .collect();
+    let missing =
+        |name: &Path| KArchiveError::WriteError(format!("{} isn't in the archive", name.display()));
+    for op in ops {
+        match op {
+            RepackOp::Add { name, source } => {
+                if sources.contains_key(name) {
+                    return Err(KArchiveError::WriteError(format!(
+                        "{} is already in the archive",
+                        name.display()
+                    )));
+                }
+                sources.insert(name.clone(), Some(source.clone()));
+            }
+            RepackOp::Replace { name, source } => {
+                *sources.get_mut(name).ok_or_else(|| missing(name))? = Some(source.clone());
+            }
+            RepackOp::Remove(name) => {
+                sources.remove(name).ok_or_else(|| missing(name))?;
+            }
+        }
+    }
+    for (name, source) in sources {
+        match source {
+            Some(source) => builder.add_path(&name, &source),
+            None => builder.add_entry(&name, src, &name),
+        }
+    }
+    Ok(())
+}
+
+/// Lays files out under a folder, the same way they'd be extracted.
+#[derive(Default)]
+pub struct DirectoryWriter<'a> {
+    files: BTreeMap<PathBuf, EntrySource<'a>>,
+    reproducible: bool,
+}
+
+impl<'a> DirectoryWriter<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gives every file the same fixed modification time instead of the current time.
+    /// Defaults to false.
+    pub fn set_reproducible(&mut self, reproducible: bool) -> &mut Self {
+        self.reproducible = reproducible;
+        self
+    }
+
+    /// Writes every file under `out_dir`, creating folders as needed. Names are joined with
+    /// `join_under`, so absolute ones land inside `out_dir` and ones that would leave it are
+    /// refused.
+    pub fn write(&self, out_dir: &Path) -> Result<(), KArchiveError> {
+        for (name, source) in &self.files {
+            let out = crate::paths::join_under(out_dir, name)?;
+            if let Some(parent) = out.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut wtr = BufWriter::new(File::create(&out)?);
+            source.copy_to(&mut wtr)?;
+            wtr.flush()?;
+            if self.reproducible {
+                wtr.get_ref()
+                    .set_modified(UNIX_EPOCH + Duration::from_secs(REPRODUCIBLE_TIMESTAMP))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ArchiveBuilder<'a> for DirectoryWriter<'a> {
+    fn add_file(&mut self, name: &Path, data: Vec<u8>) {
+        self.files.insert(name.into(), EntrySource::Buffer(data));
+    }
+
+    fn add_path(&mut self, name: &Path, source: &Path) {
+        self.files
+            .insert(name.into(), EntrySource::Path(source.into()));
+    }
+
+    fn add_entry(&mut self, name: &Path, archive: &'a KArchive, entry: &Path) {
+        self.files
+            .insert(name.into(), EntrySource::Archive(archive, entry.into()));
+    }
+
+    fn set_reproducible(&mut self, reproducible: bool) {
+        DirectoryWriter::set_reproducible(self, reproducible);
+    }
+
+    fn finish(&self, dest: &Path) -> Result<Vec<PathBuf>, KArchiveError> {
+        self.write(dest)?;
+        Ok(Vec::new())
+    }
+
+    fn verify(&self, dest: &Path) -> Result<VerifyReport, KArchiveError> {
+        verify_written(&self.files, dest)
+    }
+}
+
+impl<'a> ArchiveBuilder<'a> for MarWriter<'a> {
+    fn add_file(&mut self, name: &Path, data: Vec<u8>) {
+        self.add_source(name.into(), EntrySource::Buffer(data));
+    }
+
+    fn add_path(&mut self, name: &Path, source: &Path) {
+        self.add_source(name.into(), EntrySource::Path(source.into()));
+    }
+
+    fn add_entry(&mut self, name: &Path, archive: &'a KArchive, entry: &Path) {
+        self.add_source(name.into(), EntrySource::Archive(archive, entry.into()));
+    }
+
+    fn finish(&self, dest: &Path) -> Result<Vec<PathBuf>, KArchiveError> {
+        self.write(BufWriter::new(File::create(dest)?))?.flush()?;
+        Ok(vec![dest.to_path_buf()])
+    }
+
+    fn append(&self, dest: &Path) -> Result<Vec<PathBuf>, KArchiveError> {
+        MarWriter::append(self, dest)?;
+        Ok(vec![dest.to_path_buf()])
+    }
+
+    fn verify(&self, dest: &Path) -> Result<VerifyReport, KArchiveError> {
+        verify_written(self.sources(), dest)
+    }
+}
+
+impl<'a> ArchiveBuilder<'a> for QarWriter<'a> {
+    fn add_file(&mut self, name: &Path, data: Vec<u8>) {
+        self.add_source(name.into(), EntrySource::Buffer(data));
+    }
+
+    fn add_path(&mut self, name: &Path, source: &Path) {
+        self.add_source(name.into(), EntrySource::Path(source.into()));
+    }
+
+    fn add_entry(&mut self, name: &Path, archive: &'a KArchive, entry: &Path) {
+        self.add_source(name.into(), EntrySource::Archive(archive, entry.into()));
+    }
+
+    fn finish(&self, dest: &Path) -> Result<Vec<PathBuf>, KArchiveError> {
+        self.write(BufWriter::new(File::create(dest)?))?.flush()?;
+        Ok(vec![dest.to_path_buf()])
+    }
+
+    fn append(&self, dest: &Path) -> Result<Vec<PathBuf>, KArchiveError> {
+        QarWriter::append(self, dest)?;
+        Ok(vec![dest.to_path_buf()])
+    }
+
+    fn verify(&self, dest: &Path) -> Result<VerifyReport, KArchiveError> {
+        verify_written(self.sources(), dest)
+    }
+}
+
+impl<'a> ArchiveBuilder<'a> for CabWriter<'a> {
+    fn add_file(&mut self, name: &Path, data: Vec<u8>) {
+        self.add_source(name.into(), EntrySource::Buffer(data));
+    }
+
+    fn add_path(&mut self, name: &Path, source: &Path) {
+        self.add_source(name.into(), EntrySource::Path(source.into()));
+    }
+
+    fn add_entry(&mut self, name: &Path, archive: &'a KArchive, entry: &Path) {
+        self.add_source(name.into(), EntrySource::Archive(archive, entry.into()));
+    }
+
+    fn set_reproducible(&mut self, reproducible: bool) {
+        CabWriter::set_reproducible(self, reproducible);
+    }
+
+    fn finish(&self, dest: &Path) -> Result<Vec<PathBuf>, KArchiveError> {
+        self.write(File::create(dest)?)?;
+        Ok(vec![dest.to_path_buf()])
+    }
+
+    fn verify(&self, dest: &Path) -> Result<VerifyReport, KArchiveError> {
+        verify_written(self.sources(), dest)
+    }
+}
+
+impl<'a> ArchiveBuilder<'a> for BarWriter<'a> {
+    fn add_file(&mut self, name: &Path, data: Vec<u8>) {
+        self.add_source(name.into(), EntrySource::Buffer(data));
+    }
+
+    fn add_path(&mut self, name: &Path, source: &Path) {
+        self.add_source(name.into(), EntrySource::Path(source.into()));
+    }
+
+    fn add_entry(&mut self, name: &Path, archive: &'a KArchive, entry: &Path) {
+        self.add_source(name.into(), EntrySource::Archive(archive, entry.into()));
+    }
+
+    fn finish(&self, dest: &Path) -> Result<Vec<PathBuf>, KArchiveError> {
+        self.write(BufWriter::new(File::create(dest)?))?.flush()?;
+        Ok(vec![dest.to_path_buf()])
+    }
+
+    fn verify(&self, dest: &Path) -> Result<VerifyReport, KArchiveError> {
+        verify_written(self.sources(), dest)
+    }
+}
+
+impl<'a> ArchiveBuilder<'a> for ArcWriter<'a> {
+    fn add_file(&mut self, name: &Path, data: Vec<u8>) {
+        self.add_source(name.into(), EntrySource::Buffer(data));
+    }
+
+    fn add_path(&mut self, name: &Path, source: &Path) {
+        self.add_source(name.into(), EntrySource::Path(source.into()));
+    }
+
+    fn add_entry(&mut self, name: &Path, archive: &'a KArchive, entry: &Path) {
+        self.add_source(name.into(), EntrySource::Archive(archive, entry.into()));
+    }
+
+    fn finish(&self, dest: &Path) -> Result<Vec<PathBuf>, KArchiveError> {
+        self.write(BufWriter::new(File::create(dest)?))?.flush()?;
+        Ok(vec![dest.to_path_buf()])
+    }
+
+    fn verify(&self, dest: &Path) -> Result<VerifyReport, KArchiveError> {
+        verify_written(self.sources(), dest)
+    }
+}
+
+impl<'a> ArchiveBuilder<'a> for LstSetBuilder<'a> {
+    fn add_file(&mut self, name: &Path, data: Vec<u8>) {
+        self.add_source(name.into(), EntrySource::Buffer(data));
+    }
+
+    fn add_path(&mut self, name: &Path, source: &Path) {
+        self.add_source(name.into(), EntrySource::Path(source.into()));
+    }
+
+    fn add_entry(&mut self, name: &Path, archive: &'a KArchive, entry: &Path) {
+        self.add_source(name.into(), EntrySource::Archive(archive, entry.into()));
+    }
+
+    // parts are named after the ULST, eg. update.lst gets update-001.mar and so on
+    fn finish(&self, dest: &Path) -> Result<Vec<PathBuf>, KArchiveError> {
+        let stem = dest
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or(KArchiveError::Other("lst destination needs a file name"))?;
+        let (_, parts) = self.write_parts(dest.parent().unwrap_or(Path::new("")), stem)?;
+        Ok(parts)
+    }
+
+    fn verify(&self, dest: &Path) -> Result<VerifyReport, KArchiveError> {
+        verify_written(self.sources(), dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_for_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        std::fs::create_dir_all(source.join("sub")).unwrap();
+        std::fs::write(source.join("sub/b.bin"), [2; 0x20]).unwrap();
+        let targets = [
+            (TargetFormat::Mar { encrypt: false }, "out.mar"),
+            (TargetFormat::Cab { compress: false }, "out.cab"),
+            (TargetFormat::Arc { level: 9 }, "out.arc"),
+            (preset("POPN-M39").unwrap().target.clone(), "out.bar"),
+            (TargetFormat::Qar, "out.qar"),
+            (TargetFormat::Directory, "out"),
+        ];
+        for (target, name) in targets {
+            let dest = dir.path().join(name);
+            let mut builder = builder_for(&target);
+            builder.add_file(Path::new("data/a.bin"), vec![1; 0x10]);
+            builder.add_dir(&source).unwrap();
+            builder.finish(&dest).unwrap();
+            let report = builder.verify(&dest).unwrap();
+            assert!(report.is_ok(), "{:?}", report);
+            assert_eq!(report.verified, 2);
+            let archive = crate::mount(dest).unwrap();
+            assert_eq!(
+                archive.read(Path::new("data/a.bin")).unwrap(),
+                vec![1; 0x10]
+            );
+            assert_eq!(archive.read(Path::new("sub/b.bin")).unwrap(), vec![2; 0x20]);
+        }
+    }
+
+    #[test]
+    fn directory_stays_under_dest() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("out");
+        let backslashed = Path::new("\\windows\\rooted.bin");
+        let mut writer = DirectoryWriter::new();
+        writer.add_file(Path::new("/etc/rooted.bin"), vec![1; 0x10]);
+        writer.add_file(backslashed, vec![2; 0x10]);
+        writer.write(&dest).unwrap();
+        assert_eq!(
+            std::fs::read(dest.join("etc/rooted.bin")).unwrap(),
+            vec![1; 0x10]
+        );
+        // only a separator on windows, elsewhere it's part of the name
+        let backslashed = match cfg!(windows) {
+            true => dest.join("windows/rooted.bin"),
+            false => dest.join(backslashed.file_name().unwrap()),
+        };
+        assert_eq!(std::fs::read(backslashed).unwrap(), vec![2; 0x10]);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let mut writer = DirectoryWriter::new();
+        writer.add_file(Path::new("data/../../escaped.bin"), vec![3; 0x10]);
+        assert!(matches!(
+            writer.write(&dest),
+            Err(KArchiveError::UnsafePath { .. })
+        ));
+        assert!(!dir.path().join("escaped.bin").exists());
+    }
+
+    #[test]
+    fn repack_ops() {
+        let dir = tempfile::tempdir().unwrap();
+        let src_path = dir.path().join("src.mar");
+        MarWriter::new()
+            .add_file("data/keep.bin", vec![1; 0x10])
+            .add_file("data/swap.bin", vec![2; 0x10])
+            .add_file("data/drop.bin", vec![3; 0x10])
+            .write(File::create(&src_path).unwrap())
+            .unwrap();
+        let src = crate::mount(src_path).unwrap();
+        let new_file = dir.path().join("new.bin");
+        std::fs::write(&new_file, [4; 0x20]).unwrap();
+        let ops = [
+            RepackOp::Replace {
+                name: "data/swap.bin".into(),
+                source: new_file.clone(),
+            },
+            RepackOp::Add {
+                name: "data/added.bin".into(),
+                source: new_file.clone(),
+            },
+            RepackOp::Remove("data/drop.bin".into()),
+        ];
+        let dest = dir.path().join("out.mar");
+        let mut builder = builder_for(&TargetFormat::Mar { encrypt: false });
+        repack(&src, &ops, builder.as_mut()).unwrap();
+        builder.finish(&dest).unwrap();
+
+        let repacked = crate::mount(dest).unwrap();
+        let mut files = repacked.list_files();
+        files.sort();
+        assert_eq!(
+            files,
+            ["data/added.bin", "data/keep.bin", "data/swap.bin"].map(PathBuf::from)
+        );
+        assert_eq!(
+            repacked.read(Path::new("data/keep.bin")).unwrap(),
+            vec![1; 0x10]
+        );
+        assert_eq!(
+            repacked.read(Path::new("data/swap.bin")).unwrap(),
+            vec![4; 0x20]
+        );
+
+        let bad = [RepackOp::Remove("data/missing.bin".into())];
+        let mut builder = builder_for(&TargetFormat::Directory);
+        assert!(repack(&src, &bad, builder.as_mut()).is_err());
+    }
+
+    #[test]
+    fn verify_catches_bad_output() {
+        let dir = tempfile::tempdir().unwrap();
+        // encrypted mar files are only decrypted when their name says M32, so this one
+        // reads back as gibberish
+        let dest = dir.path().join("plain.mar");
+        let mut builder = builder_for(&TargetFormat::Mar { encrypt: true });
+        builder.add_file(Path::new("data/a.bin"), vec![1; 0x10]);
+        builder.finish(&dest).unwrap();
+        let report = builder.verify(&dest).unwrap();
+        assert_eq!(
+            report.problems,
+            [VerifyProblem::ContentMismatch("data/a.bin".into())]
+        );
+
+        builder.add_file(Path::new("data/b.bin"), vec![2; 0x10]);
+        let report = builder.verify(&dest).unwrap();
+        assert!(report
+            .problems
+            .contains(&VerifyProblem::Missing("data/b.bin".into())));
+    }
+
+    #[test]
+    fn info_for_set() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = builder_for(&TargetFormat::LstSet {
+            part_size: 0x100,
+            checksum_type: ChecksumType::Md5,
+        });
+        for i in 0..4 {
+            builder.add_file(Path::new(&format!("data/{}.bin", i)), vec![i; 0xC0]);
+        }
+        let parts = builder.finish(&dir.path().join("SET.lst")).unwrap();
+        assert_eq!(parts.len(), 4);
+        let info = dir.path().join("SET.info");
+        write_info("SET", &parts, ChecksumType::Sha256, &info).unwrap();
+
+        let strict = MountOptions {
+            verify_checksums: ChecksumPolicy::Error,
+            require_all_parts: true,
+            ..Default::default()
+        };
+        let archive = crate::mount_with_options(info, &strict).unwrap();
+        assert_eq!(archive.list_files().len(), 4);
+        assert_eq!(
+            archive.read(Path::new("data/3.bin")).unwrap(),
+            vec![3; 0xC0]
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::io::{BufReader, Cursor};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use ::zip::{CompressionMethod, ZipArchive};
+
+use crate::common::*;
+use crate::compressed::Compression;
+
+fn zip_error(error: ::zip::result::ZipError) -> KArchiveError {
+    match error {
+        ::zip::result::ZipError::Io(e) => e.into(),
+        e => KArchiveError::InvalidHeader {
+            format: "zip",
+            reason: e.to_string(),
+        },
+    }
+}
+
+/// Lists the members of a zip. Stored members are read straight out of the zip and
+/// deflated ones are inflated as they're read, like any other compressed entry.
+pub(crate) fn parse(path: PathBuf, options: &MountOptions) -> Result<KArchive, KArchiveError> {
+    parse_from(path, None, options)
+}
+
+/// Parses from `buffer` when there is one, otherwise straight from the file at `path`.
+pub(crate) fn parse_from(
+    path: PathBuf,
+    buffer: Option<Vec<u8>>,
+    options: &MountOptions,
+) -> Result<KArchive, KArchiveError> {
+    let file = match &buffer {
+        Some(buf) => InternalFile::Buffer(Cursor::new(buf)),
+        None => InternalFile::open(&path)?,
+    };
+    let mut zip = ZipArchive::new(BufReader::new(file)).map_err(zip_error)?;
+    let end = archive_len(&buffer, &path)?;
+    let mut files = HashMap::new();
+    for index in 0..zip.len() {
+        let member = zip.by_index_raw(index).map_err(zip_error)?;
+        if member.is_dir() {
+            continue;
+        }
+        let name = member.name().trim_start_matches('/').to_string();
+        check_entry_name("zip", index, &name)?;
+        let (offset, packed_size) = (member.data_start(), member.compressed_size());
+        let compression = match member.compression() {
+            CompressionMethod::Stored => None,
+            CompressionMethod::Deflated => Some(Compression::Deflate { packed_size }),
+            method => {
+                return Err(invalid_entry(
+                    "zip",
+                    index,
+                    &name,
+                    format!("{} compression isn't supported", method),
+                ))
+            }
+        };
+        // zip members aren't allowed to be partial, the central directory is at the end
+        payload_available("zip", index, &name, (offset, packed_size), end, options)?;
+        files.insert(
+            name.into(),
+            KFileInfo {
+                size: member.size(),
+                offset,
+                compression,
+                // dos timestamps have no time zone, utc is as good a guess as any
+                modified: member.last_modified().to_time().ok().map(SystemTime::from),
+                mode: member.unix_mode(),
+                ..Default::default()
+            },
+        );
+    }
+    Ok(KArchive::new(path, files, buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarWriter;
+    use ::zip::{write::FileOptions, ZipWriter};
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    #[test]
+    fn zip_mount() {
+        let mar = MarWriter::new()
+            .add_file("data/a.bin", vec![6; 0x40])
+            .write(Vec::new())
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.zip");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("readme.txt", stored).unwrap();
+        zip.write_all(b"plain").unwrap();
+        zip.start_file("updates/update.mar", FileOptions::default())
+            .unwrap();
+        zip.write_all(&mar).unwrap();
+        zip.finish().unwrap();
+
+        let in_memory = crate::mount_bytes(
+            "bundle.zip".into(),
+            std::fs::read(&path).unwrap(),
+            &MountOptions::default(),
+        )
+        .unwrap();
+        let archive = crate::mount(path).unwrap();
+        assert_eq!(in_memory.list_files().len(), archive.list_files().len());
+        assert_eq!(
+            in_memory
+                .read(Path::new("updates/update.mar/data/a.bin"))
+                .unwrap(),
+            vec![6; 0x40]
+        );
+        assert_eq!(archive.read(Path::new("readme.txt")).unwrap(), b"plain");
+        assert_eq!(archive.read(Path::new("updates/update.mar")).unwrap(), mar);
+        let mut member = archive.open(Path::new("updates/update.mar")).unwrap();
+        let mut tail = [0; 4];
+        member.seek(SeekFrom::End(-4)).unwrap();
+        member.read_exact(&mut tail).unwrap();
+        assert_eq!(tail, mar[mar.len() - 4..]);
+        member.seek(SeekFrom::Start(0)).unwrap();
+        member.read_exact(&mut tail).unwrap();
+        assert_eq!(tail, mar[..4]);
+        // archives in a bundle are mounted without having to ask
+        assert_eq!(
+            archive
+                .read(Path::new("updates/update.mar/data/a.bin"))
+                .unwrap(),
+            vec![6; 0x40]
+        );
+    }
+}
@@ -0,0 +1,85 @@
+/* C interface to k_archives. Link against the k_archives_ffi shared or static library
+ * (cargo build -p k_archives_ffi --release).
+ *
+ * Functions that can fail return NULL or -1 and leave a message for
+ * k_archives_last_error(). Open files keep their archive alive, so an archive can be
+ * freed while its files are still being read. Paths are utf8 with / between folders.
+ */
+#ifndef K_ARCHIVES_H
+#define K_ARCHIVES_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Archive k_archive;
+typedef struct File k_file;
+
+/* The message for the last call on this thread that failed, or NULL. Valid until the
+ * next call that fails. */
+const char *k_archives_last_error(void);
+
+/* Mounts the archive at path (any format k_archives reads, a ULST or info descriptor
+ * brings in its whole set). NULL on failure. */
+k_archive *k_archives_mount(const char *path);
+void k_archives_free(k_archive *archive);
+
+/* The paths of every entry, sorted. The count is written to count. NULL on failure. */
+char **k_archives_list(const k_archive *archive, size_t *count);
+void k_archives_free_list(char **list, size_t count);
+
+/* Opens an entry for reading, deciphered and decompressed as it's read. NULL if there's
+ * no such entry. */
+k_file *k_archives_open(const k_archive *archive, const char *path);
+/* The entry's size once decompressed. */
+uint64_t k_archives_size(const k_file *file);
+/* Bytes read into buf (0 at the end), or -1. */
+int64_t k_archives_read(k_file *file, void *buf, size_t len);
+/* whence is SEEK_SET, SEEK_CUR or SEEK_END. The new position, or -1. */
+int64_t k_archives_seek(k_file *file, int64_t offset, int whence);
+void k_archives_close(k_file *file);
+
+/* Deciphers the len byte payload of an M32 mar entry from data into out (which can be
+ * data). name is the entry's path as the archive stored it, the key comes from it. The
+ * cipher works both ways, so this also encrypts. 0 on success, -1 otherwise. */
+int k_archives_decrypt_mar(const char *name, const uint8_t *data, uint8_t *out, size_t len);
+
+/* For loader hooks, built with --features hooks. Archives are mounted into a vfs under the
+ * folder the game expects their files in, and paths the game opens are looked up in it the
+ * way Windows and avs look them up (any case, either slash). A hook that gets -1 from
+ * k_vfs_open passes the call on to the function it replaced, and k_vfs_is_open tells it
+ * which descriptors are its own for the read, seek and close that follow. Descriptors start
+ * at 0x4b000000 so they don't collide with real ones. */
+#ifdef K_ARCHIVES_HOOKS
+typedef struct KVfs k_vfs;
+
+k_vfs *k_vfs_new(void);
+/* Open descriptors stay readable after their vfs is freed. */
+void k_vfs_free(k_vfs *vfs);
+/* Mounts the archive so its entries show up under prefix (eg. "data", NULL for the top).
+ * Later mounts win over earlier ones for the same path. 0 on success, -1 otherwise. */
+int k_vfs_mount(const k_vfs *vfs, const char *archive, const char *prefix);
+/* A descriptor for path, or -1 if it isn't in any mounted archive. */
+int k_vfs_open(const k_vfs *vfs, const char *path);
+/* Writes the size of path to size. 0 if it's in a mounted archive, -1 if not. */
+int k_vfs_stat(const k_vfs *vfs, const char *path, uint64_t *size);
+/* 1 if fd came from k_vfs_open and isn't closed yet, 0 otherwise. */
+int k_vfs_is_open(int fd);
+/* Bytes read into buf (0 at the end), or -1. */
+int64_t k_vfs_read(int fd, void *buf, size_t len);
+/* whence is SEEK_SET, SEEK_CUR or SEEK_END. The new position, or -1. */
+int64_t k_vfs_seek(int fd, int64_t offset, int whence);
+/* The file's size once decompressed, or -1. */
+int64_t k_vfs_size(int fd);
+/* 0, or -1 if fd isn't open. */
+int k_vfs_close(int fd);
+#endif
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
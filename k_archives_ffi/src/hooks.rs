@@ -0,0 +1,307 @@
+//! Functions shaped like the file APIs game loaders hook (avs_fs_open, CreateFileW, fopen and
+//! friends), behind the `hooks` feature. Archives are mounted into a `KVfs` under the folder
+//! the game expects their files in, then a hook only has to ask it for each path the game
+//! opens: a descriptor comes back for files that are in an archive, -1 for everything else,
+//! which the hook passes on to the function it replaced.
+//!
+//! Paths are matched the way Windows and avs match them, case insensitively and with either
+//! slash. Descriptors are plain ints like the ones games deal in, starting from 0x4b000000 so
+//! they don't collide with real ones, and `k_vfs_is_open` tells a hook which are its own.
+
+use crate::{guard, open_file, path_arg, seek_from, File};
+use k_archives::KArchive;
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int};
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+// 'K', well above the descriptors and handles games are given by the OS
+const FIRST_DESCRIPTOR: c_int = 0x4b00_0000;
+
+struct Mount {
+    // normalized, "" for the top of the tree
+    prefix: String,
+    archive: Arc<KArchive>,
+    // normalized entry paths to the paths the archive stores them under
+    entries: HashMap<String, PathBuf>,
+}
+
+/// Archives mapped into a game's file tree.
+pub struct KVfs {
+    mounts: RwLock<Vec<Mount>>,
+}
+
+// lowercase with / between folders and without empty, . or .. components, so the many
+// spellings games use for one path all come out the same
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/").to_lowercase()
+}
+
+impl KVfs {
+    // the archive and entry `path` names, going by the most recent mount that has it
+    fn find(&self, path: &str) -> Option<(Arc<KArchive>, PathBuf)> {
+        let path = normalize(path);
+        let mounts = self.mounts.read().unwrap();
+        mounts.iter().rev().find_map(|mount| {
+            let rest = match mount.prefix.is_empty() {
+                true => path.as_str(),
+                false => path.strip_prefix(&mount.prefix)?.strip_prefix('/')?,
+            };
+            let entry = mount.entries.get(rest)?;
+            Some((mount.archive.clone(), entry.clone()))
+        })
+    }
+}
+
+// every open descriptor. not kept per KVfs, since read, seek and close only get the
+// descriptor
+fn files() -> &'static Mutex<HashMap<c_int, Arc<Mutex<File>>>> {
+    static FILES: OnceLock<Mutex<HashMap<c_int, Arc<Mutex<File>>>>> = OnceLock::new();
+    FILES.get_or_init(Default::default)
+}
+
+static NEXT_DESCRIPTOR: AtomicI32 = AtomicI32::new(FIRST_DESCRIPTOR);
+
+fn descriptor(fd: c_int) -> Result<Arc<Mutex<File>>, String> {
+    files()
+        .lock()
+        .unwrap()
+        .get(&fd)
+        .cloned()
+        .ok_or_else(|| format!("{} isn't an open descriptor", fd))
+}
+
+/// A new, empty vfs. Free with `k_vfs_free`.
+#[no_mangle]
+pub extern "C" fn k_vfs_new() -> *mut KVfs {
+    Box::into_raw(Box::new(KVfs {
+        mounts: RwLock::new(Vec::new()),
+    }))
+}
+
+/// Frees a vfs from `k_vfs_new`. Descriptors opened through it stay readable. NULL is ignored.
+///
+/// # Safety
+/// `vfs` must be NULL or from `k_vfs_new`, and not freed already.
+#[no_mangle]
+pub unsafe extern "C" fn k_vfs_free(vfs: *mut KVfs) {
+    if !vfs.is_null() {
+        drop(Box::from_raw(vfs));
+    }
+}
+
+/// Mounts the archive at `archive` so its entries show up under `prefix` (eg. "data", or
+/// NULL for the top of the tree). Archives mounted later win over earlier ones for the same
+/// path, like updates over the base game. 0 on success, -1 otherwise.
+///
+/// # Safety
+/// `vfs` must be from `k_vfs_new`, `archive` a nul terminated string and `prefix` NULL or
+/// one.
+#[no_mangle]
+pub unsafe extern "C" fn k_vfs_mount(
+    vfs: *const KVfs,
+    archive: *const c_char,
+    prefix: *const c_char,
+) -> c_int {
+    guard(-1, || {
+        let vfs = vfs.as_ref().ok_or("vfs is NULL")?;
+        let path = path_arg(archive, "archive")?;
+        let prefix = match prefix.is_null() {
+            true => String::new(),
+            false => normalize(&path_arg(prefix, "prefix")?.to_string_lossy()),
+        };
+        let archive = k_archives::mount(path.clone())
+            .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+        let entries = archive
+            .list_files()
+            .into_iter()
+            .map(|entry| (normalize(&entry.to_string_lossy()), entry))
+            .collect();
+        vfs.mounts.write().unwrap().push(Mount {
+            prefix,
+            archive: Arc::new(archive),
+            entries,
+        });
+        Ok(0)
+    })
+}
+
+/// Opens `path` as the game named it, returning a descriptor, or -1 when it isn't in any
+/// mounted archive (or can't be opened) and the real file should be used instead.
+///
+/// # Safety
+/// `vfs` must be from `k_vfs_new` and `path` a nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn k_vfs_open(vfs: *const KVfs, path: *const c_char) -> c_int {
+    guard(-1, || {
+        let vfs = vfs.as_ref().ok_or("vfs is NULL")?;
+        let path = path_arg(path, "path")?;
+        let (archive, entry) = vfs
+            .find(&path.to_string_lossy())
+            .ok_or_else(|| format!("{} isn't in any archive", path.display()))?;
+        let file = open_file(&archive, &entry)?;
+        let fd = NEXT_DESCRIPTOR.fetch_add(1, Ordering::Relaxed);
+        files()
+            .lock()
+            .unwrap()
+            .insert(fd, Arc::new(Mutex::new(file)));
+        Ok(fd)
+    })
+}
+
+/// Writes the size of `path` to `size` without opening it. 0 if it's in a mounted archive,
+/// -1 if not.
+///
+/// # Safety
+/// `vfs` must be from `k_vfs_new`, `path` a nul terminated string and `size` must point at a
+/// uint64_t.
+#[no_mangle]
+pub unsafe extern "C" fn k_vfs_stat(
+    vfs: *const KVfs,
+    path: *const c_char,
+    size: *mut u64,
+) -> c_int {
+    guard(-1, || {
+        let vfs = vfs.as_ref().ok_or("vfs is NULL")?;
+        let path = path_arg(path, "path")?;
+        let (archive, entry) = vfs
+            .find(&path.to_string_lossy())
+            .ok_or_else(|| format!("{} isn't in any archive", path.display()))?;
+        let meta = archive
+            .metadata(&entry)
+            .ok_or_else(|| format!("{} isn't in any archive", path.display()))?;
+        *size = meta.size;
+        Ok(0)
+    })
+}
+
+/// 1 if `fd` came from `k_vfs_open` and isn't closed yet, 0 otherwise.
+#[no_mangle]
+pub extern "C" fn k_vfs_is_open(fd: c_int) -> c_int {
+    files().lock().unwrap().contains_key(&fd) as c_int
+}
+
+/// Reads up to `len` bytes into `buf`, returning how many were read (0 at the end) or -1.
+///
+/// # Safety
+/// `buf` must have room for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn k_vfs_read(fd: c_int, buf: *mut u8, len: usize) -> i64 {
+    guard(-1, || {
+        let file = descriptor(fd)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        let buf = std::slice::from_raw_parts_mut(buf, len);
+        let read = file.lock().unwrap().file.read(buf);
+        Ok(read.map_err(|e| e.to_string())? as i64)
+    })
+}
+
+/// Moves to `offset` from where `whence` (SEEK_SET, SEEK_CUR or SEEK_END) says, returning
+/// the new position or -1.
+#[no_mangle]
+pub extern "C" fn k_vfs_seek(fd: c_int, offset: i64, whence: c_int) -> i64 {
+    guard(-1, || {
+        let file = descriptor(fd)?;
+        let position = file.lock().unwrap().file.seek(seek_from(offset, whence)?);
+        Ok(position.map_err(|e| e.to_string())? as i64)
+    })
+}
+
+/// The size of the file once decompressed, or -1.
+#[no_mangle]
+pub extern "C" fn k_vfs_size(fd: c_int) -> i64 {
+    guard(
+        -1,
+        || Ok(descriptor(fd)?.lock().unwrap().file.size() as i64),
+    )
+}
+
+/// Closes a descriptor from `k_vfs_open`. 0 on success, -1 if it isn't one.
+#[no_mangle]
+pub extern "C" fn k_vfs_close(fd: c_int) -> c_int {
+    guard(-1, || {
+        files()
+            .lock()
+            .unwrap()
+            .remove(&fd)
+            .ok_or_else(|| format!("{} isn't an open descriptor", fd))?;
+        Ok(0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k_archives::MarWriter;
+    use std::ffi::CString;
+
+    #[test]
+    fn vfs_redirect() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.mar");
+        let update = dir.path().join("update.mar");
+        MarWriter::new()
+            .add_file("sound/bgm.bin", b"old bgm".to_vec())
+            .add_file("sound/se.bin", b"se".to_vec())
+            .write(std::fs::File::create(&base).unwrap())
+            .unwrap();
+        MarWriter::new()
+            .add_file("sound/bgm.bin", b"new bgm".to_vec())
+            .write(std::fs::File::create(&update).unwrap())
+            .unwrap();
+        let c = |s: &str| CString::new(s).unwrap();
+
+        unsafe {
+            let vfs = k_vfs_new();
+            let data = c("/data/");
+            for archive in [&base, &update] {
+                let archive = c(archive.to_str().unwrap());
+                assert_eq!(k_vfs_mount(vfs, archive.as_ptr(), data.as_ptr()), 0);
+            }
+
+            // the update's copy, whichever way the game spells it
+            let fd = k_vfs_open(vfs, c("DATA\\Sound\\.\\BGM.bin").as_ptr());
+            assert!(fd >= FIRST_DESCRIPTOR);
+            assert_eq!(k_vfs_is_open(fd), 1);
+            assert_eq!(k_vfs_size(fd), 7);
+            let mut buf = [0_u8; 16];
+            assert_eq!(k_vfs_seek(fd, 4, 0), 4);
+            assert_eq!(k_vfs_read(fd, buf.as_mut_ptr(), buf.len()), 3);
+            assert_eq!(&buf[..3], b"bgm");
+            assert_eq!(k_vfs_close(fd), 0);
+            assert_eq!(k_vfs_is_open(fd), 0);
+            assert_eq!(k_vfs_close(fd), -1);
+
+            let mut size = 0;
+            assert_eq!(
+                k_vfs_stat(vfs, c("/data/sound/se.bin").as_ptr(), &mut size),
+                0
+            );
+            assert_eq!(size, 2);
+            // outside the prefix, or not in any archive, is for the real file system
+            assert_eq!(k_vfs_open(vfs, c("sound/se.bin").as_ptr()), -1);
+            assert_eq!(k_vfs_open(vfs, c("/data/sound/nope.bin").as_ptr()), -1);
+            assert_eq!(k_vfs_read(fd, buf.as_mut_ptr(), buf.len()), -1);
+
+            // open descriptors outlive the vfs
+            let fd = k_vfs_open(vfs, c("data/sound/se.bin").as_ptr());
+            k_vfs_free(vfs);
+            assert_eq!(k_vfs_read(fd, buf.as_mut_ptr(), buf.len()), 2);
+            assert_eq!(k_vfs_close(fd), 0);
+        }
+    }
+}
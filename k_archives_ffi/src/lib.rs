@@ -0,0 +1,364 @@
+//! A C interface to k_archives, built as a shared and a static library, for loader hooks and
+//! tools that aren't written in rust. k_archives.h next to this crate declares everything.
+//!
+//! Functions that can fail return NULL or -1 and leave a message for
+//! `k_archives_last_error`. Open files keep their archive alive, so an archive can be freed
+//! while its files are still being read.
+
+#[cfg(feature = "hooks")]
+mod hooks;
+
+use k_archives::{KArchive, KFile};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::io::{Read, Seek, SeekFrom};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A mounted archive.
+pub struct Archive(Arc<KArchive>);
+
+/// An entry opened for reading.
+pub struct File {
+    // declared first so it's dropped before the archive it borrows from
+    file: KFile<'static>,
+    _archive: Arc<KArchive>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(message));
+}
+
+// runs `f`, turning errors and panics into `failed` with the message saved for
+// k_archives_last_error. panics can't be allowed to unwind into C
+fn guard<T>(failed: T, f: impl FnOnce() -> Result<T, String>) -> T {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(message)) => {
+            set_error(message);
+            failed
+        }
+        Err(_) => {
+            set_error("k_archives panicked (malformed archive?)".into());
+            failed
+        }
+    }
+}
+
+// SAFETY: `s` is null or a nul terminated string
+unsafe fn path_arg(s: *const c_char, what: &str) -> Result<PathBuf, String> {
+    if s.is_null() {
+        return Err(format!("{} is NULL", what));
+    }
+    let s = CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| format!("{} isn't utf8", what))?;
+    Ok(PathBuf::from(s))
+}
+
+fn open_file(archive: &Arc<KArchive>, path: &Path) -> Result<File, String> {
+    let shared = archive.clone();
+    // SAFETY: the archive lives in an Arc the File holds on to, so it stays put for as long
+    // as the file that borrows it
+    let borrowed: &'static KArchive = unsafe { &*Arc::as_ptr(&shared) };
+    let file = borrowed
+        .open(path)
+        .map_err(|e| format!("can't open {}: {}", path.display(), e))?;
+    Ok(File {
+        file,
+        _archive: shared,
+    })
+}
+
+// lseek's whence, SEEK_SET, SEEK_CUR and SEEK_END being the same everywhere
+fn seek_from(offset: i64, whence: c_int) -> Result<SeekFrom, String> {
+    match whence {
+        0 => Ok(SeekFrom::Start(
+            u64::try_from(offset).map_err(|_| "negative offset")?,
+        )),
+        1 => Ok(SeekFrom::Current(offset)),
+        2 => Ok(SeekFrom::End(offset)),
+        _ => Err(format!("unknown whence {}", whence)),
+    }
+}
+
+/// The message for the last call on this thread that failed, or NULL. Valid until the next
+/// call that fails.
+#[no_mangle]
+pub extern "C" fn k_archives_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| match &*error.borrow() {
+        Some(message) => message.as_ptr(),
+        None => std::ptr::null(),
+    })
+}
+
+/// Mounts the archive at `path` (any format k_archives reads, a ULST or info descriptor
+/// brings in its whole set). NULL on failure. Free with `k_archives_free`.
+///
+/// # Safety
+/// `path` must be a nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn k_archives_mount(path: *const c_char) -> *mut Archive {
+    guard(std::ptr::null_mut(), || {
+        let path = path_arg(path, "path")?;
+        let archive = k_archives::mount(path.clone())
+            .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+        Ok(Box::into_raw(Box::new(Archive(Arc::new(archive)))))
+    })
+}
+
+/// Frees an archive from `k_archives_mount`. NULL is ignored.
+///
+/// # Safety
+/// `archive` must be NULL or from `k_archives_mount`, and not freed already.
+#[no_mangle]
+pub unsafe extern "C" fn k_archives_free(archive: *mut Archive) {
+    if !archive.is_null() {
+        drop(Box::from_raw(archive));
+    }
+}
+
+/// The paths of every entry, sorted, with `/` between folders. The count is written to
+/// `count`. NULL on failure. Free with `k_archives_free_list`.
+///
+/// # Safety
+/// `archive` must be from `k_archives_mount` and `count` must point at a size_t.
+#[no_mangle]
+pub unsafe extern "C" fn k_archives_list(
+    archive: *const Archive,
+    count: *mut usize,
+) -> *mut *mut c_char {
+    guard(std::ptr::null_mut(), || {
+        let archive = archive.as_ref().ok_or("archive is NULL")?;
+        let mut files = archive.0.list_files();
+        files.sort();
+        let names: Vec<*mut c_char> = files
+            .iter()
+            .map(|file| {
+                let name = file.to_string_lossy().replace('\\', "/");
+                CString::new(name).unwrap_or_default().into_raw()
+            })
+            .collect();
+        *count = names.len();
+        Ok(Box::into_raw(names.into_boxed_slice()).cast())
+    })
+}
+
+/// Frees a list from `k_archives_list`, `count` being the count it gave. NULL is ignored.
+///
+/// # Safety
+/// `list` must be NULL or from `k_archives_list` with that `count`, and not freed already.
+#[no_mangle]
+pub unsafe extern "C" fn k_archives_free_list(list: *mut *mut c_char, count: usize) {
+    if list.is_null() {
+        return;
+    }
+    let names = Box::from_raw(std::ptr::slice_from_raw_parts_mut(list, count));
+    for name in names.iter() {
+        drop(CString::from_raw(*name));
+    }
+}
+
+/// Opens the entry `path` for reading. Encrypted entries (M32 mars, qars) are deciphered
+/// and compressed ones decompressed as they're read. NULL if there's no such entry. Close
+/// with `k_archives_close`.
+///
+/// # Safety
+/// `archive` must be from `k_archives_mount` and `path` a nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn k_archives_open(
+    archive: *const Archive,
+    path: *const c_char,
+) -> *mut File {
+    guard(std::ptr::null_mut(), || {
+        let archive = archive.as_ref().ok_or("archive is NULL")?;
+        let path = path_arg(path, "path")?;
+        Ok(Box::into_raw(Box::new(open_file(&archive.0, &path)?)))
+    })
+}
+
+/// The entry's size once decompressed.
+///
+/// # Safety
+/// `file` must be from `k_archives_open`.
+#[no_mangle]
+pub unsafe extern "C" fn k_archives_size(file: *const File) -> u64 {
+    file.as_ref().map_or(0, |file| file.file.size())
+}
+
+/// Reads up to `len` bytes into `buf`, returning how many were read (0 at the end) or -1.
+///
+/// # Safety
+/// `file` must be from `k_archives_open` and `buf` must have room for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn k_archives_read(file: *mut File, buf: *mut u8, len: usize) -> i64 {
+    guard(-1, || {
+        let file = file.as_mut().ok_or("file is NULL")?;
+        if len == 0 {
+            return Ok(0);
+        }
+        let buf = std::slice::from_raw_parts_mut(buf, len);
+        let read = file.file.read(buf).map_err(|e| e.to_string())?;
+        Ok(read as i64)
+    })
+}
+
+/// Moves to `offset` from the start (`whence` SEEK_SET), the current position (SEEK_CUR) or
+/// the end (SEEK_END), returning the new position or -1. Seeking in encrypted entries
+/// means deciphering everything before the new position.
+///
+/// # Safety
+/// `file` must be from `k_archives_open`.
+#[no_mangle]
+pub unsafe extern "C" fn k_archives_seek(file: *mut File, offset: i64, whence: c_int) -> i64 {
+    guard(-1, || {
+        let file = file.as_mut().ok_or("file is NULL")?;
+        let position = file
+            .file
+            .seek(seek_from(offset, whence)?)
+            .map_err(|e| e.to_string())?;
+        Ok(position as i64)
+    })
+}
+
+/// Closes a file from `k_archives_open`. NULL is ignored.
+///
+/// # Safety
+/// `file` must be NULL or from `k_archives_open`, and not closed already.
+#[no_mangle]
+pub unsafe extern "C" fn k_archives_close(file: *mut File) {
+    if !file.is_null() {
+        drop(Box::from_raw(file));
+    }
+}
+
+/// Deciphers the `len` byte payload of an M32 mar entry found outside its archive from
+/// `data` into `out`, which can be the same buffer. `name` is the entry's path as the
+/// archive stored it, the key comes from it. The cipher works both ways, so this also
+/// encrypts. 0 on success, -1 otherwise.
+///
+/// # Safety
+/// `name` must be a nul terminated string, `data` and `out` must each hold `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn k_archives_decrypt_mar(
+    name: *const c_char,
+    data: *const u8,
+    out: *mut u8,
+    len: usize,
+) -> c_int {
+    guard(-1, || {
+        let name = path_arg(name, "name")?;
+        let name = name
+            .to_str()
+            .ok_or_else(|| format!("{} isn't valid utf-8", name.display()))?;
+        if data.is_null() {
+            return Err("data is NULL".into());
+        }
+        if out.is_null() {
+            return Err("out is NULL".into());
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        // copied first, since out is allowed to be data
+        let payload = std::slice::from_raw_parts(data, len).to_vec();
+        let out = std::slice::from_raw_parts_mut(out, len);
+        k_archives::decrypt_mar_payload(name, len as u64, &payload[..], &mut out[..])
+            .map_err(|e| e.to_string())?;
+        Ok(0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k_archives::MarWriter;
+
+    #[test]
+    fn mount_list_read_seek() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test_M32.mar");
+        let data: Vec<u8> = (0..5000_u32).map(|i| (i % 251) as u8).collect();
+        // M32 in the name is what marks the entries as encrypted
+        let mut writer = MarWriter::new();
+        writer
+            .set_encryption(true)
+            .add_file("sound/bgm.bin", data.clone())
+            .add_file("data.txt", b"hello".to_vec());
+        writer.write(std::fs::File::create(&path).unwrap()).unwrap();
+
+        unsafe {
+            let c_path = CString::new(path.to_str().unwrap()).unwrap();
+            let archive = k_archives_mount(c_path.as_ptr());
+            assert!(!archive.is_null());
+
+            let mut count = 0;
+            let list = k_archives_list(archive, &mut count);
+            let names: Vec<String> = (0..count)
+                .map(|i| CStr::from_ptr(*list.add(i)).to_str().unwrap().to_string())
+                .collect();
+            assert_eq!(names, ["data.txt", "sound/bgm.bin"]);
+            k_archives_free_list(list, count);
+
+            let entry = CString::new("sound/bgm.bin").unwrap();
+            let file = k_archives_open(archive, entry.as_ptr());
+            assert!(!file.is_null());
+            // the file keeps the archive alive
+            k_archives_free(archive);
+            assert_eq!(k_archives_size(file), 5000);
+
+            let mut buf = [0_u8; 100];
+            assert_eq!(k_archives_seek(file, 4950, 0), 4950);
+            assert_eq!(k_archives_read(file, buf.as_mut_ptr(), buf.len()), 50);
+            assert_eq!(&buf[..50], &data[4950..]);
+            assert_eq!(k_archives_read(file, buf.as_mut_ptr(), buf.len()), 0);
+            assert_eq!(k_archives_seek(file, -4900, 1), 100);
+            assert_eq!(k_archives_read(file, buf.as_mut_ptr(), buf.len()), 100);
+            assert_eq!(&buf[..], &data[100..200]);
+            assert_eq!(k_archives_seek(file, 0, 7), -1);
+            assert!(!k_archives_last_error().is_null());
+            k_archives_close(file);
+
+            let missing = CString::new("nope").unwrap();
+            let c_path = CString::new(path.to_str().unwrap()).unwrap();
+            let archive = k_archives_mount(c_path.as_ptr());
+            assert!(k_archives_open(archive, missing.as_ptr()).is_null());
+            k_archives_free(archive);
+        }
+    }
+
+    #[test]
+    fn mount_failure() {
+        let path = CString::new("/does/not/exist.mar").unwrap();
+        unsafe {
+            assert!(k_archives_mount(path.as_ptr()).is_null());
+            let error = CStr::from_ptr(k_archives_last_error()).to_str().unwrap();
+            assert!(error.contains("exist.mar"));
+        }
+    }
+
+    #[test]
+    fn decrypt_mar_round_trip() {
+        let name = CString::new("sound/bgm.bin").unwrap();
+        let data = b"some payload bytes".to_vec();
+        let mut buf = data.clone();
+        unsafe {
+            let len = buf.len();
+            let p = buf.as_mut_ptr();
+            assert_eq!(k_archives_decrypt_mar(name.as_ptr(), p, p, len), 0);
+            assert_ne!(buf, data);
+            assert_eq!(k_archives_decrypt_mar(name.as_ptr(), p, p, len), 0);
+            assert_eq!(k_archives_decrypt_mar(name.as_ptr(), p, p, 0), 0);
+            let null = std::ptr::null_mut();
+            assert_eq!(k_archives_decrypt_mar(name.as_ptr(), null, p, 0), -1);
+            assert_eq!(k_archives_decrypt_mar(name.as_ptr(), p, null, len), -1);
+        }
+        assert_eq!(buf, data);
+    }
+}
@@ -0,0 +1,117 @@
+//! k_archives for the browser. Archives are mounted from bytes the page already has (eg. a
+//! file the user dropped in), so nothing gets uploaded anywhere. Build with
+//! `wasm-pack build k_archives_wasm --target web`, index.html next to this crate shows how
+//! it's used.
+
+use k_archives::{KArchive, MountOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use wasm_bindgen::prelude::*;
+
+/// A mounted archive.
+#[wasm_bindgen]
+pub struct Archive {
+    archive: KArchive,
+}
+
+#[wasm_bindgen]
+impl Archive {
+    /// Mounts the archive in `data`. `name` is its file name, which 2dx and d2 files and
+    /// encrypted (M32) mars are recognised by. Archives inside it are mounted too, so their
+    /// files show up under the entry they're in.
+    #[wasm_bindgen(constructor)]
+    pub fn new(name: &str, data: Vec<u8>) -> Result<Archive, JsError> {
+        let options = MountOptions {
+            mount_nested: true,
+            ..Default::default()
+        };
+        let archive = k_archives::mount_bytes(name.into(), data, &options)
+            .map_err(|e| JsError::new(&format!("failed to parse {}: {}", name, e)))?;
+        Ok(Archive { archive })
+    }
+
+    /// The paths of every entry, sorted, with `/` between folders.
+    pub fn files(&self) -> Vec<String> {
+        let mut files: Vec<String> = self
+            .archive
+            .list_files()
+            .iter()
+            .map(|file| file.to_string_lossy().replace('\\', "/"))
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// The entry's size once decompressed, or undefined if there's no such entry. A number
+    /// rather than a BigInt, nothing that fits in a browser's memory is too big for one.
+    pub fn size(&self, path: &str) -> Option<f64> {
+        self.archive
+            .metadata(Path::new(path))
+            .map(|meta| meta.size as f64)
+    }
+
+    /// Reads the whole entry, deciphered and decompressed.
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, JsError> {
+        self.archive
+            .read(Path::new(path))
+            .map_err(|e| JsError::new(&format!("can't read {}: {}", path, e)))
+    }
+
+    /// Reads up to `len` bytes of the entry from `start`, eg. for previewing the start of a
+    /// large one without reading all of it.
+    pub fn read_range(&self, path: &str, start: f64, len: usize) -> Result<Vec<u8>, JsError> {
+        let error = |e: std::io::Error| JsError::new(&format!("can't read {}: {}", path, e));
+        let mut file = self.archive.open(Path::new(path)).map_err(error)?;
+        file.seek(SeekFrom::Start(start as u64)).map_err(error)?;
+        let mut data = Vec::new();
+        file.take(len as u64)
+            .read_to_end(&mut data)
+            .map_err(error)?;
+        Ok(data)
+    }
+
+    /// Problems found while mounting that didn't stop it, eg. a truncated archive.
+    pub fn warnings(&self) -> Vec<String> {
+        self.archive
+            .warnings()
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect()
+    }
+}
+
+// the happy paths only, JsError can't be made outside of a browser
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k_archives::MarWriter;
+
+    #[test]
+    fn mount_read() {
+        let data: Vec<u8> = (0..0x3000_u32).map(|i| (i % 251) as u8).collect();
+        let mut inner = MarWriter::new();
+        inner.add_file("deep.bin", b"deep".to_vec());
+        let inner = inner.write(Vec::new()).unwrap();
+        let mut mar = MarWriter::new();
+        mar.set_encryption(true)
+            .add_file("data/song.bin", data.clone())
+            .add_file("inner.mar", inner);
+        let mar = mar.write(Vec::new()).unwrap();
+
+        let archive = Archive::new("update_M32.mar", mar).unwrap_or_else(|_| unreachable!());
+        assert_eq!(
+            archive.files(),
+            ["data/song.bin", "inner.mar", "inner.mar/deep.bin"]
+        );
+        assert_eq!(archive.size("data/song.bin"), Some(0x3000 as f64));
+        assert_eq!(archive.size("nope"), None);
+        let read = |path| archive.read(path).unwrap_or_else(|_| unreachable!());
+        assert_eq!(read("data/song.bin"), data);
+        assert_eq!(read("inner.mar/deep.bin"), b"deep");
+        let range = archive
+            .read_range("data/song.bin", 0x2ffe as f64, 10)
+            .unwrap_or_else(|_| unreachable!());
+        assert_eq!(range, data[0x2ffe..]);
+        assert!(archive.warnings().is_empty());
+    }
+}
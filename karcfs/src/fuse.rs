@@ -0,0 +1,522 @@
+//! Just enough of the kernel's FUSE protocol to serve a tree read only. Requests are read
+//! from /dev/fuse one at a time and answered by writing a reply back; with nothing to write
+//! there are only a handful that matter. Layouts are from linux/fuse.h.
+
+use crate::tree::{Kind, Node, Tree};
+use k_archives::{KArchive, KFile};
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+const KERNEL_VERSION: u32 = 7;
+// the newest minor version whose replies this writes
+const KERNEL_MINOR_VERSION: u32 = 31;
+// the biggest read asked for. requests need room for this much data even though nothing
+// is ever written, the kernel refuses smaller buffers
+const MAX_WRITE: u32 = 0x20000;
+const BUFFER_SIZE: usize = MAX_WRITE as usize + 0x1000;
+// how long the kernel can cache names and attributes, in seconds. nothing ever changes
+const TTL: u64 = 3600;
+
+const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+
+// opcodes
+const LOOKUP: u32 = 1;
+const FORGET: u32 = 2;
+const GETATTR: u32 = 3;
+const SETATTR: u32 = 4;
+const SYMLINK: u32 = 6;
+const MKNOD: u32 = 8;
+const MKDIR: u32 = 9;
+const UNLINK: u32 = 10;
+const RMDIR: u32 = 11;
+const RENAME: u32 = 12;
+const LINK: u32 = 13;
+const OPEN: u32 = 14;
+const READ: u32 = 15;
+const WRITE: u32 = 16;
+const STATFS: u32 = 17;
+const RELEASE: u32 = 18;
+const SETXATTR: u32 = 21;
+const REMOVEXATTR: u32 = 24;
+const FLUSH: u32 = 25;
+const INIT: u32 = 26;
+const OPENDIR: u32 = 27;
+const READDIR: u32 = 28;
+const RELEASEDIR: u32 = 29;
+const CREATE: u32 = 35;
+const INTERRUPT: u32 = 36;
+const DESTROY: u32 = 38;
+const BATCH_FORGET: u32 = 42;
+const FALLOCATE: u32 = 43;
+const RENAME2: u32 = 45;
+
+// open reply flags: the contents never change, so the page cache can be kept across opens
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+// dirent types
+const DT_DIR: u32 = 4;
+const DT_REG: u32 = 8;
+
+// little builder for reply payloads, which are packed structs of native endian integers
+#[derive(Default)]
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+}
+
+fn u32_at(body: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(body.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(body: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_ne_bytes(body.get(at..at + 8)?.try_into().ok()?))
+}
+
+// the fuse device, once the kernel has it mounted
+pub(crate) struct Session<'a> {
+    device: File,
+    archive: &'a KArchive,
+    tree: Tree,
+    uid: u32,
+    gid: u32,
+    // open files and where the next read from them would start
+    handles: HashMap<u64, (KFile<'a>, u64)>,
+    next_handle: u64,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(device: File, archive: &'a KArchive, tree: Tree) -> Self {
+        Session {
+            device,
+            archive,
+            tree,
+            // SAFETY: neither can fail
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            handles: HashMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    /// Answers requests until the filesystem is unmounted.
+    pub fn run(&mut self) -> std::io::Result<()> {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let len = match self.device.read(&mut buffer) {
+                Ok(len) => len,
+                // ENOENT means the request was interrupted before it was read
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => continue,
+                // unmounted
+                Err(e) if e.raw_os_error() == Some(libc::ENODEV) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let request = &buffer[..len];
+            if request.len() < IN_HEADER_SIZE {
+                continue;
+            }
+            let opcode = u32_at(request, 4).unwrap();
+            let unique = u64_at(request, 8).unwrap();
+            let node = u64_at(request, 16).unwrap();
+            let body = &request[IN_HEADER_SIZE..];
+            let reply = match opcode {
+                // these are never answered
+                FORGET | BATCH_FORGET | INTERRUPT => continue,
+                DESTROY => {
+                    self.reply(unique, Ok(Reply::default()))?;
+                    return Ok(());
+                }
+                _ => self.handle(opcode, node, body),
+            };
+            self.reply(unique, reply)?;
+        }
+    }
+
+    fn reply(&mut self, unique: u64, reply: Result<Reply, i32>) -> std::io::Result<()> {
+        let (error, payload) = match reply {
+            Ok(Reply(payload)) => (0, payload),
+            Err(errno) => (-errno, Vec::new()),
+        };
+        let out = Reply::default()
+            .u32((OUT_HEADER_SIZE + payload.len()) as u32)
+            .u32(error as u32)
+            .u64(unique)
+            .bytes(&payload);
+        match self.device.write(&out.0) {
+            // the request was interrupted and the kernel isn't waiting on it anymore
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    fn handle(&mut self, opcode: u32, node: u64, body: &[u8]) -> Result<Reply, i32> {
+        match opcode {
+            INIT => init(body),
+            LOOKUP => {
+                let name = body.split(|&b| b == 0).next().unwrap_or_default();
+                let child = self
+                    .tree
+                    .lookup(node, OsStr::from_bytes(name))
+                    .ok_or(libc::ENOENT)?;
+                Ok(self.entry(child))
+            }
+            GETATTR => {
+                let attr = self.attr(node).ok_or(libc::ENOENT)?;
+                Ok(Reply::default().u64(TTL).u32(0).u32(0).bytes(&attr.0))
+            }
+            OPEN => {
+                let flags = u32_at(body, 0).ok_or(libc::EINVAL)?;
+                if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+                    return Err(libc::EROFS);
+                }
+                let Some(Kind::File { entry, .. }) = self.tree.get(node).map(|node| &node.kind)
+                else {
+                    return Err(libc::EISDIR);
+                };
+                let file = self.archive.open(entry).map_err(|e| {
+                    eprintln!("can't open {}: {}", entry.display(), e);
+                    libc::EIO
+                })?;
+                let handle = self.next_handle;
+                self.next_handle += 1;
+                self.handles.insert(handle, (file, 0));
+                Ok(Reply::default().u64(handle).u32(FOPEN_KEEP_CACHE).u32(0))
+            }
+            READ => {
+                let handle = u64_at(body, 0).ok_or(libc::EINVAL)?;
+                let offset = u64_at(body, 8).ok_or(libc::EINVAL)?;
+                let size = u32_at(body, 16).ok_or(libc::EINVAL)?;
+                let (file, position) = self.handles.get_mut(&handle).ok_or(libc::EBADF)?;
+                let read = read_at(file, position, offset, size).map_err(|e| {
+                    eprintln!("can't read {}: {}", file.name.display(), e);
+                    libc::EIO
+                })?;
+                Ok(Reply(read))
+            }
+            RELEASE => {
+                if let Some(handle) = u64_at(body, 0) {
+                    self.handles.remove(&handle);
+                }
+                Ok(Reply::default())
+            }
+            OPENDIR => match self.tree.get(node).map(|node| &node.kind) {
+                Some(Kind::Folder(_)) => Ok(Reply::default().u64(0).u32(0).u32(0)),
+                Some(Kind::File { .. }) => Err(libc::ENOTDIR),
+                None => Err(libc::ENOENT),
+            },
+            READDIR => {
+                let offset = u64_at(body, 8).ok_or(libc::EINVAL)?;
+                let size = u32_at(body, 16).ok_or(libc::EINVAL)?;
+                self.read_dir(node, offset, size as usize)
+            }
+            RELEASEDIR | FLUSH => Ok(Reply::default()),
+            STATFS => Ok(Reply::default()
+                .u64(self.tree.total_size().div_ceil(512))
+                .u64(0)
+                .u64(0)
+                .u64(self.tree.len() as u64)
+                .u64(0)
+                .u32(512)
+                .u32(255)
+                .u32(512)
+                .u32(0)
+                .bytes(&[0; 24])),
+            SETATTR | SYMLINK | MKNOD | MKDIR | UNLINK | RMDIR | RENAME | LINK | WRITE
+            | SETXATTR | REMOVEXATTR | CREATE | FALLOCATE | RENAME2 => Err(libc::EROFS),
+            _ => Err(libc::ENOSYS),
+        }
+    }
+
+    // fuse_attr for `id`
+    fn attr(&self, id: u64) -> Option<Reply> {
+        let node = self.tree.get(id)?;
+        let (mode, nlink, size) = match node.kind {
+            Kind::Folder(_) => (libc::S_IFDIR | 0o555, 2, 0),
+            Kind::File { size, .. } => (libc::S_IFREG | 0o444, 1, size),
+        };
+        let (secs, nanos) = unix_time(node);
+        Some(
+            Reply::default()
+                .u64(id)
+                .u64(size)
+                .u64(size.div_ceil(512))
+                .u64(secs)
+                .u64(secs)
+                .u64(secs)
+                .u32(nanos)
+                .u32(nanos)
+                .u32(nanos)
+                .u32(mode)
+                .u32(nlink)
+                .u32(self.uid)
+                .u32(self.gid)
+                .u32(0)
+                .u32(4096)
+                .u32(0),
+        )
+    }
+
+    // fuse_entry_out for `id`, which exists since it was just looked up
+    fn entry(&self, id: u64) -> Reply {
+        Reply::default()
+            .u64(id)
+            .u64(0)
+            .u64(TTL)
+            .u64(TTL)
+            .u32(0)
+            .u32(0)
+            .bytes(&self.attr(id).unwrap().0)
+    }
+
+    // as many dirents as fit in `size`, starting at the `offset`th. every folder starts
+    // with . and ..
+    fn read_dir(&self, id: u64, offset: u64, size: usize) -> Result<Reply, i32> {
+        let node = self.tree.get(id).ok_or(libc::ENOENT)?;
+        let Kind::Folder(children) = &node.kind else {
+            return Err(libc::ENOTDIR);
+        };
+        let mut entries = vec![
+            (id, OsStr::new("."), DT_DIR),
+            (node.parent, OsStr::new(".."), DT_DIR),
+        ];
+        entries.extend(children.iter().map(|(name, &child)| {
+            let kind = match self.tree.get(child).map(|child| &child.kind) {
+                Some(Kind::Folder(_)) => DT_DIR,
+                _ => DT_REG,
+            };
+            (child, name.as_os_str(), kind)
+        }));
+        let mut reply = Reply::default();
+        for (index, (id, name, kind)) in entries.into_iter().enumerate().skip(offset as usize) {
+            let name = name.as_bytes();
+            let len = (24 + name.len()).next_multiple_of(8);
+            if reply.0.len() + len > size {
+                break;
+            }
+            let padding = len - 24 - name.len();
+            reply = reply
+                .u64(id)
+                .u64(index as u64 + 1)
+                .u32(name.len() as u32)
+                .u32(kind)
+                .bytes(name)
+                .bytes(&[0; 8][..padding]);
+        }
+        Ok(reply)
+    }
+}
+
+fn unix_time(node: &Node) -> (u64, u32) {
+    let since = node.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since.as_secs(), since.subsec_nanos())
+}
+
+// agrees on a protocol version. nothing optional is asked for
+fn init(body: &[u8]) -> Result<Reply, i32> {
+    let major = u32_at(body, 0).ok_or(libc::EINVAL)?;
+    let minor = u32_at(body, 4).ok_or(libc::EINVAL)?;
+    let max_readahead = u32_at(body, 8).ok_or(libc::EINVAL)?;
+    if major < KERNEL_VERSION {
+        return Err(libc::EPROTO);
+    }
+    let minor = match major {
+        KERNEL_VERSION => minor.min(KERNEL_MINOR_VERSION),
+        // a newer kernel asks again with this version
+        _ => KERNEL_MINOR_VERSION,
+    };
+    let mut reply = Reply::default()
+        .u32(KERNEL_VERSION)
+        .u32(minor)
+        .u32(max_readahead)
+        .u32(0)
+        .u16(16)
+        .u16(12)
+        .u32(MAX_WRITE)
+        .u32(1)
+        .u16(0)
+        .u16(0)
+        .u32(0)
+        .bytes(&[0; 28]);
+    // kernels before 7.23 expect the reply to stop after max_write
+    if minor < 23 {
+        reply.0.truncate(24);
+    }
+    Ok(reply)
+}
+
+// reads `size` bytes at `offset` from `file`, which is at `position`. the kernel mostly
+// reads in order, so seeks (slow for compressed and encrypted entries) are skipped then
+fn read_at(
+    file: &mut KFile,
+    position: &mut u64,
+    offset: u64,
+    size: u32,
+) -> std::io::Result<Vec<u8>> {
+    if *position != offset {
+        file.seek(SeekFrom::Start(offset))?;
+        *position = offset;
+    }
+    let mut data = Vec::with_capacity(size as usize);
+    file.take(size as u64).read_to_end(&mut data)?;
+    *position += data.len() as u64;
+    Ok(data)
+}
+
+/// Mounts a read only fuse filesystem on `mountpoint` and returns the device to serve it
+/// from. Done with mount(2) when allowed (as root), otherwise through fusermount like
+/// libfuse does.
+pub(crate) fn mount(mountpoint: &Path, allow_other: bool) -> std::io::Result<File> {
+    let device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    // SAFETY: neither can fail
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let mut options = format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        device.as_raw_fd(),
+        uid,
+        gid
+    );
+    if allow_other {
+        options.push_str(",allow_other");
+    }
+    let target = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let options = CString::new(options)?;
+    // SAFETY: every pointer is to a nul terminated string that outlives the call
+    let mounted = unsafe {
+        libc::mount(
+            c"karcfs".as_ptr(),
+            target.as_ptr(),
+            c"fuse.karcfs".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_RDONLY,
+            options.as_ptr().cast(),
+        )
+    };
+    if mounted == 0 {
+        return Ok(device);
+    }
+    let error = Error::last_os_error();
+    if error.raw_os_error() != Some(libc::EPERM) {
+        return Err(error);
+    }
+    drop(device);
+    let mut options =
+        "ro,nosuid,nodev,default_permissions,fsname=karcfs,subtype=karcfs".to_string();
+    if allow_other {
+        options.push_str(",allow_other");
+    }
+    mount_with_fusermount(mountpoint, &options)
+}
+
+// fusermount (setuid root) does the mount and passes the device back over a socket
+fn mount_with_fusermount(mountpoint: &Path, options: &str) -> std::io::Result<File> {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for the pair. it's left inheritable so fusermount gets its end
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    // SAFETY: both were just created and nothing else owns them
+    let (ours, theirs) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    let mut missing = Error::from(ErrorKind::NotFound);
+    for program in ["fusermount3", "fusermount"] {
+        let status = Command::new(program)
+            .arg("-o")
+            .arg(options)
+            .arg("--")
+            .arg(mountpoint)
+            .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+            .status();
+        match status {
+            Ok(status) if status.success() => return receive_fd(&ours),
+            Ok(status) => return Err(Error::other(format!("{} failed ({})", program, status))),
+            Err(e) => missing = e,
+        }
+    }
+    Err(Error::new(
+        missing.kind(),
+        format!("can't mount without root or fusermount: {}", missing),
+    ))
+}
+
+fn receive_fd(socket: &OwnedFd) -> std::io::Result<File> {
+    let mut byte = [0_u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    // room for one control message holding one fd, aligned like cmsghdr
+    let mut control = [0_u64; 8];
+    // SAFETY: an all zero msghdr is valid, the pointers set below outlive recvmsg
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = std::mem::size_of_val(&control) as _;
+    // SAFETY: message points at buffers big enough for what it says
+    if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, 0) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    // SAFETY: recvmsg filled in the control buffer message points at
+    let header = unsafe { libc::CMSG_FIRSTHDR(&message) };
+    // SAFETY: only read when it's there, and it's an fd when the type says so
+    unsafe {
+        if header.is_null() || (*header).cmsg_type != libc::SCM_RIGHTS {
+            return Err(Error::other("fusermount didn't pass back the fuse device"));
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(header) as *const libc::c_int);
+        Ok(File::from_raw_fd(fd))
+    }
+}
+
+/// Unmounts `mountpoint`, lazily so it works while files in it are still open.
+pub(crate) fn unmount(mountpoint: &Path) -> std::io::Result<()> {
+    let target = CString::new(mountpoint.as_os_str().as_bytes())?;
+    // SAFETY: target is a nul terminated string that outlives the call
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } == 0 {
+        return Ok(());
+    }
+    let error = Error::last_os_error();
+    for program in ["fusermount3", "fusermount"] {
+        if let Ok(status) = Command::new(program)
+            .arg("-u")
+            .arg("-z")
+            .arg("--")
+            .arg(mountpoint)
+            .status()
+        {
+            return match status.success() {
+                true => Ok(()),
+                false => Err(Error::other(format!("{} -u failed ({})", program, status))),
+            };
+        }
+    }
+    Err(error)
+}
@@ -0,0 +1,85 @@
+#[cfg(target_os = "linux")]
+mod fuse;
+mod tree;
+
+use clap::Parser;
+use k_archives::{mount_with_options, MountOptions};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+/// Mounts a konami archive as a read only folder, so its files can be used in place without
+/// extracting them. Runs until Ctrl-C or until the folder is unmounted (fusermount -u)
+struct Args {
+    /// Archive to mount. Supports the same formats as unarchive, a ULST brings in its whole set
+    archive: PathBuf,
+    /// Folder to mount it on
+    mountpoint: PathBuf,
+    /// Let other users read it too. Without root this needs user_allow_other in /etc/fuse.conf
+    #[clap(long)]
+    allow_other: bool,
+    /// Mount what's present in archives that were cut off partway
+    #[clap(long)]
+    allow_truncated: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+    let options = MountOptions {
+        allow_truncated: args.allow_truncated,
+        ..Default::default()
+    };
+    let archive = mount_with_options(args.archive.clone(), &options).unwrap_or_else(|e| {
+        eprintln!("failed to parse {}: {}", args.archive.display(), e);
+        std::process::exit(2)
+    });
+    for warning in archive.warnings() {
+        eprintln!("{}: {}", args.archive.display(), warning);
+    }
+    // folders get the archive's own time
+    let modified = std::fs::metadata(&args.archive)
+        .and_then(|meta| meta.modified())
+        .unwrap_or_else(|_| SystemTime::now());
+    let tree = tree::Tree::new(&archive, modified);
+    serve(&args, &archive, tree);
+}
+
+#[cfg(target_os = "linux")]
+fn serve(args: &Args, archive: &k_archives::KArchive, tree: tree::Tree) {
+    let mountpoint = args.mountpoint.clone();
+    let device = fuse::mount(&mountpoint, args.allow_other).unwrap_or_else(|e| {
+        eprintln!("failed to mount on {}: {}", mountpoint.display(), e);
+        std::process::exit(2)
+    });
+    println!(
+        "mounted {} on {}",
+        args.archive.display(),
+        mountpoint.display()
+    );
+    // unmounting is what stops the session, the same as when it's done from outside
+    let handler = ctrlc::set_handler(move || {
+        if let Err(e) = fuse::unmount(&mountpoint) {
+            eprintln!("failed to unmount {}: {}", mountpoint.display(), e);
+        }
+    });
+    if let Err(e) = handler {
+        eprintln!(
+            "can't catch Ctrl-C, unmount with fusermount -u instead: {}",
+            e
+        );
+    }
+    if let Err(e) = fuse::Session::new(device, archive, tree).run() {
+        eprintln!("fuse session failed: {}", e);
+        let _ = fuse::unmount(&args.mountpoint);
+        std::process::exit(2)
+    }
+}
+
+// macos would need macfuse's device and mount helper, windows winfsp or dokan. none of
+// those are wired up yet
+#[cfg(not(target_os = "linux"))]
+fn serve(_: &Args, _: &k_archives::KArchive, _: tree::Tree) {
+    eprintln!("karcfs only runs on linux so far (mounting on macos needs macfuse and on windows winfsp or dokan, which it doesn't support yet), use unarchive to extract instead");
+    std::process::exit(2)
+}
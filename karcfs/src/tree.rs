@@ -0,0 +1,127 @@
+//! The folders and files of a mounted archive as numbered nodes, which is how the kernel
+//! refers to them.
+
+use k_archives::KArchive;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, PathBuf};
+use std::time::SystemTime;
+
+/// The node number of the top folder, fixed by FUSE.
+pub(crate) const ROOT: u64 = 1;
+
+pub(crate) enum Kind {
+    /// Children by name
+    Folder(BTreeMap<OsString, u64>),
+    /// The entry the file reads from
+    File { entry: PathBuf, size: u64 },
+}
+
+pub(crate) struct Node {
+    pub parent: u64,
+    pub kind: Kind,
+    pub modified: SystemTime,
+}
+
+pub(crate) struct Tree {
+    // node n is at n - 1
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    /// Lays out the entries of `archive`. Folders (and entries that don't say) get the time
+    /// `modified`, usually the archive's own.
+    pub fn new(archive: &KArchive, modified: SystemTime) -> Self {
+        let folder = |parent| Node {
+            parent,
+            kind: Kind::Folder(BTreeMap::new()),
+            modified,
+        };
+        let mut tree = Tree {
+            nodes: vec![folder(ROOT)],
+        };
+        let mut files = archive.list_files();
+        files.sort();
+        'files: for entry in files {
+            let parts: Vec<&OsStr> = entry
+                .components()
+                .filter_map(|component| match component {
+                    Component::Normal(part) => Some(part),
+                    _ => None,
+                })
+                .collect();
+            let Some((name, folders)) = parts.split_last() else {
+                continue;
+            };
+            let mut parent = ROOT;
+            for part in folders {
+                parent = match tree.lookup(parent, part) {
+                    Some(node) if matches!(tree.nodes[node as usize - 1].kind, Kind::Folder(_)) => {
+                        node
+                    }
+                    // a file is in the way, eg. an entry named like a folder another is in
+                    Some(_) => {
+                        eprintln!("{} is hidden by a file of the same name", entry.display());
+                        continue 'files;
+                    }
+                    None => tree.add(parent, part, folder(parent)),
+                };
+            }
+            if tree.lookup(parent, name).is_some() {
+                eprintln!("{} is hidden by a folder of the same name", entry.display());
+                continue;
+            }
+            // owned so entry can move into the node
+            let name = name.to_os_string();
+            let meta = archive.metadata(&entry);
+            let file = Node {
+                parent,
+                modified: meta
+                    .as_ref()
+                    .and_then(|meta| meta.modified)
+                    .unwrap_or(modified),
+                kind: Kind::File {
+                    size: meta.map_or(0, |meta| meta.size),
+                    entry,
+                },
+            };
+            tree.add(parent, &name, file);
+        }
+        tree
+    }
+
+    fn add(&mut self, parent: u64, name: &OsStr, node: Node) -> u64 {
+        self.nodes.push(node);
+        let id = self.nodes.len() as u64;
+        if let Kind::Folder(children) = &mut self.nodes[parent as usize - 1].kind {
+            children.insert(name.to_os_string(), id);
+        }
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Node> {
+        self.nodes.get(usize::try_from(id).ok()?.checked_sub(1)?)
+    }
+
+    pub fn lookup(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        match &self.get(parent)?.kind {
+            Kind::Folder(children) => children.get(name).copied(),
+            Kind::File { .. } => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// How many bytes all the files add up to.
+    pub fn total_size(&self) -> u64 {
+        self.nodes
+            .iter()
+            .map(|node| match node.kind {
+                Kind::File { size, .. } => size,
+                Kind::Folder(_) => 0,
+            })
+            .sum()
+    }
+}
@@ -0,0 +1,202 @@
+//! `unarchive apply`: extracting an update straight into a game install, like the
+//! official updater does.
+
+use crate::logging::{error, fatal, info, verbose};
+use crate::{input, mount};
+use k_archives::{
+    hash_file, hash_reader, join_under, ChecksumType, KArchive, KArchiveError, MountOptions,
+};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What applying an update did.
+#[derive(Debug, Default)]
+pub(crate) struct Summary {
+    pub added: Vec<PathBuf>,
+    pub updated: Vec<PathBuf>,
+    /// Files the install already had with the same contents.
+    pub unchanged: usize,
+    /// Entries outside the update's contents folder, or incomplete ones.
+    pub skipped: usize,
+    /// Entries that would have been written outside the target, and why.
+    pub refused: Vec<KArchiveError>,
+}
+
+// whether the file at `dest` already holds exactly what `entry` would write
+fn identical(archive: &KArchive, entry: &Path, dest: &Path) -> Result<bool, KArchiveError> {
+    let size = std::fs::metadata(dest)?.len();
+    if Some(size) != archive.metadata(entry).map(|meta| meta.size) {
+        return Ok(false);
+    }
+    Ok(hash_reader(&mut archive.open(entry)?, ChecksumType::Md5)?
+        == hash_file(dest, ChecksumType::Md5)?)
+}
+
+/// Extracts `archive` into the install's contents folder `target`. Updates keep everything
+/// under a `.../contents` folder, so only entries in there are applied, relative to it (or
+/// everything, for updates without one). Files that get replaced are copied into `backup`
+/// first, keeping their path.
+pub(crate) fn apply(
+    archive: &KArchive,
+    target: &Path,
+    backup: &Path,
+) -> Result<Summary, KArchiveError> {
+    let prefix = archive.guess_contents_folder().unwrap_or_default();
+    let mut files = archive.list_files();
+    files.sort();
+    let mut summary = Summary::default();
+    for entry in files {
+        let Ok(relative) = entry.strip_prefix(&prefix) else {
+            summary.skipped += 1;
+            continue;
+        };
+        if relative.as_os_str().is_empty()
+            || !archive.is_complete(&entry)
+            || archive.nested_archives().contains(&entry)
+        {
+            summary.skipped += 1;
+            continue;
+        }
+        let dest = match join_under(target, relative) {
+            Ok(dest) => dest,
+            Err(e) => {
+                summary.refused.push(e);
+                continue;
+            }
+        };
+        if !dest.exists() {
+            archive.extract_to(&entry, &dest, |_| {})?;
+            summary.added.push(relative.to_path_buf());
+            continue;
+        }
+        if identical(archive, &entry, &dest)? {
+            summary.unchanged += 1;
+            continue;
+        }
+        let saved = join_under(backup, relative)?;
+        std::fs::create_dir_all(saved.parent().unwrap_or(backup))?;
+        std::fs::copy(&dest, &saved)?;
+        archive.extract_to(&entry, &dest, |_| {})?;
+        summary.updated.push(relative.to_path_buf());
+    }
+    Ok(summary)
+}
+
+/// `unarchive apply`: applies each of `archives` in turn, backing replaced files up to `backup`
+/// (a folder next to `target` named after the time if not given).
+pub(crate) fn apply_all(
+    archives: Vec<PathBuf>,
+    target: &Path,
+    backup: Option<PathBuf>,
+    stdin_name: &str,
+    options: &MountOptions,
+) {
+    if !target.is_dir() {
+        fatal!(
+            "{} isn't a folder, point --target at the install's contents",
+            target.display()
+        );
+    }
+    let backup = backup.unwrap_or_else(|| {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut name = target.file_name().unwrap_or_default().to_os_string();
+        name.push(format!("-backup-{}", time));
+        target.with_file_name(name)
+    });
+    for filename in archives {
+        let filename = input(filename, stdin_name);
+        let archive = mount(filename.clone(), options);
+        let summary = apply(&archive, target, &backup)
+            .unwrap_or_else(|e| fatal!("failed to apply {}: {}", filename.display(), e));
+        for refused in &summary.refused {
+            error!("{}", refused);
+        }
+        for path in &summary.added {
+            verbose!("added {}", path.display());
+        }
+        for path in &summary.updated {
+            verbose!("updated {}", path.display());
+        }
+        info!(
+            "{}: {} added, {} updated, {} unchanged, {} skipped",
+            filename.display(),
+            summary.added.len(),
+            summary.updated.len(),
+            summary.unchanged,
+            summary.skipped
+        );
+    }
+    if backup.exists() {
+        info!("replaced files were backed up to {}", backup.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k_archives::{mount_bytes, MarWriter};
+    use std::fs;
+
+    fn update(files: &[(&str, &[u8])]) -> KArchive {
+        let mut writer = MarWriter::new();
+        for (name, data) in files {
+            writer.add_file(name, data.to_vec());
+        }
+        let data = writer.write(Vec::new()).unwrap();
+        mount_bytes("update.mar".into(), data, &MountOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn apply_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let (target, backup) = (dir.path().join("contents"), dir.path().join("backup"));
+        fs::create_dir_all(target.join("data")).unwrap();
+        fs::write(target.join("data/b.bin"), b"old b").unwrap();
+        fs::write(target.join("data/c.bin"), b"same c").unwrap();
+
+        let archive = update(&[
+            ("KFC/contents/data/a.bin", b"new a"),
+            ("KFC/contents/data/b.bin", b"new b"),
+            ("KFC/contents/data/c.bin", b"same c"),
+            ("readme.txt", b"not part of the install"),
+        ]);
+        let summary = apply(&archive, &target, &backup).unwrap();
+        assert_eq!(summary.added, [PathBuf::from("data/a.bin")]);
+        assert_eq!(summary.updated, [PathBuf::from("data/b.bin")]);
+        assert_eq!(summary.unchanged, 1);
+        assert_eq!(summary.skipped, 1);
+        assert!(summary.refused.is_empty());
+
+        assert_eq!(fs::read(target.join("data/a.bin")).unwrap(), b"new a");
+        assert_eq!(fs::read(target.join("data/b.bin")).unwrap(), b"new b");
+        // only what got replaced is backed up
+        assert_eq!(fs::read(backup.join("data/b.bin")).unwrap(), b"old b");
+        assert!(!backup.join("data/c.bin").exists());
+        assert!(!target.join("readme.txt").exists());
+
+        // applying it again changes nothing
+        let summary = apply(&archive, &target, &backup).unwrap();
+        assert!(summary.added.is_empty() && summary.updated.is_empty());
+        assert_eq!(summary.unchanged, 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn apply_refuses_escapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (target, backup) = (dir.path().join("contents"), dir.path().join("backup"));
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(&target).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, target.join("link")).unwrap();
+
+        let archive = update(&[("link/evil.bin", b"evil"), ("fine.bin", b"fine")]);
+        let summary = apply(&archive, &target, &backup).unwrap();
+        assert_eq!(summary.added, [PathBuf::from("fine.bin")]);
+        assert_eq!(summary.refused.len(), 1);
+        assert!(!outside.join("evil.bin").exists());
+    }
+}
@@ -0,0 +1,312 @@
+//! `unarchive browse`, a terminal browser for finding your way around an archive: walk its
+//! folders, look at the start of entries as hex and mark the ones to extract.
+
+use k_archives::KArchive;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// how much of an entry the preview reads
+const PREVIEW_BYTES: u64 = 0x400;
+
+enum Row {
+    Folder(PathBuf),
+    File(PathBuf),
+}
+
+impl Row {
+    fn path(&self) -> &Path {
+        match self {
+            Row::Folder(path) | Row::File(path) => path,
+        }
+    }
+}
+
+struct Browser<'a> {
+    archive: &'a KArchive,
+    files: Vec<PathBuf>,
+    // the folder being shown, "" for the top
+    folder: PathBuf,
+    rows: Vec<Row>,
+    state: ListState,
+    marked: BTreeSet<PathBuf>,
+    // the preview of the last row looked at, reading it again on every key press would be
+    // slow for compressed entries
+    preview: Option<(PathBuf, Vec<Line<'static>>)>,
+}
+
+// `bytes` as lines of 16 in the usual offset / hex / ascii columns
+fn hex_dump(bytes: &[u8]) -> Vec<Line<'static>> {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                    true => b as char,
+                    false => '.',
+                })
+                .collect();
+            Line::from(format!("{:08x}  {:<47}  {}", i * 16, hex.join(" "), ascii))
+        })
+        .collect()
+}
+
+impl<'a> Browser<'a> {
+    fn new(archive: &'a KArchive) -> Self {
+        let mut files = archive.list_files();
+        files.sort();
+        let mut browser = Browser {
+            archive,
+            files,
+            folder: PathBuf::new(),
+            rows: Vec::new(),
+            state: ListState::default(),
+            marked: BTreeSet::new(),
+            preview: None,
+        };
+        browser.open(PathBuf::new());
+        browser
+    }
+
+    // shows `folder`, subfolders first
+    fn open(&mut self, folder: PathBuf) {
+        let mut folders = BTreeSet::new();
+        let mut files = Vec::new();
+        for file in &self.files {
+            let Ok(rest) = file.strip_prefix(&folder) else {
+                continue;
+            };
+            let mut parts = rest.iter();
+            match (parts.next(), parts.next()) {
+                (Some(first), Some(_)) => {
+                    folders.insert(folder.join(first));
+                }
+                (Some(_), None) => files.push(Row::File(file.clone())),
+                _ => {}
+            }
+        }
+        self.rows = folders.into_iter().map(Row::Folder).collect();
+        self.rows.extend(files);
+        self.state.select((!self.rows.is_empty()).then_some(0));
+        self.folder = folder;
+    }
+
+    fn selected(&self) -> Option<&Row> {
+        self.rows.get(self.state.selected()?)
+    }
+
+    fn files_under<'b>(&'b self, folder: &'b Path) -> impl Iterator<Item = &'b PathBuf> {
+        self.files
+            .iter()
+            .filter(move |file| file.starts_with(folder))
+    }
+
+    fn move_by(&mut self, by: isize) {
+        if let Some(selected) = self.state.selected() {
+            let last = self.rows.len().saturating_sub(1);
+            self.state
+                .select(Some(selected.saturating_add_signed(by).min(last)));
+        }
+    }
+
+    // marks the row, or everything in it for folders. if it's all marked already it's
+    // unmarked instead
+    fn toggle(&mut self, row: &Path) {
+        let files: Vec<PathBuf> = self.files_under(row).cloned().collect();
+        if files.iter().all(|file| self.marked.contains(file)) {
+            for file in &files {
+                self.marked.remove(file);
+            }
+        } else {
+            self.marked.extend(files);
+        }
+    }
+
+    fn preview(&mut self) -> Vec<Line<'static>> {
+        let Some(row) = self.selected() else {
+            return Vec::new();
+        };
+        let path = row.path().to_path_buf();
+        if let Some((previewed, lines)) = &self.preview {
+            if *previewed == path {
+                return lines.clone();
+            }
+        }
+        let lines = match row {
+            Row::Folder(folder) => {
+                let (count, size) = self
+                    .files_under(folder)
+                    .filter_map(|file| self.archive.metadata(file))
+                    .fold((0, 0), |(count, size), meta| (count + 1, size + meta.size));
+                vec![Line::from(format!("{} files, {} bytes", count, size))]
+            }
+            Row::File(file) => self.preview_file(file),
+        };
+        self.preview = Some((path, lines.clone()));
+        lines
+    }
+
+    fn preview_file(&self, file: &Path) -> Vec<Line<'static>> {
+        let Some(meta) = self.archive.metadata(file) else {
+            return Vec::new();
+        };
+        let mut notes = vec![format!("{} bytes", meta.size)];
+        if meta.encrypted {
+            notes.push("encrypted".into());
+        }
+        if !self.archive.is_complete(file) {
+            notes.push("incomplete".into());
+        }
+        let mut lines = vec![
+            Line::from(notes.join(", ")),
+            Line::from(format!("in {}", meta.archive.display())),
+            Line::from(""),
+        ];
+        let mut head = Vec::new();
+        let read = self
+            .archive
+            .open(file)
+            .and_then(|entry| entry.take(PREVIEW_BYTES).read_to_end(&mut head));
+        match read {
+            Ok(_) => lines.extend(hex_dump(&head)),
+            Err(e) => lines.push(Line::from(format!("can't read it: {}", e))),
+        }
+        lines
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, help] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+        let [tree, preview] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(main);
+
+        let items: Vec<ListItem> = self
+            .rows
+            .iter()
+            .map(|row| {
+                let (marked, total) = match row {
+                    Row::File(file) => (self.marked.contains(file) as usize, 1),
+                    Row::Folder(folder) => {
+                        self.files_under(folder)
+                            .fold((0, 0), |(marked, total), file| {
+                                (marked + self.marked.contains(file) as usize, total + 1)
+                            })
+                    }
+                };
+                let mark = match marked {
+                    0 => "[ ]",
+                    n if n == total => "[x]",
+                    _ => "[-]",
+                };
+                // iter rather than file_name so folders called . (from tars) still get a name
+                let name = row
+                    .path()
+                    .iter()
+                    .next_back()
+                    .unwrap_or_default()
+                    .to_string_lossy();
+                ListItem::new(match row {
+                    Row::Folder(_) => format!("{} {}/", mark, name),
+                    Row::File(_) => format!("{} {}", mark, name),
+                })
+            })
+            .collect();
+        let title = match self.folder.as_os_str().is_empty() {
+            true => "/".to_string(),
+            false => format!("/{}", self.folder.display()),
+        };
+        let list = List::new(items)
+            .block(Block::bordered().title(title))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, tree, &mut self.state);
+
+        let preview_title = self
+            .selected()
+            .map(|row| row.path().display().to_string())
+            .unwrap_or_default();
+        let lines = self.preview();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(preview_title)),
+            preview,
+        );
+
+        frame.render_widget(
+            Paragraph::new(format!(
+                "up/down move, right/enter open, left back, space mark, a mark all, x extract marked, q quit ({} marked)",
+                self.marked.len()
+            )),
+            help,
+        );
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> std::io::Result<Option<Vec<PathBuf>>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+                KeyCode::Up | KeyCode::Char('k') => self.move_by(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.move_by(1),
+                KeyCode::PageUp => self.move_by(-20),
+                KeyCode::PageDown => self.move_by(20),
+                KeyCode::Right | KeyCode::Enter | KeyCode::Char('l') => {
+                    if let Some(Row::Folder(folder)) = self.selected() {
+                        self.open(folder.clone());
+                    }
+                }
+                KeyCode::Left | KeyCode::Backspace | KeyCode::Char('h') => {
+                    if let Some(parent) = self.folder.parent() {
+                        let left = self.folder.clone();
+                        self.open(parent.to_path_buf());
+                        // keep the folder that was just left selected
+                        let index = self.rows.iter().position(|row| row.path() == left);
+                        self.state.select(index.or(Some(0)));
+                    }
+                }
+                KeyCode::Char(' ') => {
+                    if let Some(row) = self.selected() {
+                        let row = row.path().to_path_buf();
+                        self.toggle(&row);
+                        self.move_by(1);
+                    }
+                }
+                KeyCode::Char('a') => self.toggle(&self.folder.clone()),
+                KeyCode::Char('x') => {
+                    // with nothing marked, the highlighted row is what's meant
+                    if self.marked.is_empty() {
+                        if let Some(row) = self.selected() {
+                            let row = row.path().to_path_buf();
+                            self.toggle(&row);
+                        }
+                    }
+                    return Ok(Some(self.marked.iter().cloned().collect()));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Lets the user look around `archive` until they quit, returning the entries they marked
+/// for extraction, if they chose to extract.
+pub(crate) fn browse(archive: &KArchive) -> std::io::Result<Option<Vec<PathBuf>>> {
+    let mut terminal = ratatui::init();
+    let picked = Browser::new(archive).run(&mut terminal);
+    ratatui::restore();
+    picked
+}
@@ -0,0 +1,91 @@
+//! Extracting into a single archive instead of loose files, which is a lot friendlier to
+//! network storage than hundreds of thousands of small files.
+
+use k_archives::KArchive;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+pub(crate) enum Bundle {
+    Zip(ZipWriter<BufWriter<File>>),
+    Tar(tar::Builder<Box<dyn Write>>),
+}
+
+// hands everything read through it to `on_chunk`, since tar pulls entries from a reader
+struct Tracked<R, F> {
+    inner: R,
+    on_chunk: F,
+}
+
+impl<R: Read, F: FnMut(&[u8])> Read for Tracked<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        (self.on_chunk)(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl Bundle {
+    pub(crate) fn zip(path: &Path) -> std::io::Result<Self> {
+        Ok(Bundle::Zip(ZipWriter::new(BufWriter::new(File::create(
+            path,
+        )?))))
+    }
+
+    /// A tar written to `path`, or stdout if it's "-".
+    pub(crate) fn tar(path: &Path) -> std::io::Result<Self> {
+        let out: Box<dyn Write> = match path.to_str() {
+            Some("-") => Box::new(BufWriter::new(std::io::stdout().lock())),
+            _ => Box::new(BufWriter::new(File::create(path)?)),
+        };
+        Ok(Bundle::Tar(tar::Builder::new(out)))
+    }
+
+    /// Streams `entry` of `archive` into the bundle as `name`.
+    pub(crate) fn add(
+        &mut self,
+        archive: &KArchive,
+        entry: &Path,
+        name: &str,
+        on_chunk: impl FnMut(&[u8]),
+    ) -> std::io::Result<()> {
+        match self {
+            Bundle::Zip(zip) => {
+                let size = archive.available_size(entry).unwrap_or_default();
+                let options = FileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .large_file(size >= u32::MAX as u64);
+                zip.start_file(name, options)?;
+                archive.extract_into(entry, zip, on_chunk)?;
+            }
+            Bundle::Tar(tar) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(archive.available_size(entry).unwrap_or_default());
+                header.set_mode(0o644);
+                // entries don't have timestamps of their own, the archive they're in does
+                let modified = archive
+                    .metadata(entry)
+                    .and_then(|meta| std::fs::metadata(meta.archive).ok())
+                    .and_then(|meta| meta.modified().ok())
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+                header.set_mtime(modified.unwrap_or_default().as_secs());
+                let file = Tracked {
+                    inner: archive.open(entry)?,
+                    on_chunk,
+                };
+                tar.append_data(&mut header, name, file)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> std::io::Result<()> {
+        match self {
+            Bundle::Zip(mut zip) => zip.finish()?.flush(),
+            Bundle::Tar(tar) => tar.into_inner()?.flush(),
+        }
+    }
+}
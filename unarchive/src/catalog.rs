@@ -0,0 +1,382 @@
+//! `unarchive catalog` and `unarchive query`, a SQLite database of what's in a collection of
+//! archives. Two tables, which `query` can run any SQL against:
+//!
+//! - `archives`: `id`, `path`, `game` and `datecode` (from a descriptor or the file name),
+//!   `entries`, `size` and `stored_size` totals, `len` and `modified` of the file when it was
+//!   cataloged (which is how unchanged archives are skipped), `hash_type` and `cataloged`.
+//! - `entries`: `archive` (an archives id), `path`, `size`, `stored_size`, `encrypted`,
+//!   `modified` and `hash` when the catalog was made with --hash.
+
+use crate::logging::{error, info, verbose};
+use k_archives::{
+    find_archives, hash_reader, is_url, mount_with_options, ChecksumType, MountOptions, UpdateInfo,
+};
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, ToSql};
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS archives (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    game TEXT,
+    datecode TEXT,
+    entries INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    stored_size INTEGER NOT NULL,
+    len INTEGER NOT NULL,
+    modified INTEGER,
+    hash_type TEXT,
+    cataloged INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS entries (
+    archive INTEGER NOT NULL REFERENCES archives(id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    stored_size INTEGER NOT NULL,
+    encrypted INTEGER NOT NULL,
+    modified INTEGER,
+    hash TEXT,
+    PRIMARY KEY (archive, path)
+);
+CREATE INDEX IF NOT EXISTS entries_path ON entries(path);
+CREATE INDEX IF NOT EXISTS entries_hash ON entries(hash);
+CREATE INDEX IF NOT EXISTS archives_game ON archives(game, datecode);
+";
+
+/// The canned queries `unarchive query` has flags for.
+pub(crate) const GAMES: &str = "SELECT game, COUNT(*) AS versions, MIN(datecode) AS first, \
+     MAX(datecode) AS last, SUM(size) AS size FROM archives GROUP BY game ORDER BY game";
+pub(crate) const VERSIONS: &str = "SELECT datecode, path, entries, size FROM archives \
+     WHERE game = ?1 ORDER BY datecode, path";
+pub(crate) const FIND: &str = "SELECT archives.path AS archive, entries.path, entries.size \
+     FROM entries JOIN archives ON archives.id = entries.archive \
+     WHERE instr(lower(entries.path), lower(?1)) > 0 ORDER BY archives.path, entries.path";
+
+fn unix_time(time: Option<SystemTime>) -> Option<i64> {
+    let since = time?.duration_since(UNIX_EPOCH).ok()?;
+    Some(since.as_secs() as i64)
+}
+
+// adds or refreshes `path`, returning whether it had to be (re)cataloged
+fn catalog_archive(
+    db: &mut Connection,
+    path: &Path,
+    hash: Option<ChecksumType>,
+    options: &MountOptions,
+) -> Result<bool, String> {
+    let path = match is_url(path) {
+        true => path.to_path_buf(),
+        false => path.canonicalize().map_err(|e| e.to_string())?,
+    };
+    let name = path.to_string_lossy().to_string();
+    let meta = std::fs::metadata(&path).ok();
+    let len = meta.as_ref().map_or(0, |meta| meta.len() as i64);
+    let modified = unix_time(meta.and_then(|meta| meta.modified().ok()));
+    let hash_type = hash.map(|hash| hash.name());
+    let existing: Option<(i64, Option<i64>, Option<String>)> = db
+        .query_row(
+            "SELECT len, modified, hash_type FROM archives WHERE path = ?1",
+            [&name],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?;
+    if modified.is_some() && existing == Some((len, modified, hash_type.map(str::to_string))) {
+        return Ok(false);
+    }
+
+    let archive = mount_with_options(path.clone(), options).map_err(|e| e.to_string())?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let info = archive
+        .update_info()
+        .cloned()
+        .unwrap_or_else(|| UpdateInfo::from_name(&file_name));
+    let mut files = archive.list_files();
+    files.sort();
+    let mut entries = Vec::with_capacity(files.len());
+    for file in files {
+        let Some(meta) = archive.metadata(&file) else {
+            continue;
+        };
+        let hash = match hash {
+            Some(hash) => {
+                let mut reader = archive.open(&file).map_err(|e| e.to_string())?;
+                let digest = hash_reader(&mut reader, hash);
+                Some(digest.map_err(|e| format!("can't read {}: {}", file.display(), e))?)
+            }
+            None => None,
+        };
+        entries.push((file.to_string_lossy().replace('\\', "/"), meta, hash));
+    }
+
+    // all or nothing, so an interrupted run doesn't leave an archive half cataloged
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    let sql = |e: rusqlite::Error| e.to_string();
+    tx.execute("DELETE FROM archives WHERE path = ?1", [&name])
+        .map_err(sql)?;
+    tx.execute(
+        "INSERT INTO archives (path, game, datecode, entries, size, stored_size, len, modified, \
+         hash_type, cataloged) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            name,
+            info.game_code,
+            info.datecode,
+            entries.len() as i64,
+            entries
+                .iter()
+                .map(|(_, meta, _)| meta.size as i64)
+                .sum::<i64>(),
+            entries
+                .iter()
+                .map(|(_, meta, _)| meta.stored_size as i64)
+                .sum::<i64>(),
+            len,
+            modified,
+            hash_type,
+            unix_time(Some(SystemTime::now())),
+        ],
+    )
+    .map_err(sql)?;
+    let id = tx.last_insert_rowid();
+    {
+        let mut insert = tx
+            .prepare(
+                "INSERT OR REPLACE INTO entries (archive, path, size, stored_size, encrypted, \
+                 modified, hash) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )
+            .map_err(sql)?;
+        for (path, meta, hash) in &entries {
+            insert
+                .execute(params![
+                    id,
+                    path,
+                    meta.size as i64,
+                    meta.stored_size as i64,
+                    meta.encrypted,
+                    unix_time(meta.modified),
+                    hash,
+                ])
+                .map_err(sql)?;
+        }
+    }
+    tx.commit().map_err(sql)?;
+    Ok(true)
+}
+
+/// Catalogs every archive in `paths` (archives, or folders searched for them) into the
+/// database at `db_path`, which is created if it doesn't exist.
+pub(crate) fn catalog(
+    paths: &[PathBuf],
+    db_path: &Path,
+    hash: Option<ChecksumType>,
+    prune: bool,
+    options: &MountOptions,
+) -> Result<(), String> {
+    let mut db = Connection::open(db_path)
+        .map_err(|e| format!("can't open {}: {}", db_path.display(), e))?;
+    db.execute_batch("PRAGMA foreign_keys = ON;")
+        .and_then(|_| db.execute_batch(SCHEMA))
+        .map_err(|e| format!("can't set up {}: {}", db_path.display(), e))?;
+    let mut archives = Vec::new();
+    for path in paths {
+        match path.is_dir() {
+            true => archives.extend(
+                find_archives(path)
+                    .map_err(|e| format!("failed to search {}: {}", path.display(), e))?,
+            ),
+            false => archives.push(path.clone()),
+        }
+    }
+    let mut cataloged = 0;
+    for archive in &archives {
+        match catalog_archive(&mut db, archive, hash, options) {
+            Ok(true) => {
+                info!("{}", archive.display());
+                cataloged += 1;
+            }
+            Ok(false) => verbose!("{} hasn't changed", archive.display()),
+            Err(e) => error!("failed to catalog {}: {}", archive.display(), e),
+        }
+    }
+    if prune {
+        let known: Vec<String> = db
+            .prepare("SELECT path FROM archives")
+            .and_then(|mut select| {
+                select
+                    .query_map([], |row| row.get(0))?
+                    .collect::<Result<_, _>>()
+            })
+            .map_err(|e| e.to_string())?;
+        for path in known {
+            if !is_url(Path::new(&path)) && !Path::new(&path).exists() {
+                info!("{} is gone", path);
+                db.execute("DELETE FROM archives WHERE path = ?1", [&path])
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+    }
+    let total: i64 = db
+        .query_row("SELECT COUNT(*) FROM archives", [], |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+    info!(
+        "cataloged {} of {} archives, {} in {}",
+        cataloged,
+        archives.len(),
+        total,
+        db_path.display()
+    );
+    Ok(())
+}
+
+fn json_value(value: ValueRef) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(n) => json!(n),
+        ValueRef::Real(n) => json!(n),
+        ValueRef::Text(text) => json!(String::from_utf8_lossy(text)),
+        ValueRef::Blob(blob) => json!(format!("<{} byte blob>", blob.len())),
+    }
+}
+
+/// Runs `sql` against the catalog at `db_path` (read only) and prints the rows, tab separated
+/// under a header or as JSON. Returns how many there were.
+pub(crate) fn query(
+    db_path: &Path,
+    sql: &str,
+    params: &[&dyn ToSql],
+    json: bool,
+) -> Result<usize, String> {
+    if !db_path.exists() {
+        return Err(format!(
+            "there's no catalog at {}, make one with `unarchive catalog`",
+            db_path.display()
+        ));
+    }
+    let db = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("can't open {}: {}", db_path.display(), e))?;
+    let mut select = db.prepare(sql).map_err(|e| e.to_string())?;
+    let columns: Vec<String> = select
+        .column_names()
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+    let mut rows = select.query(params).map_err(|e| e.to_string())?;
+    let mut found = Vec::new();
+    while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+        let values: Vec<Value> = (0..columns.len())
+            .map(|i| row.get_ref(i).map_or(Value::Null, json_value))
+            .collect();
+        found.push(values);
+    }
+    if json {
+        let objects: Vec<Value> = found
+            .iter()
+            .map(|values| {
+                let object: Map<String, Value> = columns
+                    .iter()
+                    .cloned()
+                    .zip(values.iter().cloned())
+                    .collect();
+                Value::Object(object)
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&objects).unwrap());
+    } else {
+        println!("{}", columns.join("\t"));
+        for values in &found {
+            let cells: Vec<String> = values
+                .iter()
+                .map(|value| match value {
+                    Value::Null => String::new(),
+                    Value::String(text) => text.clone(),
+                    value => value.to_string(),
+                })
+                .collect();
+            println!("{}", cells.join("\t"));
+        }
+    }
+    Ok(found.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k_archives::MarWriter;
+
+    #[test]
+    fn catalog_and_query() {
+        let dir = tempfile::tempdir().unwrap();
+        let collection = dir.path().join("updates");
+        std::fs::create_dir_all(&collection).unwrap();
+        let write = |name: &str, files: &[(&str, &[u8])]| {
+            let mut writer = MarWriter::new();
+            for (file, data) in files {
+                writer.add_file(file, data.to_vec());
+            }
+            let path = collection.join(name);
+            writer.write(std::fs::File::create(&path).unwrap()).unwrap();
+            path
+        };
+        let first = write(
+            "KFC-2023010100.mar",
+            &[("data/a.bin", b"aaaa"), ("data/b.bin", b"bb")],
+        );
+        let second = write("KFC-2023020100.mar", &[("data/B.bin", b"b")]);
+        let db_path = dir.path().join("collection.sqlite");
+        let options = MountOptions::default();
+
+        catalog(
+            std::slice::from_ref(&collection),
+            &db_path,
+            Some(ChecksumType::Md5),
+            false,
+            &options,
+        )
+        .unwrap();
+        let db = Connection::open(&db_path).unwrap();
+        let (game, datecode, entries, size): (String, String, i64, i64) = db
+            .query_row(
+                "SELECT game, datecode, entries, size FROM archives WHERE datecode = '2023010100'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (game.as_str(), datecode.as_str(), entries, size),
+            ("KFC", "2023010100", 2, 6)
+        );
+        let hash: String = db
+            .query_row(
+                "SELECT hash FROM entries WHERE path = 'data/a.bin'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(hash, "74b87337454200d4d33f80c4663dc5e5");
+
+        // unchanged archives are skipped, unless they're to be hashed another way
+        let mut db = Connection::open(&db_path).unwrap();
+        let md5 = Some(ChecksumType::Md5);
+        assert!(!catalog_archive(&mut db, &first, md5, &options).unwrap());
+        assert!(catalog_archive(&mut db, &first, None, &options).unwrap());
+
+        assert_eq!(query(&db_path, GAMES, &[], false).unwrap(), 1);
+        assert_eq!(query(&db_path, VERSIONS, &[&"KFC"], true).unwrap(), 2);
+        // case insensitive
+        assert_eq!(query(&db_path, FIND, &[&"b.BIN"], false).unwrap(), 2);
+        assert!(query(&db_path, "SELECT nope FROM archives", &[], false).is_err());
+        assert!(query(&dir.path().join("none.sqlite"), GAMES, &[], false).is_err());
+
+        // gone archives are pruned, with their entries
+        std::fs::remove_file(second).unwrap();
+        catalog(&[collection], &db_path, None, true, &options).unwrap();
+        assert_eq!(query(&db_path, VERSIONS, &[&"KFC"], false).unwrap(), 1);
+        assert_eq!(
+            query(&db_path, "SELECT * FROM entries", &[], false).unwrap(),
+            2
+        );
+    }
+}
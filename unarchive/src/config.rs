@@ -0,0 +1,46 @@
+//! Defaults for flags that otherwise get repeated on every run, read from a TOML file:
+//!
+//! ```toml
+//! output-folder = "D:/extracted"
+//! threads = 8
+//! overwrite = "newer"
+//! ```
+//!
+//! Anything given on the command line wins over the file.
+
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Config {
+    pub output_folder: Option<PathBuf>,
+    pub threads: Option<usize>,
+    // one of the --overwrite values, checked when it's used so the error names the choices
+    pub overwrite: Option<String>,
+}
+
+/// Where the config is read from when --config isn't given: unarchive/config.toml in
+/// %APPDATA% on Windows, $XDG_CONFIG_HOME or ~/.config everywhere else.
+pub(crate) fn default_path() -> Option<PathBuf> {
+    let dir = match cfg!(windows) {
+        true => PathBuf::from(std::env::var_os("APPDATA")?),
+        false => std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")))?,
+    };
+    Some(dir.join("unarchive").join("config.toml"))
+}
+
+/// Reads the config at `path`. A missing file is only an error when `required`, ie. it was
+/// asked for with --config.
+pub(crate) fn load(path: &Path, required: bool) -> Result<Config, String> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+            return Ok(Config::default())
+        }
+        Err(e) => return Err(format!("can't read {}: {}", path.display(), e)),
+    };
+    toml::from_str(&contents).map_err(|e| format!("bad config {}: {}", path.display(), e))
+}
@@ -0,0 +1,360 @@
+//! `unarchive daemon`, which keeps archives mounted and answers JSON-RPC 2.0 requests about
+//! them on a unix socket, so tools that keep coming back to the same big archive don't pay for
+//! mounting it every time. Requests and responses are one JSON object per line, eg.
+//! `{"jsonrpc": "2.0", "id": 1, "method": "stat", "params": {"archive": "/dump/update.mar", "path": "data/a.bin"}}`.
+//!
+//! - `mount {archive}` mounts an archive ahead of time (anything else mounts it on first use)
+//!   and returns how many entries it has and the warnings mounting it gave.
+//! - `list {archive}` returns the path of every entry, sorted, with `/` between folders.
+//! - `stat {archive, path}` returns the entry's `size`, `stored_size` and whether it's
+//!   `encrypted`, and its `modified` time (unix seconds) and unix `mode` where the archive
+//!   stores them, null otherwise.
+//! - `read {archive, path, offset, length}` returns the entry's bytes as base64, from `offset`
+//!   and up to `length` of them (the start and all of it if they're left out).
+//! - `unmount {archive}` forgets an archive, eg. once it's been replaced on disk.
+//! - `archives` lists what's mounted.
+//!
+//! Relative archive paths are taken from the folder the daemon was started in, so tools
+//! should send absolute ones. Urls work too, like everywhere else.
+
+use crate::logging::{info, verbose};
+use base64::Engine;
+use k_archives::{is_url, mount_with_options, KArchive, MountOptions};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+// the codes JSON-RPC defines
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// the archive or entry couldn't be mounted or read, from the range it leaves to servers
+const ARCHIVE_ERROR: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+fn invalid_params(message: String) -> RpcError {
+    RpcError {
+        code: INVALID_PARAMS,
+        message,
+    }
+}
+
+fn archive_error(message: String) -> RpcError {
+    RpcError {
+        code: ARCHIVE_ERROR,
+        message,
+    }
+}
+
+fn param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid_params(format!("{} is missing", name)))
+}
+
+// what an archive is known by, so the same one asked for two ways is only mounted once
+fn key(archive: &str) -> PathBuf {
+    let path = PathBuf::from(archive);
+    match is_url(&path) {
+        true => path,
+        false => std::fs::canonicalize(&path).unwrap_or(path),
+    }
+}
+
+struct Daemon<'a> {
+    options: &'a MountOptions,
+    archives: Mutex<HashMap<PathBuf, Arc<KArchive>>>,
+}
+
+impl Daemon<'_> {
+    fn archive(&self, params: &Value) -> Result<Arc<KArchive>, RpcError> {
+        let key = key(param(params, "archive")?);
+        if let Some(archive) = self.archives.lock().unwrap().get(&key) {
+            return Ok(archive.clone());
+        }
+        // mounted without holding the lock, so requests about other archives aren't held up
+        let archive = mount_with_options(key.clone(), self.options)
+            .map_err(|e| archive_error(format!("failed to parse {}: {}", key.display(), e)))?;
+        verbose!("mounted {}", key.display());
+        let mut archives = self.archives.lock().unwrap();
+        Ok(archives.entry(key).or_insert(Arc::new(archive)).clone())
+    }
+
+    fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "mount" => {
+                let archive = self.archive(params)?;
+                let warnings: Vec<String> =
+                    archive.warnings().iter().map(|w| w.to_string()).collect();
+                Ok(json!({"files": archive.list_files().len(), "warnings": warnings}))
+            }
+            "list" => {
+                let mut files: Vec<String> = self
+                    .archive(params)?
+                    .list_files()
+                    .iter()
+                    .map(|file| file.to_string_lossy().replace('\\', "/"))
+                    .collect();
+                files.sort();
+                Ok(json!(files))
+            }
+            "stat" => {
+                let archive = self.archive(params)?;
+                let path = param(params, "path")?;
+                let meta = archive
+                    .metadata(Path::new(path))
+                    .ok_or_else(|| archive_error(format!("{} isn't in the archive", path)))?;
+                let modified = meta
+                    .modified
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|since| since.as_secs());
+                Ok(json!({
+                    "size": meta.size,
+                    "stored_size": meta.stored_size,
+                    "encrypted": meta.encrypted,
+                    "modified": modified,
+                    "mode": meta.mode,
+                }))
+            }
+            "read" => {
+                let archive = self.archive(params)?;
+                let path = param(params, "path")?;
+                let number = |name| match params.get(name) {
+                    None | Some(Value::Null) => Ok(None),
+                    Some(value) => value
+                        .as_u64()
+                        .map(Some)
+                        .ok_or_else(|| invalid_params(format!("{} has to be a number", name))),
+                };
+                let (offset, length) = (number("offset")?, number("length")?);
+                let error =
+                    |e: std::io::Error| archive_error(format!("can't read {}: {}", path, e));
+                let mut file = archive.open(Path::new(path)).map_err(error)?;
+                file.seek(SeekFrom::Start(offset.unwrap_or(0)))
+                    .map_err(error)?;
+                let mut data = Vec::new();
+                file.take(length.unwrap_or(u64::MAX))
+                    .read_to_end(&mut data)
+                    .map_err(error)?;
+                Ok(json!(base64::engine::general_purpose::STANDARD.encode(data)))
+            }
+            "unmount" => {
+                let key = key(param(params, "archive")?);
+                Ok(json!(self.archives.lock().unwrap().remove(&key).is_some()))
+            }
+            "archives" => {
+                let mut archives: Vec<String> = self
+                    .archives
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .map(|key| key.display().to_string())
+                    .collect();
+                archives.sort();
+                Ok(json!(archives))
+            }
+            _ => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("there's no {} method", method),
+            }),
+        }
+    }
+
+    // the response to one line, none for notifications (requests without an id)
+    fn respond(&self, line: &str) -> Option<Value> {
+        let reply = |id: Value, result: Result<Value, RpcError>| match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": e.code, "message": e.message},
+            }),
+        };
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = RpcError {
+                    code: PARSE_ERROR,
+                    message: e.to_string(),
+                };
+                return Some(reply(Value::Null, Err(error)));
+            }
+        };
+        let id = request.get("id").cloned();
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            let error = RpcError {
+                code: INVALID_REQUEST,
+                message: "requests need a method".into(),
+            };
+            return Some(reply(id.unwrap_or(Value::Null), Err(error)));
+        };
+        let params = request.get("params").unwrap_or(&Value::Null);
+        let result = self.call(method, params);
+        Some(reply(id?, result))
+    }
+
+    fn handle(&self, stream: UnixStream) -> std::io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.respond(&line) {
+                writeln!(writer, "{}", response)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Mounts `archives` and answers requests on `socket` until killed.
+pub(crate) fn daemon(
+    socket: &Path,
+    archives: &[PathBuf],
+    options: &MountOptions,
+) -> Result<(), String> {
+    // a socket left behind by a daemon that was killed is in the way, one that's running isn't.
+    // anything that isn't a socket is left alone, it's more likely a mistyped --socket
+    if let Ok(meta) = std::fs::symlink_metadata(socket) {
+        if !meta.file_type().is_socket() {
+            return Err(format!(
+                "{} exists and isn't a socket, not replacing it",
+                socket.display()
+            ));
+        }
+        if UnixStream::connect(socket).is_ok() {
+            return Err(format!(
+                "a daemon is already listening on {}",
+                socket.display()
+            ));
+        }
+        std::fs::remove_file(socket)
+            .map_err(|e| format!("can't replace {}: {}", socket.display(), e))?;
+    }
+    let daemon = Daemon {
+        options,
+        archives: Mutex::new(HashMap::new()),
+    };
+    for archive in archives {
+        let params = json!({"archive": archive.to_string_lossy()});
+        daemon.archive(&params).map_err(|e| e.message)?;
+    }
+    let listener = UnixListener::bind(socket)
+        .map_err(|e| format!("can't listen on {}: {}", socket.display(), e))?;
+    info!("listening on {}", socket.display());
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let daemon = &daemon;
+                    scope.spawn(move || {
+                        if let Err(e) = daemon.handle(stream) {
+                            verbose!("connection dropped: {}", e);
+                        }
+                    });
+                }
+                Err(e) => verbose!("failed to accept a connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k_archives::MarWriter;
+
+    #[test]
+    fn responses() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.mar");
+        let mut writer = MarWriter::new();
+        writer.add_file("data/a.bin", b"0123456789".to_vec());
+        writer.write(std::fs::File::create(&path).unwrap()).unwrap();
+
+        let options = MountOptions::default();
+        let daemon = Daemon {
+            options: &options,
+            archives: Mutex::new(HashMap::new()),
+        };
+        let respond = |request: Value| daemon.respond(&request.to_string()).unwrap();
+        let archive = path.to_str().unwrap();
+
+        let response = daemon.respond("{\"jsonrpc\": \"2.0\", ").unwrap();
+        assert_eq!(response["id"], Value::Null);
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+        let response = respond(json!({"jsonrpc": "2.0", "id": 1}));
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+        let response = respond(json!({"jsonrpc": "2.0", "id": 2, "method": "nope"}));
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+
+        // notifications get no response, but still do what they ask
+        let notification =
+            json!({"jsonrpc": "2.0", "method": "mount", "params": {"archive": archive}});
+        assert_eq!(daemon.respond(&notification.to_string()), None);
+        assert_eq!(daemon.archives.lock().unwrap().len(), 1);
+
+        let read = |id, params: Value| {
+            let response =
+                respond(json!({"jsonrpc": "2.0", "id": id, "method": "read", "params": params}));
+            assert_eq!(response["id"], id);
+            let data = response["result"].as_str().unwrap();
+            base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .unwrap()
+        };
+        assert_eq!(
+            read(3, json!({"archive": archive, "path": "data/a.bin"})),
+            b"0123456789"
+        );
+        assert_eq!(
+            read(
+                4,
+                json!({"archive": archive, "path": "data/a.bin", "offset": 2, "length": 3})
+            ),
+            b"234"
+        );
+        assert_eq!(
+            read(
+                5,
+                json!({"archive": archive, "path": "data/a.bin", "offset": 7})
+            ),
+            b"789"
+        );
+        assert_eq!(
+            read(
+                6,
+                json!({"archive": archive, "path": "data/a.bin", "offset": 20})
+            ),
+            b""
+        );
+
+        let response = respond(json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": "read",
+            "params": {"archive": archive, "path": "data/a.bin", "offset": "2"},
+        }));
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        let response = respond(json!({
+            "jsonrpc": "2.0",
+            "id": 8,
+            "method": "read",
+            "params": {"archive": archive, "path": "data/nope.bin"},
+        }));
+        assert_eq!(response["error"]["code"], ARCHIVE_ERROR);
+    }
+}
@@ -0,0 +1,96 @@
+//! --dedupe: files with the same contents as one already extracted during the run are replaced
+//! with a link to it instead of being stored twice. Content addressed entries are often
+//! duplicated across parts and versions, so this adds up quickly on multi version mirrors.
+
+use crate::logging::warning;
+use clap::ArgEnum;
+use k_archives::ChecksumType;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// What extracted files are hashed with to find duplicates.
+pub(crate) const HASH: ChecksumType = ChecksumType::Sha256;
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub(crate) enum LinkKind {
+    /// Hard links, which work on any filesystem but are all the same file (editing one edits them all)
+    Hardlink,
+    /// Copy on write clones (btrfs, xfs, apfs, refs), which stay separate files
+    Reflink,
+}
+
+pub(crate) struct Dedupe {
+    kind: LinkKind,
+    // (size, hash) of everything extracted so far, and where it first went
+    seen: Mutex<HashMap<(u64, String), PathBuf>>,
+    linked: AtomicU64,
+    saved: AtomicU64,
+    // set once linking fails, so a filesystem that can't do it isn't retried for every file
+    disabled: AtomicBool,
+}
+
+impl Dedupe {
+    pub(crate) fn new(kind: LinkKind) -> Self {
+        Self {
+            kind,
+            seen: Mutex::new(HashMap::new()),
+            linked: AtomicU64::new(0),
+            saved: AtomicU64::new(0),
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    /// Called once `dest` has been written with `size` bytes hashing to `hash`. If an
+    /// earlier file had the same contents, `dest` is replaced with a link to it.
+    pub(crate) fn add(&self, dest: &Path, size: u64, hash: String) {
+        if size == 0 || self.disabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let first = match self.seen.lock().unwrap().entry((size, hash)) {
+            Entry::Occupied(first) => first.get().clone(),
+            Entry::Vacant(vacant) => {
+                vacant.insert(dest.to_path_buf());
+                return;
+            }
+        };
+        if let Err(e) = self.link(&first, dest) {
+            if !self.disabled.swap(true, Ordering::Relaxed) {
+                warning!(
+                    "can't link {} to {} ({}), not deduplicating anything else",
+                    dest.display(),
+                    first.display(),
+                    e
+                );
+            }
+            return;
+        }
+        self.linked.fetch_add(1, Ordering::Relaxed);
+        self.saved.fetch_add(size, Ordering::Relaxed);
+    }
+
+    // the link is made next to `dest` and renamed over it, so `dest` is never missing
+    fn link(&self, first: &Path, dest: &Path) -> std::io::Result<()> {
+        let mut temp = dest.as_os_str().to_owned();
+        temp.push(".dedupe");
+        let temp = PathBuf::from(temp);
+        let _ = std::fs::remove_file(&temp);
+        match self.kind {
+            LinkKind::Hardlink => std::fs::hard_link(first, &temp)?,
+            LinkKind::Reflink => reflink_copy::reflink(first, &temp)?,
+        }
+        std::fs::rename(&temp, dest).inspect_err(|_| {
+            let _ = std::fs::remove_file(&temp);
+        })
+    }
+
+    /// How many files were linked, and how many bytes that saved.
+    pub(crate) fn summary(&self) -> (u64, u64) {
+        (
+            self.linked.load(Ordering::Relaxed),
+            self.saved.load(Ordering::Relaxed),
+        )
+    }
+}
@@ -0,0 +1,804 @@
+//! Extracting: planning where each entry goes (--strip-components, --flat, --overwrite,
+//! --sync), writing them out and what happens around that (--write-manifest, --dedupe,
+//! --check-names, --to-zip/--to-tar).
+
+use crate::bundle::Bundle;
+use crate::dedupe::{self, Dedupe};
+use crate::inspect::{list, list_json, unix_time};
+use crate::logging::{self, error, fatal, info, verbose, warning, Verbosity, EXIT_INTERRUPTED};
+use crate::template::{render_template, template_fields, TEMPLATE_FIELDS};
+use crate::{input, interrupt, log_warnings, mount, Args};
+use clap::ArgEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use k_archives::{
+    content_hash_name, find_archives, hash_file, hash_reader, is_url, join_under,
+    mount_with_options, windows_safe_path, ChecksumType, Checksummer, EntryFilter, KArchive,
+    MountOptions, WriteOptions,
+};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Overwrite {
+    /// Replace files that already exist
+    Always,
+    /// Leave files that already exist alone
+    Never,
+    /// Replace existing files that are older than the archive (or part) the entry is in
+    Newer,
+    /// Ask about each file that already exists
+    Prompt,
+    /// Replace existing files whose size or contents differ from the entry
+    Changed,
+}
+
+// the entry names in a --files-from list, skipping blank lines
+fn read_names(list: &Path) -> std::io::Result<Vec<String>> {
+    let contents = match list == Path::new("-") {
+        true => std::io::read_to_string(std::io::stdin())?,
+        false => std::fs::read_to_string(list)?,
+    };
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+// a progress bar when stdout is a terminal, otherwise a line per file like before. when
+// stdout is taken (by a tar), the bar goes on stderr if that's a terminal and nothing's
+// printed otherwise
+enum Progress {
+    Bar(ProgressBar),
+    Log,
+    Quiet,
+}
+
+impl Progress {
+    fn new(total_bytes: u64, stdout_taken: bool) -> Self {
+        if !logging::enabled(Verbosity::Normal) {
+            return Progress::Quiet;
+        }
+        match stdout_taken {
+            true if !std::io::stderr().is_terminal() => return Progress::Quiet,
+            false if !std::io::stdout().is_terminal() => return Progress::Log,
+            _ => {}
+        }
+        let bar = ProgressBar::new(total_bytes);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] [{bar:40}] {bytes}/{total_bytes} ({eta}) {msg}")
+                .progress_chars("##-"),
+        );
+        Progress::Bar(bar)
+    }
+
+    fn start(&self, path: &Path) {
+        match self {
+            Progress::Bar(bar) => bar.set_message(path.display().to_string()),
+            Progress::Log => println!("{}", path.display()),
+            Progress::Quiet => {}
+        }
+    }
+
+    fn advance(&self, bytes: u64) {
+        if let Progress::Bar(bar) = self {
+            bar.inc(bytes);
+        }
+    }
+
+    fn finish(&self) {
+        if let Progress::Bar(bar) = self {
+            bar.finish_with_message("done");
+        }
+    }
+}
+
+// names inside zips and tars always use forward slashes
+fn member_name(path: &Path) -> String {
+    path.iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// how entry paths map to paths under the output folder
+#[derive(Clone, Copy)]
+enum Layout {
+    Tree { strip_components: usize },
+    Flat,
+}
+
+impl Layout {
+    // None when the entry has nothing left after stripping
+    fn relative_path(&self, entry: &Path) -> Option<PathBuf> {
+        let path: PathBuf = match self {
+            Layout::Tree { strip_components } => entry.iter().skip(*strip_components).collect(),
+            Layout::Flat => entry.file_name()?.into(),
+        };
+        Some(path).filter(|path| !path.as_os_str().is_empty())
+    }
+}
+
+// whether names Windows can't store get renamed. always on there
+fn windows_names(forced: bool) -> bool {
+    cfg!(windows) || forced
+}
+
+// an entry that extracting will write, and where to
+struct Planned<'a> {
+    entry: &'a Path,
+    dest: PathBuf,
+    size: u64,
+}
+
+// whether the file `dest` already holds what `entry` does. only hashed when the sizes match
+fn same_contents(archive: &KArchive, entry: &Path, dest: &Path) -> std::io::Result<bool> {
+    let size = archive.metadata(entry).map(|meta| meta.size);
+    if size != Some(std::fs::metadata(dest)?.len()) {
+        return Ok(false);
+    }
+    let entry_hash = hash_reader(&mut archive.open(entry)?, ChecksumType::Md5)?;
+    Ok(entry_hash == hash_file(dest, ChecksumType::Md5)?)
+}
+
+// files under `output` that none of the archive's entries (filtered or not) go to, for
+// --delete. manifests an earlier --write-manifest left there aren't stale
+fn stale_files(
+    archive: &KArchive,
+    output: &Path,
+    layout: Layout,
+    rename_for_windows: bool,
+) -> Vec<PathBuf> {
+    let nested = archive.nested_archives();
+    let wanted: HashSet<PathBuf> = archive
+        .list_files()
+        .iter()
+        .filter(|path| !nested.contains(path))
+        .filter_map(|path| layout.relative_path(path))
+        .map(|relative| match windows_safe_path(&relative) {
+            Some(safe) if rename_for_windows => output.join(safe),
+            _ => output.join(relative),
+        })
+        .collect();
+    let checksums = [
+        ChecksumType::Md5,
+        ChecksumType::Sha1,
+        ChecksumType::Sha256,
+        ChecksumType::Crc32,
+    ];
+    let manifests: HashSet<PathBuf> = checksums
+        .into_iter()
+        .flat_map(|checksum| [false, true].map(|json| manifest_path(output, checksum, json)))
+        .collect();
+    let mut stale = Vec::new();
+    let mut dirs = vec![output.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() => dirs.push(path),
+                Ok(_) if !wanted.contains(&path) && !manifests.contains(&path) => stale.push(path),
+                _ => {}
+            }
+        }
+    }
+    stale.sort();
+    stale
+}
+
+// whether `dest`, which already exists, should be replaced with `entry`. answering "all" or
+// "none" to a prompt turns `overwrite` into always or never for the rest of the run
+fn replace_existing(
+    archive: &KArchive,
+    entry: &Path,
+    dest: &Path,
+    overwrite: &mut Overwrite,
+) -> bool {
+    match overwrite {
+        Overwrite::Always => true,
+        Overwrite::Never => false,
+        Overwrite::Newer => {
+            let modified = |path: &Path| std::fs::metadata(path).and_then(|meta| meta.modified());
+            let source = archive.metadata(entry).map(|meta| meta.archive);
+            match (source.map(|source| modified(&source)), modified(dest)) {
+                (Some(Ok(source)), Ok(existing)) => source > existing,
+                // can't tell, so leave it alone
+                _ => false,
+            }
+        }
+        Overwrite::Changed => !same_contents(archive, entry, dest).unwrap_or(false),
+        Overwrite::Prompt => loop {
+            print!(
+                "{} exists, overwrite? [y]es, [n]o, [a]ll, n[o]ne: ",
+                dest.display()
+            );
+            std::io::stdout().flush().unwrap();
+            let mut answer = String::new();
+            if std::io::stdin().lock().read_line(&mut answer).unwrap_or(0) == 0 {
+                // stdin closed, don't overwrite anything without being told to
+                *overwrite = Overwrite::Never;
+                return false;
+            }
+            match answer.trim() {
+                "y" | "yes" => return true,
+                "n" | "no" => return false,
+                "a" | "all" => {
+                    *overwrite = Overwrite::Always;
+                    return true;
+                }
+                "o" | "none" => {
+                    *overwrite = Overwrite::Never;
+                    return false;
+                }
+                _ => {}
+            }
+        },
+    }
+}
+
+// works out what extracting `files` to `output` writes. incomplete entries are left out
+// (and reported), as are nested archives since their contents get extracted instead, and
+// existing files `overwrite` says to keep. `layout` decides where the rest go
+fn plan<'a>(
+    archive: &KArchive,
+    files: &'a [PathBuf],
+    output: &Path,
+    layout: Layout,
+    rename_for_windows: bool,
+    overwrite: &mut Overwrite,
+) -> Vec<Planned<'a>> {
+    let mut planned = Vec::new();
+    // stripping and flattening can send several entries to the same place
+    let mut claimed = HashMap::new();
+    for filepath in files {
+        if !archive.is_complete(filepath) {
+            error!("skipping incomplete {}", filepath.display());
+            continue;
+        }
+        if archive.nested_archives().contains(filepath) {
+            continue;
+        }
+        let Some(mut relative) = layout.relative_path(filepath) else {
+            continue;
+        };
+        if let Some(safe) = windows_safe_path(&relative).filter(|_| rename_for_windows) {
+            warning!("renamed {} to {}", filepath.display(), safe.display());
+            relative = safe;
+        }
+        let dest = match join_under(output, &relative) {
+            Ok(dest) => dest,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            }
+        };
+        if let Some(first) = claimed.insert(dest.clone(), filepath) {
+            warning!(
+                "skipping {}, {} already goes to {}",
+                filepath.display(),
+                first.display(),
+                dest.display()
+            );
+            claimed.insert(dest, first);
+            continue;
+        }
+        if dest.exists() && !replace_existing(archive, filepath, &dest, overwrite) {
+            match overwrite {
+                Overwrite::Changed => verbose!("unchanged {}", dest.display()),
+                _ => info!("skipping existing {}", dest.display()),
+            }
+            continue;
+        }
+        planned.push(Planned {
+            entry: filepath,
+            dest,
+            size: archive.available_size(filepath).unwrap_or_default(),
+        });
+    }
+    planned
+}
+
+// --check-names: entries of contents trees get hashed as they're extracted and compared
+// against the hash their path spells out
+#[derive(Default)]
+struct NameCheck {
+    checked: AtomicUsize,
+    mismatched: AtomicUsize,
+}
+
+impl NameCheck {
+    fn check(&self, planned: &Planned, checksum_type: ChecksumType, expected: &str, actual: &str) {
+        self.checked.fetch_add(1, Ordering::Relaxed);
+        if actual != expected {
+            self.mismatched.fetch_add(1, Ordering::Relaxed);
+            error!(
+                "{} doesn't match its name, its {} is {}",
+                planned.dest.display(),
+                checksum_type.name(),
+                actual
+            );
+        }
+    }
+}
+
+// returns the entry's checksum when one was asked for, None if it couldn't be extracted
+fn extract(
+    archive: &KArchive,
+    planned: &Planned,
+    progress: &Progress,
+    write: &WriteOptions,
+    checksum: Option<ChecksumType>,
+    dedupe: Option<&Dedupe>,
+    names: Option<&NameCheck>,
+) -> Option<String> {
+    progress.start(&planned.dest);
+    let mut checksummer = checksum.map(Checksummer::new);
+    let mut dedupe_hasher = dedupe.map(|_| Checksummer::new(dedupe::HASH));
+    // the name that matters is the entry's, --strip-components may have cut it from the dest
+    let named = names.and_then(|_| content_hash_name(planned.entry));
+    let mut name_hasher = named
+        .as_ref()
+        .map(|(checksum_type, _)| Checksummer::new(*checksum_type));
+    let _writing = interrupt::Writing::new(&planned.dest);
+    let extracted = archive.extract_to_with(planned.entry, &planned.dest, write, |chunk| {
+        progress.advance(chunk.len() as u64);
+        for hasher in [&mut checksummer, &mut dedupe_hasher, &mut name_hasher]
+            .into_iter()
+            .flatten()
+        {
+            hasher.update(chunk);
+        }
+    });
+    if let Err(e) = extracted {
+        error!("failed to extract {}: {}", planned.entry.display(), e);
+        return None;
+    }
+    if let (Some(dedupe), Some(hasher)) = (dedupe, dedupe_hasher) {
+        dedupe.add(&planned.dest, planned.size, hasher.finish());
+    }
+    if let (Some(names), Some((checksum_type, expected)), Some(hasher)) =
+        (names, named, name_hasher)
+    {
+        names.check(planned, checksum_type, &expected, &hasher.finish());
+    }
+    checksummer.map(Checksummer::finish)
+}
+
+/// Extracts `files` of `archive` into `output` as they're laid out in it, replacing whatever
+/// is there. For subcommands that extract a set of entries they picked themselves.
+pub(crate) fn extract_all(
+    archive: &KArchive,
+    files: &[PathBuf],
+    output: &Path,
+    write: &WriteOptions,
+) {
+    let layout = Layout::Tree {
+        strip_components: 0,
+    };
+    let planned = plan(
+        archive,
+        files,
+        output,
+        layout,
+        windows_names(false),
+        &mut Overwrite::Always,
+    );
+    let total_bytes = planned.iter().map(|planned| planned.size).sum();
+    let progress = Progress::new(total_bytes, false);
+    interrupt::install();
+    for planned in &planned {
+        if interrupt::interrupted() {
+            break;
+        }
+        extract(archive, planned, &progress, write, None, None, None);
+    }
+    progress.finish();
+    if interrupt::interrupted() {
+        logging::exit(EXIT_INTERRUPTED);
+    }
+}
+
+// where --write-manifest puts the manifest, eg. SHA256SUMS or SHA256SUMS.json
+fn manifest_path(output: &Path, checksum: ChecksumType, json: bool) -> PathBuf {
+    let path = output.join(format!("{}SUMS", checksum.name().to_ascii_uppercase()));
+    match json {
+        true => path.with_extension("json"),
+        false => path,
+    }
+}
+
+// writes a SHA256SUMS style manifest (or the same as json) into `output`, with paths
+// relative to it so `sha256sum -c` works from in there. the json one also has each
+// entry's modification time, when the archive stores them
+fn write_manifest(
+    output: &Path,
+    checksum: ChecksumType,
+    json: bool,
+    mut sums: Vec<(PathBuf, String, Option<SystemTime>)>,
+) -> std::io::Result<PathBuf> {
+    sums.sort();
+    let sums = sums.into_iter().map(|(dest, sum, modified)| {
+        let relative = dest.strip_prefix(output).unwrap_or(&dest);
+        (relative.to_string_lossy().replace('\\', "/"), sum, modified)
+    });
+    let path = manifest_path(output, checksum, json);
+    let contents = if json {
+        let files: Vec<Value> = sums
+            .map(|(path, sum, modified)| {
+                json!({ "path": path, "checksum": sum, "modified": unix_time(modified) })
+            })
+            .collect();
+        serde_json::to_string_pretty(&json!({ "type": checksum.name(), "files": files })).unwrap()
+    } else {
+        sums.map(|(path, sum, _)| format!("{}  {}\n", sum, path))
+            .collect()
+    };
+    std::fs::create_dir_all(output)?;
+    std::fs::write(&path, contents)?;
+    Ok(path)
+}
+
+/// Extracts (or lists) the archives given on the command line, which is what unarchive does
+/// without a subcommand.
+pub(crate) fn run(args: &Args, options: &MountOptions, write: &WriteOptions) -> ! {
+    if let Some(template) = &args.output_template {
+        // caught before anything gets extracted
+        let fields = TEMPLATE_FIELDS.map(|field| (field, String::new()));
+        if let Err(e) = render_template(template, &fields) {
+            fatal!("{}", e);
+        }
+    }
+    let mut filter = EntryFilter::new();
+    for pattern in &args.include {
+        if let Err(e) = filter.include(pattern) {
+            fatal!("{}", e);
+        }
+    }
+    for pattern in &args.exclude {
+        if let Err(e) = filter.exclude(pattern) {
+            fatal!("{}", e);
+        }
+    }
+    // names from --files-from that none of the archives has (yet)
+    let mut unlisted = None;
+    if let Some(list) = &args.files_from {
+        if list == Path::new("-") && args.filenames.iter().any(|path| path == Path::new("-")) {
+            fatal!("stdin can't be both an archive and the --files-from list");
+        }
+        let names =
+            read_names(list).unwrap_or_else(|e| fatal!("can't read {}: {}", list.display(), e));
+        unlisted = Some(names.iter().cloned().collect::<BTreeSet<_>>());
+        filter.only(names);
+    }
+    let layout = match args.flat {
+        true => Layout::Flat,
+        false => Layout::Tree {
+            strip_components: args.strip_components,
+        },
+    };
+    let bundle_path = args.to_zip.as_ref().or(args.to_tar.as_ref());
+    // nothing but the tar can go to stdout when that's where it's written
+    let stdout_taken = args.to_tar.as_deref() == Some(Path::new("-"));
+    let mut bundle = match (&args.to_zip, &args.to_tar) {
+        (Some(path), _) => Some(Bundle::zip(path)),
+        (_, Some(path)) => Some(Bundle::tar(path)),
+        _ => None,
+    }
+    .map(|bundle| {
+        bundle.unwrap_or_else(|e| fatal!("can't create {}: {}", bundle_path.unwrap().display(), e))
+    });
+    // a dry run doesn't write anything, so there's nothing to ask about. neither does a
+    // zip or tar, which always starts out empty
+    let mut overwrite = match args.overwrite {
+        _ if args.sync => Overwrite::Changed,
+        Overwrite::Prompt if args.dry_run => Overwrite::Always,
+        _ if bundle.is_some() => Overwrite::Always,
+        overwrite => overwrite,
+    };
+    // shared by every archive, so duplicates across versions get linked too
+    let dedupe = args.dedupe.map(Dedupe::new);
+    let names = args.check_names.then(NameCheck::default);
+    // json listings go out as a single array once every archive is done
+    let mut listings = Vec::new();
+    // (archive, where it's extracted to). archives found by a recursive scan keep the folder
+    // they were found in under the output folder, so ones with the same name don't collide
+    let mut inputs = Vec::new();
+    for filename in &args.filenames {
+        // named and placed as if it was --stdin-name in the current folder
+        if filename == Path::new("-") {
+            let name = Path::new(&args.stdin_name);
+            let output = match args.output_folder {
+                Some(ref output) => output.join(name.file_stem().unwrap_or_default()),
+                None => format!("{}-extract", name.display()).into(),
+            };
+            inputs.push((input(filename.clone(), &args.stdin_name), output));
+            continue;
+        }
+        let found = match args.recursive {
+            true => find_archives(filename).unwrap_or_else(|e| {
+                fatal!("failed to scan {} for archives: {}", filename.display(), e)
+            }),
+            false => vec![filename.clone()],
+        };
+        for archive in found {
+            let output = match args.output_folder {
+                Some(ref output) => {
+                    let relative = archive.strip_prefix(filename).unwrap_or(&archive);
+                    let dir = match is_url(&archive) {
+                        true => Path::new(""),
+                        false => relative.parent().unwrap_or(Path::new("")),
+                    };
+                    output.join(dir).join(archive.file_stem().unwrap())
+                }
+                // a url's folder goes in the current one, like with a download
+                None if is_url(&archive) => {
+                    let name = archive.file_name().unwrap_or_default();
+                    format!("{}-extract", name.to_string_lossy()).into()
+                }
+                None => format!("{}-extract", &archive.display()).into(),
+            };
+            inputs.push((archive, output));
+        }
+    }
+    // layered up front, after which it's handled like a single archive named after the top
+    // layer. a missing layer would change the result, so every one has to mount
+    let mut overlaid = None;
+    if args.overlay {
+        let mut layers = std::mem::take(&mut inputs).into_iter();
+        if let Some((first, _)) = layers.next() {
+            let mut merged = mount(first.clone(), options);
+            let mut top = first;
+            for (filename, _) in layers {
+                verbose!(
+                    "layering {} over the archives before it",
+                    filename.display()
+                );
+                merged.overlay(mount(filename.clone(), options));
+                top = filename;
+            }
+            inputs.push((top, args.output_folder.clone().unwrap()));
+            overlaid = Some(merged);
+        }
+    }
+    let several = inputs.len() > 1;
+    if !args.list && !args.json && !args.dry_run {
+        interrupt::install();
+    }
+    for (filename, output) in inputs {
+        if args.list && !args.json {
+            println!("{}:", filename.display());
+        }
+        let archive = match overlaid.take() {
+            Some(archive) => archive,
+            None => match mount_with_options(filename.clone(), options) {
+                Ok(archive) => {
+                    log_warnings(&filename.display().to_string(), &archive);
+                    archive
+                }
+                // one bad file shouldn't stop the rest from being processed
+                Err(e) if several || args.recursive => {
+                    error!("skipping {}: {}", filename.display(), e);
+                    continue;
+                }
+                Err(e) => fatal!("failed to parse {}: {}", filename.display(), e),
+            },
+        };
+        if let Some(unlisted) = unlisted.as_mut() {
+            let missing = filter.missing_from(&archive);
+            unlisted.retain(|name| missing.contains(name));
+        }
+        // the template replaces the name of the folder the archive would've gone to
+        let output = match &args.output_template {
+            Some(template) => {
+                let fields = template_fields(&filename, &archive);
+                output.with_file_name(render_template(template, &fields).unwrap())
+            }
+            None => output,
+        };
+        if args.json {
+            listings.push(list_json(&filename, &archive, &filter));
+            continue;
+        }
+        if args.list {
+            list(&archive, &filter);
+            continue;
+        }
+        let mut files = archive.list_matching(&filter);
+        // sorted so it's always the same entry that wins when several map to one path
+        files.sort();
+        let planned = plan(
+            &archive,
+            &files,
+            &output,
+            layout,
+            windows_names(args.windows_names),
+            &mut overwrite,
+        );
+        let total_bytes = planned.iter().map(|planned| planned.size).sum();
+        let stale = match args.delete {
+            true => stale_files(&archive, &output, layout, windows_names(args.windows_names)),
+            false => Vec::new(),
+        };
+        if args.dry_run {
+            for planned in &planned {
+                println!("{}", planned.dest.display());
+            }
+            for path in &stale {
+                println!("delete {}", path.display());
+            }
+            println!(
+                "{} files, {} bytes would be written to {}",
+                planned.len(),
+                total_bytes,
+                output.display()
+            );
+            continue;
+        }
+        verbose!(
+            "{}: extracting {} files, {} bytes",
+            filename.display(),
+            planned.len(),
+            total_bytes
+        );
+        let progress = Progress::new(total_bytes, stdout_taken);
+        if let Some(bundle) = bundle.as_mut() {
+            // each archive's folder in the bundle is the one it would've been extracted to
+            let folder = match args.output_folder {
+                Some(ref output_folder) if several => output.strip_prefix(output_folder).ok(),
+                None if several => filename.file_stem().map(Path::new),
+                _ => None,
+            };
+            for planned in &planned {
+                if interrupt::interrupted() {
+                    break;
+                }
+                let relative = planned.dest.strip_prefix(&output).unwrap();
+                let name = folder.unwrap_or(Path::new("")).join(relative);
+                progress.start(&name);
+                bundle
+                    .add(&archive, planned.entry, &member_name(&name), |chunk| {
+                        progress.advance(chunk.len() as u64)
+                    })
+                    .unwrap_or_else(|e| fatal!("failed to write {}: {}", name.display(), e));
+            }
+            progress.finish();
+            // the bundle is still finished below, so what's in it can be read
+            if interrupt::interrupted() {
+                break;
+            }
+            continue;
+        }
+        let sums = Mutex::new(Vec::new());
+        // each worker takes the next entry nobody has started on yet
+        let next = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..args.threads.clamp(1, planned.len().max(1)) {
+                scope.spawn(|| {
+                    while !interrupt::interrupted() {
+                        let Some(planned) = planned.get(next.fetch_add(1, Ordering::Relaxed))
+                        else {
+                            break;
+                        };
+                        if let Some(sum) = extract(
+                            &archive,
+                            planned,
+                            &progress,
+                            write,
+                            args.write_manifest,
+                            dedupe.as_ref(),
+                            names.as_ref(),
+                        ) {
+                            let modified = archive
+                                .metadata(planned.entry)
+                                .and_then(|meta| meta.modified);
+                            sums.lock()
+                                .unwrap()
+                                .push((planned.dest.clone(), sum, modified));
+                        }
+                    }
+                });
+            }
+        });
+        progress.finish();
+        // what wasn't extracted isn't stale, so nothing is deleted after a Ctrl-C
+        let stopped = interrupt::interrupted();
+        for path in stale.iter().filter(|_| !stopped) {
+            match std::fs::remove_file(path) {
+                Ok(()) => info!("deleted {}", path.display()),
+                Err(e) => error!("failed to delete {}: {}", path.display(), e),
+            }
+        }
+        if let Some(checksum) = args.write_manifest {
+            let sums = sums.into_inner().unwrap();
+            match write_manifest(&output, checksum, args.manifest_json, sums) {
+                Ok(manifest) => info!("{}", manifest.display()),
+                Err(e) => error!("failed to write the manifest: {}", e),
+            }
+        }
+        if stopped {
+            warning!(
+                "stopped after {} of the {} files in {}",
+                next.into_inner().min(planned.len()),
+                planned.len(),
+                filename.display()
+            );
+            break;
+        }
+    }
+    for name in unlisted.into_iter().flatten() {
+        error!("{} isn't in any of the archives", name);
+    }
+    if let Some(names) = names {
+        info!(
+            "checked {} hash named files, {} didn't match their names",
+            names.checked.into_inner(),
+            names.mismatched.into_inner()
+        );
+    }
+    if let Some(dedupe) = dedupe {
+        let (linked, saved) = dedupe.summary();
+        info!("linked {} duplicate files, saving {} bytes", linked, saved);
+    }
+    if let Some(bundle) = bundle {
+        let path = bundle_path.unwrap();
+        if let Err(e) = bundle.finish() {
+            fatal!("failed to write {}: {}", path.display(), e);
+        }
+        if !stdout_taken {
+            info!("{}", path.display());
+        }
+    }
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&listings).unwrap());
+    }
+    logging::exit(match interrupt::interrupted() {
+        true => EXIT_INTERRUPTED,
+        false => logging::exit_code(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k_archives::{mount_bytes, MarWriter};
+    use std::fs;
+
+    #[test]
+    fn delete_keeps_manifests() {
+        let data = MarWriter::new()
+            .add_file("data/a.bin", vec![1; 0x10])
+            .write(Vec::new())
+            .unwrap();
+        let archive = mount_bytes("update.mar".into(), data, &MountOptions::default()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        let layout = Layout::Tree {
+            strip_components: 0,
+        };
+        let dest = output.join("data/a.bin");
+        archive
+            .extract_to(Path::new("data/a.bin"), &dest, |_| {})
+            .unwrap();
+        fs::write(output.join("data/old.bin"), b"from an earlier version").unwrap();
+        // what a --write-manifest run leaves behind, in either format
+        let sums = || vec![(dest.clone(), "00".repeat(32), None)];
+        write_manifest(&output, ChecksumType::Sha256, false, sums()).unwrap();
+        write_manifest(&output, ChecksumType::Md5, true, sums()).unwrap();
+        assert!(output.join("SHA256SUMS").is_file() && output.join("MD5SUMS.json").is_file());
+
+        assert_eq!(
+            stale_files(&archive, &output, layout, false),
+            [output.join("data/old.bin")]
+        );
+    }
+}
@@ -0,0 +1,309 @@
+//! The subcommands that look into archives without extracting them: verify, diff, stats, cat
+//! and bench, and --list.
+
+use crate::logging::{fatal, info};
+use crate::{input, mount};
+use k_archives::{
+    bench_storage, diff, mount_with_options, ArchiveStats, ChecksumPolicy, EntryFilter, GroupStats,
+    KArchive, KArchiveWarning, MountOptions,
+};
+use serde_json::{json, Value};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+// returns whether everything checked out
+pub(crate) fn verify(archive_path: PathBuf, content_hashes: bool, options: &MountOptions) -> bool {
+    let options = MountOptions {
+        verify_checksums: ChecksumPolicy::Warn,
+        ..options.clone()
+    };
+    let name = archive_path.display().to_string();
+    let archive = match mount_with_options(archive_path, &options) {
+        Ok(archive) => archive,
+        Err(e) => {
+            println!("{}: {}", name, e);
+            return false;
+        }
+    };
+    let mut ok = true;
+    for warning in archive.warnings() {
+        println!("{}: {}", name, warning);
+        // these are about what couldn't be checked rather than damage
+        ok &= matches!(
+            warning,
+            KArchiveWarning::UnsupportedChecksum { .. } | KArchiveWarning::IgnoredLine { .. }
+        );
+    }
+    let mut hashed = 0;
+    if content_hashes {
+        match archive.check_content_hashes() {
+            Ok((checked, mismatches)) => {
+                hashed = checked;
+                for mismatch in &mismatches {
+                    println!("{}: {}", name, mismatch);
+                }
+                ok &= mismatches.is_empty();
+            }
+            Err(e) => {
+                println!("{}: {}", name, e);
+                ok = false;
+            }
+        }
+    }
+    if ok {
+        let mut summary = format!("{}: ok, {} entries", name, archive.list_files().len());
+        if content_hashes {
+            summary += &format!(", {} hashes checked", hashed);
+        }
+        info!("{}", summary);
+    }
+    ok
+}
+
+pub(crate) fn print_diff(old_path: PathBuf, new_path: PathBuf, json: bool, options: &MountOptions) {
+    let old = mount(old_path.clone(), options);
+    let new = mount(new_path.clone(), options);
+    let changes = diff(&old, &new).unwrap_or_else(|e| fatal!("failed to compare: {}", e));
+    let size = |archive: &KArchive, path: &Path| {
+        archive
+            .metadata(path)
+            .map(|meta| meta.size)
+            .unwrap_or_default()
+    };
+    if json {
+        let entries = |paths: &[PathBuf], archive: &KArchive| {
+            paths
+                .iter()
+                .map(|path| json!({ "path": path.to_string_lossy(), "size": size(archive, path) }))
+                .collect::<Vec<_>>()
+        };
+        let changed: Vec<Value> = changes
+            .changed
+            .iter()
+            .map(|path| {
+                json!({
+                    "path": path.to_string_lossy(),
+                    "old_size": size(&old, path),
+                    "new_size": size(&new, path),
+                })
+            })
+            .collect();
+        let report = json!({
+            "old": old_path.to_string_lossy(),
+            "new": new_path.to_string_lossy(),
+            "added": entries(&changes.added, &new),
+            "removed": entries(&changes.removed, &old),
+            "changed": changed,
+            "unchanged": changes.unchanged,
+        });
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return;
+    }
+    for path in &changes.added {
+        println!("+ {} ({} bytes)", path.display(), size(&new, path));
+    }
+    for path in &changes.removed {
+        println!("- {} ({} bytes)", path.display(), size(&old, path));
+    }
+    for path in &changes.changed {
+        println!(
+            "~ {} ({} -> {} bytes)",
+            path.display(),
+            size(&old, path),
+            size(&new, path)
+        );
+    }
+    println!(
+        "{} added, {} removed, {} changed, {} unchanged",
+        changes.added.len(),
+        changes.removed.len(),
+        changes.changed.len(),
+        changes.unchanged
+    );
+}
+
+fn group_json(group: &GroupStats) -> Value {
+    json!({
+        "name": group.name,
+        "entries": group.entries,
+        "size": group.size,
+        "stored_size": group.stored_size,
+    })
+}
+
+fn stats_json(stats: &ArchiveStats) -> Value {
+    let groups = |groups: &[GroupStats]| groups.iter().map(group_json).collect::<Vec<_>>();
+    let largest: Vec<Value> = stats
+        .largest
+        .iter()
+        .map(|(path, size)| json!({ "path": path.to_string_lossy(), "size": size }))
+        .collect();
+    json!({
+        "archive": stats.total.name,
+        "entries": stats.total.entries,
+        "size": stats.total.size,
+        "stored_size": stats.total.stored_size,
+        "largest": largest,
+        "by_extension": groups(&stats.by_extension),
+        "by_folder": groups(&stats.by_folder),
+        "by_part": groups(&stats.by_part),
+    })
+}
+
+fn print_stats(stats: &ArchiveStats) {
+    let total = &stats.total;
+    println!(
+        "{}: {} entries, {} bytes ({} bytes stored)",
+        total.name, total.entries, total.size, total.stored_size
+    );
+    println!("largest:");
+    for (path, size) in &stats.largest {
+        println!("  {:>12}  {}", size, path.display());
+    }
+    let print_groups = |title: &str, groups: &[GroupStats], blank: &str| {
+        println!("{}:", title);
+        for group in groups {
+            let name = match group.name.is_empty() {
+                true => blank,
+                false => &group.name,
+            };
+            println!(
+                "  {:>8} entries {:>12} bytes {:>12} stored  {}",
+                group.entries, group.size, group.stored_size, name
+            );
+        }
+    };
+    print_groups("by extension", &stats.by_extension, "(none)");
+    print_groups("by folder", &stats.by_folder, "(top level)");
+    // a single archive is its only part
+    if stats.by_part.len() > 1 {
+        print_groups("by part", &stats.by_part, "");
+    }
+}
+
+pub(crate) fn bench(path: &Path, options: &MountOptions) {
+    let bench = bench_storage(path, options)
+        .unwrap_or_else(|e| fatal!("can't read {}: {}", path.display(), e));
+    let ms = |duration: std::time::Duration| duration.as_secs_f64() * 1000.0;
+    println!("{}: {} bytes", path.display(), bench.size);
+    println!(
+        "  seek latency  {:.3} ms on average, {:.3} ms for all of them (over {:.0} ms is slow)",
+        ms(bench.seek_latency),
+        ms(bench.seek_total),
+        ms(bench.seek_target)
+    );
+    println!(
+        "  sequential    {:.1} MiB/s over {} bytes",
+        bench.throughput / f64::from(1 << 20),
+        bench.sampled
+    );
+    println!(
+        "  mounting      {}",
+        match bench.buffered {
+            true => "reads it into memory first",
+            false => "reads entries from disk as they're opened",
+        }
+    );
+}
+
+pub(crate) fn cat(archive: PathBuf, entry: &Path, options: &MountOptions) {
+    let archive = mount(archive, options);
+    let mut file = archive
+        .open(entry)
+        .unwrap_or_else(|e| fatal!("can't open {}: {}", entry.display(), e));
+    match std::io::copy(&mut file, &mut std::io::stdout().lock()) {
+        // whatever was reading stopped early (eg. `| head`), which is fine
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => {
+            fatal!("failed to read {}: {}", entry.display(), e)
+        }
+        _ => {}
+    }
+}
+
+// seconds since the unix epoch, how times are written in json
+pub(crate) fn unix_time(time: Option<SystemTime>) -> Option<u64> {
+    Some(time?.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+pub(crate) fn list(archive: &KArchive, filter: &EntryFilter) {
+    let mut files = archive.list_matching(filter);
+    files.sort();
+    let mut total = 0;
+    for filepath in &files {
+        let meta = archive.metadata(filepath).expect("File should exist...");
+        total += meta.size;
+        let mut notes = Vec::new();
+        if meta.encrypted {
+            notes.push("encrypted");
+        }
+        if !archive.is_complete(filepath) {
+            notes.push("incomplete");
+        }
+        println!(
+            "{:>12}  {:<20} {}",
+            meta.size,
+            notes.join(", "),
+            filepath.display()
+        );
+    }
+    println!("{} files, {} bytes", files.len(), total);
+}
+
+pub(crate) fn list_json(path: &Path, archive: &KArchive, filter: &EntryFilter) -> Value {
+    let mut files = archive.list_matching(filter);
+    files.sort();
+    let entries: Vec<Value> = files
+        .iter()
+        .map(|filepath| {
+            let meta = archive.metadata(filepath).expect("File should exist...");
+            let checksum = meta.declared_checksum.map(
+                |(checksum_type, checksum)| json!({ "type": checksum_type, "value": checksum }),
+            );
+            json!({
+                "path": filepath.to_string_lossy(),
+                "size": meta.size,
+                "offset": meta.offset,
+                "encrypted": meta.encrypted,
+                "complete": archive.is_complete(filepath),
+                "source": meta.archive.to_string_lossy(),
+                "declared_checksum": checksum,
+                "raw_fields": meta.raw_fields,
+                "modified": unix_time(meta.modified),
+                "mode": meta.mode,
+            })
+        })
+        .collect();
+    json!({
+        "archive": path.to_string_lossy(),
+        "entries": entries,
+        "warnings": archive.warnings().iter().map(|w| w.to_string()).collect::<Vec<_>>(),
+    })
+}
+
+/// `unarchive stats`: summarises each of `archives`, as text or one JSON array.
+pub(crate) fn stats(
+    archives: Vec<PathBuf>,
+    top: usize,
+    json: bool,
+    stdin_name: &str,
+    options: &MountOptions,
+) {
+    let mut summaries = Vec::new();
+    for (i, path) in archives.into_iter().enumerate() {
+        let path = input(path, stdin_name);
+        let archive = mount(path.clone(), options);
+        let stats = k_archives::stats(&archive, &path.display().to_string(), top);
+        match json {
+            true => summaries.push(stats_json(&stats)),
+            false => {
+                if i > 0 {
+                    println!();
+                }
+                print_stats(&stats);
+            }
+        }
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summaries).unwrap());
+    }
+}
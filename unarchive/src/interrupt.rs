@@ -0,0 +1,61 @@
+//! Ctrl-C while extracting. The first one lets the files being written finish, after which
+//! the run stops, writes its manifest and summary and exits with EXIT_INTERRUPTED. A second
+//! one stops right away, removing the files that were still being written so none are left
+//! cut off.
+
+use crate::logging::{self, verbose, EXIT_INTERRUPTED};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+// the files being written right now
+static WRITING: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+fn handle() {
+    if !INTERRUPTED.swap(true, Ordering::SeqCst) {
+        eprintln!("interrupted, finishing the files being written (Ctrl-C again to stop now)");
+        return;
+    }
+    for path in WRITING.lock().unwrap().iter().flatten() {
+        if std::fs::remove_file(path).is_ok() {
+            eprintln!("removed the partly written {}", path.display());
+        }
+    }
+    logging::exit(EXIT_INTERRUPTED)
+}
+
+/// Starts catching Ctrl-C. Only done for extraction, everything else can just be killed.
+pub(crate) fn install() {
+    if let Err(e) = ctrlc::set_handler(handle) {
+        verbose!("can't catch Ctrl-C: {}", e);
+    }
+}
+
+/// Whether Ctrl-C was pressed, after which no new files should be started.
+pub(crate) fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Marks `path` as being written until the returned guard is dropped.
+pub(crate) struct Writing(PathBuf);
+
+impl Writing {
+    pub(crate) fn new(path: &Path) -> Self {
+        WRITING
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashSet::new)
+            .insert(path.to_path_buf());
+        Writing(path.to_path_buf())
+    }
+}
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        if let Some(writing) = WRITING.lock().unwrap().as_mut() {
+            writing.remove(&self.0);
+        }
+    }
+}
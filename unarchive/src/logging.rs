@@ -0,0 +1,112 @@
+//! What unarchive prints besides the output that was asked for (listings, verify reports, ...),
+//! filtered by --quiet and -v, and the exit code a run ends with.
+
+use std::fmt::Arguments;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+/// Everything that was asked for was done.
+pub(crate) const EXIT_OK: i32 = 0;
+/// Some entries or archives couldn't be handled, the rest were.
+pub(crate) const EXIT_PARTIAL: i32 = 1;
+/// Nothing (more) could be done, eg. the archive doesn't parse or the output can't be written.
+/// Same as clap uses for bad arguments
+pub(crate) const EXIT_FATAL: i32 = 2;
+/// Stopped by Ctrl-C, what was extracted before that is complete. 128 + SIGINT like shells use
+pub(crate) const EXIT_INTERRUPTED: i32 = 130;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+// everything error! reported, listed again at the end so they don't get lost in a long run
+static FAILURES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub(crate) fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub(crate) fn enabled(verbosity: Verbosity) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= verbosity as u8
+}
+
+// prints the failure and makes the run exit with EXIT_PARTIAL
+pub(crate) fn fail(args: Arguments) {
+    let message = args.to_string();
+    eprintln!("{}", message);
+    FAILURES.lock().unwrap().push(message);
+}
+
+pub(crate) fn exit_code() -> i32 {
+    match FAILURES.lock().unwrap().is_empty() {
+        true => EXIT_OK,
+        false => EXIT_PARTIAL,
+    }
+}
+
+// exits with `code`, cleaning up after a spooled stdin. if anything failed along the way
+// it's all repeated first, unless there's just the one that was the last thing printed
+pub(crate) fn exit(code: i32) -> ! {
+    let failures = FAILURES.lock().unwrap();
+    if failures.len() > 1 {
+        eprintln!("{} problems:", failures.len());
+        for failure in failures.iter() {
+            eprintln!("  {}", failure);
+        }
+    }
+    drop(failures);
+    crate::stdin::remove();
+    std::process::exit(code)
+}
+
+pub(crate) fn exit_fatal(args: Arguments) -> ! {
+    eprintln!("{}", args);
+    exit(EXIT_FATAL)
+}
+
+// what was done, eg. where things were written. stdout, hidden by --quiet
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Verbosity::Normal) {
+            println!($($arg)*);
+        }
+    };
+}
+
+// extra detail for -v, on stderr so it doesn't get mixed into output being piped somewhere
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Verbosity::Verbose) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+// something worth knowing that doesn't change the outcome. stderr, hidden by --quiet
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Verbosity::Normal) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+// something that was left out or failed while the rest carried on, always printed
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::logging::fail(format_args!($($arg)*))
+    };
+}
+
+// prints the message and exits with EXIT_FATAL
+macro_rules! fatal {
+    ($($arg:tt)*) => {
+        $crate::logging::exit_fatal(format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {error, fatal, info, verbose, warning};
@@ -1,38 +1,655 @@
-use clap::Parser;
-use k_archives::mount;
-use std::{io::BufWriter, path::PathBuf};
+mod apply;
+mod browse;
+mod bundle;
+mod catalog;
+mod config;
+#[cfg(unix)]
+mod daemon;
+mod dedupe;
+mod extract;
+mod inspect;
+mod interrupt;
+mod logging;
+mod search;
+mod serve;
+mod stdin;
+mod template;
+mod updates;
+
+use clap::{ArgEnum, CommandFactory, FromArgMatches, Parser, Subcommand, ValueSource};
+use clap_complete::Shell;
+use dedupe::LinkKind;
+use extract::{extract_all, Overwrite};
+use inspect::{bench, cat, print_diff, verify};
+use k_archives::{
+    mount_with_options, Buffering, ChecksumType, IndexQuery, KArchive, MountOptions, NameEncoding,
+    WriteOptions,
+};
+use logging::{fatal, warning, Verbosity, EXIT_OK, EXIT_PARTIAL};
+use search::{index_archives, search_index};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use updates::{chain, delta};
+
+fn parse_checksum(name: &str) -> Result<ChecksumType, String> {
+    ChecksumType::from_name(name).ok_or_else(|| format!("unknown checksum type {}", name))
+}
+
+// a number of bytes, optionally with a K, M, G or T suffix (powers of 1024), eg. 512M
+fn parse_memory(size: &str) -> Result<u64, String> {
+    let upper = size.trim().to_ascii_uppercase();
+    let upper = upper.trim_end_matches("IB").trim_end_matches('B');
+    let (digits, shift) = match upper.char_indices().last() {
+        Some((i, 'K')) => (&upper[..i], 10),
+        Some((i, 'M')) => (&upper[..i], 20),
+        Some((i, 'G')) => (&upper[..i], 30),
+        Some((i, 'T')) => (&upper[..i], 40),
+        _ => (upper, 0),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("expected a size like 512M or 2G, got {}", size))
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    /// UTF-8 where the name is valid UTF-8, Shift-JIS otherwise
+    Auto,
+    Utf8,
+    /// Shift-JIS, which older titles use
+    Sjis,
+}
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Filename of konami archive. Supports (mar, bar, qar, d2, cab, lst, and info)
+    /// Filename of konami archive. Supports (mar, bar, qar, d2, cab, ifs, 2dx, s3p, arc, lst, and info).
+    /// "-" reads one from stdin. An http(s) or s3://bucket/key url is read with range requests,
+    /// downloading only the headers and the files that get extracted
     filenames: Vec<PathBuf>,
+    /// What to call an archive read from stdin, which names its output folder and tells 2dx and d2 apart
+    #[clap(long, value_name = "NAME", default_value = "stdin")]
+    stdin_name: String,
     /// Parent folder to output to. If none, the the output will default to filename+"-extract"
     #[clap(short, long)]
     output_folder: Option<PathBuf>,
+    /// Name output folders after this instead of filename+"-extract", eg. "{game}/{datecode}-{stem}".
+    /// Placeholders are {stem}, {game} (model code like KFC), {datecode} (YYYYMMDDRR) and
+    /// {version} (the datecode's YYYYMMDD), taken from the archive's name and "unknown" if it doesn't have them
+    #[clap(long, value_name = "TEMPLATE")]
+    output_template: Option<String>,
+    /// Treat the filenames as folders and extract every archive found in them, recognised by their contents rather than extension
+    #[clap(short, long)]
+    recursive: bool,
+    /// Layer the archives into one and extract the result straight into --output-folder, where
+    /// entries in later archives replace the same entries from earlier ones. Give updates
+    /// oldest first to get the content they add up to
+    #[clap(long, requires = "output-folder", conflicts_with = "output-template")]
+    overlay: bool,
+    /// Try to recover entries from bar/qar archives with a damaged entry table
+    #[clap(long)]
+    salvage: bool,
+    /// List and extract what's present in archives that were cut off partway
+    #[clap(long)]
+    allow_truncated: bool,
+    /// Extract the contents of archives found inside the archive instead of the archives themselves
+    #[clap(long)]
+    nested: bool,
+    /// How entry names are decoded. Try sjis when names of older archives come out garbled
+    #[clap(long, arg_enum, default_value = "auto")]
+    encoding: Encoding,
+    /// Never read more than this much of an archive into memory, eg. 2G. Archives on slow
+    /// storage (like network shares) are otherwise read into memory whole
+    #[clap(long, value_name = "SIZE", parse(try_from_str = parse_memory))]
+    max_memory: Option<u64>,
+    /// Always read archives from disk as entries are extracted, never into memory
+    #[clap(long, conflicts_with_all = &["max-memory", "force-buffer"])]
+    no_buffer: bool,
+    /// Always read archives into memory first, eg. when seeking around on the storage is slow
+    /// in a way that isn't detected
+    #[clap(long, conflicts_with = "max-memory")]
+    force_buffer: bool,
+    /// Print each entry's size and whether it's encrypted instead of extracting anything
+    #[clap(short, long)]
+    list: bool,
+    /// With --list, print the listing as JSON (offsets, source parts and checksums included)
+    #[clap(long, requires = "list")]
+    json: bool,
+    /// Only extract (or list) entries matching this glob, eg. "*/sound/*". Can be given more than once
+    #[clap(long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// Skip entries matching this glob, eg. "*.wmv". Can be given more than once
+    #[clap(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// Only extract (or list) the entries named in this file, one path per line as --list
+    /// prints them. "-" reads the list from stdin
+    #[clap(long, value_name = "FILE")]
+    files_from: Option<PathBuf>,
+    /// How many entries to extract at once. Helps a lot with archives of many small files
+    #[clap(short = 'j', long, default_value_t = 1)]
+    threads: usize,
+    /// Print what extracting would write (after --include/--exclude) and how much, without writing anything
+    #[clap(long)]
+    dry_run: bool,
+    /// What to do about files that already exist in the output folder
+    #[clap(long, arg_enum, default_value = "always")]
+    overwrite: Overwrite,
+    /// Drop the first N folders from each entry's path, like tar. Entries no deeper than that are skipped
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    strip_components: usize,
+    /// Put every file straight in the output folder, without the folders they're in
+    #[clap(long, conflicts_with = "strip-components")]
+    flat: bool,
+    /// Write a checksum manifest (eg. SHA256SUMS) of everything extracted into the output folder, hashed as it's written. md5, sha1, sha256 or crc32
+    #[clap(long, value_name = "TYPE", parse(try_from_str = parse_checksum))]
+    write_manifest: Option<ChecksumType>,
+    /// Write the manifest as JSON instead
+    #[clap(long, requires = "write-manifest")]
+    manifest_json: bool,
+    /// Bring an existing output folder up to date, only extracting entries whose size or
+    /// contents changed (--overwrite changed)
+    #[clap(long, conflicts_with_all = &["overwrite", "to-zip", "to-tar"])]
+    sync: bool,
+    /// With --sync, also delete files in the output folder that aren't in the archive anymore
+    #[clap(long, requires = "sync")]
+    delete: bool,
+    /// Rename entries Windows can't store (reserved names like con and aux, characters like : and ?)
+    /// even when not on Windows, eg. for trees that end up on a share Windows machines use
+    #[clap(long)]
+    windows_names: bool,
+    /// Hash the files of contents trees as they're extracted and check they match the hash
+    /// their path is named after (eg. contents/8/c/a/5682f...), flagging corrupted or tampered ones
+    #[clap(long, conflicts_with_all = &["to-zip", "to-tar"])]
+    check_names: bool,
+    /// Link files with the same contents as one already extracted to it instead of writing them again
+    #[clap(long, arg_enum, value_name = "LINK", conflicts_with_all = &["to-zip", "to-tar"])]
+    dedupe: Option<LinkKind>,
+    /// Gather up this much of each file before writing it out, eg. 8M. Bigger writes are
+    /// faster on HDDs and SMR drives [default: 64K]
+    #[clap(long, value_name = "SIZE", parse(try_from_str = parse_memory))]
+    write_buffer: Option<u64>,
+    /// Reserve each file's whole size on disk before writing it, so big files don't end up
+    /// fragmented
+    #[clap(long)]
+    preallocate: bool,
+    /// Write everything extracted into this zip instead of loose files. When several archives
+    /// are extracted, each gets its own folder in it
+    #[clap(long, value_name = "ZIP", conflicts_with_all = &["dry-run", "write-manifest"])]
+    to_zip: Option<PathBuf>,
+    /// Like --to-zip but writes a tar, to stdout if given "-" (eg. to pipe it through zstd and ssh)
+    #[clap(long, value_name = "TAR", conflicts_with_all = &["to-zip", "dry-run", "write-manifest"])]
+    to_tar: Option<PathBuf>,
+    /// Only print errors (and whatever output was asked for, like --list)
+    #[clap(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also print where each file goes
+    #[clap(short, long)]
+    verbose: bool,
+    /// Read defaults for --output-folder, --threads and --overwrite from this TOML file instead
+    /// of unarchive/config.toml in the user's config folder (~/.config or %APPDATA%)
+    #[clap(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write a single entry to stdout instead of extracting, eg. to pipe it into another tool
+    Cat {
+        /// Archive the entry is in
+        archive: PathBuf,
+        /// Path of the entry inside the archive
+        entry: PathBuf,
+    },
+    /// Check archives for damage: headers that parse, entries that fit in the file and the
+    /// part checksums declared by ULST/NAME descriptors. Exits non-zero if anything is wrong
+    Verify {
+        #[clap(required = true)]
+        archives: Vec<PathBuf>,
+        /// Also hash the files of contents trees and compare against their hash-like names
+        #[clap(long)]
+        content_hashes: bool,
+    },
+    /// Compare two archives without extracting either, listing the entries that were added,
+    /// removed or changed (by size, then contents)
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        /// Print the differences as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Extract only what a newer version of an archive added or changed compared to an older
+    /// one, eg. to keep an incremental mirror of an update chain
+    Delta {
+        old: PathBuf,
+        new: PathBuf,
+        /// Folder the added and changed entries of NEW are extracted to
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+    /// Put a game's updates in order by their datecode and work out the file set they add up
+    /// to, the latest version of every path. Lists each path and the update it comes from, or
+    /// extracts exactly that set with --output instead of each update over the last by hand
+    Chain {
+        #[clap(required = true)]
+        archives: Vec<PathBuf>,
+        /// Folder to extract the result to
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Print the order and the file set as JSON
+        #[clap(long, conflicts_with = "output")]
+        json: bool,
+    },
+    /// Summarise what archives are made of: entry counts, sizes (extracted and as stored), the
+    /// largest entries and breakdowns by extension, top level folder and part
+    Stats {
+        #[clap(required = true)]
+        archives: Vec<PathBuf>,
+        /// How many of the largest entries to list
+        #[clap(long, value_name = "N", default_value_t = 10)]
+        top: usize,
+        /// Print the summaries as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Add archives (or every archive in a folder) to a search index, so `search` can tell
+    /// which has a file without mounting any of them. Archives that haven't changed since they
+    /// were last indexed are skipped
+    Index {
+        #[clap(required = true)]
+        archives: Vec<PathBuf>,
+        /// The index file, created if it doesn't exist yet
+        #[clap(long, value_name = "FILE", default_value = "archives.idx")]
+        index: PathBuf,
+        /// Also hash every entry (md5, sha1, sha256 or crc32), so `search --hash` can find
+        /// files by their contents. Means reading every archive in full
+        #[clap(long, value_name = "TYPE", parse(try_from_str = parse_checksum))]
+        hash: Option<ChecksumType>,
+        /// Take archives that no longer exist out of the index
+        #[clap(long)]
+        prune: bool,
+    },
+    /// Find which indexed archives have entries matching a name, size or content hash. Exits
+    /// with 1 when nothing matches, like grep
+    Search {
+        /// Part of the entry's path, or a glob pattern (*, ?, [...]). Case insensitive
+        name: Option<String>,
+        /// The index file `index` wrote
+        #[clap(long, value_name = "FILE", default_value = "archives.idx")]
+        index: PathBuf,
+        /// Only entries at least this big, eg. 10M
+        #[clap(long, value_name = "SIZE", parse(try_from_str = parse_memory))]
+        min_size: Option<u64>,
+        /// Only entries at most this big
+        #[clap(long, value_name = "SIZE", parse(try_from_str = parse_memory))]
+        max_size: Option<u64>,
+        /// Only entries with this content hash, in archives indexed with --hash
+        #[clap(long)]
+        hash: Option<String>,
+        /// Print the matches as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Build (or bring up to date) a SQLite catalog of a collection: every archive with its
+    /// game and datecode, and every entry with its size and optionally hash, for `query` or
+    /// any other SQLite tool. Archives that haven't changed since they were cataloged are
+    /// skipped
+    Catalog {
+        /// Archives, or folders to search for them
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+        /// The database, created if it doesn't exist yet
+        #[clap(long, value_name = "FILE", default_value = "collection.sqlite")]
+        db: PathBuf,
+        /// Also hash every entry (md5, sha1, sha256 or crc32). Means reading every archive in
+        /// full
+        #[clap(long, value_name = "TYPE", parse(try_from_str = parse_checksum))]
+        hash: Option<ChecksumType>,
+        /// Take archives that no longer exist out of the catalog
+        #[clap(long)]
+        prune: bool,
+    },
+    /// Ask a catalog questions, with one of the flags or any SQL over its archives and entries
+    /// tables. Exits with 1 when nothing matches
+    Query {
+        /// SQL to run, eg. "SELECT game, SUM(size) FROM archives GROUP BY game"
+        #[clap(required_unless_present_any = &["games", "versions", "find"])]
+        sql: Option<String>,
+        /// The database `catalog` wrote
+        #[clap(long, value_name = "FILE", default_value = "collection.sqlite")]
+        db: PathBuf,
+        /// List the games in the catalog, with how many versions of each there are
+        #[clap(long, conflicts_with_all = &["sql", "versions", "find"])]
+        games: bool,
+        /// List the versions of a game (by game code, eg. KFC), oldest first
+        #[clap(long, value_name = "GAME", conflicts_with_all = &["sql", "find"])]
+        versions: Option<String>,
+        /// List the entries whose path contains NAME, and which archive they're in
+        #[clap(long, value_name = "NAME", conflicts_with = "sql")]
+        find: Option<String>,
+        /// Print the rows as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Extract updates straight into a game install, backing up the files they replace
+    Apply {
+        #[clap(required = true)]
+        archives: Vec<PathBuf>,
+        /// The install's contents folder. Only what's in the updates' contents folder is applied
+        #[clap(long)]
+        target: PathBuf,
+        /// Where replaced files are copied to. Defaults to a new TARGET-backup-<time> folder next to the target
+        #[clap(long)]
+        backup: Option<PathBuf>,
+    },
+    /// Decipher a gitadora (M32) mar entry's payload that was recovered on its own, without
+    /// the archive it was in
+    Decrypt {
+        file: PathBuf,
+        /// The entry's path exactly as the archive stored it, eg. "data/product/music/m0001.bin".
+        /// The key comes from it
+        #[clap(long)]
+        name: String,
+        /// Where to write the deciphered payload. If none, FILE+".decrypted"
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Look around an archive in the terminal: walk its folders, preview entries as hex and
+    /// mark the ones to extract
+    Browse {
+        archive: PathBuf,
+        /// Where marked entries are extracted to. If none, filename+"-extract"
+        #[clap(short, long)]
+        output_folder: Option<PathBuf>,
+    },
+    /// Measure how the storage archives are on performs: the seek latency mounting checks to
+    /// decide whether to read archives into memory, sequential throughput and what it decides
+    Bench {
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Serve an archive's entries over HTTP without extracting them, with folder listings and
+    /// Range support, eg. for fetching files from another machine. It's a read only WebDAV
+    /// server too, so it can be attached as a network drive. Runs until killed
+    Serve {
+        archive: PathBuf,
+        /// Address to listen on. Use 0.0.0.0:PORT to let other machines connect
+        #[clap(long, value_name = "ADDRESS", default_value = "127.0.0.1:8080")]
+        listen: String,
+    },
+    /// Keep archives mounted and answer JSON-RPC requests about them (list, stat, read, ...) on
+    /// a unix socket, so tools that query the same big archive over and over don't pay for
+    /// mounting it every time. Runs until killed
+    Daemon {
+        /// Archives to mount right away. Others are mounted the first time they're asked about
+        archives: Vec<PathBuf>,
+        /// Path of the socket to listen on
+        #[clap(long, value_name = "PATH")]
+        socket: PathBuf,
+    },
+    /// Print a completion script for the shell to stdout, eg. `unarchive completions zsh > _unarchive`
+    Completions {
+        #[clap(arg_enum)]
+        shell: Shell,
+    },
+}
+
+// fills in whatever the config file has defaults for and wasn't given on the command line
+fn apply_config(args: &mut Args, matches: &clap::ArgMatches) {
+    let (path, required) = match &args.config {
+        Some(path) => (path.clone(), true),
+        None => match config::default_path() {
+            Some(path) => (path, false),
+            None => return,
+        },
+    };
+    let config = config::load(&path, required).unwrap_or_else(|e| fatal!("{}", e));
+    let from_command_line = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+    if args.output_folder.is_none() {
+        args.output_folder = config.output_folder;
+    }
+    if let Some(threads) = config.threads.filter(|_| !from_command_line("threads")) {
+        args.threads = threads;
+    }
+    if let Some(overwrite) = config.overwrite.filter(|_| !from_command_line("overwrite")) {
+        args.overwrite = Overwrite::from_str(&overwrite, true).unwrap_or_else(|_| {
+            fatal!(
+                "bad config {}: overwrite must be always, never, newer, prompt or changed",
+                path.display()
+            )
+        });
+    }
+}
+
+// `path`, or a temporary copy of stdin if it's "-"
+fn input(path: PathBuf, stdin_name: &str) -> PathBuf {
+    if path != Path::new("-") {
+        return path;
+    }
+    stdin::spool(stdin_name).unwrap_or_else(|e| fatal!("failed to read stdin: {}", e))
+}
+
+// mounts `path` or exits, for when there's nothing else to do without it
+fn mount(path: PathBuf, options: &MountOptions) -> KArchive {
+    let name = path.display().to_string();
+    let archive = mount_with_options(path, options)
+        .unwrap_or_else(|e| fatal!("failed to parse {}: {}", name, e));
+    log_warnings(&name, &archive);
+    archive
+}
+
+fn log_warnings(name: &str, archive: &KArchive) {
+    for warning in archive.warnings() {
+        warning!("{}: {}", name, warning);
+    }
 }
 
 fn main() {
-    let args: Args = Args::parse();
-    for filename in args.filenames {
-        let output = match args.output_folder {
-            Some(ref output) => {
-                let mut new = PathBuf::new();
-                new.push(output);
-                new.push(filename.file_stem().unwrap());
-                new
-            }
-            None => format!("{}-extract", &filename.display()).into(),
-        };
-        let archive = mount(filename).expect("Failed to parse konami update archive");
-        for filepath in archive.list_files() {
-            let mut file = archive.open(&filepath).expect("File should exist...");
-            let mut output_file_path = output.clone();
-            output_file_path.push(&file.name);
-            std::fs::create_dir_all(output_file_path.parent().unwrap()).unwrap();
-            let mut file_buffer = BufWriter::new(std::fs::File::create(&output_file_path).unwrap());
-            println!("{}", output_file_path.display());
-            std::io::copy(&mut file, &mut file_buffer).unwrap();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    logging::set_verbosity(match (args.quiet, args.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (_, true) => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    });
+    let options = MountOptions {
+        salvage: args.salvage,
+        allow_truncated: args.allow_truncated,
+        mount_nested: args.nested,
+        buffering: match (args.no_buffer, args.force_buffer) {
+            (true, _) => Buffering::Never,
+            (_, true) => Buffering::Always,
+            _ => Buffering::Auto,
+        },
+        max_memory: args.max_memory,
+        name_encoding: match args.encoding {
+            Encoding::Auto => NameEncoding::Auto,
+            Encoding::Utf8 => NameEncoding::Utf8,
+            Encoding::Sjis => NameEncoding::ShiftJis,
+        },
+        ..Default::default()
+    };
+    let write = WriteOptions {
+        buffer_size: match args.write_buffer {
+            Some(size) => size.try_into().unwrap_or(usize::MAX),
+            None => WriteOptions::default().buffer_size,
+        },
+        preallocate: args.preallocate,
+    };
+    match args.command {
+        Some(Command::Completions { shell }) => {
+            let mut command = Args::command();
+            clap_complete::generate(shell, &mut command, "unarchive", &mut std::io::stdout());
+            logging::exit(EXIT_OK);
+        }
+        Some(Command::Decrypt { file, name, output }) => {
+            updates::decrypt_to(&file, &name, output);
+            logging::exit(EXIT_OK);
+        }
+        Some(Command::Bench { paths }) => {
+            for path in paths {
+                bench(&path, &options);
+            }
+            logging::exit(EXIT_OK);
+        }
+        Some(Command::Serve { archive, listen }) => {
+            let path = input(archive, &args.stdin_name);
+            let modified = std::fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .unwrap_or_else(|_| SystemTime::now());
+            let archive = mount(path, &options);
+            if let Err(e) = serve::serve(&archive, &listen, modified) {
+                fatal!("{}", e);
+            }
+            logging::exit(EXIT_OK);
+        }
+        Some(Command::Daemon { archives, socket }) => {
+            #[cfg(unix)]
+            if let Err(e) = daemon::daemon(&socket, &archives, &options) {
+                fatal!("{}", e);
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = archives;
+                fatal!(
+                    "can't listen on {}, unix sockets aren't supported here yet",
+                    socket.display()
+                );
+            }
+            logging::exit(EXIT_OK);
+        }
+        Some(Command::Browse {
+            archive,
+            output_folder,
+        }) => {
+            if !std::io::stdout().is_terminal() {
+                fatal!("browse needs a terminal");
+            }
+            let path = input(archive, &args.stdin_name);
+            let archive = mount(path.clone(), &options);
+            let picked = browse::browse(&archive).unwrap_or_else(|e| fatal!("{}", e));
+            if let Some(picked) = picked {
+                let output =
+                    output_folder.unwrap_or_else(|| format!("{}-extract", path.display()).into());
+                extract_all(&archive, &picked, &output, &write);
+            }
+            logging::exit(logging::exit_code());
+        }
+        Some(Command::Cat { archive, entry }) => {
+            cat(input(archive, &args.stdin_name), &entry, &options);
+            logging::exit(EXIT_OK);
+        }
+        Some(Command::Verify {
+            archives,
+            content_hashes,
+        }) => {
+            let mut ok = true;
+            for archive in archives {
+                ok &= verify(input(archive, &args.stdin_name), content_hashes, &options);
+            }
+            logging::exit(if ok { EXIT_OK } else { EXIT_PARTIAL });
+        }
+        Some(Command::Diff { old, new, json }) => {
+            let old = input(old, &args.stdin_name);
+            let new = input(new, &args.stdin_name);
+            print_diff(old, new, json, &options);
+            logging::exit(EXIT_OK);
+        }
+        Some(Command::Delta { old, new, output }) => {
+            let old = input(old, &args.stdin_name);
+            let new = input(new, &args.stdin_name);
+            delta(old, new, &output, &options, &write);
+            logging::exit(logging::exit_code());
+        }
+        Some(Command::Chain {
+            archives,
+            output,
+            json,
+        }) => {
+            chain(&archives, output.as_deref(), json, &options, &write);
+            logging::exit(logging::exit_code());
+        }
+        Some(Command::Stats {
+            archives,
+            top,
+            json,
+        }) => {
+            inspect::stats(archives, top, json, &args.stdin_name, &options);
+            logging::exit(EXIT_OK);
+        }
+        Some(Command::Index {
+            archives,
+            index,
+            hash,
+            prune,
+        }) => {
+            index_archives(&archives, &index, hash, prune, &options);
+            logging::exit(logging::exit_code());
+        }
+        Some(Command::Search {
+            name,
+            index,
+            min_size,
+            max_size,
+            hash,
+            json,
+        }) => {
+            let query = IndexQuery {
+                name,
+                min_size,
+                max_size,
+                hash,
+            };
+            search_index(&index, &query, json);
+            logging::exit(EXIT_OK);
+        }
+        Some(Command::Catalog {
+            paths,
+            db,
+            hash,
+            prune,
+        }) => {
+            if let Err(e) = catalog::catalog(&paths, &db, hash, prune, &options) {
+                fatal!("{}", e);
+            }
+            logging::exit(logging::exit_code());
+        }
+        Some(Command::Query {
+            sql,
+            db,
+            games,
+            versions,
+            find,
+            json,
+        }) => {
+            let found = match (games, &versions, &find) {
+                (true, _, _) => catalog::query(&db, catalog::GAMES, &[], json),
+                (_, Some(game), _) => catalog::query(&db, catalog::VERSIONS, &[game], json),
+                (_, _, Some(name)) => catalog::query(&db, catalog::FIND, &[name], json),
+                _ => catalog::query(&db, sql.as_deref().unwrap_or_default(), &[], json),
+            };
+            match found {
+                Ok(0) => logging::exit(EXIT_PARTIAL),
+                Ok(_) => logging::exit(EXIT_OK),
+                Err(e) => fatal!("{}", e),
+            }
+        }
+        Some(Command::Apply {
+            archives,
+            target,
+            backup,
+        }) => {
+            apply::apply_all(archives, &target, backup, &args.stdin_name, &options);
+            logging::exit(logging::exit_code());
         }
+        None => {}
     }
+    apply_config(&mut args, &matches);
+    extract::run(&args, &options, &write);
 }
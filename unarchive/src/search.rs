@@ -0,0 +1,94 @@
+//! `unarchive index` and `unarchive search`, over an `ArchiveIndex` kept in a file.
+
+use crate::logging::{self, error, fatal, info, verbose, EXIT_PARTIAL};
+use k_archives::{find_archives, is_url, ArchiveIndex, ChecksumType, IndexQuery, MountOptions};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+pub(crate) fn index_archives(
+    paths: &[PathBuf],
+    index_path: &Path,
+    hash: Option<ChecksumType>,
+    prune: bool,
+    options: &MountOptions,
+) {
+    let mut index = ArchiveIndex::load(index_path).unwrap_or_else(|e| fatal!("{}", e));
+    let mut archives = Vec::new();
+    for path in paths {
+        match path.is_dir() {
+            true => archives.extend(find_archives(path).unwrap_or_else(|e| {
+                fatal!("failed to search {}: {}", path.display(), e);
+            })),
+            false => archives.push(path.clone()),
+        }
+    }
+    let mut indexed = 0;
+    for archive in &archives {
+        match index.add(archive, options, hash) {
+            Ok(true) => {
+                info!("{}", archive.display());
+                indexed += 1;
+            }
+            Ok(false) => verbose!("{} hasn't changed", archive.display()),
+            Err(e) => error!("failed to index {}: {}", archive.display(), e),
+        }
+    }
+    if prune {
+        let gone: Vec<PathBuf> = index
+            .archives()
+            .iter()
+            .map(|archive| archive.path.clone())
+            .filter(|path| !is_url(path) && !path.exists())
+            .collect();
+        for path in gone {
+            info!("{} is gone", path.display());
+            index.remove(&path);
+        }
+    }
+    if let Err(e) = index.save(index_path) {
+        fatal!("failed to save {}: {}", index_path.display(), e);
+    }
+    info!(
+        "indexed {} of {} archives, {} in {}",
+        indexed,
+        archives.len(),
+        index.archives().len(),
+        index_path.display()
+    );
+}
+
+pub(crate) fn search_index(index_path: &Path, query: &IndexQuery, json: bool) {
+    if !index_path.exists() {
+        fatal!(
+            "there's no index at {}, make one with `unarchive index`",
+            index_path.display()
+        );
+    }
+    let index = ArchiveIndex::load(index_path).unwrap_or_else(|e| fatal!("{}", e));
+    let hits = index.search(query).unwrap_or_else(|e| fatal!("{}", e));
+    if json {
+        let json: Vec<Value> = hits
+            .iter()
+            .map(|(archive, entry)| {
+                json!({
+                    "archive": archive.path.to_string_lossy(),
+                    "path": entry.path,
+                    "size": entry.size,
+                    "hash": entry.hash,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&json).unwrap());
+    } else {
+        for (archive, entry) in &hits {
+            println!(
+                "{}: {} ({} bytes)",
+                archive.path.display(),
+                entry.path,
+                entry.size
+            );
+        }
+    }
+    if hits.is_empty() {
+        logging::exit(EXIT_PARTIAL);
+    }
+}
@@ -0,0 +1,428 @@
+//! `unarchive serve`, the entries of a mounted archive over HTTP: folders as plain listings
+//! and files with Range support, so players and download tools can seek in them. It's also
+//! a read only WebDAV server (OPTIONS and PROPFIND), so Windows and macOS can attach it as a
+//! network drive.
+
+use crate::logging::{info, verbose, warning};
+use k_archives::KArchive;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+// requests handled at once
+const WORKERS: usize = 4;
+
+// the methods answered, for Allow headers
+const ALLOW: &str = "OPTIONS, GET, HEAD, PROPFIND";
+
+struct Entry {
+    name: String,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Default)]
+struct Folder {
+    folders: BTreeSet<String>,
+    files: Vec<Entry>,
+}
+
+// every folder in the archive, "" for the top, so listings don't go over every entry
+fn folders(archive: &KArchive) -> BTreeMap<String, Folder> {
+    let mut folders = BTreeMap::<String, Folder>::new();
+    folders.insert(String::new(), Folder::default());
+    let mut files = archive.list_files();
+    files.sort();
+    for file in files {
+        let name = file.to_string_lossy().replace('\\', "/");
+        let meta = archive.metadata(&file);
+        let (mut parent, file_name) = match name.rsplit_once('/') {
+            Some((parent, file_name)) => (parent.to_string(), file_name.to_string()),
+            None => (String::new(), name.clone()),
+        };
+        folders
+            .entry(parent.clone())
+            .or_default()
+            .files
+            .push(Entry {
+                name: file_name,
+                size: meta.as_ref().map_or(0, |meta| meta.size),
+                modified: meta.and_then(|meta| meta.modified),
+            });
+        // make sure every folder up the tree lists the one below it
+        while !parent.is_empty() {
+            let (grandparent, folder) = match parent.rsplit_once('/') {
+                Some((grandparent, folder)) => (grandparent.to_string(), folder.to_string()),
+                None => (String::new(), parent.clone()),
+            };
+            if !folders
+                .entry(grandparent.clone())
+                .or_default()
+                .folders
+                .insert(folder)
+            {
+                break;
+            }
+            parent = grandparent;
+        }
+    }
+    folders
+}
+
+fn decode_url(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn encode_url(path: &str) -> String {
+    path.bytes()
+        .map(
+            |b| match b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+                true => (b as char).to_string(),
+                false => format!("%{:02X}", b),
+            },
+        )
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value).unwrap()
+}
+
+fn listing(path: &str, folder: &Folder) -> Response<std::io::Cursor<Vec<u8>>> {
+    let title = escape_html(&format!("/{}", path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head><body>\n<h1>{0}</h1>\n<ul>\n",
+        title
+    );
+    if !path.is_empty() {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for name in &folder.folders {
+        html.push_str(&format!(
+            "<li><a href=\"{}/\">{}/</a></li>\n",
+            encode_url(name),
+            escape_html(name)
+        ));
+    }
+    for file in &folder.files {
+        html.push_str(&format!(
+            "<li><a href=\"{}\">{}</a> {} bytes</li>\n",
+            encode_url(&file.name),
+            escape_html(&file.name),
+            file.size
+        ));
+    }
+    html.push_str("</ul>\n</body></html>\n");
+    Response::from_string(html).with_header(header("Content-Type", "text/html; charset=utf-8"))
+}
+
+// the one range in a Range header as (start, length), clamped to `size`. Err if it can't
+// be satisfied, None if there isn't one (or it's several, which get the whole file)
+fn parse_range(range: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // the last n bytes
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        _ => return Ok(None),
+    };
+    if start >= size {
+        return Err(());
+    }
+    Ok(Some((start, end - start + 1)))
+}
+
+fn serve_file(
+    request: Request,
+    archive: &KArchive,
+    entry: &Path,
+    size: u64,
+) -> std::io::Result<()> {
+    let range = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Range"))
+        .map(|header| parse_range(header.value.as_str(), size));
+    let (status, start, len) = match range {
+        Some(Err(())) => {
+            let response = Response::empty(416)
+                .with_header(header("Content-Range", &format!("bytes */{}", size)));
+            return request.respond(response);
+        }
+        Some(Ok(Some((start, len)))) => (206, start, len),
+        _ => (200, 0, size),
+    };
+    let mut file = match archive.open(entry) {
+        Ok(file) => file,
+        Err(e) => {
+            warning!("can't open {}: {}", entry.display(), e);
+            return request.respond(Response::empty(500));
+        }
+    };
+    if start > 0 {
+        file.seek(SeekFrom::Start(start))?;
+    }
+    let mut headers = vec![
+        header("Content-Type", "application/octet-stream"),
+        header("Accept-Ranges", "bytes"),
+    ];
+    if status == 206 {
+        headers.push(header(
+            "Content-Range",
+            &format!("bytes {}-{}/{}", start, start + len - 1, size),
+        ));
+    }
+    let response = Response::new(
+        StatusCode(status),
+        headers,
+        file.take(len),
+        usize::try_from(len).ok(),
+        None,
+    );
+    request.respond(response)
+}
+
+// one resource of a PROPFIND reply. `size` is None for folders
+fn dav_response(href: &str, name: &str, size: Option<u64>, modified: SystemTime) -> String {
+    let kind = match size {
+        Some(size) => format!(
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength><D:getcontenttype>application/octet-stream</D:getcontenttype>",
+            size
+        ),
+        None => "<D:resourcetype><D:collection/></D:resourcetype>".to_string(),
+    };
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname>{}<D:getlastmodified>{}</D:getlastmodified></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+        escape_html(href),
+        escape_html(name),
+        kind,
+        httpdate::fmt_http_date(modified)
+    )
+}
+
+// every property of `path` (and with Depth 1, of what's in it) whatever was asked for,
+// which clients are fine with. Depth infinity is answered like 1 rather than listing the
+// whole archive
+fn propfind(
+    request: Request,
+    archive: &KArchive,
+    folders: &BTreeMap<String, Folder>,
+    path: &str,
+    modified: SystemTime,
+) -> std::io::Result<()> {
+    let depth = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Depth"))
+        .map(|header| header.value.as_str().trim().to_string());
+    let name = |path: &str| path.rsplit('/').next().unwrap_or_default().to_string();
+    let mut xml = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n"
+        .to_string();
+    let folder_path = path.trim_end_matches('/');
+    if let Some(folder) = folders.get(folder_path) {
+        let href = match folder_path.is_empty() {
+            true => "/".to_string(),
+            false => format!("/{}/", folder_path),
+        };
+        xml.push_str(&dav_response(
+            &encode_url(&href),
+            &name(folder_path),
+            None,
+            modified,
+        ));
+        if depth.as_deref() != Some("0") {
+            for sub in &folder.folders {
+                let sub_href = encode_url(&format!("{}{}/", href, sub));
+                xml.push_str(&dav_response(&sub_href, sub, None, modified));
+            }
+            for file in &folder.files {
+                let file_href = encode_url(&format!("{}{}", href, file.name));
+                let file_modified = file.modified.unwrap_or(modified);
+                xml.push_str(&dav_response(
+                    &file_href,
+                    &file.name,
+                    Some(file.size),
+                    file_modified,
+                ));
+            }
+        }
+    } else if let Some(meta) = archive.metadata(Path::new(path)) {
+        let href = encode_url(&format!("/{}", path));
+        let file_modified = meta.modified.unwrap_or(modified);
+        xml.push_str(&dav_response(
+            &href,
+            &name(path),
+            Some(meta.size),
+            file_modified,
+        ));
+    } else {
+        return request.respond(Response::empty(404));
+    }
+    xml.push_str("</D:multistatus>\n");
+    let response = Response::from_string(xml)
+        .with_status_code(207)
+        .with_header(header("Content-Type", "application/xml; charset=utf-8"));
+    request.respond(response)
+}
+
+fn handle(
+    request: Request,
+    archive: &KArchive,
+    folders: &BTreeMap<String, Folder>,
+    modified: SystemTime,
+) {
+    let url = request
+        .url()
+        .split(['?', '#'])
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    verbose!("{} {}", request.method(), url);
+    let Some(path) = decode_url(&url) else {
+        let _ = request.respond(Response::empty(400));
+        return;
+    };
+    let path = path.trim_start_matches('/');
+    let result = match request.method() {
+        Method::Get | Method::Head => get(request, archive, folders, path),
+        // class 1 only: there are no locks to take when nothing can be written
+        Method::Options => request.respond(
+            Response::empty(200)
+                .with_header(header("DAV", "1"))
+                .with_header(header("Allow", ALLOW))
+                .with_header(header("MS-Author-Via", "DAV")),
+        ),
+        Method::NonStandard(method) if method.as_str() == "PROPFIND" => {
+            propfind(request, archive, folders, path, modified)
+        }
+        // PUT, DELETE, MKCOL, LOCK and the rest
+        _ => request.respond(Response::empty(405).with_header(header("Allow", ALLOW))),
+    };
+    // mostly clients that hung up partway
+    if let Err(e) = result {
+        verbose!("{}: {}", url, e);
+    }
+}
+
+fn get(
+    request: Request,
+    archive: &KArchive,
+    folders: &BTreeMap<String, Folder>,
+    path: &str,
+) -> std::io::Result<()> {
+    if let Some(folder) = folders.get(path.trim_end_matches('/')) {
+        match path.is_empty() || path.ends_with('/') {
+            true => request.respond(listing(path, folder)),
+            // relative links in the listing only work from under the folder
+            false => {
+                let location = format!("{}/", encode_url(&format!("/{}", path)));
+                request.respond(Response::empty(301).with_header(header("Location", &location)))
+            }
+        }
+    } else {
+        let entry = PathBuf::from(path);
+        match archive.metadata(&entry) {
+            Some(meta) => serve_file(request, archive, &entry, meta.size),
+            None => request.respond(Response::empty(404)),
+        }
+    }
+}
+
+/// Serves `archive` over HTTP on `listen` (eg. 0.0.0.0:8080) until killed. Folders and
+/// entries that don't store a time are shown as last modified at `modified`.
+pub(crate) fn serve(archive: &KArchive, listen: &str, modified: SystemTime) -> Result<(), String> {
+    let server = Server::http(listen).map_err(|e| format!("can't listen on {}: {}", listen, e))?;
+    let folders = folders(archive);
+    info!("serving on http://{}/", listen);
+    std::thread::scope(|scope| {
+        for _ in 0..WORKERS {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    handle(request, archive, &folders, modified);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), Ok(Some((0, 10))));
+        // past the end gets clamped
+        assert_eq!(parse_range("bytes=95-200", 100), Ok(Some((95, 5))));
+        // open ended
+        assert_eq!(parse_range("bytes=90-", 100), Ok(Some((90, 10))));
+        // suffix, the last n bytes
+        assert_eq!(parse_range("bytes=-10", 100), Ok(Some((90, 10))));
+        assert_eq!(parse_range("bytes=-200", 100), Ok(Some((0, 100))));
+        assert_eq!(parse_range(" bytes= 10 - 19 ", 100), Ok(Some((10, 10))));
+
+        // unsatisfiable
+        assert_eq!(parse_range("bytes=100-", 100), Err(()));
+        assert_eq!(parse_range("bytes=150-160", 100), Err(()));
+        assert_eq!(parse_range("bytes=-5", 0), Err(()));
+
+        // several ranges, or ones that don't make sense, get the whole file
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), Ok(None));
+        assert_eq!(parse_range("bytes=9-0", 100), Ok(None));
+        assert_eq!(parse_range("bytes=-0", 100), Ok(None));
+        assert_eq!(parse_range("bytes=5", 100), Ok(None));
+        assert_eq!(parse_range("items=0-9", 100), Ok(None));
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(decode_url("data/a.bin").as_deref(), Some("data/a.bin"));
+        assert_eq!(
+            decode_url("a%20b/%E3%81%82.bin").as_deref(),
+            Some("a b/\u{3042}.bin")
+        );
+        // encoding and decoding gets the same name back
+        let name = "folder/with space/100%/\u{3042}#.bin";
+        assert_eq!(decode_url(&encode_url(name)).as_deref(), Some(name));
+
+        // cut off, not hex, or not utf-8
+        assert_eq!(decode_url("a%2"), None);
+        assert_eq!(decode_url("a%"), None);
+        assert_eq!(decode_url("a%zz"), None);
+        assert_eq!(decode_url("%FF"), None);
+    }
+}
@@ -0,0 +1,35 @@
+//! Archives piped in on stdin. The parsers seek around and reopen archives by path, so stdin is
+//! copied into a temporary file (under $TMPDIR) first, which is removed again on exit.
+
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tempfile::TempDir;
+
+// the folder the copy is in, once stdin has been read. it gets a random name (and only the
+// current user can get into it), so nobody else can put anything there ahead of time
+static SPOOLED: Mutex<Option<TempDir>> = Mutex::new(None);
+
+/// Copies stdin into a temporary file called `name` and returns its path. `name` matters
+/// for the formats picked by extension (2dx, d2).
+pub(crate) fn spool(name: &str) -> std::io::Result<PathBuf> {
+    let mut spooled = SPOOLED.lock().unwrap();
+    if spooled.is_some() {
+        return Err(Error::other("stdin can only be read once"));
+    }
+    let dir = spooled.insert(tempfile::Builder::new().prefix("unarchive-").tempdir()?);
+    let name = Path::new(name).file_name().unwrap_or("stdin".as_ref());
+    let path = dir.path().join(name);
+    let mut out = BufWriter::new(File::create(&path)?);
+    std::io::copy(&mut std::io::stdin().lock(), &mut out)?;
+    out.flush()?;
+    Ok(path)
+}
+
+/// Removes the copy of stdin, if there is one.
+pub(crate) fn remove() {
+    if let Some(dir) = SPOOLED.lock().unwrap().take() {
+        let _ = dir.close();
+    }
+}
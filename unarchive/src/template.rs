@@ -0,0 +1,77 @@
+//! --output-template: naming output folders after the game and version an archive is for.
+
+use k_archives::{KArchive, UpdateInfo};
+use std::path::Path;
+
+pub(crate) const TEMPLATE_FIELDS: [&str; 4] = ["stem", "game", "datecode", "version"];
+
+// fills in the {placeholders} of an --output-template
+pub(crate) fn render_template(template: &str, fields: &[(&str, String)]) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered += &rest[..start];
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed {{ in output template {}", template))?;
+        let name = &rest[start + 1..start + end];
+        let (_, value) = fields
+            .iter()
+            .find(|(field, _)| *field == name)
+            .ok_or_else(|| format!("unknown placeholder {{{}}} in output template", name))?;
+        rendered += value;
+        rest = &rest[start + end + 1..];
+    }
+    Ok(rendered + rest)
+}
+
+// the output template fields for `archive`, read from `path`
+pub(crate) fn template_fields(path: &Path, archive: &KArchive) -> Vec<(&'static str, String)> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let info = archive
+        .update_info()
+        .cloned()
+        .unwrap_or_else(|| UpdateInfo::from_name(&name));
+    let version = info
+        .datecode
+        .as_ref()
+        .and_then(|datecode| datecode.get(..8));
+    let values = [
+        Some(
+            path.file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into(),
+        ),
+        info.game_code.clone(),
+        info.datecode.clone(),
+        version.map(str::to_string),
+    ];
+    TEMPLATE_FIELDS
+        .into_iter()
+        .zip(values)
+        .map(|(field, value)| (field, value.unwrap_or_else(|| "unknown".into())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates() {
+        let fields = [
+            ("game", "KFC".to_string()),
+            ("datecode", "2023020100".to_string()),
+        ];
+        assert_eq!(
+            render_template("{game}/{game}-{datecode}", &fields).unwrap(),
+            "KFC/KFC-2023020100"
+        );
+        assert_eq!(render_template("plain", &fields).unwrap(), "plain");
+        assert_eq!(render_template("a}b", &fields).unwrap(), "a}b");
+        assert!(render_template("{game", &fields).is_err());
+        assert!(render_template("{nope}", &fields).is_err());
+        assert!(render_template("{}", &fields).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! The subcommands for working with a game's updates: delta, chain and decrypt.
+
+use crate::extract::extract_all;
+use crate::logging::{fatal, info, verbose};
+use crate::mount;
+use k_archives::{decrypt_mar_payload, diff, MountOptions, UpdateChain, WriteOptions};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+fn decrypt(file: &Path, name: &str, output: &Path) -> std::io::Result<()> {
+    let input = File::open(file)?;
+    let size = input.metadata()?.len();
+    let out = BufWriter::new(File::create(output)?);
+    decrypt_mar_payload(name, size, BufReader::new(input), out)
+}
+
+pub(crate) fn delta(
+    old_path: PathBuf,
+    new_path: PathBuf,
+    output: &Path,
+    options: &MountOptions,
+    write: &WriteOptions,
+) {
+    let old = mount(old_path.clone(), options);
+    let new = mount(new_path.clone(), options);
+    let changes = diff(&old, &new).unwrap_or_else(|e| {
+        fatal!(
+            "failed to compare {} and {}: {}",
+            old_path.display(),
+            new_path.display(),
+            e
+        )
+    });
+    let mut files = changes.added.clone();
+    files.extend(changes.changed.iter().cloned());
+    files.sort();
+    extract_all(&new, &files, output, write);
+    for path in &changes.removed {
+        verbose!("removed {}", path.display());
+    }
+    info!(
+        "{} added, {} changed, {} removed, {} unchanged",
+        changes.added.len(),
+        changes.changed.len(),
+        changes.removed.len(),
+        changes.unchanged
+    );
+}
+
+pub(crate) fn chain(
+    archives: &[PathBuf],
+    output: Option<&Path>,
+    json: bool,
+    options: &MountOptions,
+    write: &WriteOptions,
+) {
+    let chain = UpdateChain::resolve(archives, options).unwrap_or_else(|e| fatal!("{}", e));
+    for link in chain.links() {
+        verbose!(
+            "{} {}",
+            link.info.datecode.as_deref().unwrap_or_default(),
+            link.path.display()
+        );
+    }
+    let Some(output) = output else {
+        if json {
+            let links: Vec<Value> = chain
+                .links()
+                .iter()
+                .map(|link| {
+                    json!({
+                        "path": link.path.to_string_lossy(),
+                        "game": link.info.game_code,
+                        "datecode": link.info.datecode,
+                    })
+                })
+                .collect();
+            let files: Vec<Value> = chain
+                .files()
+                .map(|(path, link)| {
+                    json!({
+                        "path": path.to_string_lossy().replace('\\', "/"),
+                        "size": chain.archive().metadata(path).map_or(0, |meta| meta.size),
+                        "from": link.path.to_string_lossy(),
+                    })
+                })
+                .collect();
+            let json = json!({"updates": links, "files": files});
+            println!("{}", serde_json::to_string_pretty(&json).unwrap());
+        } else {
+            for (path, link) in chain.files() {
+                println!(
+                    "{}\t{}",
+                    path.display(),
+                    link.info.datecode.as_deref().unwrap_or_default()
+                );
+            }
+        }
+        return;
+    };
+    let archive = chain.archive();
+    let mut files = archive.list_files();
+    files.sort();
+    extract_all(archive, &files, output, write);
+    info!("{} files from {} updates", files.len(), chain.links().len());
+}
+
+/// `unarchive decrypt`: writes the deciphered payload next to `file` unless `output` says where.
+pub(crate) fn decrypt_to(file: &Path, name: &str, output: Option<PathBuf>) {
+    let output = output.unwrap_or_else(|| {
+        let mut name = file.to_path_buf().into_os_string();
+        name.push(".decrypted");
+        name.into()
+    });
+    if let Err(e) = decrypt(file, name, &output) {
+        fatal!("failed to decrypt {}: {}", file.display(), e);
+    }
+    info!("{}", output.display());
+}